@@ -0,0 +1,44 @@
+//! `/admin/*` の管理 API。`--admin-token` を指定したときだけ有効になる。
+use crate::AppData;
+use actix_web::http::header;
+use actix_web::HttpRequest;
+use clap::Parser;
+use sha2::{Digest, Sha256};
+
+#[derive(Parser)]
+pub struct AdminOption {
+    /// Bearer token for the `/admin` endpoints, which are disabled when omitted
+    #[arg(long)]
+    admin_token: Option<String>,
+}
+
+impl AdminOption {
+    pub fn is_enabled(&self) -> bool {
+        self.admin_token.is_some()
+    }
+
+    /// Whether `req` carries the admin token. Always false when the endpoints are disabled.
+    pub fn authorize(&self, req: &HttpRequest) -> bool {
+        let Some(token) = &self.admin_token else {
+            return false;
+        };
+        let Some(given) = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+        else {
+            return false;
+        };
+        // ハッシュ同士を比べ、一致した長さからトークンを推測されないようにする
+        Sha256::digest(given.as_bytes()) == Sha256::digest(token.as_bytes())
+    }
+}
+
+/// Purges the cached outputs of every source whose key starts with `prefix`. Returns the
+/// numbers of sources and outputs purged.
+pub fn purge_prefix(app_data: &AppData, prefix: &str) -> std::io::Result<(usize, usize)> {
+    let keys = app_data.store.keys(prefix)?;
+    let outputs = keys.iter().map(|key| app_data.cache.purge(key, None)).sum();
+    Ok((keys.len(), outputs))
+}
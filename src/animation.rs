@@ -0,0 +1,391 @@
+use crate::encode::{EncodeQuality, OutputFormat};
+use crate::frame_scorer;
+use crate::jobs::{self, Stage};
+use crate::movie_keyframe;
+use anyhow::Context;
+use ffmpeg::codec;
+use ffmpeg::format::Pixel;
+use ffmpeg::software::scaling::{context::Context as ScalingContext, flag::Flags};
+use ffmpeg::util::frame::video::Video as FfmpegFrame;
+use ffmpeg_next as ffmpeg;
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::{AnimationDecoder, DynamicImage, Frames, RgbaImage};
+use scopeguard::guard;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use webp::{AnimEncoder, AnimFrame, WebPConfig};
+
+/// ポスターフレーム選びでスコアを計算するフレーム数の上限
+const MAX_POSTER_CANDIDATES: usize = 30;
+
+/// 動画から作るアニメーションのフレームレートの上限。超える分は間引く
+const MAX_VIDEO_FPS: i64 = 15;
+
+/// ffmpeg の AVIF muxer はファイルにしか書けないので一時ファイルを使う
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// 合成済みのフレームと表示時間 (ms)
+pub struct AnimationFrame {
+    pub image: RgbaImage,
+    pub delay_ms: u32,
+}
+
+pub fn is_animation_ext(ext: &str) -> bool {
+    matches!(ext.to_lowercase().as_str(), "gif" | "png" | "apng" | "webp")
+}
+
+/// アニメーションでなければ `None` (APNG でない PNG など)
+fn open_frames(path: &Path) -> Result<Option<Frames<'static>>, anyhow::Error> {
+    let ext = path
+        .extension()
+        .and_then(OsStr::to_str)
+        .unwrap_or("")
+        .to_lowercase();
+    let reader = BufReader::new(File::open(path)?);
+    match ext.as_str() {
+        "gif" => Ok(Some(GifDecoder::new(reader)?.into_frames())),
+        "png" | "apng" => {
+            let decoder = PngDecoder::new(reader)?;
+            if !decoder.is_apng()? {
+                return Ok(None);
+            }
+            Ok(Some(decoder.apng()?.into_frames()))
+        }
+        "webp" => {
+            let decoder = WebPDecoder::new(reader)?;
+            if !decoder.has_animation() {
+                return Ok(None);
+            }
+            Ok(Some(decoder.into_frames()))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// `max_frames` を超えるフレームは捨てる
+pub fn load_frames(path: &Path, max_frames: usize) -> Result<Vec<AnimationFrame>, anyhow::Error> {
+    let Some(decoded) = open_frames(path)? else {
+        return Ok(Vec::new());
+    };
+    let mut frames = Vec::new();
+    for frame in decoded.take(max_frames) {
+        let frame = frame?;
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        frames.push(AnimationFrame {
+            delay_ms: numer / denom.max(1),
+            image: frame.into_buffer(),
+        });
+    }
+    Ok(frames)
+}
+
+/// 先頭フレームではなく `movie_keyframe` と同じスコアが最も高いフレームを選ぶ
+pub fn load_poster_frame(path: &Path) -> Result<Option<DynamicImage>, anyhow::Error> {
+    let Some(decoded) = open_frames(path)? else {
+        return Ok(None);
+    };
+    let mut best: Option<(f32, DynamicImage)> = None;
+    for frame in decoded.take(MAX_POSTER_CANDIDATES) {
+        let image = DynamicImage::ImageRgba8(frame?.into_buffer());
+        let score = frame_scorer::compute_frame_score(&image);
+        if best
+            .as_ref()
+            .is_none_or(|(best_score, _)| score > *best_score)
+        {
+            best = Some((score, image));
+        }
+    }
+    Ok(best.map(|(_, image)| image))
+}
+
+/// 動画を先頭から `max_frames` 枚まで RGBA にする。長辺は `max_size` に縮め、
+/// `MAX_VIDEO_FPS` を超えるフレームは間引く。`max_duration` 秒より長い動画は `None`。
+pub fn load_video_frames(
+    path: &Path,
+    max_frames: usize,
+    max_duration: f64,
+    max_size: u32,
+    stream: Option<usize>,
+) -> Result<Option<Vec<AnimationFrame>>, anyhow::Error> {
+    ffmpeg::init().ok(); // Ignore re-init
+
+    let mut ictx = ffmpeg::format::input(&path)?;
+    let duration = ictx.duration() as f64 / movie_keyframe::AV_TIME_BASE;
+    if duration <= 0.0 || duration > max_duration {
+        return Ok(None);
+    }
+    let input = movie_keyframe::select_video_stream(&ictx, stream)?;
+    let video_stream_index = input.index();
+    let time_base = input.time_base();
+    let rotation = movie_keyframe::display_rotation(&input);
+
+    let (mut decoder, format) = movie_keyframe::open_decoder(&input)?;
+
+    let (width, height) = movie_keyframe::fit_within(decoder.width(), decoder.height(), max_size);
+    let mut scaler = ScalingContext::get(
+        format,
+        decoder.width(),
+        decoder.height(),
+        Pixel::RGBA,
+        width,
+        height,
+        Flags::BILINEAR,
+    )?;
+
+    // (表示開始時刻 ms, 画像)
+    let mut frames: Vec<(i64, RgbaImage)> = Vec::new();
+    let mut next_ms = 0;
+    let mut receive = |decoder: &mut ffmpeg::decoder::Video,
+                       frames: &mut Vec<(i64, RgbaImage)>|
+     -> Result<(), anyhow::Error> {
+        let mut decoded = FfmpegFrame::empty();
+        while frames.len() < max_frames && decoder.receive_frame(&mut decoded).is_ok() {
+            let timestamp_ms =
+                decoded.timestamp().unwrap_or(0) * 1000 * i64::from(time_base.numerator())
+                    / i64::from(time_base.denominator()).max(1);
+            if !frames.is_empty() && timestamp_ms < next_ms {
+                continue;
+            }
+            next_ms = timestamp_ms + 1000 / MAX_VIDEO_FPS;
+            let mut rgba = FfmpegFrame::empty();
+            scaler.run(&decoded, &mut rgba)?;
+            let image = DynamicImage::ImageRgba8(frame_to_rgba_image(&rgba)?);
+            frames.push((
+                timestamp_ms,
+                movie_keyframe::rotate(image, rotation).into_rgba8(),
+            ));
+        }
+        Ok(())
+    };
+
+    for (stream, packet) in ictx.packets() {
+        if stream.index() != video_stream_index {
+            continue;
+        }
+        decoder.send_packet(&packet)?;
+        receive(&mut decoder, &mut frames)?;
+        if frames.len() >= max_frames {
+            break;
+        }
+    }
+    decoder.send_eof()?;
+    receive(&mut decoder, &mut frames)?;
+
+    let end_ms = (duration * 1000.0) as i64;
+    let mut result = Vec::with_capacity(frames.len());
+    let mut frames = frames.into_iter().peekable();
+    while let Some((start_ms, image)) = frames.next() {
+        let next_ms = frames.peek().map_or(end_ms, |(ms, _)| *ms);
+        result.push(AnimationFrame {
+            delay_ms: (next_ms - start_ms).max(1000 / MAX_VIDEO_FPS) as u32,
+            image,
+        });
+    }
+    Ok(Some(result))
+}
+
+fn frame_to_rgba_image(frame: &FfmpegFrame) -> Result<RgbaImage, anyhow::Error> {
+    let (width, height) = (frame.width(), frame.height());
+    let data = frame.data(0);
+    let stride = frame.stride(0);
+    let row = width as usize * 4;
+    let mut buf = Vec::with_capacity(row * height as usize);
+    for y in 0..height as usize {
+        buf.extend_from_slice(&data[y * stride..y * stride + row]);
+    }
+    RgbaImage::from_raw(width, height, buf).context("Failed to build ImageBuffer")
+}
+
+/// Encodes composited frames into an animated image file.
+pub trait SequenceEncoder {
+    fn encode(&self, frames: &[AnimationFrame]) -> Result<Vec<u8>, anyhow::Error>;
+}
+
+/// Returns `None` for output formats that cannot hold an animation.
+pub fn sequence_encoder(
+    format: OutputFormat,
+    quality: &EncodeQuality,
+) -> Option<Box<dyn SequenceEncoder>> {
+    match format {
+        OutputFormat::WebP => Some(Box::new(WebPSequence {
+            quality: quality.webp,
+        })),
+        OutputFormat::Avif => Some(Box::new(AvifSequence {
+            quality: quality.avif,
+            speed: quality.avif_speed,
+        })),
+        _ => None,
+    }
+}
+
+/// Whether any frame has a pixel that is not fully opaque.
+pub fn has_transparency(frames: &[AnimationFrame]) -> bool {
+    frames
+        .iter()
+        .any(|frame| frame.image.pixels().any(|p| p[3] < u8::MAX))
+}
+
+pub struct WebPSequence {
+    pub quality: f32,
+}
+
+impl SequenceEncoder for WebPSequence {
+    fn encode(&self, frames: &[AnimationFrame]) -> Result<Vec<u8>, anyhow::Error> {
+        encode_webp(frames, self.quality)
+    }
+}
+
+/// ffmpeg の AV1 エンコーダと `avif` muxer による AVIF シーケンス。アルファは持てないので
+/// 透過部分は白で塗る
+pub struct AvifSequence {
+    /// 1 - 100, mapped to the AV1 CRF
+    pub quality: u8,
+    /// 1 (slowest) - 10 (fastest), mapped to libaom `cpu-used`
+    pub speed: u8,
+}
+
+/// AV1 の CRF の最大値
+const AV1_MAX_CRF: u32 = 63;
+/// フレームの pts の単位 (ms)
+const AVIF_TIME_BASE: (i32, i32) = (1, 1000);
+
+impl SequenceEncoder for AvifSequence {
+    fn encode(&self, frames: &[AnimationFrame]) -> Result<Vec<u8>, anyhow::Error> {
+        ffmpeg::init().ok(); // Ignore re-init
+
+        let first = frames.first().context("No frames")?;
+        // 4:2:0 に落とすので奇数の辺は 1px 削る
+        let width = first.image.width() & !1;
+        let height = first.image.height() & !1;
+        anyhow::ensure!(
+            width > 0 && height > 0,
+            "Too small for AVIF: {}x{}",
+            width,
+            height
+        );
+
+        let temp_path = std::env::temp_dir().join(format!(
+            "media_converter-anim-{}-{}.avif",
+            std::process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let temp_path = guard(temp_path, |p| {
+            let _ = std::fs::remove_file(p);
+        });
+
+        let mut octx = ffmpeg::format::output_as(&*temp_path, "avif")?;
+        let global_header = octx
+            .format()
+            .flags()
+            .contains(ffmpeg::format::Flags::GLOBAL_HEADER);
+        let av1 = ffmpeg::encoder::find_by_name("libaom-av1")
+            .or_else(|| ffmpeg::encoder::find(codec::Id::AV1))
+            .context("No AV1 encoder available")?;
+
+        let mut encoder = codec::Context::new_with_codec(av1).encoder().video()?;
+        encoder.set_width(width);
+        encoder.set_height(height);
+        encoder.set_format(Pixel::YUV420P);
+        encoder.set_time_base(AVIF_TIME_BASE);
+        if global_header {
+            encoder.set_flags(codec::Flags::GLOBAL_HEADER);
+        }
+        let crf = (100 - u32::from(self.quality.clamp(1, 100))) * AV1_MAX_CRF / 99;
+        let mut options = ffmpeg::Dictionary::new();
+        options.set("crf", &crf.to_string());
+        options.set("cpu-used", &self.speed.min(8).to_string());
+        let mut encoder = encoder.open_with(options)?;
+
+        let stream_index = {
+            let mut ost = octx.add_stream(av1)?;
+            ost.set_time_base(AVIF_TIME_BASE);
+            ost.set_parameters(&encoder);
+            ost.index()
+        };
+        octx.write_header()?;
+        let ost_time_base = octx
+            .stream(stream_index)
+            .context("No output stream")?
+            .time_base();
+
+        let mut scaler = ScalingContext::get(
+            Pixel::RGBA,
+            width,
+            height,
+            Pixel::YUV420P,
+            width,
+            height,
+            Flags::BILINEAR,
+        )?;
+        let write_packets = |encoder: &mut ffmpeg::encoder::Video,
+                             octx: &mut ffmpeg::format::context::Output|
+         -> Result<(), anyhow::Error> {
+            let mut packet = ffmpeg::Packet::empty();
+            while encoder.receive_packet(&mut packet).is_ok() {
+                packet.set_stream(stream_index);
+                packet.rescale_ts(AVIF_TIME_BASE, ost_time_base);
+                packet.write_interleaved(octx)?;
+            }
+            Ok(())
+        };
+
+        let mut timestamp = 0;
+        for frame in frames {
+            let mut rgba = FfmpegFrame::new(Pixel::RGBA, width, height);
+            let stride = rgba.stride(0);
+            let data = rgba.data_mut(0);
+            for (y, pixels) in frame.image.rows().take(height as usize).enumerate() {
+                for (x, pixel) in pixels.take(width as usize).enumerate() {
+                    let offset = y * stride + x * 4;
+                    data[offset..offset + 4].copy_from_slice(&over_white(pixel.0));
+                }
+            }
+            let mut yuv = FfmpegFrame::empty();
+            scaler.run(&rgba, &mut yuv)?;
+            yuv.set_pts(Some(timestamp));
+            encoder.send_frame(&yuv)?;
+            write_packets(&mut encoder, &mut octx)?;
+            timestamp += i64::from(frame.delay_ms.max(1));
+        }
+        encoder.send_eof()?;
+        write_packets(&mut encoder, &mut octx)?;
+        octx.write_trailer()?;
+        drop(octx);
+
+        Ok(std::fs::read(&*temp_path)?)
+    }
+}
+
+fn over_white([r, g, b, a]: [u8; 4]) -> [u8; 4] {
+    let blend = |c: u8| ((u32::from(c) * u32::from(a) + 255 * (255 - u32::from(a))) / 255) as u8;
+    [blend(r), blend(g), blend(b), u8::MAX]
+}
+
+pub fn encode_webp(frames: &[AnimationFrame], quality: f32) -> Result<Vec<u8>, anyhow::Error> {
+    jobs::report(Stage::Encoding, Some(frames.len() as u64), None);
+    let first = frames.first().context("No frames")?;
+    let mut config = WebPConfig::new().map_err(|_| anyhow::anyhow!("Invalid WebPConfig"))?;
+    config.quality = quality;
+
+    let mut encoder = AnimEncoder::new(first.image.width(), first.image.height(), &config);
+    encoder.set_loop_count(0);
+    let mut timestamp = 0;
+    for frame in frames {
+        encoder.add_frame(AnimFrame::from_rgba(
+            frame.image.as_raw(),
+            frame.image.width(),
+            frame.image.height(),
+            timestamp,
+        ));
+        timestamp += frame.delay_ms as i32;
+    }
+    let data = encoder
+        .try_encode()
+        .map_err(|err| anyhow::anyhow!("{:?}", err))?;
+    Ok(data.to_vec())
+}
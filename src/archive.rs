@@ -0,0 +1,195 @@
+use anyhow::Context;
+use image::DynamicImage;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use zip::ZipArchive;
+
+pub const OFFICE_EXTENSIONS: &[&str] = &["docx", "xlsx", "pptx", "odt", "ods", "odp"];
+
+pub const IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "gif", "webp", "avif", "bmp"];
+
+/// zip bomb 対策: 1 エントリあたりの展開サイズ上限
+const MAX_ENTRY_BYTES: u64 = 64 * 1024 * 1024;
+
+fn is_image(name: &str) -> bool {
+    Path::new(name)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| IMAGE_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+}
+
+/// 名前が `cover.*` の画像があればそれを、無ければ名前順で最初の画像を選ぶ。
+fn pick_cover<'a>(names: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let mut images: Vec<&str> = names.filter(|name| is_image(name)).collect();
+    images.sort_by_key(|name| name.to_lowercase());
+    images
+        .iter()
+        .find(|name| {
+            Path::new(name)
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .is_some_and(|stem| stem.eq_ignore_ascii_case("cover"))
+        })
+        .or(images.first())
+        .copied()
+}
+
+fn read_entry(archive: &mut ZipArchive<File>, name: &str) -> Result<Vec<u8>, anyhow::Error> {
+    let entry = archive
+        .by_name(name)
+        .with_context(|| format!("Missing entry {}", name))?;
+    let mut data = Vec::new();
+    entry.take(MAX_ENTRY_BYTES).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+pub fn load_image_from_cbz(path: &Path) -> Result<DynamicImage, anyhow::Error> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    let names: Vec<String> = archive.file_names().map(str::to_string).collect();
+    let cover = pick_cover(names.iter().map(String::as_str)).context("No image in archive")?;
+    let data = read_entry(&mut archive, cover)?;
+    Ok(image::load_from_memory(&data)?)
+}
+
+/// Krita は統合済みの `mergedimage.png` を保存している (古いファイルは `preview.png` のみ)
+pub fn load_image_from_kra(path: &Path) -> Result<DynamicImage, anyhow::Error> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    let name = ["mergedimage.png", "preview.png"]
+        .into_iter()
+        .find(|name| archive.index_for_name(name).is_some())
+        .context("No merged image in archive")?;
+    let data = read_entry(&mut archive, name)?;
+    Ok(image::load_from_memory(&data)?)
+}
+
+/// OOXML は `_rels/.rels` の thumbnail リレーション (通常 `docProps/thumbnail.jpeg`)、
+/// ODF は `Thumbnails/thumbnail.png` に保存時のサムネイルを持っている
+pub fn load_image_from_office(path: &Path) -> Result<DynamicImage, anyhow::Error> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    let name = match find_ooxml_thumbnail(&mut archive) {
+        Ok(name) => name,
+        Err(err) => {
+            log::debug!("{}: {}", path.display(), err);
+            "Thumbnails/thumbnail.png".to_string()
+        }
+    };
+    let data = read_entry(&mut archive, &name)?;
+    // WMF/EMF のサムネイルはデコードできない
+    Ok(image::load_from_memory(&data)?)
+}
+
+fn find_ooxml_thumbnail(archive: &mut ZipArchive<File>) -> Result<String, anyhow::Error> {
+    let rels = String::from_utf8(read_entry(archive, "_rels/.rels")?)?;
+    let rels = roxmltree::Document::parse(&rels)?;
+    let target = rels
+        .descendants()
+        .find(|node| {
+            node.has_tag_name("Relationship")
+                && node
+                    .attribute("Type")
+                    .is_some_and(|t| t.ends_with("/metadata/thumbnail"))
+        })
+        .and_then(|node| node.attribute("Target"))
+        .context("No thumbnail relationship")?;
+    // Target はパッケージルートからの相対パス (先頭の `/` は絶対パス)
+    Ok(resolve_href("", target))
+}
+
+pub fn load_image_from_epub(path: &Path) -> Result<DynamicImage, anyhow::Error> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    let cover = match find_epub_cover(&mut archive) {
+        Ok(cover) => cover,
+        Err(err) => {
+            // OPF から辿れない場合は画像ファイルから推測する
+            log::debug!("{}: {}", path.display(), err);
+            let names: Vec<String> = archive.file_names().map(str::to_string).collect();
+            pick_cover(names.iter().map(String::as_str))
+                .context("No image in archive")?
+                .to_string()
+        }
+    };
+    let data = read_entry(&mut archive, &cover)?;
+    Ok(image::load_from_memory(&data)?)
+}
+
+/// META-INF/container.xml → OPF → カバー画像のパスを返す。
+fn find_epub_cover(archive: &mut ZipArchive<File>) -> Result<String, anyhow::Error> {
+    let container = String::from_utf8(read_entry(archive, "META-INF/container.xml")?)?;
+    let container = roxmltree::Document::parse(&container)?;
+    let opf_path = container
+        .descendants()
+        .find(|node| node.has_tag_name("rootfile"))
+        .and_then(|node| node.attribute("full-path"))
+        .context("No rootfile in container.xml")?
+        .to_string();
+
+    let opf = String::from_utf8(read_entry(archive, &opf_path)?)?;
+    let opf = roxmltree::Document::parse(&opf)?;
+    let items: Vec<_> = opf
+        .descendants()
+        .filter(|node| node.has_tag_name("item"))
+        .collect();
+
+    // EPUB 3: properties="cover-image"
+    let epub3 = items.iter().find(|item| {
+        item.attribute("properties")
+            .is_some_and(|props| props.split_whitespace().any(|p| p == "cover-image"))
+    });
+    // EPUB 2: <meta name="cover" content="ITEM_ID"/>
+    let epub2 = || {
+        let id = opf
+            .descendants()
+            .find(|node| node.has_tag_name("meta") && node.attribute("name") == Some("cover"))
+            .and_then(|node| node.attribute("content"))?;
+        items.iter().find(|item| item.attribute("id") == Some(id))
+    };
+    let href = epub3
+        .or_else(epub2)
+        .and_then(|item| item.attribute("href"))
+        .context("No cover item in OPF")?;
+
+    // href は OPF からの相対パス
+    let base = opf_path.rsplit_once('/').map(|(dir, _)| dir).unwrap_or("");
+    Ok(resolve_href(base, href))
+}
+
+fn resolve_href(base: &str, href: &str) -> String {
+    let mut parts: Vec<&str> = base.split('/').filter(|s| !s.is_empty()).collect();
+    for segment in href.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            _ => parts.push(segment),
+        }
+    }
+    parts.join("/")
+}
+
+#[cfg(feature = "cbr")]
+pub fn load_image_from_cbr(path: &Path) -> Result<DynamicImage, anyhow::Error> {
+    let names: Vec<String> = unrar::Archive::new(path)
+        .open_for_listing()?
+        .filter_map(|header| header.ok())
+        .filter(|header| header.is_file())
+        .map(|header| header.filename.to_string_lossy().into_owned())
+        .collect();
+    let cover = pick_cover(names.iter().map(String::as_str)).context("No image in archive")?;
+
+    let mut archive = unrar::Archive::new(path).open_for_processing()?;
+    while let Some(header) = archive.read_header()? {
+        if header.entry().filename.to_string_lossy() == cover {
+            anyhow::ensure!(
+                header.entry().unpacked_size <= MAX_ENTRY_BYTES,
+                "Entry too large: {}",
+                cover
+            );
+            let (data, _) = header.read()?;
+            return Ok(image::load_from_memory(&data)?);
+        }
+        archive = header.skip()?;
+    }
+    anyhow::bail!("Missing entry {}", cover)
+}
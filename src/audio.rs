@@ -0,0 +1,205 @@
+use crate::color::HexColor;
+use anyhow::{Context, Result};
+use clap::Parser;
+use ffmpeg::codec;
+use ffmpeg::format::input;
+use ffmpeg::format::stream::Disposition;
+use ffmpeg::format::{sample, Sample};
+use ffmpeg::util::frame::audio::Audio as AudioFrame;
+use ffmpeg::ChannelLayout;
+use ffmpeg_next as ffmpeg;
+use image::{DynamicImage, Rgb, RgbImage};
+use imageproc::drawing::{draw_filled_ellipse_mut, draw_filled_rect_mut};
+use imageproc::rect::Rect;
+use std::path::Path;
+
+pub const AUDIO_EXTENSIONS: &[&str] = &["mp3", "flac", "m4a", "ogg", "opus"];
+
+const PLACEHOLDER_SIZE: u32 = 512;
+const PLACEHOLDER_BACKGROUND: Rgb<u8> = Rgb([224, 224, 224]);
+const PLACEHOLDER_FOREGROUND: Rgb<u8> = Rgb([128, 128, 128]);
+
+/// 波形のピークを集計する単位 (サンプル数)
+const PEAK_BLOCK_SAMPLES: usize = 256;
+
+#[derive(Parser)]
+pub struct WaveformOption {
+    /// Width of the waveform rendered for audio without album art
+    #[arg(long, default_value_t = 1200)]
+    waveform_width: u32,
+
+    #[arg(long, default_value_t = 300)]
+    waveform_height: u32,
+
+    #[arg(long, default_value = "#4a90d9")]
+    waveform_color: HexColor,
+
+    #[arg(long, default_value = "#ffffff")]
+    waveform_background: HexColor,
+}
+
+/// アルバムアート → 波形 → プレースホルダーの順に試す
+pub fn load_image_from_audio(
+    path: &Path,
+    waveform: &WaveformOption,
+) -> Result<DynamicImage, anyhow::Error> {
+    if let Some(img) = extract_attached_picture(path)? {
+        return Ok(img);
+    }
+    log::debug!("{}: no album art, rendering waveform", path.display());
+    match render_waveform(path, waveform) {
+        Ok(img) => Ok(img),
+        Err(err) => {
+            log::debug!("{}: failed to render waveform: {}", path.display(), err);
+            Ok(placeholder())
+        }
+    }
+}
+
+/// ID3 APIC / FLAC・Vorbis の PICTURE / MP4 covr / MKV の画像添付ファイルは
+/// ffmpeg では attached_pic ストリームとして見える。
+pub fn extract_attached_picture(path: &Path) -> Result<Option<DynamicImage>, anyhow::Error> {
+    ffmpeg::init().ok(); // Ignore re-init
+
+    let mut ictx = input(&path)?;
+    let Some(picture_stream_index) = ictx
+        .streams()
+        .find(|stream| stream.disposition().contains(Disposition::ATTACHED_PIC))
+        .map(|stream| stream.index())
+    else {
+        return Ok(None);
+    };
+
+    // attached_pic は該当ストリームの最初のパケットとして 1 度だけ返される
+    for (stream, packet) in ictx.packets() {
+        if stream.index() != picture_stream_index {
+            continue;
+        }
+        let data = packet.data().context("Empty attached picture")?;
+        return Ok(Some(image::load_from_memory(data)?));
+    }
+    Ok(None)
+}
+
+/// モノラル f32 にダウンミックスしてブロックごとの絶対値の最大を集める。
+fn compute_peaks(path: &Path) -> Result<Vec<f32>, anyhow::Error> {
+    ffmpeg::init().ok(); // Ignore re-init
+
+    let mut ictx = input(&path)?;
+    let input = ictx
+        .streams()
+        .best(ffmpeg::media::Type::Audio)
+        .context("No audio stream found")?;
+    let audio_stream_index = input.index();
+
+    let context_decoder = codec::Context::from_parameters(input.parameters())?;
+    let mut decoder = context_decoder.decoder().audio()?;
+    let mut resampler = decoder.resampler(
+        Sample::F32(sample::Type::Packed),
+        ChannelLayout::MONO,
+        decoder.rate(),
+    )?;
+
+    let mut peaks = Vec::new();
+    let mut block_peak = 0.0_f32;
+    let mut block_len = 0;
+    let mut collect = |samples: &[f32]| {
+        for sample in samples {
+            block_peak = block_peak.max(sample.abs());
+            block_len += 1;
+            if block_len == PEAK_BLOCK_SAMPLES {
+                peaks.push(block_peak);
+                block_peak = 0.0;
+                block_len = 0;
+            }
+        }
+    };
+
+    let mut decoded = AudioFrame::empty();
+    for (stream, packet) in ictx.packets() {
+        if stream.index() != audio_stream_index {
+            continue;
+        }
+        decoder.send_packet(&packet)?;
+        while decoder.receive_frame(&mut decoded).is_ok() {
+            let mut mono = AudioFrame::empty();
+            resampler.run(&decoded, &mut mono)?;
+            collect(mono.plane::<f32>(0));
+        }
+    }
+    decoder.send_eof()?;
+    while decoder.receive_frame(&mut decoded).is_ok() {
+        let mut mono = AudioFrame::empty();
+        resampler.run(&decoded, &mut mono)?;
+        collect(mono.plane::<f32>(0));
+    }
+    if block_len > 0 {
+        peaks.push(block_peak);
+    }
+
+    anyhow::ensure!(!peaks.is_empty(), "No audio samples decoded");
+    Ok(peaks)
+}
+
+fn render_waveform(path: &Path, option: &WaveformOption) -> Result<DynamicImage, anyhow::Error> {
+    let peaks = compute_peaks(path)?;
+    let width = option.waveform_width.max(1);
+    let height = option.waveform_height.max(1);
+
+    // 小さな音でも形が見えるように最大値で正規化する
+    let max_peak = peaks
+        .iter()
+        .copied()
+        .fold(0.0_f32, f32::max)
+        .max(f32::EPSILON);
+    let mut canvas = RgbImage::from_pixel(width, height, option.waveform_background.0);
+    let center = height as f32 / 2.0;
+    for x in 0..width {
+        let begin = peaks.len() * x as usize / width as usize;
+        let end = (peaks.len() * (x as usize + 1) / width as usize).max(begin + 1);
+        let peak = peaks[begin..end.min(peaks.len())]
+            .iter()
+            .copied()
+            .fold(0.0_f32, f32::max)
+            / max_peak;
+        let half = (peak * center).max(0.5);
+        let top = (center - half).floor().max(0.0) as u32;
+        let bottom = ((center + half).ceil() as u32).min(height);
+        for y in top..bottom {
+            canvas.put_pixel(x, y, option.waveform_color.0);
+        }
+    }
+
+    Ok(DynamicImage::ImageRgb8(canvas))
+}
+
+/// 音符のアイコン
+fn placeholder() -> DynamicImage {
+    let size = PLACEHOLDER_SIZE as i32;
+    let mut canvas =
+        RgbImage::from_pixel(PLACEHOLDER_SIZE, PLACEHOLDER_SIZE, PLACEHOLDER_BACKGROUND);
+
+    let head_center = (size * 2 / 5, size * 2 / 3);
+    let head_radius = size / 10;
+    draw_filled_ellipse_mut(
+        &mut canvas,
+        head_center,
+        head_radius * 4 / 3,
+        head_radius,
+        PLACEHOLDER_FOREGROUND,
+    );
+    let stem_x = head_center.0 + head_radius * 4 / 3 - size / 40;
+    let stem_top = size / 4;
+    draw_filled_rect_mut(
+        &mut canvas,
+        Rect::at(stem_x, stem_top).of_size((size / 40) as u32, (head_center.1 - stem_top) as u32),
+        PLACEHOLDER_FOREGROUND,
+    );
+    draw_filled_rect_mut(
+        &mut canvas,
+        Rect::at(stem_x, stem_top).of_size((size / 6) as u32, (size / 20) as u32),
+        PLACEHOLDER_FOREGROUND,
+    );
+
+    DynamicImage::ImageRgb8(canvas)
+}
@@ -0,0 +1,234 @@
+use crate::AppData;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+use anyhow::Context;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::Write;
+use std::net::UdpSocket;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::mpsc;
+
+const QUEUE_SIZE: usize = 4096;
+
+// facility=authpriv(10), severity=info(6)
+const SYSLOG_PRIORITY: u8 = 10 * 8 + 6;
+
+/// `file:PATH`, `syslog`, `syslog:HOST:PORT` or an `http(s)://` URL.
+#[derive(Clone, Debug)]
+pub enum SinkSpec {
+    File(PathBuf),
+    Syslog(Option<String>),
+    Http(String),
+}
+
+impl FromStr for SinkSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("file:") {
+            Ok(SinkSpec::File(PathBuf::from(path)))
+        } else if s == "syslog" {
+            Ok(SinkSpec::Syslog(None))
+        } else if let Some(addr) = s.strip_prefix("syslog:") {
+            Ok(SinkSpec::Syslog(Some(addr.to_string())))
+        } else if s.starts_with("http://") || s.starts_with("https://") {
+            Ok(SinkSpec::Http(s.to_string()))
+        } else {
+            Err(format!("unknown audit sink: {}", s))
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct AuditEvent {
+    time: String,
+    /// Fingerprint of the bearer token, never the token itself
+    token: Option<String>,
+    ip: Option<String>,
+    forwarded_for: Option<String>,
+    method: String,
+    route: Option<String>,
+    key: Option<String>,
+    status: u16,
+}
+
+trait AuditSink: Send {
+    fn write(&mut self, event: &AuditEvent) -> Result<(), anyhow::Error>;
+}
+
+struct FileSink {
+    file: File,
+}
+
+impl AuditSink for FileSink {
+    fn write(&mut self, event: &AuditEvent) -> Result<(), anyhow::Error> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        Ok(())
+    }
+}
+
+enum SyslogSink {
+    Local(UnixDatagram),
+    Remote(UdpSocket),
+}
+
+impl AuditSink for SyslogSink {
+    fn write(&mut self, event: &AuditEvent) -> Result<(), anyhow::Error> {
+        let message = format!(
+            "<{}>media-converter: {}",
+            SYSLOG_PRIORITY,
+            serde_json::to_string(event)?
+        );
+        match self {
+            SyslogSink::Local(socket) => socket.send(message.as_bytes())?,
+            SyslogSink::Remote(socket) => socket.send(message.as_bytes())?,
+        };
+        Ok(())
+    }
+}
+
+struct HttpSink {
+    url: String,
+    agent: ureq::Agent,
+}
+
+impl AuditSink for HttpSink {
+    fn write(&mut self, event: &AuditEvent) -> Result<(), anyhow::Error> {
+        self.agent
+            .post(&self.url)
+            .set("Content-Type", "application/json")
+            .send_string(&serde_json::to_string(event)?)?;
+        Ok(())
+    }
+}
+
+fn open_sink(spec: &SinkSpec) -> Result<Box<dyn AuditSink>, anyhow::Error> {
+    Ok(match spec {
+        SinkSpec::File(path) => Box::new(FileSink {
+            file: File::options()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Failed to open {}", path.display()))?,
+        }),
+        SinkSpec::Syslog(None) => {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect("/dev/log")?;
+            Box::new(SyslogSink::Local(socket))
+        }
+        SinkSpec::Syslog(Some(addr)) => {
+            let socket = UdpSocket::bind("0.0.0.0:0")?;
+            socket.connect(addr)?;
+            Box::new(SyslogSink::Remote(socket))
+        }
+        SinkSpec::Http(url) => Box::new(HttpSink {
+            url: url.clone(),
+            agent: ureq::AgentBuilder::new().build(),
+        }),
+    })
+}
+
+/// Hands events to a writer thread so that slow sinks never block requests.
+#[derive(Clone)]
+pub struct AuditLog {
+    sender: mpsc::SyncSender<AuditEvent>,
+}
+
+impl AuditLog {
+    pub fn start(specs: &[SinkSpec]) -> Result<Option<AuditLog>, anyhow::Error> {
+        if specs.is_empty() {
+            return Ok(None);
+        }
+
+        let mut sinks = specs.iter().map(open_sink).collect::<Result<Vec<_>, _>>()?;
+        let (sender, receiver) = mpsc::sync_channel::<AuditEvent>(QUEUE_SIZE);
+        std::thread::Builder::new()
+            .name("audit-log".to_string())
+            .spawn(move || {
+                for event in receiver {
+                    for sink in sinks.iter_mut() {
+                        sink.write(&event).unwrap_or_else(|err| {
+                            log::warn!("Failed to write audit log: {}", err);
+                        });
+                    }
+                }
+            })?;
+
+        Ok(Some(AuditLog { sender }))
+    }
+
+    fn record(&self, event: AuditEvent) {
+        if let Err(err) = self.sender.try_send(event) {
+            log::warn!("Dropped audit event: {}", err);
+        }
+    }
+}
+
+fn token_fingerprint(req: &ServiceRequest) -> Option<String> {
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_string)
+        .or_else(|| {
+            web::Query::<std::collections::HashMap<String, String>>::from_query(req.query_string())
+                .ok()
+                .and_then(|query| query.get("token").cloned())
+        })?;
+    let digest = Sha256::digest(token.as_bytes());
+    Some(digest[..8].iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+pub async fn middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let Some(audit) = req
+        .app_data::<web::Data<AppData>>()
+        .and_then(|app_data| app_data.audit.clone())
+    else {
+        return next.call(req).await;
+    };
+
+    let connection_info = req.connection_info().clone();
+    let ip = req.peer_addr().map(|addr| addr.ip().to_string());
+    let forwarded_for = connection_info
+        .realip_remote_addr()
+        .map(str::to_string)
+        .filter(|addr| Some(addr) != ip.as_ref());
+    let token = token_fingerprint(&req);
+    let method = req.method().to_string();
+    let route = req.match_pattern();
+
+    let result = next.call(req).await;
+    // match_info はルーティング後にしか埋まらない
+    let (status, key) = match &result {
+        Ok(res) => (
+            res.status(),
+            res.request().match_info().get("tail").map(str::to_string),
+        ),
+        Err(err) => (err.as_response_error().status_code(), None),
+    };
+
+    audit.record(AuditEvent {
+        time: chrono::Utc::now().to_rfc3339(),
+        token,
+        ip,
+        forwarded_for,
+        method,
+        route,
+        key,
+        status: status.as_u16(),
+    });
+    result
+}
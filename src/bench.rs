@@ -0,0 +1,173 @@
+use crate::encode::encode_webp;
+use crate::loader::{LoadRequest, LoaderRegistry};
+use crate::statistics::{OnlineStats, P2Quantile};
+use crate::{AppConfig, Size};
+use clap::Parser;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Parser)]
+pub struct BenchArgs {
+    /// Directory containing the sample corpus (scanned recursively)
+    #[arg(long)]
+    input: PathBuf,
+
+    /// Number of files processed in parallel (defaults to the number of CPUs)
+    #[arg(long)]
+    concurrency: Option<usize>,
+}
+
+struct FormatStats {
+    files: usize,
+    errors: usize,
+    bytes: u64,
+    latency: OnlineStats,
+    p50: P2Quantile,
+    p90: P2Quantile,
+    p99: P2Quantile,
+    max: f64,
+}
+
+impl FormatStats {
+    fn new() -> Self {
+        FormatStats {
+            files: 0,
+            errors: 0,
+            bytes: 0,
+            latency: OnlineStats::new(),
+            p50: P2Quantile::new(0.5),
+            p90: P2Quantile::new(0.9),
+            p99: P2Quantile::new(0.99),
+            max: 0.0,
+        }
+    }
+
+    fn update(&mut self, bytes: u64, elapsed: Duration) {
+        let ms = elapsed.as_secs_f64() * 1000.0;
+        self.files += 1;
+        self.bytes += bytes;
+        self.latency.update(ms);
+        self.p50.update(ms);
+        self.p90.update(ms);
+        self.p99.update(ms);
+        self.max = self.max.max(ms);
+    }
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// decode → (score) → resize → encode
+fn process(path: &Path, config: &AppConfig, loaders: &LoaderRegistry) -> Result<(), anyhow::Error> {
+    let (w, h) = Size::Medium.dimensions();
+    let request = LoadRequest {
+        target: Some((w, h)),
+        ..Default::default()
+    };
+    let img = loaders.load(path, &config.load_image_option, &request)?;
+    encode_webp(img.thumbnail(w, h), path, config.thumbnail_quality)?;
+    Ok(())
+}
+
+pub fn run(args: &BenchArgs, config: &AppConfig, loaders: &LoaderRegistry) -> std::io::Result<()> {
+    let mut files = Vec::new();
+    collect_files(&args.input, &mut files)?;
+    let concurrency = args
+        .concurrency
+        .or_else(|| std::thread::available_parallelism().ok().map(|n| n.get()))
+        .unwrap_or(1);
+    log::info!(
+        "Benchmarking {} files with concurrency {}",
+        files.len(),
+        concurrency
+    );
+
+    let next = AtomicUsize::new(0);
+    let stats: Mutex<BTreeMap<String, FormatStats>> = Mutex::new(BTreeMap::new());
+    let started = Instant::now();
+
+    std::thread::scope(|scope| {
+        for _ in 0..concurrency {
+            scope.spawn(|| {
+                while let Some(path) = files.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let format = path
+                        .extension()
+                        .and_then(OsStr::to_str)
+                        .unwrap_or("")
+                        .to_lowercase();
+                    let bytes = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+
+                    let begin = Instant::now();
+                    let result = process(path, config, loaders);
+                    let elapsed = begin.elapsed();
+
+                    let mut stats = stats.lock().unwrap();
+                    let entry = stats.entry(format).or_insert_with(FormatStats::new);
+                    match result {
+                        Ok(()) => entry.update(bytes, elapsed),
+                        Err(err) => {
+                            log::debug!("{}: {}", path.display(), err);
+                            entry.errors += 1;
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    let wall = started.elapsed().as_secs_f64();
+    let stats = stats.into_inner().unwrap();
+
+    println!(
+        "{:<8} {:>7} {:>7} {:>9} {:>9} {:>9} {:>9} {:>9} {:>9}",
+        "format",
+        "files",
+        "errors",
+        "files/s",
+        "mean(ms)",
+        "p50(ms)",
+        "p90(ms)",
+        "p99(ms)",
+        "max(ms)"
+    );
+    for (format, s) in &stats {
+        println!(
+            "{:<8} {:>7} {:>7} {:>9.2} {:>9.1} {:>9.1} {:>9.1} {:>9.1} {:>9.1}",
+            if format.is_empty() { "-" } else { format },
+            s.files,
+            s.errors,
+            s.files as f64 / wall,
+            s.latency.mean(),
+            s.p50.quantile(),
+            s.p90.quantile(),
+            s.p99.quantile(),
+            s.max,
+        );
+    }
+
+    let total_files: usize = stats.values().map(|s| s.files).sum();
+    let total_errors: usize = stats.values().map(|s| s.errors).sum();
+    let total_bytes: u64 = stats.values().map(|s| s.bytes).sum();
+    println!(
+        "total: {} files ({} errors) in {:.2}s: {:.2} files/s, {:.2} MiB/s",
+        total_files,
+        total_errors,
+        wall,
+        total_files as f64 / wall,
+        total_bytes as f64 / wall / (1024.0 * 1024.0),
+    );
+    Ok(())
+}
@@ -0,0 +1,986 @@
+//! 生成したサムネイルなどのキャッシュ。
+//!
+//! キーは元ファイルのキー・更新時刻・変換パラメータ (サイドカー名に全部入っている) のハッシュで、
+//! 元ファイルが更新されると別のキーになるので古いエントリを引くことはない。
+//! メモリ (LRU)・ディスク・Redis などの層を `ThumbnailCache` として重ね、ハンドラは
+//! `LayeredCache` だけを見る。
+use crate::{sidecar, FileKey};
+use actix_web::web::Bytes;
+use clap::{Parser, ValueEnum};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// 1 つの元ファイルについて一覧に残す出力の数。古いものから忘れる
+const MAX_RENDITIONS: usize = 256;
+/// `--cache-max-size` を超えたらこの割合まで減らす
+const EVICTION_LOW_WATER: f64 = 0.9;
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+/// ヒット数を数える出力の数。超えたら全部の回数を半分にし、0 になったものを忘れる
+const HOT_OUTPUTS_CAPACITY: usize = 4096;
+
+#[derive(Parser)]
+pub struct CacheOption {
+    /// Directory for the disk cache of generated thumbnails and conversions. Disabled when
+    /// omitted
+    #[arg(long)]
+    cache_dir: Option<PathBuf>,
+
+    /// Byte budget of the in-memory LRU of recently served outputs, e.g. `256M` (0 disables).
+    /// Outputs larger than an eighth of it are not kept
+    #[arg(long, default_value = "64M", value_parser = parse_size)]
+    memory_cache_bytes: u64,
+
+    /// Lookup order of the cache layers. Layers that are not configured are skipped
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "memory,disk,redis"
+    )]
+    cache_layers: Vec<CacheLayer>,
+
+    /// Size limit of the disk cache, e.g. `50G`. Entries are evicted in the background when
+    /// it is exceeded. Unlimited when omitted
+    #[arg(long, value_parser = parse_size)]
+    cache_max_size: Option<u64>,
+
+    /// Which disk cache entries go first when `--cache-max-size` is exceeded
+    #[arg(long, value_enum, default_value = "lru")]
+    cache_eviction_policy: EvictionPolicy,
+
+    /// Only log the disk cache entries that would be evicted
+    #[arg(long)]
+    cache_eviction_dry_run: bool,
+
+    /// When the source has changed, serve the output cached for its previous version and
+    /// regenerate it in the background
+    #[arg(long)]
+    pub stale_while_revalidate: bool,
+}
+
+impl CacheOption {
+    pub fn cache_dir(&self) -> Option<&Path> {
+        self.cache_dir.as_deref()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum CacheLayer {
+    /// `--memory-cache-bytes`
+    Memory,
+    /// `--cache-dir`
+    Disk,
+    /// `--redis-url`, only with the `redis` feature
+    Redis,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum EvictionPolicy {
+    /// Least recently used first
+    Lru,
+    /// Least often used since startup first, least recently used first among equals
+    Lfu,
+}
+
+/// Content address of one generated output.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CacheKey(String);
+
+impl CacheKey {
+    /// `name` is the sidecar name of the output, which encodes every transform parameter.
+    pub fn new(key: &FileKey, modified: SystemTime, name: &str) -> Self {
+        CacheKey::from_nanos(key, nanos(modified), name)
+    }
+
+    fn from_nanos(key: &FileKey, modified: u128, name: &str) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(key.hkey.as_bytes());
+        hasher.update([0]);
+        hasher.update(modified.to_le_bytes());
+        hasher.update(name.as_bytes());
+        CacheKey::from_digest(hasher)
+    }
+
+    /// Where the source version of the newest cached `name` is kept.
+    fn latest(key: &FileKey, name: &str) -> Self {
+        // 更新時刻を含まないので、元ファイルが更新されても同じキーになる
+        let mut hasher = Sha256::new();
+        hasher.update(b"latest\0");
+        hasher.update(key.hkey.as_bytes());
+        hasher.update([0]);
+        hasher.update(name.as_bytes());
+        CacheKey::from_digest(hasher)
+    }
+
+    /// Where the outputs generated for `key` are listed, for `LayeredCache::purge`.
+    fn renditions(key: &FileKey) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(b"renditions\0");
+        hasher.update(key.hkey.as_bytes());
+        CacheKey::from_digest(hasher)
+    }
+
+    fn from_digest(hasher: Sha256) -> Self {
+        let digest = hasher.finalize();
+        CacheKey(digest.iter().map(|b| format!("{:02x}", b)).collect())
+    }
+}
+
+fn nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_nanos())
+}
+
+impl std::fmt::Display for CacheKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// `512`, `64K`, `256M` or `50G` (powers of 1024).
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let (number, unit) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&s[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .ok_or_else(|| format!("invalid size {}", s))
+}
+
+/// `30s`, `15m`, `12h` or `30d`.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (number, unit) = match s.char_indices().last() {
+        Some((i, 's')) => (&s[..i], 1),
+        Some((i, 'm')) => (&s[..i], 60),
+        Some((i, 'h')) => (&s[..i], 60 * 60),
+        Some((i, 'd')) => (&s[..i], 24 * 60 * 60),
+        _ => return Err(format!("invalid duration {}", s)),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("invalid duration {}", s))
+}
+
+/// A cached output: in memory, or a file that can be sent as is.
+pub enum Cached {
+    Bytes(Bytes),
+    File(PathBuf),
+}
+
+/// Hit/miss counters of one layer. Sizes are `None` when the layer cannot tell cheaply.
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: Option<usize>,
+    pub bytes: Option<u64>,
+}
+
+/// One storage for generated outputs. Implementations handle their own failures (logging and
+/// treating them as a miss) so that a broken cache only costs the conversion.
+pub trait ThumbnailCache: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn get(&self, key: &CacheKey) -> Option<Cached>;
+
+    fn put(&self, key: &CacheKey, data: &[u8]);
+
+    fn evict(&self, key: &CacheKey);
+
+    /// Removes every entry and returns how many were removed.
+    fn clear(&self) -> u64;
+
+    fn stats(&self) -> CacheStats;
+
+    /// Whether an output of `len` bytes is stored at all.
+    fn accepts(&self, _len: u64) -> bool {
+        true
+    }
+
+    /// The file `key` is stored in, if the layer keeps files. Not counted as a lookup.
+    fn file(&self, _key: &CacheKey) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// Counters for `CacheStats`, shared by the layer implementations.
+#[derive(Default)]
+pub struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl Counters {
+    /// Counts a lookup as a hit or a miss and passes the result through.
+    pub fn record<T>(&self, found: Option<T>) -> Option<T> {
+        let counter = if found.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
+    }
+
+    pub fn evicted(&self) {
+        self.evictions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn evicted_many(&self, count: u64) {
+        self.evictions.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self, entries: Option<usize>, bytes: Option<u64>) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            entries,
+            bytes,
+        }
+    }
+}
+
+/// Caches nothing. Used when no layer is configured.
+pub struct NoopCache;
+
+impl ThumbnailCache for NoopCache {
+    fn name(&self) -> &'static str {
+        "noop"
+    }
+
+    fn get(&self, _key: &CacheKey) -> Option<Cached> {
+        None
+    }
+
+    fn put(&self, _key: &CacheKey, _data: &[u8]) {}
+
+    fn evict(&self, _key: &CacheKey) {}
+
+    fn clear(&self) -> u64 {
+        0
+    }
+
+    fn stats(&self) -> CacheStats {
+        Counters::default().stats(Some(0), Some(0))
+    }
+
+    fn accepts(&self, _len: u64) -> bool {
+        false
+    }
+}
+
+/// Layers looked up in order. A hit is copied into the layers before it, so that the next
+/// lookup stops earlier.
+pub struct LayeredCache {
+    layers: Vec<Box<dyn ThumbnailCache>>,
+    /// `(元ファイル, 出力名)` ごとのヒット数
+    hot: Mutex<HashMap<(String, String), u64>>,
+}
+
+impl LayeredCache {
+    /// `redis` is built by the caller since it only exists with the `redis` feature.
+    pub fn new(option: &CacheOption, mut redis: Option<Box<dyn ThumbnailCache>>) -> Self {
+        let mut layers: Vec<Box<dyn ThumbnailCache>> = Vec::new();
+        for layer in &option.cache_layers {
+            let cache: Option<Box<dyn ThumbnailCache>> = match layer {
+                CacheLayer::Memory => MemoryCache::new(option).map(|c| Box::new(c) as _),
+                CacheLayer::Disk => DiskCache::new(option).map(|c| Box::new(c) as _),
+                CacheLayer::Redis => redis.take(),
+            };
+            layers.extend(cache);
+        }
+        if layers.is_empty() {
+            layers.push(Box::new(NoopCache));
+        }
+        LayeredCache {
+            layers,
+            hot: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn layers(&self) -> impl Iterator<Item = &dyn ThumbnailCache> {
+        self.layers.iter().map(|layer| layer.as_ref())
+    }
+
+    pub fn get(&self, key: &CacheKey) -> Option<Cached> {
+        for (i, layer) in self.layers.iter().enumerate() {
+            let Some(cached) = layer.get(key) else {
+                continue;
+            };
+            let upper = &self.layers[..i];
+            let len = match &cached {
+                Cached::Bytes(data) => data.len() as u64,
+                Cached::File(path) => std::fs::metadata(path).map_or(0, |m| m.len()),
+            };
+            if !upper.iter().any(|layer| layer.accepts(len)) {
+                return Some(cached);
+            }
+            // 小さいものは上の層に上げる。ファイルはメモリに読んでから返す
+            let data = match cached {
+                Cached::Bytes(data) => data,
+                Cached::File(path) => match std::fs::read(&path) {
+                    Ok(data) => Bytes::from(data),
+                    Err(err) => {
+                        // 読めないエントリは捨てて次の層を探す
+                        log::warn!("Failed to read cache: {}:{}", path.display(), err);
+                        layer.evict(key);
+                        continue;
+                    }
+                },
+            };
+            for layer in upper.iter().filter(|layer| layer.accepts(len)) {
+                layer.put(key, &data);
+            }
+            return Some(Cached::Bytes(data));
+        }
+        None
+    }
+
+    /// The file `key` is stored in by the first layer that keeps files.
+    pub fn file(&self, key: &CacheKey) -> Option<PathBuf> {
+        self.layers.iter().find_map(|layer| layer.file(key))
+    }
+
+    pub fn put(&self, key: &CacheKey, data: &[u8]) {
+        for layer in self
+            .layers
+            .iter()
+            .filter(|layer| layer.accepts(data.len() as u64))
+        {
+            layer.put(key, data);
+        }
+    }
+
+    /// Records that `name` was generated from the version of the source modified at `modified`.
+    pub fn put_latest(&self, key: &FileKey, name: &str, modified: SystemTime) {
+        self.put(&CacheKey::latest(key, name), &nanos(modified).to_le_bytes());
+    }
+
+    /// Lists `name` generated from the version modified at `modified` among the outputs of
+    /// `key`.
+    pub fn record_rendition(&self, key: &FileKey, name: &str, modified: SystemTime) {
+        // 一覧は `{更新時刻のナノ秒} {name}` の行で、他の出力と同じく各層に置く
+        let mut renditions = self.renditions(key);
+        let rendition = (nanos(modified), name.to_string());
+        if renditions.contains(&rendition) {
+            return;
+        }
+        renditions.push(rendition);
+        let skip = renditions.len().saturating_sub(MAX_RENDITIONS);
+        self.put_renditions(key, &renditions[skip..]);
+    }
+
+    /// Evicts the outputs listed for `key` from every layer, only those generated from the
+    /// version modified at `version` when given. Returns the number of outputs purged.
+    pub fn purge(&self, key: &FileKey, version: Option<SystemTime>) -> usize {
+        let (purged, kept): (Vec<_>, Vec<_>) = self
+            .renditions(key)
+            .into_iter()
+            .partition(|(modified, _)| version.is_none_or(|version| *modified == nanos(version)));
+        for (modified, name) in &purged {
+            self.evict(&CacheKey::from_nanos(key, *modified, name));
+        }
+        if version.is_some() {
+            self.put_renditions(key, &kept);
+        } else {
+            for (_, name) in &purged {
+                self.evict(&CacheKey::latest(key, name));
+            }
+            self.evict(&CacheKey::renditions(key));
+        }
+        purged.len()
+    }
+
+    fn renditions(&self, key: &FileKey) -> Vec<(u128, String)> {
+        let data = match self.get(&CacheKey::renditions(key)) {
+            Some(Cached::Bytes(data)) => data.to_vec(),
+            Some(Cached::File(path)) => std::fs::read(path).unwrap_or_default(),
+            None => return Vec::new(),
+        };
+        String::from_utf8_lossy(&data)
+            .lines()
+            .filter_map(|line| {
+                let (modified, name) = line.split_once(' ')?;
+                Some((modified.parse().ok()?, name.to_string()))
+            })
+            .collect()
+    }
+
+    fn put_renditions(&self, key: &FileKey, renditions: &[(u128, String)]) {
+        let data: String = renditions
+            .iter()
+            .map(|(modified, name)| format!("{} {}\n", modified, name))
+            .collect();
+        self.put(&CacheKey::renditions(key), data.as_bytes());
+    }
+
+    fn evict(&self, key: &CacheKey) {
+        for layer in &self.layers {
+            layer.evict(key);
+        }
+    }
+
+    /// Counts a hit on the output `name` of `key`, for `hottest`.
+    pub fn record_hit(&self, key: &FileKey, name: &str) {
+        let mut hot = self.hot.lock().unwrap();
+        let output = (format!("{}.{}", key.hkey, key.ext), name.to_string());
+        if let Some(hits) = hot.get_mut(&output) {
+            *hits += 1;
+            return;
+        }
+        // 回数を減衰させ、最近よく引かれるものだけを残す
+        while hot.len() >= HOT_OUTPUTS_CAPACITY {
+            hot.retain(|_, hits| {
+                *hits /= 2;
+                *hits > 0
+            });
+        }
+        hot.insert(output, 1);
+    }
+
+    /// The `n` outputs hit most often, as `(source, output name, hits)`. Counts are halved
+    /// from time to time, so they are relative.
+    pub fn hottest(&self, n: usize) -> Vec<(String, String, u64)> {
+        let mut hottest: Vec<_> = self
+            .hot
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((source, name), hits)| (source.clone(), name.clone(), *hits))
+            .collect();
+        hottest.sort_by_key(|(_, _, hits)| std::cmp::Reverse(*hits));
+        hottest.truncate(n);
+        hottest
+    }
+
+    /// Empties every layer. Returns the number of entries removed from each.
+    pub fn clear(&self) -> Vec<(&'static str, u64)> {
+        let cleared = self
+            .layers
+            .iter()
+            .map(|layer| (layer.name(), layer.clear()))
+            .collect();
+        self.hot.lock().unwrap().clear();
+        cleared
+    }
+
+    /// Modification time of the source version the newest cached `name` was generated from.
+    pub fn get_latest(&self, key: &FileKey, name: &str) -> Option<SystemTime> {
+        let data = match self.get(&CacheKey::latest(key, name))? {
+            Cached::Bytes(data) => data.to_vec(),
+            Cached::File(path) => std::fs::read(path).ok()?,
+        };
+        let nanos = u128::from_le_bytes(data.try_into().ok()?);
+        let duration = Duration::new(
+            u64::try_from(nanos / 1_000_000_000).ok()?,
+            (nanos % 1_000_000_000) as u32,
+        );
+        UNIX_EPOCH.checked_add(duration)
+    }
+}
+
+pub struct DiskCache {
+    inner: Arc<DiskInner>,
+    /// `--cache-max-size` を超えたときに追い出しのスレッドを起こす
+    wake: Option<mpsc::SyncSender<()>>,
+}
+
+struct DiskInner {
+    dir: PathBuf,
+    counters: Counters,
+    limit: Option<DiskLimit>,
+}
+
+struct DiskLimit {
+    max_bytes: u64,
+    policy: EvictionPolicy,
+    dry_run: bool,
+    index: Mutex<DiskIndex>,
+}
+
+struct DiskEntry {
+    len: u64,
+    last_used: SystemTime,
+    /// 起動してからのヒット数
+    uses: u64,
+}
+
+#[derive(Default)]
+struct DiskIndex {
+    entries: HashMap<CacheKey, DiskEntry>,
+    bytes: u64,
+    /// 起動時の走査が終わるまでは合計が分からない
+    scanned: bool,
+}
+
+impl DiskIndex {
+    fn insert(&mut self, key: CacheKey, entry: DiskEntry) {
+        self.bytes += entry.len;
+        if let Some(old) = self.entries.insert(key, entry) {
+            self.bytes -= old.len;
+        }
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.bytes -= entry.len;
+        }
+    }
+}
+
+impl DiskCache {
+    pub fn new(option: &CacheOption) -> Option<Self> {
+        let limit = option.cache_max_size.map(|max_bytes| DiskLimit {
+            max_bytes,
+            policy: option.cache_eviction_policy,
+            dry_run: option.cache_eviction_dry_run,
+            index: Mutex::new(DiskIndex::default()),
+        });
+        let inner = Arc::new(DiskInner {
+            dir: option.cache_dir.clone()?,
+            counters: Counters::default(),
+            limit,
+        });
+        let wake = inner.limit.is_some().then(|| {
+            let (sender, receiver) = mpsc::sync_channel(1);
+            let evictor = inner.clone();
+            std::thread::Builder::new()
+                .name("cache-evictor".to_string())
+                .spawn(move || evictor.run_evictor(receiver))
+                .expect("Failed to start the cache evictor");
+            sender
+        });
+        Some(DiskCache { inner, wake })
+    }
+}
+
+/// `{cache_dir}/{先頭 2 文字}/{key}`。元ファイルと同じく 1 ディレクトリに集中させない
+fn disk_path(dir: &Path, key: &CacheKey) -> PathBuf {
+    dir.join(&key.0[..2]).join(&key.0)
+}
+
+impl DiskInner {
+    fn path(&self, key: &CacheKey) -> PathBuf {
+        disk_path(&self.dir, key)
+    }
+
+    /// 既存のエントリを走査してから、上限を超えるたびに追い出す。`DiskCache` が捨てられると終わる
+    fn run_evictor(&self, wake: mpsc::Receiver<()>) {
+        let Some(limit) = &self.limit else {
+            return;
+        };
+        let found = scan(&self.dir);
+        {
+            let mut index = limit.index.lock().unwrap();
+            for (key, entry) in found {
+                // 走査中に書かれたものの方が新しい
+                if !index.entries.contains_key(&key) {
+                    index.insert(key, entry);
+                }
+            }
+            index.scanned = true;
+            log::info!(
+                "Disk cache: {} entries, {} bytes (limit {})",
+                index.entries.len(),
+                index.bytes,
+                limit.max_bytes
+            );
+        }
+        loop {
+            self.evict_over_limit(limit);
+            // 外から書き足された分なども拾えるよう、起こされなくても定期的に見直す
+            match wake.recv_timeout(EVICTION_INTERVAL) {
+                Ok(()) | Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    }
+
+    fn evict_over_limit(&self, limit: &DiskLimit) {
+        let (victims, bytes) = {
+            let index = limit.index.lock().unwrap();
+            if index.bytes <= limit.max_bytes {
+                return;
+            }
+            let mut candidates: Vec<_> = index.entries.iter().collect();
+            match limit.policy {
+                EvictionPolicy::Lru => candidates.sort_by_key(|(_, entry)| entry.last_used),
+                EvictionPolicy::Lfu => {
+                    candidates.sort_by_key(|(_, entry)| (entry.uses, entry.last_used))
+                }
+            }
+            // 上限ぎりぎりまでしか消さないと、書くたびに追い出しが走る
+            let target = (limit.max_bytes as f64 * EVICTION_LOW_WATER) as u64;
+            let mut bytes = index.bytes;
+            let mut victims = Vec::new();
+            for (key, entry) in candidates {
+                if bytes <= target {
+                    break;
+                }
+                bytes -= entry.len;
+                victims.push((key.clone(), entry.len));
+            }
+            (victims, index.bytes - bytes)
+        };
+        if limit.dry_run {
+            for (key, len) in &victims {
+                log::debug!("Would evict {} ({} bytes)", self.path(key).display(), len);
+            }
+            log::info!(
+                "Disk cache over {} bytes: would evict {} entries, {} bytes (dry run)",
+                limit.max_bytes,
+                victims.len(),
+                bytes
+            );
+            return;
+        }
+        for (key, _) in &victims {
+            if remove_file(&self.path(key)) {
+                self.counters.evicted();
+            }
+            limit.index.lock().unwrap().remove(key);
+        }
+        log::info!(
+            "Disk cache over {} bytes: evicted {} entries, {} bytes",
+            limit.max_bytes,
+            victims.len(),
+            bytes
+        );
+    }
+}
+
+/// `{cache_dir}/{2 文字}/{key}` のエントリを列挙する。最後に使った時刻はアクセス時刻で代用する
+fn scan(dir: &Path) -> Vec<(CacheKey, DiskEntry)> {
+    let mut found = Vec::new();
+    let shards = match std::fs::read_dir(dir) {
+        Ok(shards) => shards,
+        Err(err) => {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                log::warn!("Failed to scan cache: {}:{}", dir.display(), err);
+            }
+            return found;
+        }
+    };
+    for shard in shards.flatten() {
+        let Ok(files) = std::fs::read_dir(shard.path()) else {
+            continue;
+        };
+        for file in files.flatten() {
+            // 書きかけの `.tmp` などは飛ばす
+            let Some(name) = file
+                .file_name()
+                .into_string()
+                .ok()
+                .filter(|name| name.len() == 64 && name.bytes().all(|b| b.is_ascii_hexdigit()))
+            else {
+                continue;
+            };
+            let Ok(metadata) = file.metadata() else {
+                continue;
+            };
+            if !metadata.is_file() {
+                continue;
+            }
+            let last_used = metadata
+                .accessed()
+                .or_else(|_| metadata.modified())
+                .unwrap_or(UNIX_EPOCH);
+            found.push((
+                CacheKey(name),
+                DiskEntry {
+                    len: metadata.len(),
+                    last_used,
+                    uses: 0,
+                },
+            ));
+        }
+    }
+    found
+}
+
+/// What `prune_disk` found in and removed from the disk cache.
+#[derive(Default)]
+pub struct PruneReport {
+    pub entries: usize,
+    pub bytes: u64,
+    /// `max_age` より長く使われていなかったもの
+    pub expired_entries: usize,
+    pub expired_bytes: u64,
+    /// `max_size` に収めるために消したもの
+    pub evicted_entries: usize,
+    pub evicted_bytes: u64,
+}
+
+/// Removes the disk cache entries unused for longer than `max_age`, then the least recently
+/// used ones until the rest fits in `max_size`. Only reports them when `dry_run` is set.
+pub fn prune_disk(
+    option: &CacheOption,
+    max_age: Option<Duration>,
+    max_size: Option<u64>,
+    dry_run: bool,
+) -> std::io::Result<PruneReport> {
+    let Some(dir) = &option.cache_dir else {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "--cache-dir is required",
+        ));
+    };
+    let mut entries = scan(dir);
+    entries.sort_by_key(|(_, entry)| entry.last_used);
+    let mut report = PruneReport {
+        entries: entries.len(),
+        bytes: entries.iter().map(|(_, entry)| entry.len).sum(),
+        ..PruneReport::default()
+    };
+    let now = SystemTime::now();
+    let mut remaining = report.bytes;
+    for (key, entry) in &entries {
+        let expired = max_age.is_some_and(|max_age| {
+            now.duration_since(entry.last_used)
+                .is_ok_and(|age| age > max_age)
+        });
+        // 古い順に並べたので、どちらにも当たらなくなったら残りも当たらない
+        if !expired && max_size.is_none_or(|max_size| remaining <= max_size) {
+            break;
+        }
+        let path = disk_path(dir, key);
+        if dry_run {
+            log::debug!("Would remove {} ({} bytes)", path.display(), entry.len);
+        } else if !remove_file(&path) {
+            continue;
+        }
+        remaining -= entry.len;
+        if expired {
+            report.expired_entries += 1;
+            report.expired_bytes += entry.len;
+        } else {
+            report.evicted_entries += 1;
+            report.evicted_bytes += entry.len;
+        }
+    }
+    Ok(report)
+}
+
+impl ThumbnailCache for DiskCache {
+    fn name(&self) -> &'static str {
+        "disk"
+    }
+
+    fn file(&self, key: &CacheKey) -> Option<PathBuf> {
+        let path = self.inner.path(key);
+        path.is_file().then_some(path)
+    }
+
+    fn get(&self, key: &CacheKey) -> Option<Cached> {
+        let path = self.inner.path(key);
+        let Some(metadata) = std::fs::metadata(&path).ok().filter(|m| m.is_file()) else {
+            return self.inner.counters.record(None);
+        };
+        if let Some(limit) = &self.inner.limit {
+            let mut index = limit.index.lock().unwrap();
+            if let Some(entry) = index.entries.get_mut(key) {
+                entry.last_used = SystemTime::now();
+                entry.uses += 1;
+            } else if index.scanned {
+                // 他のインスタンスが同じディレクトリに書いたもの
+                index.insert(
+                    key.clone(),
+                    DiskEntry {
+                        len: metadata.len(),
+                        last_used: SystemTime::now(),
+                        uses: 1,
+                    },
+                );
+            }
+        }
+        self.inner.counters.record(Some(Cached::File(path)))
+    }
+
+    fn put(&self, key: &CacheKey, data: &[u8]) {
+        let path = self.inner.path(key);
+        if let Err(err) = sidecar::write(&path, data) {
+            log::warn!("Failed to write cache: {}:{}", path.display(), err);
+            return;
+        }
+        let Some(limit) = &self.inner.limit else {
+            return;
+        };
+        let mut index = limit.index.lock().unwrap();
+        index.insert(
+            key.clone(),
+            DiskEntry {
+                len: data.len() as u64,
+                last_used: SystemTime::now(),
+                uses: 0,
+            },
+        );
+        // dry run では定期的な見直しのときだけログを出す
+        if index.scanned && index.bytes > limit.max_bytes && !limit.dry_run {
+            if let Some(wake) = &self.wake {
+                // 既に起こしてあれば何もしない
+                let _ = wake.try_send(());
+            }
+        }
+    }
+
+    fn evict(&self, key: &CacheKey) {
+        if remove_file(&self.inner.path(key)) {
+            self.inner.counters.evicted();
+        }
+        if let Some(limit) = &self.inner.limit {
+            limit.index.lock().unwrap().remove(key);
+        }
+    }
+
+    fn clear(&self) -> u64 {
+        let mut removed = 0;
+        for (key, _) in scan(&self.inner.dir) {
+            if remove_file(&self.inner.path(&key)) {
+                self.inner.counters.evicted();
+                removed += 1;
+            }
+            if let Some(limit) = &self.inner.limit {
+                limit.index.lock().unwrap().remove(&key);
+            }
+        }
+        removed
+    }
+
+    /// 件数と容量は `--cache-max-size` を指定して走査が終わったときだけ分かる
+    fn stats(&self) -> CacheStats {
+        let sizes = self.inner.limit.as_ref().and_then(|limit| {
+            let index = limit.index.lock().unwrap();
+            index.scanned.then(|| (index.entries.len(), index.bytes))
+        });
+        self.inner.counters.stats(
+            sizes.map(|(entries, _)| entries),
+            sizes.map(|(_, bytes)| bytes),
+        )
+    }
+}
+
+fn remove_file(path: &Path) -> bool {
+    match std::fs::remove_file(path) {
+        Ok(()) => true,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => false,
+        Err(err) => {
+            log::warn!("Failed to remove cache: {}:{}", path.display(), err);
+            false
+        }
+    }
+}
+
+/// メモリキャッシュ 1 件の上限は予算のこの割合。大きな `/media` 1 件で全部押し出さないようにする
+const MEMORY_ENTRY_MAX_RATIO: u64 = 8;
+
+/// Bounded in-memory LRU of recently served outputs.
+pub struct MemoryCache {
+    budget: u64,
+    state: Mutex<LruState>,
+    counters: Counters,
+}
+
+#[derive(Default)]
+struct LruState {
+    entries: HashMap<CacheKey, (Bytes, u64)>,
+    /// 最後に使った順。値は `entries` のキー
+    order: BTreeMap<u64, CacheKey>,
+    bytes: u64,
+    tick: u64,
+}
+
+impl MemoryCache {
+    pub fn new(option: &CacheOption) -> Option<Self> {
+        (option.memory_cache_bytes > 0).then(|| MemoryCache {
+            budget: option.memory_cache_bytes,
+            state: Mutex::new(LruState::default()),
+            counters: Counters::default(),
+        })
+    }
+}
+
+impl ThumbnailCache for MemoryCache {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn accepts(&self, len: u64) -> bool {
+        len <= self.budget / MEMORY_ENTRY_MAX_RATIO
+    }
+
+    fn get(&self, key: &CacheKey) -> Option<Cached> {
+        let mut state = self.state.lock().unwrap();
+        state.tick += 1;
+        let tick = state.tick;
+        let Some((data, last_used)) = state.entries.get_mut(key) else {
+            return self.counters.record(None);
+        };
+        let previous = std::mem::replace(last_used, tick);
+        let data = data.clone();
+        state.order.remove(&previous);
+        state.order.insert(tick, key.clone());
+        self.counters.record(Some(Cached::Bytes(data)))
+    }
+
+    fn put(&self, key: &CacheKey, data: &[u8]) {
+        if !self.accepts(data.len() as u64) {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.tick += 1;
+        let tick = state.tick;
+        state.bytes += data.len() as u64;
+        let data = Bytes::copy_from_slice(data);
+        if let Some((old, last_used)) = state.entries.insert(key.clone(), (data, tick)) {
+            state.bytes -= old.len() as u64;
+            state.order.remove(&last_used);
+        }
+        state.order.insert(tick, key.clone());
+        while state.bytes > self.budget {
+            let Some((_, oldest)) = state.order.pop_first() else {
+                break;
+            };
+            if let Some((data, _)) = state.entries.remove(&oldest) {
+                state.bytes -= data.len() as u64;
+                self.counters.evicted();
+            }
+        }
+    }
+
+    fn evict(&self, key: &CacheKey) {
+        let mut state = self.state.lock().unwrap();
+        if let Some((data, last_used)) = state.entries.remove(key) {
+            state.bytes -= data.len() as u64;
+            state.order.remove(&last_used);
+            self.counters.evicted();
+        }
+    }
+
+    fn clear(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let removed = state.entries.len() as u64;
+        *state = LruState::default();
+        self.counters.evicted_many(removed);
+        removed
+    }
+
+    fn stats(&self) -> CacheStats {
+        let state = self.state.lock().unwrap();
+        self.counters
+            .stats(Some(state.entries.len()), Some(state.bytes))
+    }
+}
@@ -0,0 +1,89 @@
+//! エンドポイントごとの `Cache-Control`。
+//!
+//! キーは元ファイルの内容のハッシュなので、同じ URL の中身が変わるのは変換パラメータの解釈や
+//! エンコーダを変えたときくらいしかない。CDN の前に置くなら `immutable` で長く持たせてよい。
+use actix_web::http::header;
+use actix_web::HttpResponse;
+use clap::Parser;
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Parser)]
+pub struct CacheControlOption {
+    /// `Cache-Control` of /thumbnail and the other generated images and metadata, as comma
+    /// separated `max-age=DURATION`, `s-maxage=DURATION` and `immutable`
+    #[arg(long, default_value = "max-age=30d")]
+    pub thumbnail_cache_control: CachePolicy,
+
+    /// `Cache-Control` of /media, including originals passed through
+    #[arg(long, default_value = "max-age=30d")]
+    pub media_cache_control: CachePolicy,
+
+    /// `Cache-Control` of /raw. Not sent when omitted
+    #[arg(long)]
+    pub raw_cache_control: Option<CachePolicy>,
+}
+
+/// A `public` `Cache-Control` value.
+#[derive(Clone, Debug)]
+pub struct CachePolicy {
+    max_age: Duration,
+    s_maxage: Option<Duration>,
+    immutable: bool,
+}
+
+impl FromStr for CachePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut policy = CachePolicy::revalidate();
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some(("max-age", value)) => policy.max_age = crate::cache::parse_duration(value)?,
+                Some(("s-maxage", value)) => {
+                    policy.s_maxage = Some(crate::cache::parse_duration(value)?)
+                }
+                None if directive == "immutable" => policy.immutable = true,
+                _ => return Err(format!("unknown Cache-Control directive {}", directive)),
+            }
+        }
+        Ok(policy)
+    }
+}
+
+impl CachePolicy {
+    /// `max-age=0`: cached, but checked with the server before every use.
+    pub fn revalidate() -> Self {
+        CachePolicy {
+            max_age: Duration::ZERO,
+            s_maxage: None,
+            immutable: false,
+        }
+    }
+
+    pub fn header_value(&self) -> header::CacheControl {
+        let mut directives = vec![
+            header::CacheDirective::Public,
+            header::CacheDirective::MaxAge(self.max_age.as_secs().try_into().unwrap_or(u32::MAX)),
+        ];
+        if let Some(s_maxage) = self.s_maxage {
+            directives.push(header::CacheDirective::SMaxAge(
+                s_maxage.as_secs().try_into().unwrap_or(u32::MAX),
+            ));
+        }
+        if self.immutable {
+            directives.push(header::CacheDirective::Extension(
+                "immutable".to_string(),
+                None,
+            ));
+        }
+        header::CacheControl(directives)
+    }
+
+    pub fn apply(&self, mut res: HttpResponse) -> HttpResponse {
+        if let Ok(value) = header::HeaderValue::from_str(&self.header_value().to_string()) {
+            res.headers_mut().insert(header::CACHE_CONTROL, value);
+        }
+        res
+    }
+}
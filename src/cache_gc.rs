@@ -0,0 +1,58 @@
+//! `cache-gc` サブコマンド: ディスクキャッシュから古いエントリを消し、指定の容量に収めて終了する。
+//!
+//! サーバーを `--cache-max-size` なしで動かしている NAS で、cron から定期的に片付ける用。
+//! 最後に使った時刻はファイルのアクセス時刻で判断するので、`noatime` でマウントしていると
+//! 書き込んだ時刻から数えることになる。
+use crate::cache::{self, CacheOption};
+use clap::Parser;
+use std::time::{Duration, Instant};
+
+#[derive(Parser)]
+pub struct CacheGcArgs {
+    /// Remove entries unused for longer than this, e.g. `90d`
+    #[arg(long, value_parser = cache::parse_duration, required_unless_present = "max_size")]
+    max_age: Option<Duration>,
+
+    /// Then remove the least recently used entries until the cache fits in this size, e.g. `50G`
+    #[arg(long, value_parser = cache::parse_size)]
+    max_size: Option<u64>,
+
+    /// Only report what would be removed
+    #[arg(long)]
+    dry_run: bool,
+}
+
+fn mib(bytes: u64) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0)
+}
+
+pub fn run(args: &CacheGcArgs, option: &CacheOption) -> std::io::Result<()> {
+    let started = Instant::now();
+    let report = cache::prune_disk(option, args.max_age, args.max_size, args.dry_run)?;
+    let removed_entries = report.expired_entries + report.evicted_entries;
+    let removed_bytes = report.expired_bytes + report.evicted_bytes;
+    println!(
+        "scanned {} entries, {:.1} MiB in {:.2}s",
+        report.entries,
+        mib(report.bytes),
+        started.elapsed().as_secs_f64()
+    );
+    println!(
+        "{} {} entries, {:.1} MiB ({} unused for too long, {} over the size limit)",
+        if args.dry_run {
+            "would remove"
+        } else {
+            "removed"
+        },
+        removed_entries,
+        mib(removed_bytes),
+        report.expired_entries,
+        report.evicted_entries,
+    );
+    println!(
+        "remaining: {} entries, {:.1} MiB",
+        report.entries - removed_entries,
+        mib(report.bytes - removed_bytes)
+    );
+    Ok(())
+}
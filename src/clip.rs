@@ -0,0 +1,68 @@
+//! Clip Studio Paint (.clip) のプレビュー画像を取り出す。
+//!
+//! `.clip` は `CSFCHUNK` で始まるチャンク列で、`CHNKSQLi` チャンクに SQLite のデータベースが
+//! 丸ごと入っている。その `CanvasPreview` テーブルに PNG のプレビューが保存されている。
+use anyhow::Context;
+use image::DynamicImage;
+use rusqlite::{Connection, OpenFlags};
+use scopeguard::guard;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+fn read_u64(reader: &mut impl Read) -> std::io::Result<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+pub fn load_image_from_clip(path: &Path) -> Result<DynamicImage, anyhow::Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    anyhow::ensure!(&magic == b"CSFCHUNK", "Not a CLIP file");
+    let _file_size = read_u64(&mut reader)?;
+    let _header_offset = read_u64(&mut reader)?;
+
+    loop {
+        let mut name = [0; 8];
+        reader
+            .read_exact(&mut name)
+            .context("No SQLite chunk in file")?;
+        let len = read_u64(&mut reader)?;
+        if &name != b"CHNKSQLi" {
+            reader.seek_relative(i64::try_from(len)?)?;
+            continue;
+        }
+
+        // rusqlite はファイルからしか開けないので一時ファイルに書き出す
+        let temp_path = std::env::temp_dir().join(format!(
+            "media_converter-clip-{}-{}.sqlite",
+            std::process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let temp_path = guard(temp_path, |p| {
+            let _ = std::fs::remove_file(p);
+        });
+        std::io::copy(
+            &mut (&mut reader).take(len),
+            &mut File::create(&*temp_path)?,
+        )?;
+        return load_preview(&temp_path);
+    }
+}
+
+fn load_preview(db_path: &Path) -> Result<DynamicImage, anyhow::Error> {
+    let conn = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let data: Vec<u8> = conn
+        .query_row(
+            "SELECT ImageData FROM CanvasPreview ORDER BY ImageWidth * ImageHeight DESC LIMIT 1",
+            [],
+            |row| row.get(0),
+        )
+        .context("No preview in file")?;
+    Ok(image::load_from_memory(&data)?)
+}
@@ -0,0 +1,86 @@
+//! 同じ出力を同時に作るリクエストをまとめる (singleflight)。
+//!
+//! ギャラリーを開くと同じ動画のポスターに数十件のリクエストが同時に来る。最初の 1 件だけが
+//! 変換し、残りはその結果を待って使い回す。ハンドラは変換中もワーカースレッドを占有しているので、
+//! 待つ側もスレッドを止めて待つ。
+use crate::cache::CacheKey;
+use crate::ApiError;
+use actix_web::http::StatusCode;
+use actix_web::Error;
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Runs one conversion per `CacheKey` at a time. Waiters get the value of the running one, or
+/// only its status code when it failed since errors cannot be cloned.
+pub struct Coalescer<T> {
+    inflight: Mutex<HashMap<CacheKey, Arc<Flight<T>>>>,
+}
+
+struct Flight<T> {
+    result: Mutex<Option<Result<T, StatusCode>>>,
+    done: Condvar,
+}
+
+impl<T> Default for Coalescer<T> {
+    fn default() -> Self {
+        Coalescer {
+            inflight: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<T: Clone> Coalescer<T> {
+    pub fn is_running(&self, key: &CacheKey) -> bool {
+        self.inflight.lock().unwrap().contains_key(key)
+    }
+
+    /// Runs `convert` unless the same `key` is already being converted, in which case its
+    /// result is waited for.
+    pub fn run(
+        &self,
+        key: &CacheKey,
+        convert: impl FnOnce() -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let (flight, leader) = {
+            let mut inflight = self.inflight.lock().unwrap();
+            match inflight.get(key) {
+                Some(flight) => (flight.clone(), false),
+                None => {
+                    let flight = Arc::new(Flight {
+                        result: Mutex::new(None),
+                        done: Condvar::new(),
+                    });
+                    inflight.insert(key.clone(), flight.clone());
+                    (flight, true)
+                }
+            }
+        };
+        if !leader {
+            let mut result = flight.result.lock().unwrap();
+            while result.is_none() {
+                result = flight.done.wait(result).unwrap();
+            }
+            return match result.as_ref().unwrap() {
+                Ok(value) => Ok(value.clone()),
+                Err(status) => Err(ApiError::FailedEarlier(*status).into()),
+            };
+        }
+
+        // パニックしても待っている側を起こす
+        let mut publish = scopeguard::guard(
+            Err(StatusCode::INTERNAL_SERVER_ERROR),
+            |result: Result<T, StatusCode>| {
+                self.inflight.lock().unwrap().remove(key);
+                *flight.result.lock().unwrap() = Some(result);
+                flight.done.notify_all();
+            },
+        );
+        let result = convert();
+        *publish = match &result {
+            Ok(value) => Ok(value.clone()),
+            Err(err) => Err(err.as_response_error().status_code()),
+        };
+        drop(publish);
+        result
+    }
+}
@@ -0,0 +1,43 @@
+use image::{DynamicImage, Rgb, RgbImage};
+use std::str::FromStr;
+
+/// `#RRGGBB` or `RRGGBB` given on the command line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HexColor(pub Rgb<u8>);
+
+impl FromStr for HexColor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("expected #RRGGBB: {}", s));
+        }
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap();
+        Ok(HexColor(Rgb([channel(0), channel(2), channel(4)])))
+    }
+}
+
+impl HexColor {
+    /// サイドカー名などに使う `rrggbb`
+    pub fn to_hex(self) -> String {
+        let [r, g, b] = self.0 .0;
+        format!("{:02x}{:02x}{:02x}", r, g, b)
+    }
+}
+
+/// Composites `img` over a solid `background`. Images without alpha are returned unchanged.
+pub fn flatten(img: DynamicImage, background: HexColor) -> DynamicImage {
+    if !img.color().has_alpha() {
+        return img;
+    }
+    let rgba = img.to_rgba8();
+    let [br, bg, bb] = background.0 .0;
+    let blend = |c: u8, b: u8, a: u8| {
+        ((u16::from(c) * u16::from(a) + u16::from(b) * (255 - u16::from(a)) + 127) / 255) as u8
+    };
+    DynamicImage::ImageRgb8(RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        Rgb([blend(r, br, a), blend(g, bg, a), blend(b, bb, a)])
+    }))
+}
@@ -0,0 +1,99 @@
+//! `/contactsheet` で返す、動画全体から等間隔に取ったフレームの一覧画像。
+use crate::movie_keyframe;
+use crate::tonemap::ToneMapOperator;
+use ab_glyph::{FontVec, PxScale};
+use anyhow::Context;
+use image::{DynamicImage, Rgb, RgbImage};
+use std::path::Path;
+
+/// `?cols=` / `?rows=` の上限
+pub const MAX_GRID: u32 = 10;
+pub const DEFAULT_GRID: u32 = 4;
+
+const GAP: u32 = 4;
+const BACKGROUND: Rgb<u8> = Rgb([24, 24, 24]);
+
+const LABEL_FONT_SIZE: f32 = 14.0;
+const LABEL_HEIGHT: u32 = 18;
+const LABEL_PADDING: u32 = 4;
+const LABEL_BACKGROUND: Rgb<u8> = Rgb([0, 0, 0]);
+const LABEL_FOREGROUND: Rgb<u8> = Rgb([255, 255, 255]);
+
+/// Renders `cols` x `rows` frames, each fitted into a `tile_size` square. Timestamps are
+/// drawn in the bottom left corner of each tile when `font_path` is given.
+pub fn render(
+    path: &Path,
+    cols: u32,
+    rows: u32,
+    tile_size: u32,
+    font_path: Option<&Path>,
+    tone_map: ToneMapOperator,
+) -> Result<DynamicImage, anyhow::Error> {
+    let font = font_path
+        .map(|font_path| {
+            let bytes = std::fs::read(font_path)
+                .with_context(|| format!("Failed to read font {}", font_path.display()))?;
+            FontVec::try_from_vec(bytes).context("Failed to parse font")
+        })
+        .transpose()?;
+
+    let frames = movie_keyframe::sample_frames(path, (cols * rows) as usize, tile_size, tone_map)?;
+    let (tile_width, tile_height) = frames
+        .first()
+        .map(|(_, frame)| (frame.width(), frame.height()))
+        .context("No frames")?;
+    let mut sheet = RgbImage::from_pixel(
+        GAP + (tile_width + GAP) * cols,
+        GAP + (tile_height + GAP) * rows,
+        BACKGROUND,
+    );
+    for (index, (seconds, frame)) in frames.iter().enumerate() {
+        let index = index as u32;
+        let x = GAP + (tile_width + GAP) * (index % cols);
+        let y = GAP + (tile_height + GAP) * (index / cols);
+        image::imageops::replace(&mut sheet, &frame.to_rgb8(), i64::from(x), i64::from(y));
+        if let Some(font) = &font {
+            draw_label(
+                &mut sheet,
+                font,
+                x,
+                y + tile_height,
+                &format_timestamp(*seconds),
+            );
+        }
+    }
+    Ok(DynamicImage::ImageRgb8(sheet))
+}
+
+/// `(x, bottom)` はタイルの左下
+fn draw_label(sheet: &mut RgbImage, font: &FontVec, x: u32, bottom: u32, text: &str) {
+    let scale = PxScale::from(LABEL_FONT_SIZE);
+    let (text_width, _) = imageproc::drawing::text_size(scale, font, text);
+    let top = bottom.saturating_sub(LABEL_HEIGHT);
+    imageproc::drawing::draw_filled_rect_mut(
+        sheet,
+        imageproc::rect::Rect::at(x as i32, top as i32)
+            .of_size(text_width + LABEL_PADDING * 2, LABEL_HEIGHT),
+        LABEL_BACKGROUND,
+    );
+    imageproc::drawing::draw_text_mut(
+        sheet,
+        LABEL_FOREGROUND,
+        (x + LABEL_PADDING) as i32,
+        (top + 2) as i32,
+        scale,
+        font,
+        text,
+    );
+}
+
+/// `M:SS`、1 時間以上なら `H:MM:SS`
+fn format_timestamp(seconds: f64) -> String {
+    let total = seconds as u64;
+    let (hours, minutes, seconds) = (total / 3600, total / 60 % 60, total % 60);
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{}:{:02}", minutes, seconds)
+    }
+}
@@ -0,0 +1,68 @@
+//! `?grayscale=` / `?blur=` / `?brightness=` / `?contrast=` による縮小後の加工。
+use crate::pipeline::{Op, MAX_BLUR_SIGMA, MAX_BRIGHTNESS, MAX_CONTRAST};
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Effects {
+    pub grayscale: bool,
+    /// Gaussian blur sigma in pixels of the resized image
+    pub blur: Option<f32>,
+    /// Added to every channel, -255 to 255
+    pub brightness: Option<i32>,
+    /// Percent, -100 to 100
+    pub contrast: Option<f32>,
+}
+
+impl Effects {
+    /// Invalid or no-op values are ignored rather than rejected.
+    pub fn from_query(query: &HashMap<String, String>) -> Self {
+        let number = |name: &str| {
+            query
+                .get(name)
+                .and_then(|s| s.parse::<f32>().ok())
+                .filter(|v| v.is_finite() && *v != 0.0)
+        };
+        Effects {
+            grayscale: query
+                .get("grayscale")
+                .is_some_and(|s| matches!(s.as_str(), "1" | "true")),
+            blur: number("blur")
+                .filter(|&v| v > 0.0)
+                .map(|v| v.min(MAX_BLUR_SIGMA)),
+            brightness: number("brightness")
+                .map(|v| (v.round() as i32).clamp(-MAX_BRIGHTNESS, MAX_BRIGHTNESS))
+                .filter(|&v| v != 0),
+            contrast: number("contrast").map(|v| v.clamp(-MAX_CONTRAST, MAX_CONTRAST)),
+        }
+    }
+
+    /// サイドカー名に入れる識別子。加工なしなら `None`。
+    pub fn variant(&self) -> Option<String> {
+        let mut parts = Vec::new();
+        if self.grayscale {
+            parts.push("gray".to_string());
+        }
+        if let Some(sigma) = self.blur {
+            parts.push(format!("blur{}", sigma));
+        }
+        if let Some(value) = self.brightness {
+            parts.push(format!("bright{}", value));
+        }
+        if let Some(value) = self.contrast {
+            parts.push(format!("contrast{}", value));
+        }
+        (!parts.is_empty()).then(|| parts.join("-"))
+    }
+
+    /// 適用順は grayscale, brightness, contrast, blur
+    pub fn ops(&self) -> Vec<Op> {
+        let mut ops = Vec::new();
+        if self.grayscale {
+            ops.push(Op::Grayscale);
+        }
+        ops.extend(self.brightness.map(Op::Brightness));
+        ops.extend(self.contrast.map(Op::Contrast));
+        ops.extend(self.blur.map(Op::Blur));
+        ops
+    }
+}
@@ -0,0 +1,448 @@
+//! 出力フォーマットの選択とエンコード。
+use crate::jobs::{self, Stage};
+use crate::ApiError;
+use clap::{Parser, ValueEnum};
+use image::codecs::avif::AvifEncoder;
+use image::codecs::png::PngEncoder;
+use image::{ColorType, DynamicImage};
+use jpeg_encoder::SamplingFactor;
+use std::collections::HashMap;
+use std::path::Path;
+use webp::{Encoder, WebPConfig};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    WebP,
+    Avif,
+    Jpeg,
+    Png,
+    #[cfg(feature = "jxl")]
+    Jxl,
+}
+
+impl OutputFormat {
+    pub fn from_str(s: &str) -> Self {
+        match s {
+            "avif" => OutputFormat::Avif,
+            "jpeg" | "jpg" => OutputFormat::Jpeg,
+            "png" => OutputFormat::Png,
+            #[cfg(feature = "jxl")]
+            "jxl" => OutputFormat::Jxl,
+            _ => OutputFormat::WebP,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::WebP => "webp",
+            OutputFormat::Avif => "avif",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Png => "png",
+            #[cfg(feature = "jxl")]
+            OutputFormat::Jxl => "jxl",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            OutputFormat::WebP => "image/webp",
+            OutputFormat::Avif => "image/avif",
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Png => "image/png",
+            #[cfg(feature = "jxl")]
+            OutputFormat::Jxl => "image/jxl",
+        }
+    }
+}
+
+/// Picks AVIF > WebP > JPEG from an `Accept` header. JPEG is the safe choice for clients that
+/// send no header or only wildcards.
+pub fn negotiate(accept: Option<&str>) -> OutputFormat {
+    let accept = accept.unwrap_or("");
+    if accepts(accept, "image/avif") {
+        OutputFormat::Avif
+    } else if accepts(accept, "image/webp") {
+        OutputFormat::WebP
+    } else {
+        OutputFormat::Jpeg
+    }
+}
+
+/// Whether `mime` is listed explicitly with a non-zero q-value. Wildcards are ignored since
+/// clients send `*/*` without being able to render every format.
+pub fn accepts(accept: &str, mime: &str) -> bool {
+    accept.split(',').any(|range| {
+        let mut params = range.split(';').map(str::trim);
+        params.next().is_some_and(|m| m.eq_ignore_ascii_case(mime))
+            && !params.any(|p| {
+                p.strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q <= 0.0)
+            })
+    })
+}
+
+/// libwebp settings beyond the quality, configurable per request with `?webp_method=`,
+/// `?webp_alpha_quality=`, `?webp_psnr=` and `?sharp_yuv=`.
+#[derive(Parser, Clone, Copy, Debug, PartialEq)]
+pub struct WebPOptions {
+    /// 0 (fastest) - 6 (slowest, smallest)
+    #[arg(long = "webp-method", default_value_t = 4)]
+    pub method: u8,
+
+    /// Quality of the alpha plane, 0 - 100
+    #[arg(long = "webp-alpha-quality", default_value_t = 100)]
+    pub alpha_quality: u8,
+
+    /// Target PSNR in dB. The quality is searched to reach it when set
+    #[arg(long = "webp-target-psnr")]
+    pub target_psnr: Option<f32>,
+
+    /// Use the slower but more accurate RGB to YUV conversion (less color bleeding on edges)
+    #[arg(long = "webp-sharp-yuv")]
+    pub sharp_yuv: bool,
+}
+
+impl WebPOptions {
+    /// Applies per-request overrides. Returns whether anything was overridden.
+    pub fn override_from_query(&mut self, query: &HashMap<String, String>) -> bool {
+        let before = *self;
+        if let Some(method) = query.get("webp_method").and_then(|s| s.parse::<u8>().ok()) {
+            self.method = method.min(6);
+        }
+        if let Some(alpha) = query
+            .get("webp_alpha_quality")
+            .and_then(|s| s.parse::<u8>().ok())
+        {
+            self.alpha_quality = alpha.min(100);
+        }
+        if let Some(psnr) = query
+            .get("webp_psnr")
+            .and_then(|s| s.parse::<f32>().ok())
+            .filter(|&psnr| psnr > 0.0 && psnr <= 99.0)
+        {
+            self.target_psnr = Some(psnr);
+        }
+        if let Some(sharp_yuv) = query.get("sharp_yuv") {
+            self.sharp_yuv = matches!(sharp_yuv.as_str(), "1" | "true");
+        }
+        *self != before
+    }
+
+    /// サイドカー名に入れる識別子
+    pub fn variant(&self) -> String {
+        let mut s = format!("m{}-a{}", self.method, self.alpha_quality);
+        if let Some(psnr) = self.target_psnr {
+            s = format!("{}-psnr{}", s, psnr);
+        }
+        if self.sharp_yuv {
+            s.push_str("-sharpyuv");
+        }
+        s
+    }
+
+    fn config(&self, quality: f32, lossless: bool) -> Result<WebPConfig, String> {
+        let mut config = WebPConfig::new().map_err(|_| "Invalid WebPConfig".to_string())?;
+        config.lossless = i32::from(lossless);
+        config.alpha_compression = i32::from(!lossless);
+        config.quality = quality;
+        config.method = i32::from(self.method.min(6));
+        config.alpha_quality = i32::from(self.alpha_quality.min(100));
+        config.use_sharp_yuv = i32::from(self.sharp_yuv);
+        if let Some(psnr) = self.target_psnr.filter(|_| !lossless) {
+            config.target_PSNR = psnr;
+            // cwebp と同じく、目標値がある場合は複数パスで探す
+            config.pass = 6;
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ChromaSubsampling {
+    #[value(name = "420")]
+    S420,
+    #[value(name = "422")]
+    S422,
+    #[value(name = "444")]
+    S444,
+}
+
+#[derive(Parser, Clone, Copy, Debug)]
+pub struct JpegOptions {
+    /// Progressive JPEG renders incrementally while large responses download
+    #[arg(long = "jpeg-progressive", default_value_t = true, action = clap::ArgAction::Set)]
+    pub progressive: bool,
+
+    /// 444 keeps sharp colored edges (text, line art) at the cost of size
+    #[arg(long = "jpeg-chroma-subsampling", value_enum, default_value_t = ChromaSubsampling::S420)]
+    pub chroma_subsampling: ChromaSubsampling,
+}
+
+/// Encoder settings for one endpoint. `/thumbnail` and `/media` use different qualities.
+#[derive(Clone)]
+pub struct EncodeQuality {
+    pub webp: f32,
+    /// Lossless WebP for screenshots and line art; `webp` is ignored
+    pub webp_lossless: bool,
+    pub webp_options: WebPOptions,
+    pub avif: u8,
+    pub avif_speed: u8,
+    pub jpeg: u8,
+    pub jpeg_options: JpegOptions,
+    #[cfg(feature = "jxl")]
+    pub jxl_distance: f32,
+}
+
+pub fn encode(
+    img: DynamicImage,
+    format: OutputFormat,
+    path: &Path,
+    quality: &EncodeQuality,
+) -> Result<Vec<u8>, ApiError> {
+    jobs::report(Stage::Encoding, None, None);
+    match format {
+        OutputFormat::WebP => encode_webp_advanced(
+            img,
+            path,
+            quality.webp,
+            quality.webp_lossless,
+            &quality.webp_options,
+        ),
+        OutputFormat::Avif => encode_avif(img, path, quality.avif, quality.avif_speed),
+        OutputFormat::Jpeg => encode_jpeg(img, path, quality.jpeg, &quality.jpeg_options),
+        OutputFormat::Png => encode_png(img, path),
+        #[cfg(feature = "jxl")]
+        OutputFormat::Jxl => encode_jxl(img, path, quality.jxl_distance),
+    }
+}
+
+/// Lowers the quality by binary search until the output fits in `max_bytes`. If even the lowest
+/// quality does not fit, the smallest result is returned anyway. Lossless formats are encoded
+/// once as they have no quality to trade.
+pub fn encode_within(
+    img: DynamicImage,
+    format: OutputFormat,
+    path: &Path,
+    quality: &EncodeQuality,
+    max_bytes: usize,
+) -> Result<Vec<u8>, ApiError> {
+    let with_quality = |q: u8| {
+        let mut quality = quality.clone();
+        match format {
+            OutputFormat::WebP => quality.webp = f32::from(q),
+            OutputFormat::Avif => quality.avif = q,
+            OutputFormat::Jpeg => quality.jpeg = q,
+            _ => {}
+        }
+        quality
+    };
+    let initial = match format {
+        OutputFormat::WebP if !quality.webp_lossless => quality.webp.clamp(0.0, 100.0) as u8,
+        OutputFormat::Avif => quality.avif,
+        OutputFormat::Jpeg => quality.jpeg,
+        _ => return encode(img, format, path, quality),
+    };
+
+    let mut best = encode(img.clone(), format, path, quality)?;
+    if best.len() <= max_bytes {
+        return Ok(best);
+    }
+    // 収まった中で最も高い品質を探す。収まらなければ最小のものを残す
+    let (mut low, mut high) = (1, initial.saturating_sub(1));
+    let mut fitted = false;
+    while low <= high {
+        let mid = low + (high - low) / 2;
+        let data = encode(img.clone(), format, path, &with_quality(mid))?;
+        if data.len() <= max_bytes {
+            best = data;
+            fitted = true;
+            low = mid + 1;
+        } else {
+            if !fitted && data.len() < best.len() {
+                best = data;
+            }
+            high = mid - 1;
+        }
+    }
+    Ok(best)
+}
+
+/// 品質を探す範囲。これより低いとブロックノイズが SSIM に表れにくい
+const PERCEPTUAL_MIN_QUALITY: u8 = 30;
+const PERCEPTUAL_MAX_QUALITY: u8 = 95;
+
+/// Searches for the lowest quality whose decoded output reaches `target_ssim` against `img`.
+/// Only WebP and JPEG can be decoded back, other formats use the fixed quality.
+pub fn encode_perceptual(
+    img: DynamicImage,
+    format: OutputFormat,
+    path: &Path,
+    quality: &EncodeQuality,
+    target_ssim: f64,
+) -> Result<Vec<u8>, ApiError> {
+    let decode_format = match format {
+        OutputFormat::WebP if !quality.webp_lossless => image::ImageFormat::WebP,
+        OutputFormat::Jpeg => image::ImageFormat::Jpeg,
+        _ => return encode(img, format, path, quality),
+    };
+    let with_quality = |q: u8| {
+        let mut quality = quality.clone();
+        match format {
+            OutputFormat::WebP => quality.webp = f32::from(q),
+            _ => quality.jpeg = q,
+        }
+        quality
+    };
+    let reference = img.to_luma8();
+    let score = |data: &[u8]| -> Result<f64, ApiError> {
+        let decoded = image::load_from_memory_with_format(data, decode_format)
+            .map_err(|err| encode_error(path, err))?;
+        Ok(crate::ssim::ssim(&reference, &decoded.to_luma8()))
+    };
+
+    let (mut low, mut high) = (PERCEPTUAL_MIN_QUALITY, PERCEPTUAL_MAX_QUALITY);
+    let mut best = None;
+    while low <= high {
+        let mid = low + (high - low) / 2;
+        let data = encode(img.clone(), format, path, &with_quality(mid))?;
+        if score(&data)? >= target_ssim {
+            best = Some(data);
+            high = mid - 1;
+        } else {
+            low = mid + 1;
+        }
+    }
+    match best {
+        Some(data) => Ok(data),
+        None => encode(img, format, path, &with_quality(PERCEPTUAL_MAX_QUALITY)),
+    }
+}
+
+fn to_8bit(img: DynamicImage) -> DynamicImage {
+    match img.color() {
+        ColorType::Rgb32F => DynamicImage::ImageRgb8(img.to_rgb8()),
+        ColorType::Rgba32F => DynamicImage::ImageRgba8(img.to_rgba8()),
+        ColorType::Rgb16 => DynamicImage::ImageRgb8(img.to_rgb8()),
+        ColorType::Rgba16 => DynamicImage::ImageRgba8(img.to_rgba8()),
+        ColorType::Rgb8 | ColorType::Rgba8 => img,
+        _ => DynamicImage::ImageRgb8(img.to_rgb8()),
+    }
+}
+
+fn encode_error(path: &Path, err: impl std::fmt::Display) -> ApiError {
+    log::warn!(
+        "Failed to encode image: {}:{}",
+        path.to_str().unwrap_or("N/A"),
+        err,
+    );
+    ApiError::FailedToEncode(err.to_string())
+}
+
+pub fn encode_webp(img: DynamicImage, path: &Path, quality: f32) -> Result<Vec<u8>, ApiError> {
+    let rgba8 = to_8bit(img);
+
+    let encoder = Encoder::from_image(&rgba8).map_err(|err| encode_error(path, err))?;
+    Ok(encoder.encode(quality).to_vec()) // copy
+}
+
+fn encode_webp_advanced(
+    img: DynamicImage,
+    path: &Path,
+    quality: f32,
+    lossless: bool,
+    options: &WebPOptions,
+) -> Result<Vec<u8>, ApiError> {
+    let rgba8 = to_8bit(img);
+
+    let config = options
+        .config(quality, lossless)
+        .map_err(|err| encode_error(path, err))?;
+    let encoder = Encoder::from_image(&rgba8).map_err(|err| encode_error(path, err))?;
+    let data = encoder
+        .encode_advanced(&config)
+        .map_err(|err| encode_error(path, format!("{:?}", err)))?;
+    Ok(data.to_vec())
+}
+
+/// 色数が `max_colors` 以下ならスクリーンショットや線画とみなす
+pub fn is_low_color(img: &DynamicImage, max_colors: usize) -> bool {
+    let rgba = img.to_rgba8();
+    let mut colors = std::collections::HashSet::new();
+    for px in rgba.pixels() {
+        if colors.insert(px.0) && colors.len() > max_colors {
+            return false;
+        }
+    }
+    true
+}
+
+fn encode_avif(
+    img: DynamicImage,
+    path: &Path,
+    quality: u8,
+    speed: u8,
+) -> Result<Vec<u8>, ApiError> {
+    let rgba8 = to_8bit(img);
+
+    let mut avif_data = Vec::new();
+    let encoder = AvifEncoder::new_with_speed_quality(&mut avif_data, speed, quality);
+    rgba8
+        .write_with_encoder(encoder)
+        .map_err(|err| encode_error(path, err))?;
+    Ok(avif_data)
+}
+
+/// JPEG はアルファを持てないので RGB に落とす
+fn encode_jpeg(
+    img: DynamicImage,
+    path: &Path,
+    quality: u8,
+    options: &JpegOptions,
+) -> Result<Vec<u8>, ApiError> {
+    let rgb8 = img.to_rgb8();
+
+    let mut jpeg_data = Vec::new();
+    let mut encoder = jpeg_encoder::Encoder::new(&mut jpeg_data, quality.clamp(1, 100));
+    encoder.set_progressive(options.progressive);
+    encoder.set_sampling_factor(match options.chroma_subsampling {
+        ChromaSubsampling::S420 => SamplingFactor::R_4_2_0,
+        ChromaSubsampling::S422 => SamplingFactor::R_4_2_2,
+        ChromaSubsampling::S444 => SamplingFactor::R_4_4_4,
+    });
+    let (width, height) = rgb8.dimensions();
+    encoder
+        .encode(
+            rgb8.as_raw(),
+            u16::try_from(width).map_err(|err| encode_error(path, err))?,
+            u16::try_from(height).map_err(|err| encode_error(path, err))?,
+            jpeg_encoder::ColorType::Rgb,
+        )
+        .map_err(|err| encode_error(path, err))?;
+    Ok(jpeg_data)
+}
+
+/// 可逆なので 16bit の画像は 16bit のまま書き出す
+fn encode_png(img: DynamicImage, path: &Path) -> Result<Vec<u8>, ApiError> {
+    let img = match img.color() {
+        ColorType::L8
+        | ColorType::La8
+        | ColorType::Rgb8
+        | ColorType::Rgba8
+        | ColorType::L16
+        | ColorType::La16
+        | ColorType::Rgb16
+        | ColorType::Rgba16 => img,
+        _ => to_8bit(img),
+    };
+
+    let mut png_data = Vec::new();
+    img.write_with_encoder(PngEncoder::new(&mut png_data))
+        .map_err(|err| encode_error(path, err))?;
+    Ok(png_data)
+}
+
+#[cfg(feature = "jxl")]
+fn encode_jxl(img: DynamicImage, path: &Path, distance: f32) -> Result<Vec<u8>, ApiError> {
+    crate::jxl::encode_jxl(&img, distance).map_err(|err| encode_error(path, err))
+}
@@ -0,0 +1,76 @@
+//! 読み込みに失敗した元ファイルを覚えておく。
+//!
+//! 壊れたファイルがギャラリーにあると、ページを開くたびに時間をかけて読み込みに失敗する。
+//! `--failure-cache-ttl` の間は読み込まずに同じステータスで失敗させる。ファイルが更新されれば
+//! 更新時刻が変わるので読み直す。
+use crate::{ApiError, FileKey};
+use actix_web::http::StatusCode;
+use actix_web::Error;
+use clap::Parser;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// 覚えている件数がこれを超えたら期限切れのものを捨てる
+const PRUNE_THRESHOLD: usize = 4096;
+
+#[derive(Parser)]
+pub struct FailureCacheOption {
+    /// How long a source that failed to decode keeps failing without being decoded again,
+    /// e.g. `10m` (`0s` disables)
+    #[arg(long, default_value = "5m", value_parser = crate::cache::parse_duration)]
+    failure_cache_ttl: Duration,
+}
+
+pub struct FailureCache {
+    ttl: Duration,
+    failures: Mutex<HashMap<(String, SystemTime), (StatusCode, Instant)>>,
+}
+
+impl FailureCache {
+    pub fn new(option: &FailureCacheOption) -> Self {
+        FailureCache {
+            ttl: option.failure_cache_ttl,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Fails with the remembered status if `key` failed to decode within the TTL.
+    pub fn check(&self, key: &FileKey, modified: SystemTime) -> Result<(), ApiError> {
+        let failures = self.failures.lock().unwrap();
+        match failures.get(&(key.hkey.clone(), modified)) {
+            Some((status, expires)) if Instant::now() < *expires => {
+                Err(ApiError::FailedEarlier(*status))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Forgets the failures of every version of `key`.
+    pub fn forget(&self, key: &FileKey) {
+        self.failures
+            .lock()
+            .unwrap()
+            .retain(|(hkey, _), _| *hkey != key.hkey);
+    }
+
+    /// Remembers `err` if it is a decode failure. Other errors depend on the request.
+    pub fn record(&self, key: &FileKey, modified: SystemTime, err: &Error) {
+        if self.ttl.is_zero()
+            || !err
+                .as_error::<ApiError>()
+                .is_some_and(ApiError::is_decode_failure)
+        {
+            return;
+        }
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() >= PRUNE_THRESHOLD {
+            failures.retain(|_, (_, expires)| now < *expires);
+        }
+        failures.insert(
+            (key.hkey.clone(), modified),
+            (err.as_response_error().status_code(), now + self.ttl),
+        );
+    }
+}
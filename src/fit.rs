@@ -0,0 +1,251 @@
+//! `?fit=` / `?gravity=` によるサムネイルの縮小・切り抜き。
+use clap::ValueEnum;
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, GrayImage, RgbaImage};
+
+/// smart gravity で注目度を計算する縮小画像の長辺
+const SMART_ANALYSIS_SIZE: u32 = 128;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fit {
+    /// Fit inside the box, keeping the aspect ratio
+    Contain,
+    /// Fill the box exactly and crop the overflow
+    Cover,
+    /// Stretch to the box, ignoring the aspect ratio
+    Fill,
+}
+
+impl Fit {
+    pub fn from_str(s: &str) -> Self {
+        Self::parse(s).unwrap_or(Fit::Contain)
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "contain" => Some(Fit::Contain),
+            "cover" => Some(Fit::Cover),
+            "fill" => Some(Fit::Fill),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Fit::Contain => "contain",
+            Fit::Cover => "cover",
+            Fit::Fill => "fill",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Gravity {
+    Center,
+    North,
+    /// Keep the window with the most edge detail
+    Smart,
+}
+
+impl Gravity {
+    pub fn from_str(s: &str) -> Self {
+        Self::parse(s).unwrap_or(Gravity::Center)
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "center" => Some(Gravity::Center),
+            "north" => Some(Gravity::North),
+            "smart" => Some(Gravity::Smart),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Gravity::Center => "center",
+            Gravity::North => "north",
+            Gravity::Smart => "smart",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ResizeFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Lanczos3,
+}
+
+impl ResizeFilter {
+    /// `?filter=`. Unknown names are ignored.
+    pub fn parse(s: &str) -> Option<Self> {
+        <Self as ValueEnum>::from_str(s, true).ok()
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ResizeFilter::Nearest => "nearest",
+            ResizeFilter::Triangle => "triangle",
+            ResizeFilter::CatmullRom => "catmull-rom",
+            ResizeFilter::Lanczos3 => "lanczos3",
+        }
+    }
+
+    fn filter_type(&self) -> FilterType {
+        match self {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Triangle => FilterType::Triangle,
+            ResizeFilter::CatmullRom => FilterType::CatmullRom,
+            ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// サイドカー名に入れる識別子。デフォルト (`contain`、フィルタ指定なし) は `None` で従来の名前のまま。
+pub fn variant(fit: Fit, gravity: Gravity, filter: Option<ResizeFilter>) -> Option<String> {
+    let fit = match fit {
+        Fit::Contain => None,
+        Fit::Cover => Some(format!("{}-{}", fit.as_str(), gravity.as_str())),
+        Fit::Fill => Some(fit.as_str().to_string()),
+    };
+    match (fit, filter) {
+        (None, None) => None,
+        (Some(fit), None) => Some(fit),
+        (None, Some(filter)) => Some(filter.as_str().to_string()),
+        (Some(fit), Some(filter)) => Some(format!("{}.{}", fit, filter.as_str())),
+    }
+}
+
+/// `filter` が `None` なら `contain` は `thumbnail()` の高速な縮小、それ以外は triangle を使う
+pub fn resize(
+    img: &DynamicImage,
+    width: u32,
+    height: u32,
+    fit: Fit,
+    gravity: Gravity,
+    filter: Option<ResizeFilter>,
+) -> DynamicImage {
+    let filter_type = filter.map_or(FilterType::Triangle, |f| f.filter_type());
+    match fit {
+        Fit::Contain if filter.is_none() => img.thumbnail(width, height),
+        Fit::Contain => img.resize(width, height, filter_type),
+        Fit::Fill => img.resize_exact(width, height, filter_type),
+        Fit::Cover => cover(img, width, height, gravity, filter_type),
+    }
+}
+
+fn cover(
+    img: &DynamicImage,
+    width: u32,
+    height: u32,
+    gravity: Gravity,
+    filter_type: FilterType,
+) -> DynamicImage {
+    if img.width() == 0 || img.height() == 0 {
+        return img.clone();
+    }
+    crop(img, width, height, gravity).resize_exact(width, height, filter_type)
+}
+
+/// 透明な余白で `width`x`height` ちょうどにする (中央寄せ)。はみ出す分は切れる。
+/// 余白の色は後段の `bg` で塗る。
+pub fn pad(img: &DynamicImage, width: u32, height: u32) -> DynamicImage {
+    if img.dimensions() == (width, height) {
+        return img.clone();
+    }
+    let mut canvas = RgbaImage::new(width, height);
+    let x = (i64::from(width) - i64::from(img.width())) / 2;
+    let y = (i64::from(height) - i64::from(img.height())) / 2;
+    image::imageops::overlay(&mut canvas, &img.to_rgba8(), x, y);
+    DynamicImage::ImageRgba8(canvas)
+}
+
+/// 縦横比が `width`:`height` になるように、はみ出した方だけを切り抜く
+pub fn crop(img: &DynamicImage, width: u32, height: u32, gravity: Gravity) -> DynamicImage {
+    let (src_w, src_h) = img.dimensions();
+    if src_w == 0 || src_h == 0 || width == 0 || height == 0 {
+        return img.clone();
+    }
+    // 短い方の辺が箱に合うように拡縮してからはみ出した方を切る
+    let scale = (width as f64 / src_w as f64).max(height as f64 / src_h as f64);
+    let crop_w = ((width as f64 / scale).round() as u32).clamp(1, src_w);
+    let crop_h = ((height as f64 / scale).round() as u32).clamp(1, src_h);
+
+    let (x, y) = match gravity {
+        Gravity::Center => ((src_w - crop_w) / 2, (src_h - crop_h) / 2),
+        Gravity::North => ((src_w - crop_w) / 2, 0),
+        Gravity::Smart => smart_offset(img, crop_w, crop_h),
+    };
+    img.crop_imm(x, y, crop_w, crop_h)
+}
+
+/// 縮小したグレースケールの勾配の和が最大になる位置を切り抜き窓の左上とする。
+/// 切り抜くのは縦か横の一方だけなので 1 次元の探索で済む。
+fn smart_offset(img: &DynamicImage, crop_w: u32, crop_h: u32) -> (u32, u32) {
+    let (src_w, src_h) = img.dimensions();
+    let small = img
+        .thumbnail(SMART_ANALYSIS_SIZE, SMART_ANALYSIS_SIZE)
+        .to_luma8();
+    let energy = edge_energy(&small);
+    let ratio = small.width() as f64 / src_w as f64;
+
+    if crop_w < src_w {
+        let window = ((crop_w as f64 * ratio).round() as usize).clamp(1, energy.columns.len());
+        let best = best_window(&energy.columns, window);
+        let x = (best as f64 / ratio).round() as u32;
+        (x.min(src_w - crop_w), (src_h - crop_h) / 2)
+    } else {
+        let window = ((crop_h as f64 * ratio).round() as usize).clamp(1, energy.rows.len());
+        let best = best_window(&energy.rows, window);
+        let y = (best as f64 / ratio).round() as u32;
+        ((src_w - crop_w) / 2, y.min(src_h - crop_h))
+    }
+}
+
+struct EdgeEnergy {
+    columns: Vec<u64>,
+    rows: Vec<u64>,
+}
+
+fn edge_energy(gray: &GrayImage) -> EdgeEnergy {
+    let (w, h) = gray.dimensions();
+    let mut columns = vec![0; w as usize];
+    let mut rows = vec![0; h as usize];
+    for y in 0..h {
+        for x in 0..w {
+            let v = i32::from(gray.get_pixel(x, y)[0]);
+            let dx = if x + 1 < w {
+                (i32::from(gray.get_pixel(x + 1, y)[0]) - v).unsigned_abs()
+            } else {
+                0
+            };
+            let dy = if y + 1 < h {
+                (i32::from(gray.get_pixel(x, y + 1)[0]) - v).unsigned_abs()
+            } else {
+                0
+            };
+            let e = u64::from(dx + dy);
+            columns[x as usize] += e;
+            rows[y as usize] += e;
+        }
+    }
+    EdgeEnergy { columns, rows }
+}
+
+/// 和が最大の長さ `window` の区間の開始位置。同点なら中央寄りを選ぶ。
+fn best_window(values: &[u64], window: usize) -> usize {
+    let positions = values.len() - window + 1;
+    let center = (positions - 1) as f64 / 2.0;
+    let mut sum: u64 = values[..window].iter().sum();
+    let mut best = (sum, 0);
+    for start in 1..positions {
+        sum = sum + values[start + window - 1] - values[start - 1];
+        let closer = (start as f64 - center).abs() < (best.1 as f64 - center).abs();
+        if sum > best.0 || (sum == best.0 && closer) {
+            best = (sum, start);
+        }
+    }
+    best.1
+}
@@ -0,0 +1,192 @@
+//! 動画のキーフレームがサムネイルとしてどれだけ良いかを測るスコア。
+//!
+//! `--movie-frame-scoring` で切り替える。試したい指標は `FrameScorer` を実装して
+//! `FrameScoring` に足せば、`movie_keyframe` の選択ループはそのまま使える。
+use crate::statistics;
+use clap::ValueEnum;
+use image::{DynamicImage, GrayImage};
+
+/// 輪郭とみなす隣の画素との明るさの差
+const EDGE_CONTRAST: u8 = 32;
+
+/// Rates how well a frame represents a video. Higher is better.
+pub trait FrameScorer {
+    fn score(&self, image: &DynamicImage) -> f32;
+
+    /// Score at which a frame is taken without looking at later keyframes, used when
+    /// `--movie-frame-score-threshold` is not given.
+    fn default_threshold(&self) -> f32;
+
+    /// Compared with `--movie-frame-sharpness-threshold`. Variance of the Laplacian by default.
+    fn sharpness(&self, image: &DynamicImage) -> f64 {
+        compute_frame_sharpness(image)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum FrameScoring {
+    /// Spread of brightness times mean saturation, penalizing very dark or bright frames
+    Heuristic,
+    /// Shannon entropy of the brightness histogram (0 to 8 bits)
+    Entropy,
+    /// Fraction of pixels on an edge
+    EdgeDensity,
+}
+
+impl FrameScoring {
+    /// The scorer, with `entropy_weight` times the brightness entropy added unless it is
+    /// already `entropy`.
+    pub fn scorer(self, entropy_weight: f32) -> Box<dyn FrameScorer> {
+        let scorer: Box<dyn FrameScorer> = match self {
+            FrameScoring::Heuristic => Box::new(Heuristic),
+            FrameScoring::Entropy => return Box::new(Entropy),
+            FrameScoring::EdgeDensity => Box::new(EdgeDensity),
+        };
+        if entropy_weight > 0.0 {
+            Box::new(WithEntropy {
+                inner: scorer,
+                weight: entropy_weight,
+            })
+        } else {
+            scorer
+        }
+    }
+}
+
+pub struct Heuristic;
+
+impl FrameScorer for Heuristic {
+    fn score(&self, image: &DynamicImage) -> f32 {
+        compute_frame_score(image)
+    }
+
+    fn default_threshold(&self) -> f32 {
+        1.0
+    }
+}
+
+pub struct Entropy;
+
+impl FrameScorer for Entropy {
+    fn score(&self, image: &DynamicImage) -> f32 {
+        brightness_entropy(&image.to_luma8())
+    }
+
+    /// 自然な映像はおおむね 7 ビット前後、黒味や単色の画面は 0 に近い
+    fn default_threshold(&self) -> f32 {
+        7.0
+    }
+}
+
+pub struct EdgeDensity;
+
+impl FrameScorer for EdgeDensity {
+    fn score(&self, image: &DynamicImage) -> f32 {
+        edge_density(&image.to_luma8())
+    }
+
+    fn default_threshold(&self) -> f32 {
+        0.1
+    }
+}
+
+/// 彩度の低い書類や雪景色は `Heuristic` だとほぼ 0 になるので、エントロピーを足して細部の多さも評価する
+pub struct WithEntropy {
+    inner: Box<dyn FrameScorer>,
+    weight: f32,
+}
+
+impl FrameScorer for WithEntropy {
+    fn score(&self, image: &DynamicImage) -> f32 {
+        self.inner.score(image) + self.weight * brightness_entropy(&image.to_luma8())
+    }
+
+    /// 足した分だけ閾値も上げ、エントロピーだけで即決しないようにする
+    fn default_threshold(&self) -> f32 {
+        self.inner.default_threshold() + self.weight * Entropy.default_threshold()
+    }
+
+    fn sharpness(&self, image: &DynamicImage) -> f64 {
+        self.inner.sharpness(image)
+    }
+}
+
+pub fn compute_frame_score(image: &DynamicImage) -> f32 {
+    let rgb = image.to_rgb8();
+    let mut brightness_stats = statistics::OnlineStats::new();
+    let mut saturation_stats = statistics::OnlineStats::new();
+
+    for pixel in rgb.pixels() {
+        let [r, g, b] = pixel.0;
+
+        // 明度 (Luma: Y)
+        // TODO: HSV の V で良い説
+        let luma = 0.299 * r as f64 + 0.587 * g as f64 + 0.114 * b as f64;
+        brightness_stats.update(luma);
+
+        // 彩度 (HSV の S)
+        let rf = r as f64 / 255.0;
+        let gf = g as f64 / 255.0;
+        let bf = b as f64 / 255.0;
+        let max = rf.max(gf).max(bf);
+        let min = rf.min(gf).min(bf);
+        let saturation = if max == 0.0 { 0.0 } else { (max - min) / max };
+        saturation_stats.update(saturation);
+    }
+
+    let brightness_penalty = 1.0 - ((brightness_stats.mean() - 128.0).abs() / 128.0);
+
+    (brightness_stats.stddev() * saturation_stats.mean() * brightness_penalty) as f32
+}
+
+fn compute_frame_sharpness(image: &DynamicImage) -> f64 {
+    let gray: GrayImage = image.to_luma8();
+
+    let lap = imageproc::filter::laplacian_filter(&gray);
+
+    let mut stats = statistics::OnlineStats::new();
+    for pixel in lap.pixels() {
+        let v = pixel[0] as f64;
+        stats.update(v);
+    }
+
+    stats.variance()
+}
+
+/// 明るさのヒストグラムのエントロピー。彩度に頼らないので、書類や雪景色でも細部が多ければ高い
+fn brightness_entropy(gray: &GrayImage) -> f32 {
+    let mut histogram = [0_u32; 256];
+    for pixel in gray.pixels() {
+        histogram[usize::from(pixel[0])] += 1;
+    }
+    let total = (gray.width() * gray.height()).max(1) as f32;
+    histogram
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f32 / total;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// 右か下の画素との明るさの差が `EDGE_CONTRAST` 以上の画素の割合
+fn edge_density(gray: &GrayImage) -> f32 {
+    let (width, height) = gray.dimensions();
+    let mut edges = 0_usize;
+    for y in 0..height {
+        for x in 0..width {
+            let value = gray.get_pixel(x, y)[0];
+            let right = (x + 1 < width).then(|| gray.get_pixel(x + 1, y)[0]);
+            let below = (y + 1 < height).then(|| gray.get_pixel(x, y + 1)[0]);
+            if [right, below]
+                .into_iter()
+                .flatten()
+                .any(|neighbor| value.abs_diff(neighbor) >= EDGE_CONTRAST)
+            {
+                edges += 1;
+            }
+        }
+    }
+    edges as f32 / (width * height).max(1) as f32
+}
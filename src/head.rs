@@ -0,0 +1,46 @@
+//! HEAD リクエスト。
+//!
+//! actix-web は HEAD への応答の本文を書かないだけで、ファイルやストリームは最後まで読んでしまう。
+//! 本文を捨てて `Content-Length` だけを残す。変換がまだの出力はハンドラ側で変換せずに返す。
+use actix_web::body::{BodySize, EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::web::Bytes;
+use actix_web::Error;
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Reports the size of the dropped body without producing it.
+struct HeadBody(BodySize);
+
+impl MessageBody for HeadBody {
+    type Error = Infallible;
+
+    fn size(&self) -> BodySize {
+        self.0
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        Poll::Ready(None)
+    }
+}
+
+pub async fn middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let head = req.method() == Method::HEAD;
+    let res = next.call(req).await?;
+    Ok(res.map_body(|_, body| {
+        if head {
+            EitherBody::right(HeadBody(body.size()))
+        } else {
+            EitherBody::left(body)
+        }
+    }))
+}
@@ -0,0 +1,34 @@
+use anyhow::Context;
+use image::{DynamicImage, RgbImage, RgbaImage};
+use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+use std::path::Path;
+
+pub fn load_image_from_heif(path: &Path) -> Result<DynamicImage, anyhow::Error> {
+    let path = path.to_str().context("Non UTF-8 path")?;
+    let context = HeifContext::read_from_file(path)?;
+    let handle = context.primary_image_handle()?;
+
+    let has_alpha = handle.has_alpha_channel();
+    let chroma = if has_alpha {
+        RgbChroma::Rgba
+    } else {
+        RgbChroma::Rgb
+    };
+    let image = LibHeif::new().decode(&handle, ColorSpace::Rgb(chroma), None)?;
+    let plane = image.planes().interleaved.context("No interleaved plane")?;
+
+    // stride は行末にパディングを含むことがあるので詰め直す
+    let channels = if has_alpha { 4 } else { 3 };
+    let row_bytes = plane.width as usize * channels;
+    let mut pixels = Vec::with_capacity(row_bytes * plane.height as usize);
+    for row in plane.data.chunks(plane.stride).take(plane.height as usize) {
+        pixels.extend_from_slice(&row[..row_bytes]);
+    }
+
+    let img = if has_alpha {
+        RgbaImage::from_raw(plane.width, plane.height, pixels).map(DynamicImage::ImageRgba8)
+    } else {
+        RgbImage::from_raw(plane.width, plane.height, pixels).map(DynamicImage::ImageRgb8)
+    };
+    img.context("Failed to build ImageBuffer")
+}
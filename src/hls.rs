@@ -0,0 +1,133 @@
+//! `/hls` で配信する HLS (MPEG-TS セグメントと VOD プレイリスト)。
+//!
+//! 最初のリクエストで動画全体を変換してキャッシュディレクトリに置き、以降はそこから返す。
+//! 変換は一時ディレクトリで行って rename するので、書きかけのプレイリストは見えない。
+use crate::transcode::{self, Container, TranscodeOption};
+use crate::FileKey;
+use anyhow::Context;
+use clap::Parser;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+pub const MASTER_PLAYLIST: &str = "master.m3u8";
+const MEDIA_PLAYLIST: &str = "index.m3u8";
+
+#[derive(Parser, Clone, Debug)]
+pub struct HlsOption {
+    /// Where generated playlists and segments are cached. Defaults to a directory under the
+    /// system temp dir
+    #[arg(long)]
+    hls_cache_dir: Option<PathBuf>,
+
+    /// Target length of each segment in seconds
+    #[arg(long, default_value_t = 6)]
+    hls_segment_seconds: u32,
+
+    /// Re-encode videos that are not H.264/HEVC with AAC/MP3 audio instead of answering 415
+    #[arg(long)]
+    hls_transcode: bool,
+
+    /// Downscale taller videos to this height. Only with `--hls-transcode`
+    #[arg(long)]
+    hls_max_height: Option<u32>,
+}
+
+impl HlsOption {
+    fn cache_dir(&self) -> PathBuf {
+        self.hls_cache_dir
+            .clone()
+            .unwrap_or_else(|| std::env::temp_dir().join("media_converter-hls"))
+    }
+
+    /// Returns the directory holding the playlists and segments of `key`, converting
+    /// `source` first when the cache is missing or older than the source.
+    pub fn prepare(
+        &self,
+        key: &FileKey,
+        source: &Path,
+        transcode_option: &TranscodeOption,
+    ) -> Result<PathBuf, anyhow::Error> {
+        let dir = self.cache_dir().join(&key.hkey);
+        let source_modified = std::fs::metadata(source)?.modified()?;
+        if is_fresh(&dir, source_modified) {
+            return Ok(dir);
+        }
+
+        let cache_dir = self.cache_dir();
+        std::fs::create_dir_all(&cache_dir)?;
+        let temp_dir = cache_dir.join(format!(
+            ".{}.{}.{}",
+            key.hkey,
+            std::process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir(&temp_dir)?;
+        let temp_dir = scopeguard::guard(temp_dir, |path| {
+            let _ = std::fs::remove_dir_all(path);
+        });
+
+        let job = transcode::Job {
+            container: Container::Hls,
+            max_height: self.hls_max_height.filter(|_| self.hls_transcode),
+            video_bit_rate: None,
+            range: None,
+            allow_encode: self.hls_transcode,
+            muxer_options: vec![
+                ("hls_time".to_string(), self.hls_segment_seconds.to_string()),
+                ("hls_list_size".to_string(), "0".to_string()),
+                ("hls_playlist_type".to_string(), "vod".to_string()),
+                (
+                    "hls_segment_filename".to_string(),
+                    temp_dir.join("seg%05d.ts").to_string_lossy().into_owned(),
+                ),
+                ("master_pl_name".to_string(), MASTER_PLAYLIST.to_string()),
+            ],
+        };
+        transcode::run(
+            source,
+            &temp_dir.join(MEDIA_PLAYLIST),
+            &job,
+            transcode_option,
+        )
+        .with_context(|| format!("Failed to convert {} to HLS", source.display()))?;
+
+        // 並行リクエストが先に置いた場合はそちらを使う
+        if dir.exists() && !is_fresh(&dir, source_modified) {
+            std::fs::remove_dir_all(&dir)?;
+        }
+        if let Err(err) = std::fs::rename(&*temp_dir, &dir) {
+            if !is_fresh(&dir, source_modified) {
+                return Err(err.into());
+            }
+        }
+        Ok(dir)
+    }
+}
+
+/// 完成したキャッシュにはマスタープレイリストがあり、元の動画より新しい
+fn is_fresh(dir: &Path, source_modified: SystemTime) -> bool {
+    std::fs::metadata(dir.join(MASTER_PLAYLIST))
+        .and_then(|metadata| metadata.modified())
+        .is_ok_and(|modified| modified >= source_modified)
+}
+
+/// Only the files the muxer writes can be requested; anything else (e.g. `..`) is rejected.
+pub fn is_valid_file_name(name: &str) -> bool {
+    if name == MASTER_PLAYLIST || name == MEDIA_PLAYLIST {
+        return true;
+    }
+    name.strip_prefix("seg")
+        .and_then(|rest| rest.strip_suffix(".ts"))
+        .is_some_and(|number| !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()))
+}
+
+pub fn content_type(name: &str) -> &'static str {
+    if name.ends_with(".m3u8") {
+        "application/vnd.apple.mpegurl"
+    } else {
+        "video/mp2t"
+    }
+}
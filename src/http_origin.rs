@@ -0,0 +1,216 @@
+//! `--origin-url`: 元ファイルを上流の HTTP / WebDAV サーバーから読む。
+//!
+//! アーカイブは遠くに置いたまま、サーバーをクライアントの近くで動かす。上流にはローカルと同じ
+//! `{先頭 2 文字}/{key}.{ext}` の構成で置き、大きさと更新時刻は HEAD で取る。一覧は WebDAV の
+//! PROPFIND で取るので、ただの HTTP サーバーではウォームアップと接頭辞での削除が使えない。
+//! 接続は ureq のエージェントで使い回す。
+use crate::media_store::{self, LocalSource, MediaStore, MetadataCache, SourceMetadata, Staging};
+use crate::{cache, FileKey};
+use clap::Parser;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+/// 一覧に使う PROPFIND の本文。名前だけでよいので何も求めない
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<propfind xmlns="DAV:"><prop><resourcetype/></prop></propfind>"#;
+
+#[derive(Parser)]
+pub struct OriginOption {
+    /// Read the sources from this HTTP or WebDAV server (e.g. `https://archive.example.com/media`)
+    /// instead of the base path
+    #[arg(long, conflicts_with = "s3_bucket")]
+    origin_url: Option<String>,
+
+    /// Keep the fetched sources under the base path and reuse them while they are unchanged.
+    /// Otherwise they are fetched into the system temp dir for every conversion
+    #[arg(long)]
+    origin_staging: bool,
+
+    /// Idle connections kept open to the origin
+    #[arg(long, default_value_t = 16)]
+    origin_pool_size: usize,
+
+    /// Timeout for connecting to the origin and for each read from it
+    #[arg(long, value_parser = cache::parse_duration, default_value = "30s")]
+    origin_timeout: Duration,
+}
+
+pub struct HttpOrigin {
+    agent: ureq::Agent,
+    url: String,
+    /// `ORIGIN_AUTHORIZATION` の値をそのまま `Authorization` に付ける
+    authorization: Option<String>,
+    metadata: MetadataCache,
+    staging: Staging,
+}
+
+impl HttpOrigin {
+    /// `None` unless `--origin-url` is given.
+    pub fn new(option: &OriginOption, base_path: PathBuf) -> Option<Self> {
+        let url = option.origin_url.as_ref()?;
+        let agent = ureq::AgentBuilder::new()
+            .max_idle_connections(option.origin_pool_size)
+            .max_idle_connections_per_host(option.origin_pool_size)
+            .timeout_connect(option.origin_timeout)
+            .timeout_read(option.origin_timeout)
+            .build();
+        let staging = if option.origin_staging {
+            Staging::new(base_path)
+        } else {
+            Staging::temporary(std::env::temp_dir().join("media_converter-origin"))
+        };
+        Some(HttpOrigin {
+            agent,
+            url: url.trim_end_matches('/').to_string(),
+            authorization: std::env::var("ORIGIN_AUTHORIZATION").ok(),
+            metadata: MetadataCache::default(),
+            staging,
+        })
+    }
+
+    fn request(&self, method: &str, url: &str) -> ureq::Request {
+        let request = self.agent.request(method, url);
+        match &self.authorization {
+            Some(authorization) => request.set("Authorization", authorization),
+            None => request,
+        }
+    }
+
+    fn source_url(&self, key: &FileKey) -> String {
+        format!(
+            "{}/{}/{}",
+            self.url,
+            &key.hkey[..2],
+            key.build_filename().to_string_lossy()
+        )
+    }
+
+    /// Names of the entries in the collection at `url`, with a trailing `/` for collections.
+    fn list(&self, url: &str) -> io::Result<Vec<String>> {
+        let body = self
+            .request("PROPFIND", url)
+            .set("Depth", "1")
+            .set("Content-Type", "application/xml")
+            .send_string(PROPFIND_BODY)
+            .map_err(|err| match err {
+                ureq::Error::Status(405 | 501, _) => io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "The origin does not support WebDAV listing",
+                ),
+                err => to_io_error(err, url),
+            })?
+            .into_string()?;
+        let document = roxmltree::Document::parse(&body).map_err(io::Error::other)?;
+        let mut names = Vec::new();
+        for href in document
+            .descendants()
+            .filter(|node| node.tag_name().name() == "href")
+            .filter_map(|node| node.text())
+        {
+            // 一覧には問い合わせたコレクション自身も入る
+            let trimmed = href.trim_end_matches('/');
+            let Some((_, name)) = trimmed.rsplit_once('/') else {
+                continue;
+            };
+            if url.trim_end_matches('/').ends_with(trimmed) {
+                continue;
+            }
+            names.push(if href.ends_with('/') {
+                format!("{}/", name)
+            } else {
+                name.to_string()
+            });
+        }
+        Ok(names)
+    }
+}
+
+impl MediaStore for HttpOrigin {
+    fn name(&self) -> &'static str {
+        "http"
+    }
+
+    fn metadata(&self, key: &FileKey) -> io::Result<SourceMetadata> {
+        self.metadata.get_or_fetch(key, || {
+            let url = self.source_url(key);
+            let response = self
+                .request("HEAD", &url)
+                .call()
+                .map_err(|err| to_io_error(err, &url))?;
+            let len = response
+                .header("Content-Length")
+                .and_then(|len| len.parse().ok())
+                .ok_or_else(|| io::Error::other(format!("No Content-Length for {}", url)))?;
+            // 更新時刻を返さないサーバーでは変わらないものとして扱う
+            let modified = response
+                .header("Last-Modified")
+                .and_then(|date| httpdate::parse_http_date(date).ok())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            Ok(SourceMetadata { len, modified })
+        })
+    }
+
+    fn open(
+        &self,
+        key: &FileKey,
+        start: u64,
+        length: Option<u64>,
+    ) -> io::Result<Box<dyn Read + Send>> {
+        let url = self.source_url(key);
+        let mut request = self.request("GET", &url);
+        match length {
+            Some(0) => return Ok(Box::new(io::empty())),
+            Some(length) => {
+                request = request.set("Range", &format!("bytes={}-{}", start, start + length - 1));
+            }
+            None if start > 0 => request = request.set("Range", &format!("bytes={}-", start)),
+            None => {}
+        }
+        let response = request.call().map_err(|err| to_io_error(err, &url))?;
+        // 範囲を無視して全体を返された
+        if start > 0 && response.status() != 206 {
+            return Err(io::Error::other(format!("No partial content for {}", url)));
+        }
+        Ok(Box::new(response.into_reader()))
+    }
+
+    fn local_path(&self, key: &FileKey) -> io::Result<LocalSource> {
+        let metadata = self.metadata(key)?;
+        self.staging
+            .fetch(key, metadata, || self.open(key, 0, None))
+    }
+
+    fn keys(&self, prefix: &str) -> io::Result<Vec<FileKey>> {
+        let mut keys = Vec::new();
+        for shard in self.list(&format!("{}/", self.url))? {
+            let Some(shard) = shard.strip_suffix('/') else {
+                continue;
+            };
+            if !media_store::may_hold(shard, prefix) {
+                continue;
+            }
+            for name in self.list(&format!("{}/{}/", self.url, shard))? {
+                if let Some(key) = FileKey::parse(name)
+                    .ok()
+                    .filter(|key| key.hkey.starts_with(prefix) && key.hkey.starts_with(shard))
+                {
+                    keys.push(key);
+                }
+            }
+        }
+        Ok(keys)
+    }
+}
+
+fn to_io_error(err: ureq::Error, url: &str) -> io::Error {
+    match err {
+        ureq::Error::Status(404, _) => {
+            io::Error::new(io::ErrorKind::NotFound, format!("{} not found", url))
+        }
+        ureq::Error::Status(status, _) => {
+            io::Error::other(format!("The origin returned {} for {}", status, url))
+        }
+        ureq::Error::Transport(transport) => io::Error::other(transport),
+    }
+}
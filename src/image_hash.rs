@@ -0,0 +1,199 @@
+use image::imageops::FilterType;
+use image::DynamicImage;
+
+const PHASH_SAMPLE: usize = 32;
+const PHASH_SIZE: usize = 8;
+
+pub fn blurhash(img: &DynamicImage) -> Result<String, anyhow::Error> {
+    // 成分数が少ないので縮小してから計算しても結果はほぼ変わらない
+    let small = img.thumbnail(32, 32).to_rgba8();
+    blurhash::encode(4, 3, small.width(), small.height(), small.as_raw())
+        .map_err(|err| anyhow::anyhow!("Failed to compute blurhash: {}", err))
+}
+
+/// ThumbHash は 100x100 以下の画像から計算する
+const THUMBHASH_MAX_SIZE: u32 = 100;
+
+/// ThumbHash (https://evanw.github.io/thumbhash/). Unlike blurhash it keeps the aspect ratio
+/// and alpha.
+pub fn thumbhash(img: &DynamicImage) -> Vec<u8> {
+    let small = img
+        .thumbnail(THUMBHASH_MAX_SIZE, THUMBHASH_MAX_SIZE)
+        .to_rgba8();
+    let (w, h) = (small.width() as usize, small.height() as usize);
+    let pixels: Vec<[f64; 4]> = small
+        .pixels()
+        .map(|p| p.0.map(|c| f64::from(c) / 255.0))
+        .collect();
+
+    // 透過部分の色は平均色で埋める
+    let (mut avg_r, mut avg_g, mut avg_b, mut avg_a) = (0.0, 0.0, 0.0, 0.0);
+    for [r, g, b, a] in &pixels {
+        avg_r += a * r;
+        avg_g += a * g;
+        avg_b += a * b;
+        avg_a += a;
+    }
+    if avg_a > 0.0 {
+        avg_r /= avg_a;
+        avg_g /= avg_a;
+        avg_b /= avg_a;
+    }
+
+    let has_alpha = avg_a < (w * h) as f64;
+    let l_limit = if has_alpha { 5.0 } else { 7.0 };
+    let longer = w.max(h) as f64;
+    let lx = ((l_limit * w as f64 / longer).round() as usize).max(1);
+    let ly = ((l_limit * h as f64 / longer).round() as usize).max(1);
+
+    let mut l = Vec::with_capacity(w * h);
+    let mut p = Vec::with_capacity(w * h);
+    let mut q = Vec::with_capacity(w * h);
+    let mut a = Vec::with_capacity(w * h);
+    for [r, g, b, alpha] in &pixels {
+        let r = avg_r * (1.0 - alpha) + alpha * r;
+        let g = avg_g * (1.0 - alpha) + alpha * g;
+        let b = avg_b * (1.0 - alpha) + alpha * b;
+        l.push((r + g + b) / 3.0);
+        p.push((r + g) / 2.0 - b);
+        q.push(r - g);
+        a.push(*alpha);
+    }
+
+    let (l_dc, l_ac, l_scale) = thumbhash_channel(&l, w, h, lx.max(3), ly.max(3));
+    let (p_dc, p_ac, p_scale) = thumbhash_channel(&p, w, h, 3, 3);
+    let (q_dc, q_ac, q_scale) = thumbhash_channel(&q, w, h, 3, 3);
+
+    let is_landscape = w > h;
+    let header24 = (63.0 * l_dc).round() as u32
+        | ((31.5 + 31.5 * p_dc).round() as u32) << 6
+        | ((31.5 + 31.5 * q_dc).round() as u32) << 12
+        | ((31.0 * l_scale).round() as u32) << 18
+        | u32::from(has_alpha) << 23;
+    let header16 = (if is_landscape { ly } else { lx }) as u32
+        | ((63.0 * p_scale).round() as u32) << 3
+        | ((63.0 * q_scale).round() as u32) << 9
+        | u32::from(is_landscape) << 15;
+    let mut hash = vec![
+        header24 as u8,
+        (header24 >> 8) as u8,
+        (header24 >> 16) as u8,
+        header16 as u8,
+        (header16 >> 8) as u8,
+    ];
+
+    let mut channels = vec![l_ac, p_ac, q_ac];
+    if has_alpha {
+        let (a_dc, a_ac, a_scale) = thumbhash_channel(&a, w, h, 5, 5);
+        hash.push((15.0 * a_dc).round() as u8 | ((15.0 * a_scale).round() as u8) << 4);
+        channels.push(a_ac);
+    }
+    // AC 成分は 4 bit ずつ詰める
+    for (i, f) in channels.into_iter().flatten().enumerate() {
+        let nibble = (15.0 * f).round() as u8;
+        if i % 2 == 0 {
+            hash.push(nibble);
+        } else {
+            *hash.last_mut().expect("pushed above") |= nibble << 4;
+        }
+    }
+    hash
+}
+
+/// DC 成分, 0-1 に正規化した AC 成分, AC の最大絶対値
+fn thumbhash_channel(
+    channel: &[f64],
+    w: usize,
+    h: usize,
+    nx: usize,
+    ny: usize,
+) -> (f64, Vec<f64>, f64) {
+    let mut dc = 0.0;
+    let mut ac = Vec::new();
+    let mut scale = 0.0_f64;
+    let mut fx = vec![0.0; w];
+    for cy in 0..ny {
+        let mut cx = 0;
+        // 高周波側の三角形は捨てる
+        while cx * ny < nx * (ny - cy) {
+            for (x, value) in fx.iter_mut().enumerate() {
+                *value = (std::f64::consts::PI / w as f64 * cx as f64 * (x as f64 + 0.5)).cos();
+            }
+            let mut f = 0.0;
+            for y in 0..h {
+                let fy = (std::f64::consts::PI / h as f64 * cy as f64 * (y as f64 + 0.5)).cos();
+                for x in 0..w {
+                    f += channel[x + y * w] * fx[x] * fy;
+                }
+            }
+            f /= (w * h) as f64;
+            if cx > 0 || cy > 0 {
+                ac.push(f);
+                scale = scale.max(f.abs());
+            } else {
+                dc = f;
+            }
+            cx += 1;
+        }
+    }
+    if scale > 0.0 {
+        for f in &mut ac {
+            *f = 0.5 + 0.5 / scale * *f;
+        }
+    }
+    (dc, ac, scale)
+}
+
+/// DCT-based perceptual hash (64 bit).
+pub fn phash(img: &DynamicImage) -> u64 {
+    let gray = img
+        .resize_exact(
+            PHASH_SAMPLE as u32,
+            PHASH_SAMPLE as u32,
+            FilterType::Triangle,
+        )
+        .to_luma8();
+    let pixels: Vec<f64> = gray.pixels().map(|p| p[0] as f64).collect();
+
+    let cos_table: Vec<Vec<f64>> = (0..PHASH_SIZE)
+        .map(|u| {
+            (0..PHASH_SAMPLE)
+                .map(|x| {
+                    ((2 * x + 1) as f64 * u as f64 * std::f64::consts::PI
+                        / (2 * PHASH_SAMPLE) as f64)
+                        .cos()
+                })
+                .collect()
+        })
+        .collect();
+
+    // 低周波成分だけ必要なので分離して計算する
+    let mut rows = vec![[0.0_f64; PHASH_SIZE]; PHASH_SAMPLE];
+    for (y, row) in rows.iter_mut().enumerate() {
+        for (u, value) in row.iter_mut().enumerate() {
+            *value = (0..PHASH_SAMPLE)
+                .map(|x| pixels[y * PHASH_SAMPLE + x] * cos_table[u][x])
+                .sum();
+        }
+    }
+
+    let mut coeffs = [0.0_f64; PHASH_SIZE * PHASH_SIZE];
+    for v in 0..PHASH_SIZE {
+        for u in 0..PHASH_SIZE {
+            coeffs[v * PHASH_SIZE + u] = (0..PHASH_SAMPLE)
+                .map(|y| rows[y][u] * cos_table[v][y])
+                .sum();
+        }
+    }
+
+    // DC 成分は除いて中央値を取る
+    let mut sorted = coeffs[1..].to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let median = sorted[sorted.len() / 2];
+
+    coeffs
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| **c > median)
+        .fold(0u64, |hash, (i, _)| hash | (1 << i))
+}
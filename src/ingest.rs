@@ -0,0 +1,117 @@
+//! `POST /ingest/<filename>`: 取り込んだばかりのファイルの事前処理。
+//!
+//! サムネイルは `/thumbnail` と同じ名前・同じ手順で作ってキャッシュに入れるので、最初のギャラリー
+//! 表示からキャッシュにある。ハッシュと寸法はサイドカーの `ingest.json` に書く。
+use crate::encode::OutputFormat;
+use crate::loader::LoadRequest;
+use crate::{
+    cache, convert_once, image_hash, prepare_thumbnail, save_sidecar, AppData, FileKey, Size,
+};
+use actix_web::web;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// 作るサムネイルの形式。ブラウザには `Accept` に応じてどちらかが返る
+const THUMBNAIL_FORMATS: [OutputFormat; 2] = [OutputFormat::Avif, OutputFormat::WebP];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum IngestStep {
+    /// Generate thumbnails for every size preset
+    Thumbnails,
+    /// Compute the BlurHash of the source
+    Blurhash,
+    /// Compute the perceptual hash of the source
+    Phash,
+    /// Record source dimensions, size and modification time
+    Probe,
+}
+
+#[derive(Serialize, Default)]
+struct IngestResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    blurhash: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    phash: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    probe: Option<Probe>,
+}
+
+#[derive(Serialize)]
+struct Probe {
+    width: u32,
+    height: u32,
+    bytes: u64,
+    modified: String,
+}
+
+pub fn run(app_data: &web::Data<AppData>, key: &FileKey) -> Result<(), anyhow::Error> {
+    let steps = &app_data.config.ingest_steps;
+    let metadata = app_data.store.metadata(key)?;
+
+    if steps.contains(&IngestStep::Thumbnails) {
+        warm_thumbnails(app_data, key, &metadata)?;
+    }
+    if !steps.iter().any(|step| {
+        matches!(
+            step,
+            IngestStep::Blurhash | IngestStep::Phash | IngestStep::Probe
+        )
+    }) {
+        return Ok(());
+    }
+
+    let canonical_path = app_data.store.local_path(key)?;
+    let img = app_data.loaders.load(
+        &canonical_path,
+        &app_data.config.load_image_option,
+        &LoadRequest {
+            hkey: Some(key.hkey.clone()),
+            ..Default::default()
+        },
+    )?;
+    let mut result = IngestResult::default();
+    if steps.contains(&IngestStep::Blurhash) {
+        result.blurhash = Some(image_hash::blurhash(&img)?);
+    }
+    if steps.contains(&IngestStep::Phash) {
+        result.phash = Some(format!("{:016x}", image_hash::phash(&img)));
+    }
+    if steps.contains(&IngestStep::Probe) {
+        result.probe = Some(Probe {
+            width: img.width(),
+            height: img.height(),
+            bytes: metadata.len,
+            modified: chrono::DateTime::<chrono::Utc>::from(metadata.modified).to_rfc3339(),
+        });
+    }
+
+    save_sidecar(app_data, key, "ingest.json", &serde_json::to_vec(&result)?);
+    log::info!("Ingested {}", canonical_path.display());
+    Ok(())
+}
+
+/// Generates the thumbnails `/thumbnail` serves without parameters into the cache layers.
+fn warm_thumbnails(
+    app_data: &web::Data<AppData>,
+    key: &FileKey,
+    metadata: &crate::media_store::SourceMetadata,
+) -> Result<(), anyhow::Error> {
+    let query = HashMap::new();
+    for format in THUMBNAIL_FORMATS {
+        for size in Size::ALL {
+            let (output_name, convert) =
+                prepare_thumbnail(app_data, key, &query, size, format, metadata.modified)
+                    .map_err(|err| anyhow::anyhow!("{}", err))?;
+            let cache_key = cache::CacheKey::new(key, metadata.modified, &output_name);
+            if app_data.cache.get(&cache_key).is_some() {
+                continue;
+            }
+            convert_once(app_data, key, metadata.modified, &output_name, convert)
+                .map_err(|err| anyhow::anyhow!("{}", err))?;
+        }
+    }
+    Ok(())
+}
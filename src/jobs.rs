@@ -0,0 +1,328 @@
+//! 時間のかかる変換のジョブ。
+//!
+//! 動画のサムネイルや `/transcode` の変換には数十秒かかることがあり、ロードバランサのタイムアウトや
+//! クライアントの再試行で同じ変換が積み重なる。最初のリクエストには `202 Accepted` とジョブの URL を
+//! 返してバックグラウンドで変換し、`/jobs/{id}` で終わったかどうかを返す。終わったら元の URL を
+//! もう一度リクエストすればキャッシュから返る。
+//!
+//! 進み具合は変換するスレッドから `report` で知らせ、`/jobs/{id}/events` で Server-Sent Events
+//! として流す。変換の関数に引数で渡すと、ジョブでないリクエストでの呼び出しまで変わるので、
+//! ジョブのスレッドにだけ置いたスレッドローカルに書く。
+use crate::AppData;
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::http::header;
+use actix_web::rt::time::Interval;
+use actix_web::web::{self, Bytes};
+use actix_web::HttpResponse;
+use clap::Parser;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+/// 終わったジョブを覚えておく時間。過ぎたら `/jobs/{id}` は 404 になる
+const FINISHED_TTL: Duration = Duration::from_secs(600);
+/// 202 と実行中の状態に付ける `Retry-After` の秒数
+const RETRY_AFTER_SECS: u32 = 5;
+/// `/jobs/{id}/events` が状態を見に行く間隔
+const EVENT_INTERVAL: Duration = Duration::from_millis(500);
+/// 変化がなくてもこの回数ごとにコメントを送り、途中のプロキシに切られないようにする
+const KEEPALIVE_TICKS: u32 = 30;
+
+thread_local! {
+    /// このスレッドで動いているジョブの進み具合
+    static CURRENT: RefCell<Option<Arc<Mutex<Progress>>>> = const { RefCell::new(None) };
+}
+
+#[derive(Parser)]
+pub struct JobOption {
+    /// Answer `/thumbnail` and `/media` of videos that are not cached yet with `202 Accepted`
+    /// and a job URL, and generate them in the background
+    #[arg(long)]
+    pub async_video: bool,
+}
+
+#[derive(Clone, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum JobState {
+    Running,
+    Done,
+    Failed { error: String },
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// 動画のキーフレームを探している
+    Scanning,
+    /// 動画を変換している
+    Transcoding,
+    /// 画像やアニメーションにエンコードしている
+    Encoding,
+}
+
+/// How far a running job has got.
+#[derive(Clone, Default, PartialEq, Serialize)]
+pub struct Progress {
+    stage: Option<Stage>,
+    /// 読んだフレーム数
+    frames: u64,
+    /// 動画の長さのうち読み終えた割合
+    percent: Option<f64>,
+}
+
+/// Updates the progress of the job running on this thread. Does nothing outside jobs.
+pub fn report(stage: Stage, frames: Option<u64>, position: Option<(f64, f64)>) {
+    CURRENT.with_borrow(|current| {
+        let Some(progress) = current else {
+            return;
+        };
+        let mut progress = progress.lock().unwrap();
+        if progress.stage != Some(stage) {
+            *progress = Progress::default();
+            progress.stage = Some(stage);
+        }
+        if let Some(frames) = frames {
+            progress.frames = frames;
+        }
+        if let Some((seconds, duration)) = position.filter(|&(_, duration)| duration > 0.0) {
+            let percent = (seconds / duration * 100.0).clamp(0.0, 100.0);
+            progress.percent = Some((percent * 10.0).round() / 10.0);
+        }
+    });
+}
+
+/// Where a job started by `Jobs::start` reports its progress.
+pub struct Tracker(Arc<Mutex<Progress>>);
+
+impl Tracker {
+    /// Sends the `report`s on this thread to the job until the guard is dropped.
+    pub fn enter(&self) -> impl Drop + use<> {
+        CURRENT.set(Some(self.0.clone()));
+        scopeguard::guard((), |_| CURRENT.set(None))
+    }
+}
+
+struct Job {
+    /// ジョブを始めたリクエストの URL。終わったらここを取り直す
+    url: String,
+    state: JobState,
+    progress: Arc<Mutex<Progress>>,
+    started: Instant,
+    finished: Option<Instant>,
+}
+
+#[derive(Serialize)]
+pub struct JobStatus {
+    id: String,
+    url: String,
+    #[serde(flatten)]
+    state: JobState,
+    progress: Progress,
+    elapsed_ms: u128,
+}
+
+#[derive(Default)]
+pub struct Jobs {
+    jobs: Mutex<HashMap<String, Job>>,
+}
+
+/// The ID of the job generating `target`, so that every request for it finds the same job.
+pub fn job_id(target: &str) -> String {
+    let digest = Sha256::digest(target.as_bytes());
+    digest[..16].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl Jobs {
+    /// Registers the job `id` started by a request for `url`. Returns `None` when it is
+    /// already running.
+    pub fn start(&self, id: &str, url: &str) -> Option<Tracker> {
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.retain(|_, job| {
+            job.finished
+                .is_none_or(|finished| finished.elapsed() < FINISHED_TTL)
+        });
+        if jobs
+            .get(id)
+            .is_some_and(|job| matches!(job.state, JobState::Running))
+        {
+            return None;
+        }
+        // 終わったジョブでも、ここに来たならキャッシュから消えているので作り直す
+        Some(register(&mut jobs, id, url))
+    }
+
+    /// The tracker of the job `id` about to run. A job that is not running is registered again,
+    /// as for queued tasks that outlived a restart or are retried.
+    pub fn resume(&self, id: &str, url: &str) -> Tracker {
+        let mut jobs = self.jobs.lock().unwrap();
+        match jobs.get(id) {
+            Some(job) if matches!(job.state, JobState::Running) => {
+                *job.progress.lock().unwrap() = Progress::default();
+                Tracker(job.progress.clone())
+            }
+            _ => register(&mut jobs, id, url),
+        }
+    }
+
+    pub fn finish(&self, id: &str, result: Result<(), String>) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(id) {
+            job.state = match result {
+                Ok(()) => JobState::Done,
+                Err(error) => JobState::Failed { error },
+            };
+            job.finished = Some(Instant::now());
+        }
+    }
+
+    /// The error of the job `id` if it failed within the last `FINISHED_TTL`.
+    pub fn failure(&self, id: &str) -> Option<String> {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs.get(id)?;
+        match &job.state {
+            JobState::Failed { error }
+                if job
+                    .finished
+                    .is_some_and(|finished| finished.elapsed() < FINISHED_TTL) =>
+            {
+                Some(error.clone())
+            }
+            _ => None,
+        }
+    }
+
+    pub fn status(&self, id: &str) -> Option<JobStatus> {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs.get(id)?;
+        Some(JobStatus {
+            id: id.to_string(),
+            url: job.url.clone(),
+            state: job.state.clone(),
+            progress: job.progress.lock().unwrap().clone(),
+            elapsed_ms: job
+                .finished
+                .unwrap_or_else(Instant::now)
+                .duration_since(job.started)
+                .as_millis(),
+        })
+    }
+}
+
+fn register(jobs: &mut HashMap<String, Job>, id: &str, url: &str) -> Tracker {
+    let progress = Arc::new(Mutex::new(Progress::default()));
+    jobs.insert(
+        id.to_string(),
+        Job {
+            url: url.to_string(),
+            state: JobState::Running,
+            progress: progress.clone(),
+            started: Instant::now(),
+            finished: None,
+        },
+    );
+    Tracker(progress)
+}
+
+impl JobStatus {
+    pub fn is_running(&self) -> bool {
+        matches!(self.state, JobState::Running)
+    }
+}
+
+/// `202 Accepted` pointing at `/jobs/{id}`.
+pub fn accepted(id: &str) -> HttpResponse {
+    let url = format!("/jobs/{}", id);
+    HttpResponse::Accepted()
+        .insert_header((header::LOCATION, url.clone()))
+        .insert_header((header::RETRY_AFTER, RETRY_AFTER_SECS.to_string()))
+        .json(serde_json::json!({ "id": id, "status": "running", "job": url }))
+}
+
+/// The state of a job. Polled until it is done, so it is never cached.
+pub fn status_response(status: &JobStatus) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    response.insert_header(header::CacheControl(vec![header::CacheDirective::NoStore]));
+    if status.is_running() {
+        response.insert_header((header::RETRY_AFTER, RETRY_AFTER_SECS.to_string()));
+    }
+    response.json(status)
+}
+
+/// `text/event-stream` of `/jobs/{id}/events`: a `progress` event whenever the state changes,
+/// then `done` or `failed`, after which the stream ends.
+pub fn events_response(app_data: web::Data<AppData>, id: &str) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(header::CacheControl(vec![header::CacheDirective::NoStore]))
+        // nginx にまとめてから送られないようにする
+        .insert_header(("X-Accel-Buffering", "no"))
+        .body(Events {
+            app_data,
+            id: id.to_string(),
+            interval: actix_web::rt::time::interval(EVENT_INTERVAL),
+            last: None,
+            idle: 0,
+            finished: false,
+        })
+}
+
+struct Events {
+    app_data: web::Data<AppData>,
+    id: String,
+    interval: Interval,
+    last: Option<(JobState, Progress)>,
+    idle: u32,
+    finished: bool,
+}
+
+impl MessageBody for Events {
+    type Error = Infallible;
+
+    fn size(&self) -> BodySize {
+        BodySize::Stream
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.get_mut();
+        loop {
+            if this.finished {
+                return Poll::Ready(None);
+            }
+            ready!(this.interval.poll_tick(cx));
+            // 忘れられたジョブはそこで終える
+            let Some(status) = this.app_data.jobs.status(&this.id) else {
+                return Poll::Ready(None);
+            };
+            this.finished = !status.is_running();
+            // 経過時間は毎回変わるので比べない
+            let current = (status.state.clone(), status.progress.clone());
+            if this.last.as_ref() != Some(&current) {
+                this.last = Some(current);
+                this.idle = 0;
+                let event = match status.state {
+                    JobState::Running => "progress",
+                    JobState::Done => "done",
+                    JobState::Failed { .. } => "failed",
+                };
+                let data = serde_json::to_string(&status).unwrap_or_default();
+                return Poll::Ready(Some(Ok(Bytes::from(format!(
+                    "event: {}\ndata: {}\n\n",
+                    event, data
+                )))));
+            }
+            this.idle += 1;
+            if this.idle >= KEEPALIVE_TICKS {
+                this.idle = 0;
+                return Poll::Ready(Some(Ok(Bytes::from_static(b": keepalive\n\n"))));
+            }
+        }
+    }
+}
@@ -0,0 +1,10 @@
+use image::DynamicImage;
+use std::path::Path;
+
+pub const JPEG2000_EXTENSIONS: &[&str] = &["jp2", "j2k", "j2c", "jpx"];
+
+/// OpenJPEG でデコードする。JP2 コンテナとコードストリームのみ (`.j2k`) の両方に対応。
+pub fn load_image_from_jpeg2000(path: &Path) -> Result<DynamicImage, anyhow::Error> {
+    let image = jpeg2k::Image::from_file(path)?;
+    Ok(DynamicImage::try_from(&image)?)
+}
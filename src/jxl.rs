@@ -0,0 +1,40 @@
+use anyhow::Context;
+use image::{DynamicImage, GrayAlphaImage, GrayImage, RgbImage, RgbaImage};
+use jpegxl_rs::encode::EncoderResult;
+use jpegxl_rs::{decoder_builder, encoder_builder};
+use std::path::Path;
+
+pub fn load_image_from_jxl(path: &Path) -> Result<DynamicImage, anyhow::Error> {
+    let bytes = std::fs::read(path)?;
+    let decoder = decoder_builder().build()?;
+    let (metadata, pixels) = decoder.decode_with::<u8>(&bytes)?;
+
+    let (width, height) = (metadata.width, metadata.height);
+    let channels = metadata.num_color_channels + u32::from(metadata.has_alpha_channel);
+    let img = match channels {
+        1 => GrayImage::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8),
+        2 => GrayAlphaImage::from_raw(width, height, pixels).map(DynamicImage::ImageLumaA8),
+        3 => RgbImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8),
+        4 => RgbaImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgba8),
+        _ => anyhow::bail!("Unsupported channel count: {}", channels),
+    };
+    img.context("Failed to build ImageBuffer")
+}
+
+/// `distance` is the butteraugli distance: 0.0 is lossless, 1.0 is visually lossless.
+pub fn encode_jxl(img: &DynamicImage, distance: f32) -> Result<Vec<u8>, anyhow::Error> {
+    let has_alpha = img.color().has_alpha();
+    let mut encoder = encoder_builder()
+        .has_alpha(has_alpha)
+        .quality(distance)
+        .build()?;
+
+    let result: EncoderResult<u8> = if has_alpha {
+        let rgba = img.to_rgba8();
+        encoder.encode::<u8, u8>(rgba.as_raw(), rgba.width(), rgba.height())?
+    } else {
+        let rgb = img.to_rgb8();
+        encoder.encode::<u8, u8>(rgb.as_raw(), rgb.width(), rgb.height())?
+    };
+    Ok(result.data)
+}
@@ -0,0 +1,233 @@
+//! `--movie-keyframe-db`: 動画ごとのキーフレームのスコアを SQLite に残す。
+//!
+//! キーは元ファイルのキー（内容のハッシュ）と、スコアの付け方を決めるオプションのダイジェスト。
+//! しきい値はダイジェストに含めず、残したスコアから選び直すので、しきい値を変えてもデコードし直さない。
+//! そのため索引を使うときは、しきい値を超えたフレームが見つかっても最後まで走査して全部のスコアを残す。
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS scans (
+    hkey TEXT NOT NULL,
+    stream INTEGER NOT NULL,
+    version TEXT NOT NULL,
+    selected_pts INTEGER,
+    scanned_at INTEGER NOT NULL,
+    PRIMARY KEY (hkey, stream, version)
+);
+CREATE TABLE IF NOT EXISTS frames (
+    hkey TEXT NOT NULL,
+    stream INTEGER NOT NULL,
+    version TEXT NOT NULL,
+    ordinal INTEGER NOT NULL,
+    pts INTEGER,
+    seconds REAL NOT NULL,
+    score REAL,
+    sharpness REAL,
+    skipped TEXT,
+    PRIMARY KEY (hkey, stream, version, ordinal)
+);
+";
+
+/// A keyframe seen while scanning a video.
+#[derive(Serialize)]
+pub struct ScoredFrame {
+    /// presentation timestamp in the time base of the video stream
+    pub pts: Option<i64>,
+    pub seconds: f64,
+    pub score: Option<f32>,
+    pub sharpness: Option<f32>,
+    /// `flat`, `text` or `outside` the window; such frames are only used when nothing was scored
+    pub skipped: Option<String>,
+}
+
+/// The keyframe a scan picks with these thresholds: the first that passes them, otherwise the
+/// best scored, otherwise the first skipped one.
+pub fn select(
+    frames: &[ScoredFrame],
+    threshold_score: f32,
+    threshold_sharpness: Option<f32>,
+) -> Option<i64> {
+    let passes = |frame: &&ScoredFrame| {
+        frame.score.is_some_and(|score| score >= threshold_score)
+            && threshold_sharpness.is_none_or(|threshold| {
+                frame
+                    .sharpness
+                    .is_some_and(|sharpness| sharpness >= threshold)
+            })
+    };
+    if let Some(frame) = frames.iter().find(passes) {
+        return frame.pts;
+    }
+    // 同点なら先のフレーム
+    let mut best: Option<&ScoredFrame> = None;
+    for frame in frames.iter().filter(|frame| frame.score.is_some()) {
+        if best.is_none_or(|best| frame.score > best.score) {
+            best = Some(frame);
+        }
+    }
+    best.or_else(|| frames.first()).and_then(|frame| frame.pts)
+}
+
+/// One scan of a video, for the debug endpoint.
+#[derive(Serialize)]
+pub struct Scan {
+    stream: Option<usize>,
+    version: String,
+    selected_pts: Option<i64>,
+    /// UNIX time of the scan
+    scanned_at: i64,
+    frames: Vec<ScoredFrame>,
+}
+
+pub struct KeyframeIndex {
+    conn: Connection,
+}
+
+/// `?stream=` is part of the key; the automatic choice is stored as -1.
+fn stream_column(stream: Option<usize>) -> i64 {
+    stream.map_or(-1, |stream| stream as i64)
+}
+
+impl KeyframeIndex {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        // 複数のワーカーから同時に書き込む
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(KeyframeIndex { conn })
+    }
+
+    /// The keyframes scored for `hkey` with the scoring options `version`, in the order they
+    /// were seen.
+    pub fn frames(
+        &self,
+        hkey: &str,
+        stream: Option<usize>,
+        version: &str,
+    ) -> rusqlite::Result<Option<Vec<ScoredFrame>>> {
+        let scanned = self
+            .conn
+            .query_row(
+                "SELECT 1 FROM scans WHERE hkey = ?1 AND stream = ?2 AND version = ?3",
+                params![hkey, stream_column(stream), version],
+                |_| Ok(()),
+            )
+            .optional()?;
+        if scanned.is_none() {
+            return Ok(None);
+        }
+        self.load_frames(hkey, stream_column(stream), version)
+            .map(Some)
+    }
+
+    fn load_frames(
+        &self,
+        hkey: &str,
+        stream: i64,
+        version: &str,
+    ) -> rusqlite::Result<Vec<ScoredFrame>> {
+        let mut statement = self.conn.prepare(
+            "SELECT pts, seconds, score, sharpness, skipped FROM frames
+             WHERE hkey = ?1 AND stream = ?2 AND version = ?3 ORDER BY ordinal",
+        )?;
+        statement
+            .query_map(params![hkey, stream, version], |row| {
+                Ok(ScoredFrame {
+                    pts: row.get(0)?,
+                    seconds: row.get(1)?,
+                    score: row.get(2)?,
+                    sharpness: row.get(3)?,
+                    skipped: row.get(4)?,
+                })
+            })?
+            .collect()
+    }
+
+    /// Replaces the scan of `hkey` with `frames` and the keyframe picked from them.
+    pub fn store(
+        &mut self,
+        hkey: &str,
+        stream: Option<usize>,
+        version: &str,
+        frames: &[ScoredFrame],
+        selected_pts: Option<i64>,
+    ) -> rusqlite::Result<()> {
+        let stream = stream_column(stream);
+        let scanned_at = crate::secs(SystemTime::now()) as i64;
+        let transaction = self.conn.transaction()?;
+        transaction.execute(
+            "DELETE FROM frames WHERE hkey = ?1 AND stream = ?2 AND version = ?3",
+            params![hkey, stream, version],
+        )?;
+        {
+            let mut insert = transaction.prepare(
+                "INSERT INTO frames
+                 (hkey, stream, version, ordinal, pts, seconds, score, sharpness, skipped)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            )?;
+            for (ordinal, frame) in frames.iter().enumerate() {
+                insert.execute(params![
+                    hkey,
+                    stream,
+                    version,
+                    ordinal as i64,
+                    frame.pts,
+                    frame.seconds,
+                    frame.score,
+                    frame.sharpness,
+                    frame.skipped,
+                ])?;
+            }
+        }
+        transaction.execute(
+            "INSERT OR REPLACE INTO scans (hkey, stream, version, selected_pts, scanned_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![hkey, stream, version, selected_pts, scanned_at],
+        )?;
+        transaction.commit()
+    }
+
+    /// Records the keyframe picked again from a stored scan.
+    pub fn set_selected(
+        &self,
+        hkey: &str,
+        stream: Option<usize>,
+        version: &str,
+        selected_pts: Option<i64>,
+    ) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "UPDATE scans SET selected_pts = ?4 WHERE hkey = ?1 AND stream = ?2 AND version = ?3",
+            params![hkey, stream_column(stream), version, selected_pts],
+        )?;
+        Ok(())
+    }
+
+    /// Every scan of `hkey`, newest first.
+    pub fn scans(&self, hkey: &str) -> rusqlite::Result<Vec<Scan>> {
+        let mut statement = self.conn.prepare(
+            "SELECT stream, version, selected_pts, scanned_at FROM scans
+             WHERE hkey = ?1 ORDER BY scanned_at DESC",
+        )?;
+        let scans: Vec<(i64, String, Option<i64>, i64)> = statement
+            .query_map(params![hkey], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        scans
+            .into_iter()
+            .map(|(stream, version, selected_pts, scanned_at)| {
+                Ok(Scan {
+                    stream: usize::try_from(stream).ok(),
+                    frames: self.load_frames(hkey, stream, &version)?,
+                    version,
+                    selected_pts,
+                    scanned_at,
+                })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,690 @@
+use crate::{
+    animation, archive, audio, clip, model, movie_keyframe, psd_stream, raw, sidecar, sniff, svg,
+    text_preview, tiff_page, xcf, ApiError, LoadImageOption,
+};
+use image::error::ImageError;
+use image::{DynamicImage, ImageDecoder};
+use psd::Psd;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Decodes a source file into a `DynamicImage`.
+pub trait MediaLoader: Send + Sync {
+    fn load(
+        &self,
+        path: &Path,
+        option: &LoadImageOption,
+        request: &LoadRequest,
+    ) -> Result<DynamicImage, ApiError>;
+}
+
+/// Per-request hints for loaders.
+#[derive(Clone, Debug, Default)]
+pub struct LoadRequest {
+    /// Bounding box the result will be resized into. Vector formats render at this size.
+    pub target: Option<(u32, u32)>,
+
+    /// 1-based page for multi-page documents (`?page=N`)
+    pub page: Option<u32>,
+
+    /// Seconds from the start of a video (`?t=`); skips keyframe scoring
+    pub timestamp: Option<f64>,
+
+    /// Index of the video stream to use (`?stream=N`) instead of the automatic choice
+    pub stream: Option<usize>,
+
+    /// Sidecar remembering the keyframe chosen for a video. `None` scores the keyframes on
+    /// every request
+    pub keyframe_sidecar: Option<PathBuf>,
+
+    /// Key of the source, under which `--movie-keyframe-db` keeps the keyframe scores
+    pub hkey: Option<String>,
+}
+
+impl LoadRequest {
+    /// Inserts the request variants (e.g. `page2`, `t12.5`) before the extension of a sidecar
+    /// name.
+    pub fn sidecar_name(&self, name: &str) -> String {
+        let name = match self.page {
+            Some(page) => sidecar::with_variant(name, &format!("page{}", page)),
+            None => name.to_string(),
+        };
+        let name = match self.timestamp {
+            Some(seconds) => sidecar::with_variant(&name, &format!("t{}", seconds)),
+            None => name,
+        };
+        match self.stream {
+            Some(index) => sidecar::with_variant(&name, &format!("s{}", index)),
+            None => name,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoaderKey {
+    Extension(String),
+    /// `type/subtype` or `type/*`
+    Mime(String),
+}
+
+impl LoaderKey {
+    fn matches(&self, ext: &str, mime: &mime::Mime) -> bool {
+        match self {
+            LoaderKey::Extension(e) => e == ext,
+            LoaderKey::Mime(m) => match m.strip_suffix("/*") {
+                Some(type_) => mime.type_().as_str() == type_,
+                None => mime.essence_str() == m,
+            },
+        }
+    }
+}
+
+/// `KEY=LOADER[:PRIORITY]` given on the command line.
+///
+/// `KEY` containing `/` is treated as a MIME type, otherwise as an extension.
+#[derive(Clone, Debug)]
+pub struct LoaderMapping {
+    key: LoaderKey,
+    loader: String,
+    priority: i32,
+}
+
+impl FromStr for LoaderMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, rest) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected KEY=LOADER[:PRIORITY]: {}", s))?;
+        let (loader, priority) = match rest.split_once(':') {
+            Some((loader, priority)) => (
+                loader,
+                priority
+                    .parse()
+                    .map_err(|err| format!("invalid priority {}: {}", priority, err))?,
+            ),
+            None => (rest, CONFIG_PRIORITY),
+        };
+
+        let key = key.to_lowercase();
+        let key = if key.contains('/') {
+            LoaderKey::Mime(key)
+        } else {
+            LoaderKey::Extension(key)
+        };
+
+        Ok(LoaderMapping {
+            key,
+            loader: loader.to_string(),
+            priority,
+        })
+    }
+}
+
+pub const BUILTIN_PRIORITY: i32 = 0;
+const CONFIG_PRIORITY: i32 = 100;
+
+struct Registration {
+    key: LoaderKey,
+    priority: i32,
+    loader: Arc<dyn MediaLoader>,
+}
+
+pub struct LoaderRegistry {
+    named: HashMap<String, Arc<dyn MediaLoader>>,
+    registrations: Vec<Registration>,
+    fallback: Arc<dyn MediaLoader>,
+}
+
+impl LoaderRegistry {
+    pub fn with_builtin() -> Self {
+        let mut registry = LoaderRegistry {
+            named: HashMap::new(),
+            registrations: Vec::new(),
+            fallback: Arc::new(ImageLoader),
+        };
+
+        registry.add_loader("image", Arc::new(ImageLoader));
+        registry.add_loader("psd", Arc::new(PsdLoader));
+        registry.add_loader("tiff", Arc::new(TiffLoader));
+        registry.add_loader("xcf", Arc::new(XcfLoader));
+        registry.add_loader("kra", Arc::new(KraLoader));
+        registry.add_loader("clip", Arc::new(ClipLoader));
+        registry.add_loader("apng", Arc::new(ApngLoader));
+        registry.add_loader("movie", Arc::new(MovieLoader));
+        registry.add_loader("text", Arc::new(TextLoader));
+        registry.add_loader("audio", Arc::new(AudioLoader));
+        registry.add_loader("model", Arc::new(ModelLoader));
+        registry.add_loader("raw", Arc::new(RawLoader));
+        registry.add_loader(
+            "svg",
+            Arc::new(SvgLoader {
+                fontdb: svg::load_system_fonts(),
+            }),
+        );
+        registry.add_loader("epub", Arc::new(EpubLoader));
+        registry.add_loader("cbz", Arc::new(CbzLoader));
+        registry.add_loader("office", Arc::new(OfficeLoader));
+        #[cfg(feature = "cbr")]
+        registry.add_loader("cbr", Arc::new(CbrLoader));
+        #[cfg(feature = "jxl")]
+        registry.add_loader("jxl", Arc::new(JxlLoader));
+        #[cfg(feature = "heif")]
+        registry.add_loader("heif", Arc::new(HeifLoader));
+        #[cfg(feature = "jpeg2000")]
+        registry.add_loader("jpeg2000", Arc::new(Jpeg2000Loader));
+
+        let builtin = [
+            ("psd", &["psd", "psb"][..]),
+            ("tiff", tiff_page::TIFF_EXTENSIONS),
+            ("xcf", &["xcf"][..]),
+            ("kra", &["kra"][..]),
+            ("clip", &["clip"][..]),
+            ("apng", &["png", "apng"][..]),
+            ("audio", audio::AUDIO_EXTENSIONS),
+            ("model", model::MODEL_EXTENSIONS),
+            ("raw", raw::RAW_EXTENSIONS),
+            ("svg", svg::SVG_EXTENSIONS),
+            ("epub", &["epub"][..]),
+            ("cbz", &["cbz"][..]),
+            ("office", archive::OFFICE_EXTENSIONS),
+            #[cfg(feature = "cbr")]
+            ("cbr", &["cbr"][..]),
+            #[cfg(feature = "jxl")]
+            ("jxl", &["jxl"][..]),
+            #[cfg(feature = "heif")]
+            ("heif", &["heic", "heif", "hif"][..]),
+            #[cfg(feature = "jpeg2000")]
+            ("jpeg2000", crate::jpeg2000::JPEG2000_EXTENSIONS),
+        ];
+        for (name, extensions) in builtin {
+            for ext in extensions {
+                registry
+                    .register(
+                        LoaderKey::Extension(ext.to_string()),
+                        BUILTIN_PRIORITY,
+                        name,
+                    )
+                    .expect("builtin loader");
+            }
+        }
+        for ext in text_preview::TEXT_EXTENSIONS {
+            registry
+                .register(
+                    LoaderKey::Extension(ext.to_string()),
+                    BUILTIN_PRIORITY,
+                    "text",
+                )
+                .expect("builtin loader");
+        }
+
+        registry
+    }
+
+    /// Routes `extensions` to the keyframe extractor at builtin priority.
+    pub fn register_movie_extensions(&mut self, extensions: &[String]) {
+        for ext in extensions {
+            self.register(
+                LoaderKey::Extension(ext.to_lowercase()),
+                BUILTIN_PRIORITY,
+                "movie",
+            )
+            .expect("builtin loader");
+        }
+    }
+
+    /// Makes a loader available by name to `register` and `--loader` mappings.
+    pub fn add_loader(&mut self, name: impl Into<String>, loader: Arc<dyn MediaLoader>) {
+        self.named.insert(name.into(), loader);
+    }
+
+    pub fn register(&mut self, key: LoaderKey, priority: i32, name: &str) -> Result<(), String> {
+        let loader = self
+            .named
+            .get(name)
+            .ok_or_else(|| format!("unknown loader: {}", name))?
+            .clone();
+        self.registrations.push(Registration {
+            key,
+            priority,
+            loader,
+        });
+        Ok(())
+    }
+
+    pub fn apply(&mut self, mapping: &LoaderMapping) -> Result<(), String> {
+        self.register(mapping.key.clone(), mapping.priority, &mapping.loader)
+    }
+
+    /// Picks the highest priority loader. On ties the later registration wins.
+    fn find(&self, ext: &str) -> &dyn MediaLoader {
+        let mime = actix_files::file_extension_to_mime(ext);
+
+        self.registrations
+            .iter()
+            .filter(|r| r.key.matches(ext, &mime))
+            .fold(None, |best: Option<&Registration>, r| match best {
+                Some(b) if b.priority > r.priority => Some(b),
+                _ => Some(r),
+            })
+            .map(|r| r.loader.as_ref())
+            .unwrap_or(self.fallback.as_ref())
+    }
+
+    /// Dispatches on the extension. Keys without one, and files the extension's loader fails
+    /// on, are retried with the loader for the format sniffed from the content.
+    pub fn load(
+        &self,
+        path: &Path,
+        option: &LoadImageOption,
+        request: &LoadRequest,
+    ) -> Result<DynamicImage, ApiError> {
+        let ext = path
+            .extension()
+            .and_then(OsStr::to_str)
+            .unwrap_or("")
+            .to_lowercase();
+        if ext.is_empty() {
+            let sniffed = sniff::sniff_extension(path).unwrap_or("");
+            return self.find(sniffed).load(path, option, request);
+        }
+
+        let loader = self.find(&ext);
+        match loader.load(path, option, request) {
+            Err(ApiError::NotFound()) => Err(ApiError::NotFound()),
+            Err(err) => {
+                let Some(sniffed) = sniff::sniff_extension(path) else {
+                    return Err(err);
+                };
+                let fallback = self.find(sniffed);
+                if std::ptr::addr_eq(fallback, loader) {
+                    return Err(err);
+                }
+                log::debug!(
+                    "{}: retrying as {} after error: {}",
+                    path.display(),
+                    sniffed,
+                    err
+                );
+                fallback.load(path, option, request)
+            }
+            ok => ok,
+        }
+    }
+}
+
+struct ImageLoader;
+
+impl MediaLoader for ImageLoader {
+    fn load(
+        &self,
+        path: &Path,
+        _option: &LoadImageOption,
+        _request: &LoadRequest,
+    ) -> Result<DynamicImage, ApiError> {
+        load_image_from_file(path).map_err(ApiError::FailedToDecode)
+    }
+}
+
+/// Small/Medium のサムネイルは PSD 内蔵のサムネイルで済ませる
+const PSD_THUMBNAIL_RESOURCE_MAX_TARGET: u32 = 300;
+
+struct PsdLoader;
+
+impl MediaLoader for PsdLoader {
+    fn load(
+        &self,
+        path: &Path,
+        _option: &LoadImageOption,
+        request: &LoadRequest,
+    ) -> Result<DynamicImage, ApiError> {
+        if request
+            .target
+            .is_some_and(|(w, h)| w.max(h) <= PSD_THUMBNAIL_RESOURCE_MAX_TARGET)
+        {
+            match psd_stream::load_thumbnail_resource(path) {
+                Ok(Some(img)) => return Ok(img),
+                Ok(None) => {}
+                Err(err) => {
+                    log::debug!("{}: ignoring thumbnail resource: {}", path.display(), err)
+                }
+            }
+        }
+        match psd_stream::load_image_from_psd_stream(path) {
+            Ok(img) => Ok(img),
+            Err(err) => {
+                log::debug!("{}: falling back to psd crate: {}", path.display(), err);
+                load_image_from_psd(path).map_err(ApiError::FailedToDecode)
+            }
+        }
+    }
+}
+
+struct XcfLoader;
+
+impl MediaLoader for XcfLoader {
+    fn load(
+        &self,
+        path: &Path,
+        _option: &LoadImageOption,
+        _request: &LoadRequest,
+    ) -> Result<DynamicImage, ApiError> {
+        xcf::load_image_from_xcf(path).map_err(|err| ApiError::FailedToDecodeFormat("xcf", err))
+    }
+}
+
+struct KraLoader;
+
+impl MediaLoader for KraLoader {
+    fn load(
+        &self,
+        path: &Path,
+        _option: &LoadImageOption,
+        _request: &LoadRequest,
+    ) -> Result<DynamicImage, ApiError> {
+        archive::load_image_from_kra(path).map_err(|err| ApiError::FailedToDecodeFormat("kra", err))
+    }
+}
+
+struct ClipLoader;
+
+impl MediaLoader for ClipLoader {
+    fn load(
+        &self,
+        path: &Path,
+        _option: &LoadImageOption,
+        _request: &LoadRequest,
+    ) -> Result<DynamicImage, ApiError> {
+        clip::load_image_from_clip(path).map_err(|err| ApiError::FailedToDecodeFormat("clip", err))
+    }
+}
+
+/// Picks a representative frame of an APNG. Plain PNGs are decoded as usual.
+struct ApngLoader;
+
+impl MediaLoader for ApngLoader {
+    fn load(
+        &self,
+        path: &Path,
+        _option: &LoadImageOption,
+        _request: &LoadRequest,
+    ) -> Result<DynamicImage, ApiError> {
+        match animation::load_poster_frame(path) {
+            Ok(Some(img)) => Ok(img),
+            Ok(None) => load_image_from_file(path).map_err(ApiError::FailedToDecode),
+            Err(err) => Err(ApiError::FailedToDecodeFormat("apng", err)),
+        }
+    }
+}
+
+struct TiffLoader;
+
+impl MediaLoader for TiffLoader {
+    fn load(
+        &self,
+        path: &Path,
+        _option: &LoadImageOption,
+        request: &LoadRequest,
+    ) -> Result<DynamicImage, ApiError> {
+        match request.page {
+            None | Some(1) => load_image_from_file(path).map_err(ApiError::FailedToDecode),
+            Some(page) => tiff_page::load_image_from_tiff_page(path, page)
+                .map_err(|err| ApiError::FailedToDecodeFormat("tiff", err))?
+                .ok_or(ApiError::NotFound()),
+        }
+    }
+}
+
+struct MovieLoader;
+
+impl MediaLoader for MovieLoader {
+    fn load(
+        &self,
+        path: &Path,
+        option: &LoadImageOption,
+        request: &LoadRequest,
+    ) -> Result<DynamicImage, ApiError> {
+        if let Some(seconds) = request.timestamp {
+            return movie_keyframe::load_image_at_timestamp(path, seconds, request.stream)
+                .map(|image| option.keyframe.crop(image))
+                .map_err(movie_error);
+        }
+        // カバー画像が埋め込まれていればキーフレームを探すより速く、内容も代表的。
+        // ストリームを指定された場合はそのストリームから取る
+        if request.stream.is_none() {
+            match audio::extract_attached_picture(path) {
+                Ok(Some(img)) => return Ok(img),
+                Ok(None) => {}
+                Err(err) => log::debug!("{}: ignoring cover art: {}", path.display(), err),
+            }
+        }
+        movie_keyframe::load_image_from_movie_keyframe(
+            path,
+            &option.keyframe,
+            request.stream,
+            request.keyframe_sidecar.as_deref(),
+            request.hkey.as_deref(),
+        )
+        .map(|image| option.keyframe.crop(image))
+        .map_err(movie_error)
+    }
+}
+
+/// 存在しないストリームの指定はリクエストの誤り
+fn movie_error(err: anyhow::Error) -> ApiError {
+    match err.downcast::<movie_keyframe::NotVideoStream>() {
+        Ok(not_video) => ApiError::InvalidOperation(not_video.to_string()),
+        Err(err) => ApiError::FailedToDecodeMovie(err),
+    }
+}
+
+struct TextLoader;
+
+impl MediaLoader for TextLoader {
+    fn load(
+        &self,
+        path: &Path,
+        option: &LoadImageOption,
+        _request: &LoadRequest,
+    ) -> Result<DynamicImage, ApiError> {
+        text_preview::load_image_from_text(path, &option.text_preview_font)
+            .map_err(ApiError::FailedToRenderText)
+    }
+}
+
+struct AudioLoader;
+
+impl MediaLoader for AudioLoader {
+    fn load(
+        &self,
+        path: &Path,
+        option: &LoadImageOption,
+        _request: &LoadRequest,
+    ) -> Result<DynamicImage, ApiError> {
+        audio::load_image_from_audio(path, &option.waveform)
+            .map_err(|err| ApiError::FailedToDecodeFormat("audio", err))
+    }
+}
+
+struct ModelLoader;
+
+impl MediaLoader for ModelLoader {
+    fn load(
+        &self,
+        path: &Path,
+        option: &LoadImageOption,
+        _request: &LoadRequest,
+    ) -> Result<DynamicImage, ApiError> {
+        model::load_image_from_model(path, &option.model)
+            .map_err(|err| ApiError::FailedToDecodeFormat("model", err))
+    }
+}
+
+struct RawLoader;
+
+impl MediaLoader for RawLoader {
+    fn load(
+        &self,
+        path: &Path,
+        _option: &LoadImageOption,
+        _request: &LoadRequest,
+    ) -> Result<DynamicImage, ApiError> {
+        raw::load_image_from_raw(path).map_err(|err| ApiError::FailedToDecodeFormat("raw", err))
+    }
+}
+
+struct SvgLoader {
+    fontdb: Arc<resvg::usvg::fontdb::Database>,
+}
+
+impl MediaLoader for SvgLoader {
+    fn load(
+        &self,
+        path: &Path,
+        _option: &LoadImageOption,
+        request: &LoadRequest,
+    ) -> Result<DynamicImage, ApiError> {
+        svg::load_image_from_svg(path, self.fontdb.clone(), request.target)
+            .map_err(|err| ApiError::FailedToDecodeFormat("svg", err))
+    }
+}
+
+struct EpubLoader;
+
+impl MediaLoader for EpubLoader {
+    fn load(
+        &self,
+        path: &Path,
+        _option: &LoadImageOption,
+        _request: &LoadRequest,
+    ) -> Result<DynamicImage, ApiError> {
+        archive::load_image_from_epub(path)
+            .map_err(|err| ApiError::FailedToDecodeFormat("epub", err))
+    }
+}
+
+struct CbzLoader;
+
+impl MediaLoader for CbzLoader {
+    fn load(
+        &self,
+        path: &Path,
+        _option: &LoadImageOption,
+        _request: &LoadRequest,
+    ) -> Result<DynamicImage, ApiError> {
+        archive::load_image_from_cbz(path).map_err(|err| ApiError::FailedToDecodeFormat("cbz", err))
+    }
+}
+
+struct OfficeLoader;
+
+impl MediaLoader for OfficeLoader {
+    fn load(
+        &self,
+        path: &Path,
+        _option: &LoadImageOption,
+        _request: &LoadRequest,
+    ) -> Result<DynamicImage, ApiError> {
+        archive::load_image_from_office(path)
+            .map_err(|err| ApiError::FailedToDecodeFormat("office", err))
+    }
+}
+
+#[cfg(feature = "cbr")]
+struct CbrLoader;
+
+#[cfg(feature = "cbr")]
+impl MediaLoader for CbrLoader {
+    fn load(
+        &self,
+        path: &Path,
+        _option: &LoadImageOption,
+        _request: &LoadRequest,
+    ) -> Result<DynamicImage, ApiError> {
+        archive::load_image_from_cbr(path).map_err(|err| ApiError::FailedToDecodeFormat("cbr", err))
+    }
+}
+
+#[cfg(feature = "jxl")]
+struct JxlLoader;
+
+#[cfg(feature = "jxl")]
+impl MediaLoader for JxlLoader {
+    fn load(
+        &self,
+        path: &Path,
+        _option: &LoadImageOption,
+        _request: &LoadRequest,
+    ) -> Result<DynamicImage, ApiError> {
+        crate::jxl::load_image_from_jxl(path)
+            .map_err(|err| ApiError::FailedToDecodeFormat("jxl", err))
+    }
+}
+
+#[cfg(feature = "heif")]
+struct HeifLoader;
+
+#[cfg(feature = "heif")]
+impl MediaLoader for HeifLoader {
+    fn load(
+        &self,
+        path: &Path,
+        _option: &LoadImageOption,
+        _request: &LoadRequest,
+    ) -> Result<DynamicImage, ApiError> {
+        crate::heif::load_image_from_heif(path)
+            .map_err(|err| ApiError::FailedToDecodeFormat("heif", err))
+    }
+}
+
+#[cfg(feature = "jpeg2000")]
+struct Jpeg2000Loader;
+
+#[cfg(feature = "jpeg2000")]
+impl MediaLoader for Jpeg2000Loader {
+    fn load(
+        &self,
+        path: &Path,
+        _option: &LoadImageOption,
+        _request: &LoadRequest,
+    ) -> Result<DynamicImage, ApiError> {
+        crate::jpeg2000::load_image_from_jpeg2000(path)
+            .map_err(|err| ApiError::FailedToDecodeFormat("jpeg2000", err))
+    }
+}
+
+/// EXIF の Orientation を反映して、スマホの写真が横倒しにならないようにする
+fn load_image_from_file(path: &Path) -> Result<DynamicImage, ImageError> {
+    let mut decoder = image::ImageReader::open(path)?
+        .with_guessed_format()?
+        .into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut img = DynamicImage::from_decoder(decoder)?;
+    img.apply_orientation(orientation);
+    Ok(img)
+}
+
+fn load_image_from_psd(path: &Path) -> Result<DynamicImage, ImageError> {
+    let bytes = std::fs::read(path)?;
+    let psd = Psd::from_bytes(&bytes).map_err(|err| {
+        image::ImageError::Decoding(image::error::DecodingError::new(
+            image::error::ImageFormatHint::Unknown,
+            format!("Failed to parse PSD: {}", err),
+        ))
+    })?;
+
+    let rgba = psd.rgba();
+    let width = psd.width();
+    let height = psd.height();
+
+    let img_buf = image::ImageBuffer::<image::Rgba<u8>, _>::from_raw(width, height, rgba.to_vec())
+        .ok_or_else(|| {
+            image::ImageError::Limits(image::error::LimitError::from_kind(
+                image::error::LimitErrorKind::DimensionError,
+            ))
+        })?;
+    Ok(DynamicImage::ImageRgba8(img_buf))
+}
@@ -0,0 +1,48 @@
+//! `/lqip` で返す、本来のサムネイルを読み込むまでのインライン用の極小プレビュー。
+use crate::fit::{Fit, Gravity, ResizeFilter};
+use crate::pipeline::{Op, Pipeline, MAX_BLUR_SIGMA};
+use clap::Parser;
+use std::collections::HashMap;
+
+#[derive(Parser, Clone, Copy, Debug)]
+pub struct LqipOption {
+    /// Longer side of `/lqip` previews
+    #[arg(long, default_value_t = 20)]
+    lqip_size: u32,
+
+    /// Encoder quality of `/lqip` previews (1-100)
+    #[arg(long, default_value_t = 20)]
+    lqip_quality: u8,
+
+    /// Default Gaussian blur sigma of `/lqip` previews, overridable with `?blur=`
+    #[arg(long)]
+    lqip_blur: Option<f32>,
+}
+
+impl LqipOption {
+    pub fn quality(&self) -> u8 {
+        self.lqip_quality.clamp(1, 100)
+    }
+
+    /// `?blur=0` disables the configured blur.
+    pub fn blur(&self, query: &HashMap<String, String>) -> Option<f32> {
+        let blur = match query.get("blur") {
+            Some(s) => s.parse::<f32>().ok().filter(|v| v.is_finite()),
+            None => self.lqip_blur,
+        };
+        blur.filter(|&v| v > 0.0).map(|v| v.min(MAX_BLUR_SIGMA))
+    }
+
+    /// 縮小は元画像が大きくても安い Triangle で十分
+    pub fn pipeline(&self, blur: Option<f32>) -> Pipeline {
+        let mut ops = vec![Op::Resize {
+            width: self.lqip_size,
+            height: self.lqip_size,
+            fit: Fit::Contain,
+            gravity: Gravity::Center,
+            filter: Some(ResizeFilter::Triangle),
+        }];
+        ops.extend(blur.map(Op::Blur));
+        Pipeline::new(ops)
+    }
+}
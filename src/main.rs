@@ -1,21 +1,88 @@
 use actix_files as fs;
 use actix_web::http::header;
 use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
 use actix_web::{
-    get, middleware::Logger, web, App, Either, Error, HttpRequest, HttpResponse, HttpServer,
-    Responder, ResponseError,
+    delete, get, middleware, middleware::Logger, post, route, web, App, Either, Error, HttpMessage,
+    HttpRequest, HttpResponse, HttpServer, Responder, ResponseError,
 };
-use clap::Parser;
+use base64::Engine;
+use clap::{CommandFactory, Parser, Subcommand};
+use encode::OutputFormat;
 use image::error::ImageError;
-use image::{ColorType, DynamicImage};
-use psd::Psd;
-use std::ffi::OsStr;
+use pipeline::{Op, Pipeline};
 use std::fmt::Debug;
+use std::io::Read;
 use std::path::{Path, PathBuf};
-use std::time::SystemTime;
-use webp::Encoder;
+use std::time::{SystemTime, UNIX_EPOCH};
+mod admin;
+mod animation;
+mod archive;
+mod audio;
+mod audit;
+mod bench;
+mod cache;
+mod cache_control;
+mod cache_gc;
+mod clip;
+mod coalesce;
+mod color;
+mod contact_sheet;
+mod effect;
+mod encode;
+#[cfg(feature = "face")]
+mod face;
+mod failure_cache;
+mod fit;
+mod frame_scorer;
+mod head;
+#[cfg(feature = "heif")]
+mod heif;
+mod hls;
+mod http_origin;
+mod image_hash;
+mod ingest;
+mod jobs;
+#[cfg(feature = "jpeg2000")]
+mod jpeg2000;
+#[cfg(feature = "jxl")]
+mod jxl;
+mod keyframe_index;
+mod loader;
+mod lqip;
+mod media_store;
+mod model;
 mod movie_keyframe;
+mod offload;
+#[cfg(feature = "pdf")]
+mod pdf;
+mod pipeline;
+mod preset;
+mod proxy;
+mod psd_stream;
+mod queue;
+mod range;
+mod raw;
+#[cfg(feature = "redis")]
+mod redis_cache;
+mod s3;
+mod sidecar;
+mod sniff;
+mod ssim;
 mod statistics;
+mod storyboard;
+mod strip;
+mod svg;
+mod text_preview;
+mod tiff_page;
+mod tonemap;
+mod transcode;
+mod video_info;
+mod warmup;
+#[cfg(feature = "wasm")]
+mod wasm_plugin;
+mod watch;
+mod xcf;
 
 #[derive(Debug)]
 enum Size {
@@ -25,6 +92,8 @@ enum Size {
 }
 
 impl Size {
+    const ALL: [Size; 3] = [Size::Small, Size::Medium, Size::Large];
+
     fn from_str(s: &str) -> Self {
         match s {
             "small" => Size::Small,
@@ -33,6 +102,18 @@ impl Size {
         }
     }
 
+    fn as_str(&self) -> &'static str {
+        match self {
+            Size::Small => "small",
+            Size::Medium => "medium",
+            Size::Large => "large",
+        }
+    }
+
+    fn sidecar_name(&self, format: OutputFormat) -> String {
+        format!("thumb.{}.{}", self.as_str(), format.extension())
+    }
+
     fn dimensions(&self) -> (u32, u32) {
         match self {
             Size::Small => (120, 120),
@@ -58,6 +139,46 @@ pub enum ApiError {
 
     #[error("Failed to encode: err={0}")]
     FailedToDecodeMovie(anyhow::Error),
+
+    #[error("Failed to render text: err={0}")]
+    FailedToRenderText(anyhow::Error),
+
+    #[error("Failed to decode by plugin: err={0}")]
+    FailedToDecodePlugin(anyhow::Error),
+
+    #[error("Failed to decode {0}: err={1}")]
+    FailedToDecodeFormat(&'static str, anyhow::Error),
+
+    #[error("{0}")]
+    InvalidOperation(String),
+
+    #[error("unknown preset {0}")]
+    UnknownPreset(String),
+
+    #[error("metadata cannot be stripped from this format")]
+    MetadataNotStrippable(),
+
+    #[error("{0}")]
+    UnsupportedCodec(transcode::UnsupportedCodec),
+
+    #[error("conversion failed earlier with {0}")]
+    FailedEarlier(StatusCode),
+
+    #[error("unauthorized")]
+    Unauthorized(),
+}
+
+impl ApiError {
+    /// Whether the source itself could not be read, as opposed to a bad request.
+    fn is_decode_failure(&self) -> bool {
+        matches!(
+            self,
+            ApiError::FailedToDecode(_)
+                | ApiError::FailedToDecodeMovie(_)
+                | ApiError::FailedToDecodePlugin(_)
+                | ApiError::FailedToDecodeFormat(_, _)
+        )
+    }
 }
 
 impl ResponseError for ApiError {
@@ -68,6 +189,15 @@ impl ResponseError for ApiError {
             ApiError::FailedToDecode(_) => StatusCode::INTERNAL_SERVER_ERROR,
             ApiError::FailedToEncode(_) => StatusCode::INTERNAL_SERVER_ERROR,
             ApiError::FailedToDecodeMovie(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::FailedToRenderText(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::FailedToDecodePlugin(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::FailedToDecodeFormat(_, _) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::InvalidOperation(_) => StatusCode::BAD_REQUEST,
+            ApiError::UnknownPreset(_) => StatusCode::BAD_REQUEST,
+            ApiError::MetadataNotStrippable() => StatusCode::FORBIDDEN,
+            ApiError::UnsupportedCodec(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::FailedEarlier(status) => *status,
+            ApiError::Unauthorized() => StatusCode::UNAUTHORIZED,
         }
     }
 
@@ -82,6 +212,7 @@ impl ResponseError for ApiError {
     }
 }
 
+#[derive(Clone)]
 pub struct FileKey {
     hkey: String,
     ext: String,
@@ -127,255 +258,2601 @@ impl FileKey {
 }
 
 fn is_not_modified(req: &HttpRequest, modified_time: SystemTime) -> bool {
+    // If-None-Match があれば If-Modified-Since は見ない。ETag と合わせた判定は `not_modified` で行う
+    if req.headers().contains_key(header::IF_NONE_MATCH) {
+        return false;
+    }
     if let Some(ims) = req.headers().get(header::IF_MODIFIED_SINCE) {
         if let Ok(ims_str) = ims.to_str() {
             if let Ok(ims_time) = httpdate::parse_http_date(ims_str) {
-                return modified_time <= ims_time;
+                // Last-Modified は秒単位なので、秒未満を切り捨てて比べる
+                return secs(modified_time) <= secs(ims_time);
             }
         }
     }
     false
 }
 
-fn passthrough_file(path: &Path) -> Result<fs::NamedFile, Error> {
-    let named_file = fs::NamedFile::open(path)?;
-    Ok(named_file
-        .use_last_modified(true)
-        .set_content_disposition(header::ContentDisposition {
-            disposition: header::DispositionType::Attachment,
-            parameters: vec![],
-        }))
+fn secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+/// Strong validator of a source file, sent as is or with its metadata stripped. The key is
+/// the hash of the content, so it does not depend on the modification time.
+fn source_etag(key: &FileKey, stripped: bool) -> header::EntityTag {
+    header::EntityTag::new_strong(if stripped {
+        format!("{}-stripped", key.hkey)
+    } else {
+        key.hkey.clone()
+    })
+}
+
+/// Weak validator of one generated variant. It changes with the source and with every
+/// transform parameter, so `?size=small` never validates a cached `?size=large`.
+fn variant_etag(key: &FileKey, modified_time: SystemTime, name: &str) -> header::EntityTag {
+    let digest = cache::CacheKey::new(key, modified_time, name).to_string();
+    header::EntityTag::new_weak(digest[..16].to_string())
+}
+
+fn etag_matches(req: &HttpRequest, etag: &header::EntityTag) -> bool {
+    match req.get_header::<header::IfNoneMatch>() {
+        Some(header::IfNoneMatch::Any) => true,
+        Some(header::IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+        None => false,
+    }
+}
+
+/// 304 when the client's copy is current. `If-None-Match` is compared with `etags`, every
+/// variant the request may resolve to, and `If-Modified-Since` only counts without it.
+fn not_modified(
+    req: &HttpRequest,
+    modified_time: SystemTime,
+    etags: &[header::EntityTag],
+) -> Option<HttpResponse> {
+    let etag = if req.headers().contains_key(header::IF_NONE_MATCH) {
+        Some(etags.iter().find(|etag| etag_matches(req, etag))?)
+    } else if is_not_modified(req, modified_time) {
+        // 304 にも 200 で返す ETag を付ける
+        etags.first()
+    } else {
+        return None;
+    };
+    let response = HttpResponse::NotModified().finish();
+    Some(match etag {
+        Some(etag) => with_etag(response, etag),
+        None => response,
+    })
+}
+
+fn with_etag(mut res: HttpResponse, etag: &header::EntityTag) -> HttpResponse {
+    if let Ok(value) = header::HeaderValue::from_str(&etag.to_string()) {
+        res.headers_mut().insert(header::ETAG, value);
+    }
+    res
+}
+
+/// The source file as an attachment. Downloads resume with `Range` while `If-Range` still
+/// matches.
+fn passthrough_response(
+    req: &HttpRequest,
+    app_data: &AppData,
+    key: &FileKey,
+    metadata: media_store::SourceMetadata,
+) -> Result<HttpResponse, Error> {
+    let content_type = actix_files::file_extension_to_mime(&key.ext).to_string();
+    let response = range::reader_response(
+        req,
+        metadata.len,
+        &content_type,
+        &source_etag(key, false),
+        metadata.modified,
+        |start, length| app_data.store.open(key, start, Some(length)),
+    )?;
+    Ok(as_attachment(response))
+}
+
+fn as_attachment(mut res: HttpResponse) -> HttpResponse {
+    res.headers_mut().insert(
+        header::CONTENT_DISPOSITION,
+        header::HeaderValue::from_static("attachment"),
+    );
+    res
 }
 
-#[get("/raw/{tail:.*}")]
+#[route("/raw/{tail:.*}", method = "GET", method = "HEAD")]
 async fn original(
-    _req: HttpRequest,
+    req: HttpRequest,
     path: web::Path<String>,
+    query: web::Query<std::collections::HashMap<String, String>>,
     app_data: web::Data<AppData>,
-) -> Result<fs::NamedFile, Error> {
+) -> Result<HttpResponse, Error> {
     let key = FileKey::parse(path.into_inner())?;
-    let canonical_path = key.build_path(app_data.base_path.as_path());
-    passthrough_file(&canonical_path)
+    let strip = parse_strip(&query, &app_data.config);
+    let cache_control = &app_data.config.cache_control.raw_cache_control;
+    let apply = |response: HttpResponse| match cache_control {
+        Some(cache_control) => cache_control.apply(response),
+        None => response,
+    };
+
+    let metadata = app_data.store.metadata(&key)?;
+    let modified_time = metadata.modified;
+    let etag = source_etag(&key, strip);
+    if let Some(response) = not_modified(&req, modified_time, std::slice::from_ref(&etag)) {
+        return Ok(response);
+    }
+    if !strip {
+        return Ok(apply(passthrough_response(
+            &req, &app_data, &key, metadata,
+        )?));
+    }
+    let mut data = Vec::new();
+    app_data.store.open(&key, 0, None)?.read_to_end(&mut data)?;
+    let stripped = strip::strip_metadata(&data)
+        .map_err(|err| ApiError::FailedToDecodeFormat("metadata", err))?
+        .ok_or(ApiError::MetadataNotStrippable())?;
+    let response = range::bytes_response(
+        &req,
+        Bytes::from(stripped),
+        actix_files::file_extension_to_mime(&key.ext).as_ref(),
+        &etag,
+        modified_time,
+    );
+    Ok(apply(as_attachment(response)))
 }
 
-#[get("/media/{tail:.*}")]
+#[route("/media/{tail:.*}", method = "GET", method = "HEAD")]
 async fn media(
     req: HttpRequest,
     path: web::Path<String>,
+    query: web::Query<std::collections::HashMap<String, String>>,
     app_data: web::Data<AppData>,
-) -> Result<Either<fs::NamedFile, HttpResponse>, Error> {
+) -> Result<HttpResponse, Error> {
+    let requested_format = query.get("format").map(|s| OutputFormat::from_str(s));
+    let negotiated = requested_format.is_none();
+    let accept = accept_header(&req);
     let key = FileKey::parse(path.into_inner())?;
-    let canonical_path = key.build_path(app_data.base_path.as_path());
+    let format = requested_format.unwrap_or_else(|| encode::negotiate(accept));
+    // 明示的に別の形式を要求された場合や、クライアントが表示できない形式の場合は元ファイルを返さない。
+    // メタデータを落とす場合は常に再エンコードする
+    let can_passthrough = !parse_strip(&query, &app_data.config)
+        && match requested_format {
+            Some(f) => f.extension() == key.ext,
+            None => match key.ext.as_str() {
+                "avif" | "webp" => {
+                    encode::accepts(accept.unwrap_or(""), &format!("image/{}", key.ext))
+                }
+                _ => true,
+            },
+        };
+    let cache_control = &app_data.config.cache_control.media_cache_control;
+    let passthrough = || -> Result<HttpResponse, Error> {
+        let metadata = app_data.store.metadata(&key)?;
+        let etag = source_etag(&key, false);
+        let response = match not_modified(&req, metadata.modified, std::slice::from_ref(&etag)) {
+            Some(response) => response,
+            None => cache_control.apply(passthrough_response(&req, &app_data, &key, metadata)?),
+        };
+        Ok(with_vary_accept(response, negotiated))
+    };
 
-    if key.ext == "gif" || key.ext == "avif" || key.ext == "webp" {
-        return passthrough_file(&canonical_path).map(Either::Left);
+    if can_passthrough && (key.ext == "avif" || key.ext == "webp") {
+        return passthrough();
     }
 
     // Check Last Modified header
-    let metadata = std::fs::metadata(&canonical_path)?;
-    let modified_time = metadata.modified().unwrap_or(SystemTime::now());
-    if is_not_modified(&req, modified_time) {
-        return Ok(Either::Right(HttpResponse::NotModified().finish()));
-    }
-
+    let metadata = app_data.store.metadata(&key)?;
+    let modified_time = metadata.modified;
     if let Some(threshold) = app_data.config.media_passthrough_max_bytes {
-        if metadata.len() <= threshold {
-            return passthrough_file(&canonical_path).map(Either::Left);
+        if can_passthrough && metadata.len <= threshold {
+            return passthrough();
         }
     }
 
-    let img = load_image(&canonical_path, &app_data.config.load_image_option)?;
-    Ok(Either::Right(build_webp_response(
-        img,
-        &canonical_path,
+    // 透過のあるアニメーションは AVIF だとアルファを失うので、交渉で決めた場合は WebP に切り替える
+    let webp_fallback = negotiated && encode::accepts(accept.unwrap_or(""), "image/webp");
+    let (output_name, convert) = prepare_media(
+        &app_data,
+        &key,
+        &query,
+        format,
+        webp_fallback,
+        modified_time,
+    );
+    // WebP に切り替えた結果は WebP の名前で保存してあるので、そちらも探す
+    let candidates = [format]
+        .into_iter()
+        .chain((format == OutputFormat::Avif && webp_fallback).then_some(OutputFormat::WebP));
+    let etag = |format: OutputFormat| variant_etag(&key, modified_time, &output_name(format));
+    let etags: Vec<_> = candidates.clone().map(etag).collect();
+    if let Some(response) = not_modified(&req, modified_time, &etags) {
+        return Ok(with_vary_accept(response, negotiated));
+    }
+    let cached = candidates.clone().find_map(|format| {
+        serve_cached(
+            &req,
+            &app_data,
+            &key,
+            modified_time,
+            &output_name(format),
+            format.content_type(),
+            cache_control,
+        )
+    });
+    if let Some(response) = cached {
+        return Ok(with_vary_accept(response, negotiated));
+    }
+    if let Some(response) = head_response(
+        &req,
+        format.content_type(),
         modified_time,
-        app_data.config.media_quality,
-    )?))
+        cache_control,
+        &etag(format),
+    ) {
+        return Ok(with_vary_accept(response, negotiated));
+    }
+    // WebP に切り替えるかどうかは変換してみるまで分からないので、要求された形式の名前でまとめる
+    let requested_name = output_name(format);
+    let stale = candidates.clone().find_map(|format| {
+        serve_stale(
+            &req,
+            &app_data,
+            &key,
+            modified_time,
+            &output_name(format),
+            format.content_type(),
+        )
+    });
+    if let Some(response) = stale {
+        revalidate(&app_data, &key, modified_time, &requested_name, convert);
+        return Ok(with_vary_accept(response, negotiated));
+    }
+    if is_async(&app_data, &key) {
+        let task = queue::Task::Media {
+            key: key.build_filename().to_string_lossy().into_owned(),
+            query: query.into_inner(),
+            format: format.extension().to_string(),
+            webp_fallback,
+        };
+        let response = accept_job(
+            &req,
+            &app_data,
+            &key,
+            modified_time,
+            &requested_name,
+            convert,
+            task,
+        );
+        return Ok(with_vary_accept(response, negotiated));
+    }
+    let (data, format) = convert_once(&app_data, &key, modified_time, &requested_name, convert)?;
+    // 作ったばかりの出力もディスクキャッシュに書けていれば前段に送らせる
+    let offloaded = app_data
+        .config
+        .offload
+        .is_enabled()
+        .then(|| {
+            let cache_key = cache::CacheKey::new(&key, modified_time, &output_name(format));
+            let path = app_data.cache.file(&cache_key)?;
+            offloaded_response(
+                &app_data,
+                &path,
+                format.content_type(),
+                modified_time,
+                cache_control,
+            )
+        })
+        .flatten();
+    // まだキャッシュに無い範囲の要求は、作った出力全体から切り出す
+    let response = offloaded.unwrap_or_else(|| {
+        cache_control.apply(range::bytes_response(
+            &req,
+            data,
+            format.content_type(),
+            &etag(format),
+            modified_time,
+        ))
+    });
+    Ok(with_vary_accept(
+        with_etag(response, &etag(format)),
+        negotiated,
+    ))
+}
+
+/// Output names and conversion of `/media` for `query`, where `format` is the requested or
+/// negotiated format. Also used by the queue workers.
+fn prepare_media(
+    app_data: &web::Data<AppData>,
+    key: &FileKey,
+    query: &std::collections::HashMap<String, String>,
+    format: OutputFormat,
+    webp_fallback: bool,
+    modified_time: SystemTime,
+) -> (
+    impl Fn(OutputFormat) -> String + Clone + 'static,
+    impl Convert,
+) {
+    let mut request = loader::LoadRequest {
+        page: parse_page(query),
+        timestamp: parse_timestamp(query, "t"),
+        stream: parse_stream(query),
+        hkey: Some(key.hkey.clone()),
+        ..Default::default()
+    };
+    request.keyframe_sidecar = keyframe_sidecar(app_data, key, &request);
+    let lossless = parse_lossless(query);
+    let output_name = {
+        let request = request.clone();
+        move |format: OutputFormat| {
+            request.sidecar_name(&if lossless && format == OutputFormat::WebP {
+                format!("media.lossless.{}", format.extension())
+            } else {
+                format!("media.{}", format.extension())
+            })
+        }
+    };
+    let convert = {
+        let app_data = app_data.clone();
+        let key = key.clone();
+        let output_name = output_name.clone();
+        move || {
+            let canonical_path = app_data.store.local_path(&key)?;
+            // 時刻を指定された動画はその 1 フレームだけを返す
+            let animation = match request.timestamp {
+                Some(_) => None,
+                None => encode_animation(
+                    &app_data,
+                    &key,
+                    &canonical_path,
+                    format,
+                    webp_fallback,
+                    request.stream,
+                )?,
+            };
+            let (data, format) = match animation {
+                Some(encoded) => encoded,
+                None => {
+                    let img = app_data.loaders.load(
+                        &canonical_path,
+                        &app_data.config.load_image_option,
+                        &request,
+                    )?;
+                    let img = tonemap::tone_map(img, app_data.config.tone_map);
+                    let mut quality = app_data.config.media_encode_quality();
+                    quality.webp_lossless = lossless || app_data.config.is_lossless_candidate(&img);
+                    (
+                        encode::encode(img, format, &canonical_path, &quality)?,
+                        format,
+                    )
+                }
+            };
+            let output_name = output_name(format);
+            save_sidecar(&app_data, &key, &output_name, &data);
+            cache_output(&app_data, &key, modified_time, &output_name, &data);
+            Ok((data, format))
+        }
+    };
+    (output_name, convert)
 }
 
-#[get("/thumbnail/{tail:.*}")]
+#[route("/thumbnail/{tail:.*}", method = "GET", method = "HEAD")]
 async fn thumbnail(
     req: HttpRequest,
     path: web::Path<String>,
     query: web::Query<std::collections::HashMap<String, String>>,
     app_data: web::Data<AppData>,
 ) -> Result<impl Responder, Error> {
+    let mut query = query.into_inner();
+    preset::expand(&app_data.config.presets, &mut query).map_err(ApiError::UnknownPreset)?;
     let size = query
         .get("size")
         .map(|s| Size::from_str(s))
         .unwrap_or(Size::Medium);
+    let requested_format = query.get("format").map(|s| OutputFormat::from_str(s));
+    let negotiated = requested_format.is_none();
+    let format = requested_format.unwrap_or_else(|| encode::negotiate(accept_header(&req)));
     let key = FileKey::parse(path.into_inner())?;
-    let canonical_path = key.build_path(app_data.base_path.as_path());
 
     // Check Last Modified header
-    let modified_time = std::fs::metadata(&canonical_path)?
-        .modified()
-        .unwrap_or(SystemTime::now());
-    if is_not_modified(&req, modified_time) {
-        return Ok(HttpResponse::NotModified().finish());
-    }
-
-    let img = load_image(&canonical_path, &app_data.config.load_image_option)?;
-    let (w, h) = size.dimensions();
-    let resized = img.thumbnail(w, h);
-    Ok(build_webp_response(
-        resized,
-        &canonical_path,
+    let modified_time = app_data.store.metadata(&key)?.modified;
+    let (output_name, convert) =
+        prepare_thumbnail(&app_data, &key, &query, size, format, modified_time)?;
+    let etag = variant_etag(&key, modified_time, &output_name);
+    if let Some(response) = not_modified(&req, modified_time, std::slice::from_ref(&etag)) {
+        return Ok(with_vary_accept(response, negotiated));
+    }
+    if let Some(response) = serve_cached(
+        &req,
+        &app_data,
+        &key,
+        modified_time,
+        &output_name,
+        format.content_type(),
+        &app_data.config.cache_control.thumbnail_cache_control,
+    ) {
+        return Ok(with_vary_accept(response, negotiated));
+    }
+    if let Some(response) = head_response(
+        &req,
+        format.content_type(),
+        modified_time,
+        &app_data.config.cache_control.thumbnail_cache_control,
+        &etag,
+    ) {
+        return Ok(with_vary_accept(response, negotiated));
+    }
+    if let Some(response) = serve_stale(
+        &req,
+        &app_data,
+        &key,
         modified_time,
-        app_data.config.thumbnail_quality,
-    )?)
+        &output_name,
+        format.content_type(),
+    ) {
+        revalidate(&app_data, &key, modified_time, &output_name, convert);
+        return Ok(with_vary_accept(response, negotiated));
+    }
+    if is_async(&app_data, &key) {
+        let task = queue::Task::Thumbnail {
+            key: key.build_filename().to_string_lossy().into_owned(),
+            query,
+            format: format.extension().to_string(),
+        };
+        let response = accept_job(
+            &req,
+            &app_data,
+            &key,
+            modified_time,
+            &output_name,
+            convert,
+            task,
+        );
+        return Ok(with_vary_accept(response, negotiated));
+    }
+    let (data, format) = convert_once(&app_data, &key, modified_time, &output_name, convert)?;
+    Ok(with_vary_accept(
+        with_etag(
+            build_image_response(
+                data,
+                format,
+                modified_time,
+                &app_data.config.cache_control.thumbnail_cache_control,
+            ),
+            &etag,
+        ),
+        negotiated,
+    ))
+}
+
+/// Output name and conversion of `/thumbnail` for `query`. Also used by `warmup` so that it
+/// fills the same cache entries.
+fn prepare_thumbnail(
+    app_data: &web::Data<AppData>,
+    key: &FileKey,
+    query: &std::collections::HashMap<String, String>,
+    size: Size,
+    format: OutputFormat,
+    modified_time: SystemTime,
+) -> Result<(String, impl Convert), Error> {
+    let (mut pipeline, sidecar_name) = thumbnail_pipeline(query, size, format, &app_data.config)?;
+    if let Some(amount) = app_data.config.sharpen {
+        pipeline.insert_after_resize(Op::Sharpen {
+            amount,
+            radius: app_data.config.sharpen_radius,
+            threshold: app_data.config.sharpen_threshold,
+        });
+    }
+    let mut quality = app_data.config.thumbnail_encode_quality();
+    let requested_quality = parse_quality(query);
+    let quality_overridden = requested_quality.is_some();
+    let sidecar_name = match requested_quality {
+        Some(q) => {
+            quality.webp = f32::from(q);
+            quality.avif = q;
+            quality.jpeg = q;
+            sidecar::with_variant(&sidecar_name, &format!("q{}", q))
+        }
+        None => sidecar_name,
+    };
+    let max_bytes = query
+        .get("max_bytes")
+        .and_then(|s| s.parse::<usize>().ok())
+        .filter(|&n| n > 0);
+    let sidecar_name = match max_bytes {
+        Some(n) => sidecar::with_variant(&sidecar_name, &format!("max{}", n)),
+        None => sidecar_name,
+    };
+    let sidecar_name =
+        if quality.webp_options.override_from_query(query) && format == OutputFormat::WebP {
+            sidecar::with_variant(
+                &sidecar_name,
+                &format!("webp-{}", quality.webp_options.variant()),
+            )
+        } else {
+            sidecar_name
+        };
+    let lossless = parse_lossless(query);
+    let sidecar_name = if lossless && format == OutputFormat::WebP {
+        sidecar::with_variant(&sidecar_name, "lossless")
+    } else {
+        sidecar_name
+    };
+    let background = parse_background(query, &app_data.config);
+    let sidecar_name = match background {
+        Some(color) => sidecar::with_variant(&sidecar_name, &format!("bg{}", color.to_hex())),
+        None => sidecar_name,
+    };
+    let mut request = loader::LoadRequest {
+        target: pipeline.target(),
+        page: parse_page(query),
+        timestamp: parse_timestamp(query, "t"),
+        stream: parse_stream(query),
+        hkey: Some(key.hkey.clone()),
+        ..Default::default()
+    };
+    request.keyframe_sidecar = keyframe_sidecar(app_data, key, &request);
+    let output_name = request.sidecar_name(&sidecar_name);
+    let convert = {
+        let app_data = app_data.clone();
+        let key = key.clone();
+        let output_name = output_name.clone();
+        move || {
+            let canonical_path = app_data.store.local_path(&key)?;
+            let img = app_data.loaders.load(
+                &canonical_path,
+                &app_data.config.load_image_option,
+                &request,
+            )?;
+            let resized = pipeline.run(img, app_data.config.tone_map);
+            let resized = match background {
+                Some(color) => color::flatten(resized, color),
+                None => resized,
+            };
+            quality.webp_lossless = lossless || app_data.config.is_lossless_candidate(&resized);
+            let data = match max_bytes {
+                Some(max_bytes) => {
+                    encode::encode_within(resized, format, &canonical_path, &quality, max_bytes)?
+                }
+                None => match app_data.config.thumbnail_target_ssim {
+                    Some(target) if !quality_overridden => encode::encode_perceptual(
+                        resized,
+                        format,
+                        &canonical_path,
+                        &quality,
+                        target,
+                    )?,
+                    _ => encode::encode(resized, format, &canonical_path, &quality)?,
+                },
+            };
+            save_sidecar(&app_data, &key, &output_name, &data);
+            cache_output(&app_data, &key, modified_time, &output_name, &data);
+            Ok((data, format))
+        }
+    };
+    Ok((output_name, convert))
 }
 
-fn load_image(path: &Path, option: &LoadImageOption) -> Result<DynamicImage, ApiError> {
-    let ext = path
-        .extension()
-        .and_then(OsStr::to_str)
-        .unwrap_or("")
-        .to_lowercase();
+#[route("/lqip/{tail:.*}", method = "GET", method = "HEAD")]
+async fn placeholder(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+    app_data: web::Data<AppData>,
+) -> Result<impl Responder, Error> {
+    let requested_format = query.get("format").map(|s| OutputFormat::from_str(s));
+    let negotiated = requested_format.is_none();
+    let format = requested_format.unwrap_or_else(|| encode::negotiate(accept_header(&req)));
+    let key = FileKey::parse(path.into_inner())?;
 
-    match ext.as_str() {
-        "psd" => load_image_from_psd(path).map_err(ApiError::FailedToDecode),
-        "mp4" | "webm" | "mov" => movie_keyframe::load_image_from_movie_keyframe(
-            path,
-            option.movie_max_keyframes,
-            option.movie_frame_score_threshold,
-            option.movie_frame_sharpness_threshold,
-        )
-        .map_err(ApiError::FailedToDecodeMovie),
-        _ => load_image_from_file(path).map_err(ApiError::FailedToDecode),
+    let modified_time = app_data.store.metadata(&key)?.modified;
+    let option = &app_data.config.lqip;
+    let blur = option.blur(&query);
+    let pipeline = option.pipeline(blur);
+    let sidecar_name = format!("lqip.{}", format.extension());
+    let sidecar_name = match blur {
+        Some(sigma) => sidecar::with_variant(&sidecar_name, &format!("blur{}", sigma)),
+        None => sidecar_name,
+    };
+    let mut request = loader::LoadRequest {
+        target: pipeline.target(),
+        page: parse_page(&query),
+        hkey: Some(key.hkey.clone()),
+        ..Default::default()
+    };
+    request.keyframe_sidecar = keyframe_sidecar(&app_data, &key, &request);
+    let output_name = request.sidecar_name(&sidecar_name);
+    let etag = variant_etag(&key, modified_time, &output_name);
+    if let Some(response) = not_modified(&req, modified_time, std::slice::from_ref(&etag)) {
+        return Ok(with_vary_accept(response, negotiated));
+    }
+    if let Some(response) = serve_cached(
+        &req,
+        &app_data,
+        &key,
+        modified_time,
+        &output_name,
+        format.content_type(),
+        &app_data.config.cache_control.thumbnail_cache_control,
+    ) {
+        return Ok(with_vary_accept(response, negotiated));
     }
+    if let Some(response) = head_response(
+        &req,
+        format.content_type(),
+        modified_time,
+        &app_data.config.cache_control.thumbnail_cache_control,
+        &etag,
+    ) {
+        return Ok(with_vary_accept(response, negotiated));
+    }
+    let convert = {
+        let app_data = app_data.clone();
+        let key = key.clone();
+        let output_name = output_name.clone();
+        move || {
+            let option = &app_data.config.lqip;
+            let canonical_path = app_data.store.local_path(&key)?;
+            let img = app_data.loaders.load(
+                &canonical_path,
+                &app_data.config.load_image_option,
+                &request,
+            )?;
+            let img = pipeline.run(img, app_data.config.tone_map);
+            let mut quality = app_data.config.thumbnail_encode_quality();
+            quality.webp = f32::from(option.quality());
+            quality.avif = option.quality();
+            quality.jpeg = option.quality();
+            let data = encode::encode(img, format, &canonical_path, &quality)?;
+            save_sidecar(&app_data, &key, &output_name, &data);
+            cache_output(&app_data, &key, modified_time, &output_name, &data);
+            Ok((data, format))
+        }
+    };
+    if let Some(response) = serve_stale(
+        &req,
+        &app_data,
+        &key,
+        modified_time,
+        &output_name,
+        format.content_type(),
+    ) {
+        revalidate(&app_data, &key, modified_time, &output_name, convert);
+        return Ok(with_vary_accept(response, negotiated));
+    }
+    let (data, format) = convert_once(&app_data, &key, modified_time, &output_name, convert)?;
+    Ok(with_vary_accept(
+        with_etag(
+            build_image_response(
+                data,
+                format,
+                modified_time,
+                &app_data.config.cache_control.thumbnail_cache_control,
+            ),
+            &etag,
+        ),
+        negotiated,
+    ))
 }
 
-fn load_image_from_file(path: &Path) -> Result<DynamicImage, ImageError> {
-    image::ImageReader::open(path)?.decode()
+#[derive(serde::Serialize)]
+struct ThumbHashResponse {
+    /// Standard base64 of the ThumbHash bytes
+    hash: String,
+    /// Size of the decoded source image
+    width: u32,
+    height: u32,
 }
 
-fn load_image_from_psd(path: &Path) -> Result<DynamicImage, ImageError> {
-    let bytes = std::fs::read(path)?;
-    let psd = Psd::from_bytes(&bytes).map_err(|err| {
-        image::ImageError::Decoding(image::error::DecodingError::new(
-            image::error::ImageFormatHint::Unknown,
-            format!("Failed to parse PSD: {}", err),
-        ))
-    })?;
+#[route("/thumbhash/{tail:.*}", method = "GET", method = "HEAD")]
+async fn thumbhash(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+    app_data: web::Data<AppData>,
+) -> Result<impl Responder, Error> {
+    let key = FileKey::parse(path.into_inner())?;
 
-    let rgba = psd.rgba();
-    let width = psd.width();
-    let height = psd.height();
+    let modified_time = app_data.store.metadata(&key)?.modified;
+    // 元の寸法を返すので縮小前提の読み込み (target) はしない
+    let mut request = loader::LoadRequest {
+        page: parse_page(&query),
+        hkey: Some(key.hkey.clone()),
+        ..Default::default()
+    };
+    request.keyframe_sidecar = keyframe_sidecar(&app_data, &key, &request);
+    let output_name = request.sidecar_name("thumbhash.json");
+    let etag = variant_etag(&key, modified_time, &output_name);
+    if let Some(response) = not_modified(&req, modified_time, std::slice::from_ref(&etag)) {
+        return Ok(response);
+    }
+    if let Some(response) = head_response(
+        &req,
+        "application/json",
+        modified_time,
+        &app_data.config.cache_control.thumbnail_cache_control,
+        &etag,
+    ) {
+        return Ok(response);
+    }
+    let canonical_path = app_data.store.local_path(&key)?;
 
-    let img_buf = image::ImageBuffer::<image::Rgba<u8>, _>::from_raw(width, height, rgba.to_vec())
-        .ok_or_else(|| {
-            image::ImageError::Limits(image::error::LimitError::from_kind(
-                image::error::LimitErrorKind::DimensionError,
-            ))
-        })?;
-    Ok(DynamicImage::ImageRgba8(img_buf))
+    let img = app_data.loaders.load(
+        &canonical_path,
+        &app_data.config.load_image_option,
+        &request,
+    )?;
+    let img = tonemap::tone_map(img, app_data.config.tone_map);
+    let response = ThumbHashResponse {
+        hash: base64::engine::general_purpose::STANDARD.encode(image_hash::thumbhash(&img)),
+        width: img.width(),
+        height: img.height(),
+    };
+    let data =
+        serde_json::to_vec(&response).map_err(|err| ApiError::FailedToEncode(err.to_string()))?;
+    save_sidecar(&app_data, &key, &output_name, &data);
+    Ok(with_etag(
+        build_cached_response(
+            data,
+            "application/json",
+            modified_time,
+            &app_data.config.cache_control.thumbnail_cache_control,
+        ),
+        &etag,
+    ))
 }
 
-fn build_webp_response(
-    img: DynamicImage,
-    path: &Path,
-    modified_time: SystemTime,
-    quality: f32,
-) -> Result<HttpResponse, ApiError> {
-    let rgba8 = match img.color() {
-        ColorType::Rgb32F => DynamicImage::ImageRgb8(img.to_rgb8()),
-        ColorType::Rgba32F => DynamicImage::ImageRgba8(img.to_rgba8()),
-        ColorType::Rgb16 => DynamicImage::ImageRgb8(img.to_rgb8()),
-        ColorType::Rgba16 => DynamicImage::ImageRgba8(img.to_rgba8()),
-        ColorType::Rgb8 | ColorType::Rgba8 => img,
-        _ => DynamicImage::ImageRgb8(img.to_rgb8()),
-    };
-
-    let encoder = Encoder::from_image(&rgba8).map_err(|err| {
-        log::warn!(
-            "Failed to encode image: {}:{}",
-            path.to_str().unwrap_or("N/A"),
-            err,
-        );
-        ApiError::FailedToEncode(err.to_string())
-    })?;
-    let webp_data = encoder.encode(quality);
-
-    Ok(HttpResponse::Ok()
-        .content_type("image/webp")
-        .insert_header(header::CacheControl(vec![
-            header::CacheDirective::Public,
-            header::CacheDirective::MaxAge(2592000u32),
-        ]))
-        .insert_header(header::LastModified(modified_time.into()))
-        .body(webp_data.to_vec())) // copy
+/// Short looping animation of frames sampled across a video, for hover previews.
+#[route("/preview/{tail:.*}", method = "GET", method = "HEAD")]
+async fn preview(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+    app_data: web::Data<AppData>,
+) -> Result<impl Responder, Error> {
+    // アニメーションにできない形式を指定された場合も WebP にする
+    let format = match query.get("format").map(|s| OutputFormat::from_str(s)) {
+        Some(OutputFormat::Avif) => OutputFormat::Avif,
+        _ => OutputFormat::WebP,
+    };
+    let key = FileKey::parse(path.into_inner())?;
+    let config = &app_data.config;
+    if !config.load_image_option.is_movie_ext(&key.ext) {
+        return Err(ApiError::NotFound().into());
+    }
+    let modified_time = app_data.store.metadata(&key)?.modified;
+    let output_name = format!("preview.{}", format.extension());
+    let etag = variant_etag(&key, modified_time, &output_name);
+    if let Some(response) = not_modified(&req, modified_time, std::slice::from_ref(&etag)) {
+        return Ok(response);
+    }
+    if let Some(response) = serve_cached(
+        &req,
+        &app_data,
+        &key,
+        modified_time,
+        &output_name,
+        format.content_type(),
+        &config.cache_control.thumbnail_cache_control,
+    ) {
+        return Ok(response);
+    }
+    if let Some(response) = head_response(
+        &req,
+        format.content_type(),
+        modified_time,
+        &config.cache_control.thumbnail_cache_control,
+        &etag,
+    ) {
+        return Ok(response);
+    }
+    let convert = {
+        let app_data = app_data.clone();
+        let key = key.clone();
+        let output_name = output_name.clone();
+        move || {
+            let config = &app_data.config;
+            let canonical_path = app_data.store.local_path(&key)?;
+            let frames = movie_keyframe::sample_frames(
+                &canonical_path,
+                config.preview_frames,
+                config.preview_size,
+                config.tone_map,
+            )
+            .map_err(ApiError::FailedToDecodeMovie)?
+            .into_iter()
+            .map(|(_, image)| animation::AnimationFrame {
+                image: image.to_rgba8(),
+                delay_ms: config.preview_frame_ms,
+            })
+            .collect::<Vec<_>>();
+            let encoder = animation::sequence_encoder(format, &config.thumbnail_encode_quality())
+                .ok_or_else(|| {
+                ApiError::FailedToEncode(format!("{:?} is not animatable", format))
+            })?;
+            let data = encoder.encode(&frames).map_err(|err| {
+                log::warn!(
+                    "Failed to encode preview: {}:{}",
+                    canonical_path.to_str().unwrap_or("N/A"),
+                    err,
+                );
+                ApiError::FailedToEncode(err.to_string())
+            })?;
+            save_sidecar(&app_data, &key, &output_name, &data);
+            cache_output(&app_data, &key, modified_time, &output_name, &data);
+            Ok((data, format))
+        }
+    };
+    let (data, format) = convert_once(&app_data, &key, modified_time, &output_name, convert)?;
+    Ok(with_etag(
+        build_image_response(
+            data,
+            format,
+            modified_time,
+            &config.cache_control.thumbnail_cache_control,
+        ),
+        &etag,
+    ))
 }
 
-#[derive(Parser)]
-#[command(name = "media-thumb-server")]
-#[command(about = "Serve thumbnails from NAS")]
-struct Args {
-    #[arg(long, default_value = "127.0.0.1")]
-    bind: String,
+/// Sprite sheet of frames at fixed intervals, or with `?format=vtt` the WebVTT that maps
+/// timestamps to its tiles.
+#[route("/storyboard/{tail:.*}", method = "GET", method = "HEAD")]
+async fn storyboard_sprite(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+    app_data: web::Data<AppData>,
+) -> Result<impl Responder, Error> {
+    let key = FileKey::parse(path.into_inner())?;
+    let config = &app_data.config;
+    if !config.load_image_option.is_movie_ext(&key.ext) {
+        return Err(ApiError::NotFound().into());
+    }
+    let is_vtt = query.get("format").is_some_and(|s| s == "vtt");
+    let requested_format = query
+        .get("format")
+        .filter(|_| !is_vtt)
+        .map(|s| OutputFormat::from_str(s));
+    let negotiated = !is_vtt && requested_format.is_none();
 
-    #[arg(short, long, default_value_t = 8080)]
-    port: u16,
+    let modified_time = app_data.store.metadata(&key)?.modified;
+    let format = requested_format.unwrap_or_else(|| encode::negotiate(accept_header(&req)));
+    let (output_name, convert) = prepare_storyboard(&app_data, &key, format, modified_time);
+    // VTT とスプライトは別の ETag にする
+    let etag = variant_etag(
+        &key,
+        modified_time,
+        if is_vtt {
+            "storyboard.vtt"
+        } else {
+            &output_name
+        },
+    );
+    if let Some(response) = not_modified(&req, modified_time, std::slice::from_ref(&etag)) {
+        return Ok(with_vary_accept(response, negotiated));
+    }
 
-    #[arg(long)]
-    base_path: PathBuf,
+    if is_vtt {
+        if let Some(response) = head_response(
+            &req,
+            "text/vtt",
+            modified_time,
+            &config.cache_control.thumbnail_cache_control,
+            &etag,
+        ) {
+            return Ok(response);
+        }
+        let canonical_path = app_data.store.local_path(&key)?;
+        let (_, layout) = storyboard::open(&canonical_path, &config.storyboard)
+            .map_err(ApiError::FailedToDecodeMovie)?;
+        // VTT の URL からクエリを除いた相対パスがスプライトになる
+        let vtt = layout.webvtt(&key.build_filename().to_string_lossy());
+        save_sidecar(&app_data, &key, "storyboard.vtt", vtt.as_bytes());
+        return Ok(with_etag(
+            build_cached_response(
+                vtt.into_bytes(),
+                "text/vtt",
+                modified_time,
+                &app_data.config.cache_control.thumbnail_cache_control,
+            ),
+            &etag,
+        ));
+    }
 
-    #[command(flatten)]
-    config: AppConfig,
+    if let Some(response) = serve_cached(
+        &req,
+        &app_data,
+        &key,
+        modified_time,
+        &output_name,
+        format.content_type(),
+        &config.cache_control.thumbnail_cache_control,
+    ) {
+        return Ok(with_vary_accept(response, negotiated));
+    }
+    if let Some(response) = head_response(
+        &req,
+        format.content_type(),
+        modified_time,
+        &config.cache_control.thumbnail_cache_control,
+        &etag,
+    ) {
+        return Ok(with_vary_accept(response, negotiated));
+    }
+    if is_async(&app_data, &key) {
+        let task = queue::Task::Storyboard {
+            key: key.build_filename().to_string_lossy().into_owned(),
+            format: format.extension().to_string(),
+        };
+        let response = accept_job(
+            &req,
+            &app_data,
+            &key,
+            modified_time,
+            &output_name,
+            convert,
+            task,
+        );
+        return Ok(with_vary_accept(response, negotiated));
+    }
+    let (data, format) = convert_once(&app_data, &key, modified_time, &output_name, convert)?;
+    Ok(with_vary_accept(
+        with_etag(
+            build_image_response(
+                data,
+                format,
+                modified_time,
+                &config.cache_control.thumbnail_cache_control,
+            ),
+            &etag,
+        ),
+        negotiated,
+    ))
 }
 
-#[derive(Parser)]
-struct AppConfig {
-    #[arg(short, long, default_value_t = 75.0)]
-    thumbnail_quality: f32,
+/// Output name and conversion of the `/storyboard` sprite. Also used by the queue workers.
+fn prepare_storyboard(
+    app_data: &web::Data<AppData>,
+    key: &FileKey,
+    format: OutputFormat,
+    modified_time: SystemTime,
+) -> (String, impl Convert) {
+    let output_name = format!("storyboard.{}", format.extension());
+    let convert = {
+        let app_data = app_data.clone();
+        let key = key.clone();
+        let output_name = output_name.clone();
+        move || {
+            let config = &app_data.config;
+            let canonical_path = app_data.store.local_path(&key)?;
+            let (mut source, layout) = storyboard::open(&canonical_path, &config.storyboard)
+                .map_err(ApiError::FailedToDecodeMovie)?;
+            let sprite = storyboard::render(&mut source, &layout, config.tone_map)
+                .map_err(ApiError::FailedToDecodeMovie)?;
+            let data = encode::encode(
+                sprite,
+                format,
+                &canonical_path,
+                &config.thumbnail_encode_quality(),
+            )?;
+            save_sidecar(&app_data, &key, &output_name, &data);
+            cache_output(&app_data, &key, modified_time, &output_name, &data);
+            Ok((data, format))
+        }
+    };
+    (output_name, convert)
+}
 
-    #[arg(short, long, default_value_t = 75.0)]
-    media_quality: f32,
+/// Grid of frames sampled across a video, `?cols=` x `?rows=`, with `?timestamps=1`
+/// burned in.
+#[route("/contactsheet/{tail:.*}", method = "GET", method = "HEAD")]
+async fn contact_sheet_image(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+    app_data: web::Data<AppData>,
+) -> Result<impl Responder, Error> {
+    let key = FileKey::parse(path.into_inner())?;
+    let config = &app_data.config;
+    if !config.load_image_option.is_movie_ext(&key.ext) {
+        return Err(ApiError::NotFound().into());
+    }
+    let requested_format = query.get("format").map(|s| OutputFormat::from_str(s));
+    let negotiated = requested_format.is_none();
+    let format = requested_format.unwrap_or_else(|| encode::negotiate(accept_header(&req)));
 
-    #[arg(long)]
-    media_passthrough_max_bytes: Option<u64>,
+    let grid = |name: &str| {
+        query
+            .get(name)
+            .and_then(|s| s.parse::<u32>().ok())
+            .filter(|&n| n > 0)
+            .unwrap_or(contact_sheet::DEFAULT_GRID)
+            .min(contact_sheet::MAX_GRID)
+    };
+    let (cols, rows) = (grid("cols"), grid("rows"));
+    let timestamps = query
+        .get("timestamps")
+        .is_some_and(|s| matches!(s.as_str(), "1" | "true"));
+    let sidecar_name = format!("contactsheet.{}", format.extension());
+    let sidecar_name = sidecar::with_variant(&sidecar_name, &format!("{}x{}", cols, rows));
+    let sidecar_name = if timestamps {
+        sidecar::with_variant(&sidecar_name, "ts")
+    } else {
+        sidecar_name
+    };
 
-    #[command(flatten)]
-    load_image_option: LoadImageOption,
+    let modified_time = app_data.store.metadata(&key)?.modified;
+    let etag = variant_etag(&key, modified_time, &sidecar_name);
+    if let Some(response) = not_modified(&req, modified_time, std::slice::from_ref(&etag)) {
+        return Ok(with_vary_accept(response, negotiated));
+    }
+    if let Some(response) = serve_cached(
+        &req,
+        &app_data,
+        &key,
+        modified_time,
+        &sidecar_name,
+        format.content_type(),
+        &config.cache_control.thumbnail_cache_control,
+    ) {
+        return Ok(with_vary_accept(response, negotiated));
+    }
+    if let Some(response) = head_response(
+        &req,
+        format.content_type(),
+        modified_time,
+        &config.cache_control.thumbnail_cache_control,
+        &etag,
+    ) {
+        return Ok(with_vary_accept(response, negotiated));
+    }
+    let convert = {
+        let app_data = app_data.clone();
+        let key = key.clone();
+        let sidecar_name = sidecar_name.clone();
+        move || {
+            let config = &app_data.config;
+            let canonical_path = app_data.store.local_path(&key)?;
+            let sheet = contact_sheet::render(
+                &canonical_path,
+                cols,
+                rows,
+                config.contact_sheet_tile_size,
+                timestamps.then_some(config.load_image_option.text_preview_font.as_path()),
+                config.tone_map,
+            )
+            .map_err(ApiError::FailedToDecodeMovie)?;
+            let data = encode::encode(
+                sheet,
+                format,
+                &canonical_path,
+                &config.thumbnail_encode_quality(),
+            )?;
+            save_sidecar(&app_data, &key, &sidecar_name, &data);
+            cache_output(&app_data, &key, modified_time, &sidecar_name, &data);
+            Ok((data, format))
+        }
+    };
+    let (data, format) = convert_once(&app_data, &key, modified_time, &sidecar_name, convert)?;
+    Ok(with_vary_accept(
+        with_etag(
+            build_image_response(
+                data,
+                format,
+                modified_time,
+                &config.cache_control.thumbnail_cache_control,
+            ),
+            &etag,
+        ),
+        negotiated,
+    ))
 }
 
-#[derive(Parser)]
-struct LoadImageOption {
-    #[arg(short, long, default_value_t = 10)]
-    movie_max_keyframes: i32,
-
-    #[arg(short, long, default_value_t = 1.0)]
-    movie_frame_score_threshold: f32,
+/// Duration, resolution, codecs and streams of a video as JSON.
+#[route("/info/{tail:.*}", method = "GET", method = "HEAD")]
+async fn info(
+    req: HttpRequest,
+    path: web::Path<String>,
+    app_data: web::Data<AppData>,
+) -> Result<impl Responder, Error> {
+    let key = FileKey::parse(path.into_inner())?;
+    if !app_data.config.load_image_option.is_movie_ext(&key.ext) {
+        return Err(ApiError::NotFound().into());
+    }
+    let modified_time = app_data.store.metadata(&key)?.modified;
+    let etag = variant_etag(&key, modified_time, "info.json");
+    if let Some(response) = not_modified(&req, modified_time, std::slice::from_ref(&etag)) {
+        return Ok(response);
+    }
+    if let Some(response) = head_response(
+        &req,
+        "application/json",
+        modified_time,
+        &app_data.config.cache_control.thumbnail_cache_control,
+        &etag,
+    ) {
+        return Ok(response);
+    }
+    let canonical_path = app_data.store.local_path(&key)?;
 
-    #[arg(short, long)]
-    movie_frame_sharpness_threshold: Option<f32>,
+    let info = video_info::probe(&canonical_path).map_err(ApiError::FailedToDecodeMovie)?;
+    let data =
+        serde_json::to_vec(&info).map_err(|err| ApiError::FailedToEncode(err.to_string()))?;
+    save_sidecar(&app_data, &key, "info.json", &data);
+    Ok(with_etag(
+        build_cached_response(
+            data,
+            "application/json",
+            modified_time,
+            &app_data.config.cache_control.thumbnail_cache_control,
+        ),
+        &etag,
+    ))
 }
 
-struct AppData {
-    base_path: PathBuf,
-    config: AppConfig,
+/// HLS playlists (`master.m3u8`) and segments of a video, converted on the first request.
+#[route("/hls/{key}/{file}", method = "GET", method = "HEAD")]
+async fn hls_file(
+    path: web::Path<(String, String)>,
+    app_data: web::Data<AppData>,
+) -> Result<fs::NamedFile, Error> {
+    let (key, file) = path.into_inner();
+    let key = FileKey::parse(key)?;
+    if !app_data.config.load_image_option.is_movie_ext(&key.ext) || !hls::is_valid_file_name(&file)
+    {
+        return Err(ApiError::NotFound().into());
+    }
+    app_data.store.metadata(&key)?;
+
+    // 変換は動画の長さに比例して時間がかかるのでワーカーを塞がない
+    let app_data = app_data.into_inner();
+    let dir = actix_web::rt::task::spawn_blocking(move || {
+        let config = &app_data.config;
+        let canonical_path = app_data.store.local_path(&key)?;
+        config.hls.prepare(&key, &canonical_path, &config.transcode)
+    })
+    .await
+    .map_err(|err| ApiError::FailedToDecodeMovie(err.into()))?
+    .map_err(|err| match err.downcast::<transcode::UnsupportedCodec>() {
+        Ok(unsupported) => ApiError::UnsupportedCodec(unsupported),
+        Err(err) => ApiError::FailedToDecodeMovie(err),
+    })?;
+
+    let named_file = fs::NamedFile::open(dir.join(&file))?;
+    Ok(named_file
+        .use_last_modified(true)
+        .set_content_type(hls::content_type(&file).parse().unwrap()))
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("INFO"));
+/// H.264/AAC MP4 proxy of a video. The first request starts the conversion in the background
+/// and answers 202 until it is ready.
+#[route("/transcode/{tail:.*}", method = "GET", method = "HEAD")]
+async fn transcode_proxy(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+    app_data: web::Data<AppData>,
+) -> Result<Either<fs::NamedFile, HttpResponse>, Error> {
+    let key = FileKey::parse(path.into_inner())?;
+    if !app_data.config.load_image_option.is_movie_ext(&key.ext) {
+        return Err(ApiError::NotFound().into());
+    }
+    let source_modified = app_data.store.metadata(&key)?.modified;
 
-    let args = Args::parse();
-    let base_path = args.base_path.canonicalize().expect("Invalid base path");
-    let app_data = web::Data::new(AppData {
-        base_path,
-        config: args.config,
-    });
+    let params = proxy::ProxyParams::from_query(&query);
+    let proxy_path = app_data.config.proxy.build_path(&key, &params);
+    if proxy::is_fresh(&proxy_path, source_modified) {
+        let named_file = fs::NamedFile::open(&proxy_path)?;
+        return Ok(Either::Left(named_file.use_last_modified(true)));
+    }
+    // HEAD では変換を始めない
+    let etag = variant_etag(
+        &key,
+        source_modified,
+        &proxy_path.file_name().unwrap_or_default().to_string_lossy(),
+    );
+    if let Some(response) = head_response(
+        &req,
+        transcode::Container::Mp4.content_type(),
+        source_modified,
+        &app_data.config.cache_control.thumbnail_cache_control,
+        &etag,
+    ) {
+        return Ok(Either::Right(response));
+    }
+
+    let id = jobs::job_id(&proxy_path.to_string_lossy());
+    // 失敗した変換はしばらくやり直さず、202 で待たせ続けないようにエラーを返す
+    if let Some(error) = app_data.jobs.failure(&id) {
+        return Err(ApiError::FailedToDecodeMovie(anyhow::anyhow!(error)).into());
+    }
+    let url = req.uri().to_string();
+    if let Some(queue) = &app_data.queue {
+        if app_data.jobs.start(&id, &url).is_some() {
+            let task = queue::Task::Transcode {
+                key: key.build_filename().to_string_lossy().into_owned(),
+                height: params.height,
+                bit_rate: params.bit_rate,
+            };
+            queue.submit(&app_data.jobs, &id, &url, &task);
+        }
+    } else if let Some(tracker) = app_data.jobs.start(&id, &url) {
+        let app_data = app_data.into_inner();
+        let id = id.clone();
+        actix_web::rt::task::spawn_blocking(move || {
+            let _tracking = tracker.enter();
+            let result = app_data
+                .store
+                .local_path(&key)
+                .map_err(anyhow::Error::from)
+                .and_then(|canonical_path| {
+                    proxy::generate(
+                        &canonical_path,
+                        &proxy_path,
+                        &params,
+                        &app_data.config.transcode,
+                    )
+                });
+            if let Err(err) = &result {
+                log::warn!(
+                    "Failed to transcode {}: {}",
+                    key.build_filename().display(),
+                    err
+                );
+            }
+            app_data
+                .jobs
+                .finish(&id, result.map_err(|err| err.to_string()));
+        });
+    }
+    Ok(Either::Right(jobs::accepted(&id)))
+}
+
+/// Excerpt `?start=`-`?end=` of a video as MP4 or WebM. Streams are copied when the container
+/// allows it, which cuts at the keyframe before `start`.
+#[route("/clip/{tail:.*}", method = "GET", method = "HEAD")]
+async fn video_clip(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+    app_data: web::Data<AppData>,
+) -> Result<impl Responder, Error> {
+    let key = FileKey::parse(path.into_inner())?;
+    if !app_data.config.load_image_option.is_movie_ext(&key.ext) {
+        return Err(ApiError::NotFound().into());
+    }
+    let (Some(start), Some(end)) = (
+        parse_timestamp(&query, "start"),
+        parse_timestamp(&query, "end"),
+    ) else {
+        return Err(ApiError::InvalidOperation("start and end are required".to_string()).into());
+    };
+    if end <= start {
+        return Err(ApiError::InvalidOperation(format!("empty range {}-{}", start, end)).into());
+    }
+    let end = end.min(start + app_data.config.clip_max_duration);
+    let container = match query.get("format").map(String::as_str) {
+        Some("webm") => transcode::Container::WebM,
+        Some("mp4") => transcode::Container::Mp4,
+        _ if key.ext.eq_ignore_ascii_case("webm") => transcode::Container::WebM,
+        _ => transcode::Container::Mp4,
+    };
+    let modified_time = app_data.store.metadata(&key)?.modified;
+    let output_name = format!("clip.{}-{}.{}", start, end, container.extension());
+    let etag = variant_etag(&key, modified_time, &output_name);
+    if let Some(response) = not_modified(&req, modified_time, std::slice::from_ref(&etag)) {
+        return Ok(response);
+    }
+    if let Some(response) = head_response(
+        &req,
+        container.content_type(),
+        modified_time,
+        &app_data.config.cache_control.thumbnail_cache_control,
+        &etag,
+    ) {
+        return Ok(response);
+    }
+
+    let job = transcode::Job {
+        container,
+        max_height: None,
+        video_bit_rate: None,
+        range: Some((start, end)),
+        allow_encode: true,
+        muxer_options: Vec::new(),
+    };
+    // 再エンコードは切り出す長さに比例して時間がかかるのでワーカーを塞がない
+    let data = {
+        let app_data = app_data.clone();
+        let key = key.clone();
+        actix_web::rt::task::spawn_blocking(move || {
+            let canonical_path = app_data.store.local_path(&key)?;
+            transcode::run_to_vec(&canonical_path, &job, &app_data.config.transcode)
+        })
+        .await
+        .map_err(|err| ApiError::FailedToDecodeMovie(err.into()))?
+        .map_err(ApiError::FailedToDecodeMovie)?
+    };
+    save_sidecar(&app_data, &key, &output_name, &data);
+    Ok(with_etag(
+        build_cached_response(
+            data,
+            container.content_type(),
+            modified_time,
+            &app_data.config.cache_control.thumbnail_cache_control,
+        ),
+        &etag,
+    ))
+}
+
+/// Builds the operations for `/thumbnail` and the sidecar name they are cached under.
+/// `?ops=` takes precedence over the individual parameters; without a resize step it starts
+/// from the `?size=` thumbnail.
+fn thumbnail_pipeline(
+    query: &std::collections::HashMap<String, String>,
+    size: Size,
+    format: OutputFormat,
+    config: &AppConfig,
+) -> Result<(Pipeline, String), ApiError> {
+    let max = (config.thumbnail_max_width, config.thumbnail_max_height);
+    let dpr = parse_dpr(query);
+    if let Some(ops) = query.get("ops") {
+        let mut pipeline = Pipeline::parse(ops, max).map_err(ApiError::InvalidOperation)?;
+        if !pipeline.has_resize() {
+            let (width, height) = size.dimensions();
+            pipeline.prepend(Op::Resize {
+                width,
+                height,
+                fit: fit::Fit::Contain,
+                gravity: fit::Gravity::Center,
+                filter: config.resize_filter,
+            });
+        }
+        if let Some(dpr) = dpr {
+            pipeline.scale(dpr, max);
+        }
+        let sidecar_name = sidecar::with_variant(
+            &format!("thumb.ops.{}", format.extension()),
+            &pipeline.variant(),
+        );
+        return Ok((pipeline, sidecar_name));
+    }
+
+    // 片方の辺だけなら、もう片方は元画像の縦横比から決まる。その枠には切り抜きも引き伸ばしも要らない
+    let one_sided = parse_side(query, "w").is_none() != parse_side(query, "h").is_none();
+    let fit = query
+        .get("fit")
+        .map(|s| fit::Fit::from_str(s))
+        .filter(|_| !one_sided)
+        .unwrap_or(fit::Fit::Contain);
+    let gravity = query
+        .get("gravity")
+        .map(|s| fit::Gravity::from_str(s))
+        .unwrap_or(fit::Gravity::Center);
+    let requested_filter = query
+        .get("filter")
+        .and_then(|s| fit::ResizeFilter::parse(s));
+    let ((width, height), sidecar_name) = match parse_dimensions(query, config) {
+        Some((w, h)) => ((w, h), format!("thumb.{}x{}.{}", w, h, format.extension())),
+        None => (size.dimensions(), size.sidecar_name(format)),
+    };
+    let effects = effect::Effects::from_query(query);
+    // 余白は透明で追加し、色は `bg` で塗る
+    let pad = query
+        .get("pad")
+        .is_some_and(|s| matches!(s.as_str(), "1" | "true"));
+    let border = parse_border(query);
+
+    let mut variants = Vec::new();
+    variants.extend(fit::variant(fit, gravity, requested_filter));
+    variants.extend(effects.variant());
+    if pad {
+        variants.push("pad".to_string());
+    }
+    if let Some(Op::Border { width, color }) = border {
+        variants.push(format!("border{}-{}", width, color.to_hex()));
+    }
+    if let Some(dpr) = dpr {
+        variants.push(format!("dpr{}", dpr));
+    }
+    let sidecar_name = variants.iter().fold(sidecar_name, |name, variant| {
+        sidecar::with_variant(&name, variant)
+    });
+
+    let mut ops = vec![Op::Resize {
+        width,
+        height,
+        fit,
+        gravity,
+        filter: requested_filter.or(config.resize_filter),
+    }];
+    ops.extend(effects.ops());
+    if pad {
+        ops.push(Op::Pad { width, height });
+    }
+    ops.extend(border);
+    let mut pipeline = Pipeline::new(ops);
+    if let Some(dpr) = dpr {
+        pipeline.scale(dpr, max);
+    }
+    Ok((pipeline, sidecar_name))
+}
+
+/// Re-encodes every frame of an animated source, or of a short video with
+/// `--animation-max-video-duration`. Returns `None` for still images and output formats
+/// without animation support so the caller falls back to a single frame. The returned
+/// format differs from `format` only when AVIF is swapped for WebP to keep transparency.
+fn encode_animation(
+    app_data: &AppData,
+    key: &FileKey,
+    path: &Path,
+    format: OutputFormat,
+    webp_fallback: bool,
+    stream: Option<usize>,
+) -> Result<Option<(Vec<u8>, OutputFormat)>, ApiError> {
+    let config = &app_data.config;
+    let quality = config.media_encode_quality();
+    let Some(mut encoder) = animation::sequence_encoder(format, &quality) else {
+        return Ok(None);
+    };
+    let frames = if animation::is_animation_ext(&key.ext) {
+        animation::load_frames(path, config.animation_max_frames)
+            .map_err(|err| ApiError::FailedToDecodeFormat("animation", err))?
+    } else if let Some(max_duration) = config
+        .animation_max_video_duration
+        .filter(|_| config.load_image_option.is_movie_ext(&key.ext))
+    {
+        let frames = animation::load_video_frames(
+            path,
+            config.animation_max_frames,
+            max_duration,
+            config.animation_video_max_size,
+            stream,
+        )
+        .map_err(
+            |err| match err.downcast::<movie_keyframe::NotVideoStream>() {
+                Ok(not_video) => ApiError::InvalidOperation(not_video.to_string()),
+                Err(err) => ApiError::FailedToDecodeFormat("video", err),
+            },
+        )?;
+        frames.unwrap_or_default()
+    } else {
+        return Ok(None);
+    };
+    if frames.len() < 2 {
+        return Ok(None);
+    }
+
+    let mut format = format;
+    if format == OutputFormat::Avif && webp_fallback && animation::has_transparency(&frames) {
+        format = OutputFormat::WebP;
+        encoder = Box::new(animation::WebPSequence {
+            quality: quality.webp,
+        });
+    }
+    let data = match encoder.encode(&frames) {
+        // libaom が無い、フレームの大きさを受け付けないなどで AVIF にできなければ WebP で返す
+        Err(err) if format == OutputFormat::Avif && webp_fallback => {
+            log::warn!(
+                "Failed to encode animated AVIF, falling back to WebP: {}:{}",
+                path.to_str().unwrap_or("N/A"),
+                err,
+            );
+            format = OutputFormat::WebP;
+            encoder = Box::new(animation::WebPSequence {
+                quality: quality.webp,
+            });
+            encoder.encode(&frames)
+        }
+        result => result,
+    }
+    .map_err(|err| {
+        log::warn!(
+            "Failed to encode animation: {}:{}",
+            path.to_str().unwrap_or("N/A"),
+            err,
+        );
+        ApiError::FailedToEncode(err.to_string())
+    })?;
+    Ok(Some((data, format)))
+}
+
+/// `?w=` / `?h=` override the named size. A missing side is only bounded by the configured
+/// maximum, so `?w=200` alone yields a 200px wide thumbnail.
+fn parse_dimensions(
+    query: &std::collections::HashMap<String, String>,
+    config: &AppConfig,
+) -> Option<(u32, u32)> {
+    let (w, h) = (parse_side(query, "w"), parse_side(query, "h"));
+    if w.is_none() && h.is_none() {
+        return None;
+    }
+    Some((
+        w.unwrap_or(u32::MAX).min(config.thumbnail_max_width),
+        h.unwrap_or(u32::MAX).min(config.thumbnail_max_height),
+    ))
+}
+
+fn parse_side(query: &std::collections::HashMap<String, String>, name: &str) -> Option<u32> {
+    query
+        .get(name)
+        .and_then(|s| s.parse::<u32>().ok())
+        .filter(|&v| v > 0)
+}
+
+/// `?quality=` (1-100) overrides the WebP, AVIF and JPEG quality. JPEG XL keeps its distance.
+fn parse_quality(query: &std::collections::HashMap<String, String>) -> Option<u8> {
+    query
+        .get("quality")
+        .and_then(|s| s.parse().ok())
+        .filter(|q| (1..=100).contains(q))
+}
+
+/// `?bg=RRGGBB` flattens alpha onto a solid color; `?bg=none` keeps alpha even when
+/// `--background` is set.
+fn parse_background(
+    query: &std::collections::HashMap<String, String>,
+    config: &AppConfig,
+) -> Option<color::HexColor> {
+    match query.get("bg").map(String::as_str) {
+        Some("none") => None,
+        Some(s) => s.parse().ok().or(config.background),
+        None => config.background,
+    }
+}
+
+const MAX_DPR: f32 = 4.0;
+
+/// `?dpr=` between 1 and 4. Sizes are multiplied after `?preset=` has been expanded.
+fn parse_dpr(query: &std::collections::HashMap<String, String>) -> Option<f32> {
+    query
+        .get("dpr")
+        .and_then(|s| s.parse::<f32>().ok())
+        .filter(|&dpr| dpr > 1.0 && dpr <= MAX_DPR)
+}
+
+/// `?border=N&border_color=RRGGBB`. Out of range widths are clamped.
+fn parse_border(query: &std::collections::HashMap<String, String>) -> Option<Op> {
+    let width = query
+        .get("border")
+        .and_then(|s| s.parse::<u32>().ok())
+        .filter(|&w| w > 0)?
+        .min(pipeline::MAX_BORDER);
+    let color = query
+        .get("border_color")
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(pipeline::DEFAULT_BORDER_COLOR);
+    Some(Op::Border { width, color })
+}
+
+fn parse_lossless(query: &std::collections::HashMap<String, String>) -> bool {
+    query
+        .get("lossless")
+        .is_some_and(|s| matches!(s.as_str(), "1" | "true"))
+}
+
+/// `?strip=1`, or always with `--strip-metadata`.
+fn parse_strip(query: &std::collections::HashMap<String, String>, config: &AppConfig) -> bool {
+    config.strip_metadata
+        || query
+            .get("strip")
+            .is_some_and(|s| matches!(s.as_str(), "1" | "true"))
+}
+
+/// Seconds from the start of a video in the `name` parameter.
+fn parse_timestamp(query: &std::collections::HashMap<String, String>, name: &str) -> Option<f64> {
+    query
+        .get(name)
+        .and_then(|s| s.parse::<f64>().ok())
+        .filter(|t| t.is_finite() && *t >= 0.0)
+}
+
+/// `?stream=N`: index of the video stream as listed by `/info`
+fn parse_stream(query: &std::collections::HashMap<String, String>) -> Option<usize> {
+    query.get("stream").and_then(|s| s.parse().ok())
+}
+
+fn parse_page(query: &std::collections::HashMap<String, String>) -> Option<u32> {
+    query
+        .get("page")
+        .and_then(|s| s.parse().ok())
+        .filter(|&page| page > 0)
+}
+
+/// Where the keyframe chosen for a video is remembered. Only with a sidecar mode and only
+/// when the keyframes are scored at all.
+fn keyframe_sidecar(
+    app_data: &AppData,
+    key: &FileKey,
+    request: &loader::LoadRequest,
+) -> Option<PathBuf> {
+    if request.timestamp.is_some() || !app_data.config.load_image_option.is_movie_ext(&key.ext) {
+        return None;
+    }
+    app_data.config.sidecar.build_path(
+        key,
+        &app_data.base_path,
+        &request.sidecar_name("keyframe.json"),
+    )
+}
+
+fn save_sidecar(app_data: &AppData, key: &FileKey, name: &str, data: &[u8]) {
+    let sidecar = &app_data.config.sidecar;
+    let Some(sidecar_path) = sidecar.build_path(key, &app_data.base_path, name) else {
+        return;
+    };
+    sidecar::write(&sidecar_path, data).unwrap_or_else(|err| {
+        log::warn!(
+            "Failed to write sidecar: {}:{}",
+            sidecar_path.display(),
+            err
+        );
+    });
+}
+
+#[post("/ingest/{tail:.*}")]
+async fn ingest_file(
+    req: HttpRequest,
+    path: web::Path<String>,
+    app_data: web::Data<AppData>,
+) -> Result<HttpResponse, Error> {
+    if app_data.config.ingest_steps.is_empty() {
+        return Err(ApiError::NotFound().into());
+    }
+    // 変換とディスクへの書き込みを誰からでも始められないようにする
+    authorize_admin(&req, &app_data)?;
+    let key = FileKey::parse(path.into_inner())?;
+    app_data.store.metadata(&key)?;
+
+    actix_web::rt::task::spawn_blocking(move || {
+        ingest::run(&app_data, &key).unwrap_or_else(|err| {
+            log::warn!(
+                "Failed to ingest {}: {}",
+                key.build_filename().display(),
+                err
+            );
+        })
+    });
+    Ok(HttpResponse::Accepted().finish())
+}
+
+/// Checks the admin token. The endpoints look missing when no token is configured.
+fn authorize_admin(req: &HttpRequest, app_data: &AppData) -> Result<(), ApiError> {
+    if app_data.config.admin.authorize(req) {
+        Ok(())
+    } else if app_data.config.admin.is_enabled() {
+        Err(ApiError::Unauthorized())
+    } else {
+        Err(ApiError::NotFound())
+    }
+}
+
+/// Purges every cached output of one source.
+#[delete("/admin/cache/{key}")]
+async fn purge_cache_key(
+    req: HttpRequest,
+    path: web::Path<String>,
+    app_data: web::Data<AppData>,
+) -> Result<HttpResponse, Error> {
+    authorize_admin(&req, &app_data)?;
+    let key = FileKey::parse(path.into_inner())?;
+    let outputs = web::block(move || app_data.cache.purge(&key, None)).await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "outputs": outputs })))
+}
+
+#[derive(serde::Deserialize)]
+struct PurgeRequest {
+    /// Key prefix of the sources to purge. Everything is purged when omitted
+    prefix: Option<String>,
+}
+
+/// Purges the outputs of the sources whose key starts with `prefix`, or the whole cache.
+#[post("/admin/cache/purge")]
+async fn purge_cache(
+    req: HttpRequest,
+    body: Option<web::Json<PurgeRequest>>,
+    app_data: web::Data<AppData>,
+) -> Result<HttpResponse, Error> {
+    authorize_admin(&req, &app_data)?;
+    let prefix = body.and_then(|body| body.into_inner().prefix);
+    if let Some(prefix) = &prefix {
+        if prefix.is_empty() || !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(ApiError::InvalidOperation(format!("malformed prefix {}", prefix)).into());
+        }
+    }
+    let response = web::block(move || match prefix {
+        Some(prefix) => admin::purge_prefix(&app_data, &prefix).map(
+            |(sources, outputs)| serde_json::json!({ "sources": sources, "outputs": outputs }),
+        ),
+        None => {
+            let layers: serde_json::Map<_, _> = app_data
+                .cache
+                .clear()
+                .into_iter()
+                .map(|(layer, removed)| (layer.to_string(), removed.into()))
+                .collect();
+            Ok(serde_json::json!({ "layers": layers }))
+        }
+    })
+    .await??;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// Number of outputs listed in `/admin/cache/stats` unless `?top=` is given.
+const DEFAULT_HOTTEST: usize = 20;
+
+#[derive(serde::Deserialize)]
+struct CacheStatsQuery {
+    /// Number of the hottest outputs to list
+    top: Option<usize>,
+}
+
+/// Counters and sizes of each cache layer and the hottest outputs, for sizing the caches.
+#[get("/admin/cache/stats")]
+async fn cache_stats(
+    req: HttpRequest,
+    query: web::Query<CacheStatsQuery>,
+    app_data: web::Data<AppData>,
+) -> Result<HttpResponse, Error> {
+    authorize_admin(&req, &app_data)?;
+    let layers: Vec<_> = app_data
+        .cache
+        .layers()
+        .map(|layer| {
+            let stats = layer.stats();
+            let lookups = stats.hits + stats.misses;
+            serde_json::json!({
+                "name": layer.name(),
+                "hits": stats.hits,
+                "misses": stats.misses,
+                "hit_ratio": (lookups > 0).then(|| stats.hits as f64 / lookups as f64),
+                "evictions": stats.evictions,
+                "entries": stats.entries,
+                "bytes": stats.bytes,
+            })
+        })
+        .collect();
+    let hottest: Vec<_> = app_data
+        .cache
+        .hottest(query.top.unwrap_or(DEFAULT_HOTTEST))
+        .into_iter()
+        .map(|(source, output, hits)| {
+            serde_json::json!({ "source": source, "output": output, "hits": hits })
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "layers": layers, "hottest": hottest })))
+}
+
+/// Keyframe scores of a video stored in `--movie-keyframe-db`, for tuning the thresholds.
+#[get("/admin/keyframes/{key}")]
+async fn keyframe_scores(
+    req: HttpRequest,
+    path: web::Path<String>,
+    app_data: web::Data<AppData>,
+) -> Result<HttpResponse, Error> {
+    authorize_admin(&req, &app_data)?;
+    let key = FileKey::parse(path.into_inner())?;
+    let option = &app_data.config.load_image_option.keyframe;
+    let index = option.index().ok_or(ApiError::NotFound())?;
+    let scans = index
+        .lock()
+        .unwrap()
+        .scans(&key.hkey)
+        .map_err(std::io::Error::other)?;
+    let (score, sharpness) = option.thresholds();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "version": option.scoring_digest(),
+        "thresholds": { "score": score, "sharpness": sharpness },
+        "scans": scans,
+    })))
+}
+
+/// The queue of `--queue-db`, for the admin endpoints. Missing when it is not configured.
+fn admin_queue<'a>(req: &HttpRequest, app_data: &'a AppData) -> Result<&'a queue::Queue, ApiError> {
+    authorize_admin(req, app_data)?;
+    app_data.queue.as_ref().ok_or(ApiError::NotFound())
+}
+
+/// Numbers of queued, running and dead tasks, and the dead letters.
+#[get("/admin/queue")]
+async fn queue_status(
+    req: HttpRequest,
+    app_data: web::Data<AppData>,
+) -> Result<HttpResponse, Error> {
+    let queue = admin_queue(&req, &app_data)?;
+    let counts = queue.counts().map_err(std::io::Error::other)?;
+    let dead_letters = queue.dead_letters().map_err(std::io::Error::other)?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "counts": counts,
+        "dead_letters": dead_letters,
+    })))
+}
+
+/// Queues a dead task again and answers 202 with the URL of its job.
+#[post("/admin/queue/dead/{id}/retry")]
+async fn retry_dead_task(
+    req: HttpRequest,
+    path: web::Path<String>,
+    app_data: web::Data<AppData>,
+) -> Result<HttpResponse, Error> {
+    let queue = admin_queue(&req, &app_data)?;
+    let url = queue
+        .retry(&path)
+        .map_err(std::io::Error::other)?
+        .ok_or(ApiError::NotFound())?;
+    // ワーカーが拾う前に `/jobs/{id}` を見ても失敗のままにならないようにする
+    app_data.jobs.resume(&path, &url);
+    Ok(jobs::accepted(&path))
+}
+
+/// Drops a dead task.
+#[delete("/admin/queue/dead/{id}")]
+async fn discard_dead_task(
+    req: HttpRequest,
+    path: web::Path<String>,
+    app_data: web::Data<AppData>,
+) -> Result<HttpResponse, Error> {
+    let queue = admin_queue(&req, &app_data)?;
+    if !queue.discard(&path).map_err(std::io::Error::other)? {
+        return Err(ApiError::NotFound().into());
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Counters of each cache layer in the Prometheus text format, for sizing the caches.
+#[get("/metrics")]
+async fn metrics(app_data: web::Data<AppData>) -> HttpResponse {
+    let layers: Vec<_> = app_data
+        .cache
+        .layers()
+        .map(|layer| (layer.name(), layer.stats()))
+        .collect();
+    type Value = fn(&cache::CacheStats) -> Option<u64>;
+    let metrics: [(&str, &str, Value); 5] = [
+        ("cache_hits_total", "counter", |stats| Some(stats.hits)),
+        ("cache_misses_total", "counter", |stats| Some(stats.misses)),
+        ("cache_evictions_total", "counter", |stats| {
+            Some(stats.evictions)
+        }),
+        ("cache_entries", "gauge", |stats| {
+            stats.entries.map(|n| n as u64)
+        }),
+        ("cache_bytes", "gauge", |stats| stats.bytes),
+    ];
+    let mut body = String::new();
+    for (name, kind, value) in metrics {
+        body.push_str(&format!("# TYPE media_converter_{} {}\n", name, kind));
+        for (layer, stats) in &layers {
+            if let Some(value) = value(stats) {
+                body.push_str(&format!(
+                    "media_converter_{}{{layer=\"{}\"}} {}\n",
+                    name, layer, value
+                ));
+            }
+        }
+    }
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body)
+}
+
+fn accept_header(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+}
+
+/// Headers of an output that is not generated yet, for HEAD. Converting it only to drop the
+/// body would waste the work, so `Content-Length` is left out.
+fn head_response(
+    req: &HttpRequest,
+    content_type: &str,
+    modified_time: SystemTime,
+    cache_control: &cache_control::CachePolicy,
+    etag: &header::EntityTag,
+) -> Option<HttpResponse> {
+    (req.method() == actix_web::http::Method::HEAD).then(|| {
+        with_etag(
+            build_cached_response(
+                actix_web::body::None::new(),
+                content_type,
+                modified_time,
+                cache_control,
+            ),
+            etag,
+        )
+    })
+}
+
+/// Responses whose format was picked from `Accept` must not be shared between clients.
+fn with_vary_accept(mut res: HttpResponse, negotiated: bool) -> HttpResponse {
+    if negotiated {
+        res.headers_mut()
+            .insert(header::VARY, header::HeaderValue::from_static("Accept"));
+    }
+    res
+}
+
+/// Serves a previously generated output from the cache layers, with the same headers as
+/// `build_cached_response` and its `variant_etag`. Files are sent as they are. `Range` is
+/// served from the cached output.
+fn serve_cached(
+    req: &HttpRequest,
+    app_data: &AppData,
+    key: &FileKey,
+    modified_time: SystemTime,
+    name: &str,
+    content_type: &str,
+    cache_control: &cache_control::CachePolicy,
+) -> Option<HttpResponse> {
+    let etag = variant_etag(key, modified_time, name);
+    let response = match app_data
+        .cache
+        .get(&cache::CacheKey::new(key, modified_time, name))
+        .inspect(|_| app_data.cache.record_hit(key, name))?
+    {
+        cache::Cached::Bytes(data) => {
+            range::bytes_response(req, data, content_type, &etag, modified_time)
+        }
+        cache::Cached::File(path) => {
+            // 範囲の切り出しも前段に任せる
+            if let Some(response) =
+                offloaded_response(app_data, &path, content_type, modified_time, cache_control)
+            {
+                return Some(with_etag(response, &etag));
+            }
+            // 直前に消された場合はキャッシュに無かったものとして作り直す
+            let file = std::fs::File::open(path).ok()?;
+            let len = file.metadata().ok()?.len();
+            range::file_response(req, file, len, content_type, &etag, modified_time).ok()?
+        }
+    };
+    Some(cache_control.apply(response))
+}
+
+/// An empty response that lets the front proxy send the cache file `path`, with `--offload`.
+fn offloaded_response(
+    app_data: &AppData,
+    path: &Path,
+    content_type: &str,
+    modified_time: SystemTime,
+    cache_control: &cache_control::CachePolicy,
+) -> Option<HttpResponse> {
+    let (name, value) = app_data
+        .config
+        .offload
+        .header(app_data.config.cache.cache_dir()?, path)?;
+    let mut response = build_cached_response((), content_type, modified_time, cache_control);
+    response.headers_mut().insert(
+        header::HeaderName::from_static(name),
+        header::HeaderValue::from_str(&value).ok()?,
+    );
+    Some(response)
+}
+
+/// Generates one output, on the request or in the background.
+trait Convert: FnOnce() -> Result<(Vec<u8>, OutputFormat), Error> + Send + 'static {}
+
+impl<F> Convert for F where F: FnOnce() -> Result<(Vec<u8>, OutputFormat), Error> + Send + 'static {}
+
+/// With `--stale-while-revalidate`, serves the output cached for an older version of the source.
+/// It is revalidated on every use so that clients pick up the regenerated one.
+fn serve_stale(
+    req: &HttpRequest,
+    app_data: &AppData,
+    key: &FileKey,
+    modified_time: SystemTime,
+    name: &str,
+    content_type: &str,
+) -> Option<HttpResponse> {
+    if !app_data.config.cache.stale_while_revalidate {
+        return None;
+    }
+    let stale_time = app_data
+        .cache
+        .get_latest(key, name)
+        .filter(|&time| time != modified_time)?;
+    serve_cached(
+        req,
+        app_data,
+        key,
+        stale_time,
+        name,
+        content_type,
+        &cache_control::CachePolicy::revalidate(),
+    )
+}
+
+/// With `--async-video`, whether an output of `key` that is not cached yet is generated in the
+/// background instead of on the request.
+fn is_async(app_data: &AppData, key: &FileKey) -> bool {
+    app_data.config.jobs.async_video && app_data.config.load_image_option.is_movie_ext(&key.ext)
+}
+
+/// Generates the output `name` in the background and answers 202 with the URL of its job. With
+/// `--queue-db` it is queued as `task`, otherwise `convert` runs on the blocking pool.
+fn accept_job(
+    req: &HttpRequest,
+    app_data: &web::Data<AppData>,
+    key: &FileKey,
+    modified_time: SystemTime,
+    name: &str,
+    convert: impl Convert,
+    task: queue::Task,
+) -> HttpResponse {
+    let id = jobs::job_id(&cache::CacheKey::new(key, modified_time, name).to_string());
+    let url = req.uri().to_string();
+    if let Some(queue) = &app_data.queue {
+        if app_data.jobs.start(&id, &url).is_some() {
+            queue.submit(&app_data.jobs, &id, &url, &task);
+        }
+    } else if let Some(tracker) = app_data.jobs.start(&id, &url) {
+        let app_data = app_data.clone();
+        let key = key.clone();
+        let name = name.to_string();
+        let id = id.clone();
+        actix_web::rt::task::spawn_blocking(move || {
+            let _tracking = tracker.enter();
+            let result = convert_once(&app_data, &key, modified_time, &name, convert);
+            app_data
+                .jobs
+                .finish(&id, result.map(|_| ()).map_err(|err| err.to_string()));
+        });
+    }
+    jobs::accepted(&id)
+}
+
+/// State of a job started by `/transcode` or, with `--async-video`, `/thumbnail` and `/media`.
+#[get("/jobs/{id}")]
+async fn job_status(
+    path: web::Path<String>,
+    app_data: web::Data<AppData>,
+) -> Result<HttpResponse, Error> {
+    let status = app_data.jobs.status(&path).ok_or(ApiError::NotFound())?;
+    Ok(jobs::status_response(&status))
+}
+
+/// Progress of a job as Server-Sent Events, until it finishes.
+#[get("/jobs/{id}/events")]
+async fn job_events(
+    path: web::Path<String>,
+    app_data: web::Data<AppData>,
+) -> Result<HttpResponse, Error> {
+    app_data.jobs.status(&path).ok_or(ApiError::NotFound())?;
+    Ok(jobs::events_response(app_data, &path))
+}
+
+/// Runs `convert` in the background unless it is already running.
+fn revalidate(
+    app_data: &web::Data<AppData>,
+    key: &FileKey,
+    modified_time: SystemTime,
+    name: &str,
+    convert: impl Convert,
+) {
+    if app_data
+        .coalescer
+        .is_running(&cache::CacheKey::new(key, modified_time, name))
+    {
+        return;
+    }
+    let app_data = app_data.clone();
+    let key = key.clone();
+    let name = name.to_string();
+    actix_web::rt::task::spawn_blocking(move || {
+        if let Err(err) = convert_once(&app_data, &key, modified_time, &name, convert) {
+            log::warn!("Failed to regenerate {}.{}: {}", key.hkey, name, err);
+        }
+    });
+}
+
+/// Runs `convert` for an output that is not cached, once for concurrent requests of the same
+/// output. Sources that failed to decode recently fail without running it.
+fn convert_once(
+    app_data: &AppData,
+    key: &FileKey,
+    modified_time: SystemTime,
+    name: &str,
+    convert: impl Convert,
+) -> Result<(Bytes, OutputFormat), Error> {
+    app_data.failures.check(key, modified_time)?;
+    let cache_key = cache::CacheKey::new(key, modified_time, name);
+    app_data.coalescer.run(&cache_key, || {
+        convert()
+            .map(|(data, format)| (Bytes::from(data), format))
+            .inspect_err(|err| app_data.failures.record(key, modified_time, err))
+    })
+}
+
+fn cache_output(
+    app_data: &AppData,
+    key: &FileKey,
+    modified_time: SystemTime,
+    name: &str,
+    data: &[u8],
+) {
+    app_data
+        .cache
+        .put(&cache::CacheKey::new(key, modified_time, name), data);
+    app_data.cache.record_rendition(key, name, modified_time);
+    if app_data.config.cache.stale_while_revalidate {
+        app_data.cache.put_latest(key, name, modified_time);
+    }
+}
+
+fn build_image_response(
+    data: impl actix_web::body::MessageBody + 'static,
+    format: OutputFormat,
+    modified_time: SystemTime,
+    cache_control: &cache_control::CachePolicy,
+) -> HttpResponse {
+    build_cached_response(data, format.content_type(), modified_time, cache_control)
+}
+
+fn build_cached_response(
+    data: impl actix_web::body::MessageBody + 'static,
+    content_type: &str,
+    modified_time: SystemTime,
+    cache_control: &cache_control::CachePolicy,
+) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header(cache_control.header_value())
+        .insert_header(header::LastModified(modified_time.into()))
+        .body(data)
+}
+
+#[derive(Parser)]
+#[command(name = "media-thumb-server")]
+#[command(about = "Serve thumbnails from NAS")]
+#[command(subcommand_negates_reqs = true)]
+struct Args {
+    #[arg(long, default_value = "127.0.0.1")]
+    bind: String,
+
+    #[arg(short, long, default_value_t = 8080)]
+    port: u16,
+
+    #[arg(long, required = true)]
+    base_path: Option<PathBuf>,
+
+    #[command(flatten)]
+    config: AppConfig,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run decode → resize → encode over a sample corpus and report throughput and latency
+    Bench(bench::BenchArgs),
+    /// Remove old disk cache entries and shrink the disk cache to a size, then exit
+    CacheGc(cache_gc::CacheGcArgs),
+    /// Generate thumbnails for every source into the caches and exit
+    Warmup(warmup::WarmupArgs),
+}
+
+#[derive(Parser)]
+struct AppConfig {
+    #[arg(short, long, default_value_t = 75.0)]
+    thumbnail_quality: f32,
+
+    #[arg(short, long, default_value_t = 75.0)]
+    media_quality: f32,
+
+    /// Upper bound for `?w=` on `/thumbnail`
+    #[arg(long, default_value_t = 2048)]
+    thumbnail_max_width: u32,
+
+    /// Upper bound for `?h=` on `/thumbnail`
+    #[arg(long, default_value_t = 2048)]
+    thumbnail_max_height: u32,
+
+    /// Unsharp mask amount applied after downscaling thumbnails (e.g. 0.5). Off when unset
+    #[arg(long)]
+    sharpen: Option<f32>,
+
+    /// Gaussian radius (sigma) of the unsharp mask
+    #[arg(long, default_value_t = 0.5)]
+    sharpen_radius: f32,
+
+    /// Differences below this are left alone so that flat areas do not get noisy
+    #[arg(long, default_value_t = 2)]
+    sharpen_threshold: u8,
+
+    /// Default for `?filter=`. Without it thumbnails use the fast `thumbnail()` sampling
+    #[arg(long, value_enum)]
+    resize_filter: Option<fit::ResizeFilter>,
+
+    /// Flatten thumbnail alpha onto this color unless `?bg=none`. Alpha is kept when unset
+    #[arg(long)]
+    background: Option<color::HexColor>,
+
+    /// Encode WebP losslessly when the image has at most this many colors (screenshots,
+    /// line art). Lossy output smears text
+    #[arg(long)]
+    lossless_max_colors: Option<usize>,
+
+    /// Pick the WebP/JPEG thumbnail quality per image so that the output reaches this SSIM
+    /// (e.g. 0.98) instead of using a fixed quality. `?quality=` still wins
+    #[arg(long)]
+    thumbnail_target_ssim: Option<f64>,
+
+    /// Named `/thumbnail` parameters for `?preset=NAME` (e.g. `grid_tile:w=256&h=256&fit=cover`)
+    #[arg(long = "preset", value_name = "NAME:QUERY")]
+    presets: Vec<preset::Preset>,
+
+    #[arg(long, default_value_t = 60)]
+    avif_thumbnail_quality: u8,
+
+    #[arg(long, default_value_t = 60)]
+    avif_media_quality: u8,
+
+    #[arg(long, default_value_t = 80)]
+    jpeg_thumbnail_quality: u8,
+
+    #[arg(long, default_value_t = 85)]
+    jpeg_media_quality: u8,
+
+    /// 1 (slowest, smallest) - 10 (fastest)
+    #[arg(long, default_value_t = 6)]
+    avif_speed: u8,
+
+    /// Butteraugli distance: 0.0 is lossless, 1.0 is visually lossless
+    #[cfg(feature = "jxl")]
+    #[arg(long, default_value_t = 1.0)]
+    jxl_distance: f32,
+
+    #[arg(long)]
+    media_passthrough_max_bytes: Option<u64>,
+
+    /// Never serve original bytes with EXIF/XMP: `/media` always re-encodes and `/raw` strips
+    /// JPEG/PNG/WebP metadata and refuses other formats with 403
+    #[arg(long)]
+    strip_metadata: bool,
+
+    /// Frames beyond this are dropped when re-encoding animations for `/media`
+    #[arg(long, default_value_t = 500)]
+    animation_max_frames: usize,
+
+    /// Videos up to this many seconds are served from `/media` as animated AVIF/WebP instead
+    /// of a still keyframe
+    #[arg(long)]
+    animation_max_video_duration: Option<f64>,
+
+    /// Longer side of the frames of animations made from videos
+    #[arg(long, default_value_t = 480)]
+    animation_video_max_size: u32,
+
+    /// Frames sampled evenly across the runtime for `/preview`
+    #[arg(long, default_value_t = 10)]
+    preview_frames: usize,
+
+    /// Longer side of `/preview` clips
+    #[arg(long, default_value_t = 320)]
+    preview_size: u32,
+
+    /// Display time of each `/preview` frame in milliseconds
+    #[arg(long, default_value_t = 500)]
+    preview_frame_ms: u32,
+
+    /// Longest excerpt `/clip` returns; longer ranges are cut at `start` plus this
+    #[arg(long, default_value_t = 300.0)]
+    clip_max_duration: f64,
+
+    /// Longer side of each frame in `/contactsheet`
+    #[arg(long, default_value_t = 320)]
+    contact_sheet_tile_size: u32,
+
+    /// How floating-point HDR images are mapped to 8-bit output
+    #[arg(long, value_enum, default_value_t = tonemap::ToneMapOperator::Reinhard)]
+    tone_map: tonemap::ToneMapOperator,
+
+    #[command(flatten)]
+    lqip: lqip::LqipOption,
+
+    #[command(flatten)]
+    storyboard: storyboard::StoryboardOption,
+
+    #[command(flatten)]
+    hls: hls::HlsOption,
+
+    #[command(flatten)]
+    transcode: transcode::TranscodeOption,
+
+    #[command(flatten)]
+    proxy: proxy::ProxyOption,
+
+    #[command(flatten)]
+    jobs: jobs::JobOption,
+
+    #[command(flatten)]
+    queue: queue::QueueOption,
+
+    #[command(flatten)]
+    webp: encode::WebPOptions,
+
+    #[command(flatten)]
+    jpeg: encode::JpegOptions,
+
+    #[command(flatten)]
+    load_image_option: LoadImageOption,
+
+    #[command(flatten)]
+    s3: s3::S3Option,
+
+    #[command(flatten)]
+    origin: http_origin::OriginOption,
+
+    #[command(flatten)]
+    sidecar: sidecar::SidecarOption,
+
+    #[command(flatten)]
+    cache: cache::CacheOption,
+
+    #[command(flatten)]
+    failure_cache: failure_cache::FailureCacheOption,
+
+    #[command(flatten)]
+    cache_control: cache_control::CacheControlOption,
+
+    /// Watch the base path and purge cached outputs of sources that are replaced or removed
+    #[arg(long)]
+    watch: bool,
+
+    #[command(flatten)]
+    admin: admin::AdminOption,
+
+    #[command(flatten)]
+    offload: offload::OffloadOption,
+
+    #[cfg(feature = "redis")]
+    #[command(flatten)]
+    redis_cache: redis_cache::RedisCacheOption,
+
+    /// Steps run by `POST /ingest/<filename>`; results are stored as sidecars
+    #[arg(long, value_enum, value_delimiter = ',')]
+    ingest_steps: Vec<ingest::IngestStep>,
+
+    /// Audit log sink: `file:PATH`, `syslog`, `syslog:HOST:PORT` or an http(s) URL
+    #[arg(long = "audit-log", value_name = "SINK")]
+    audit_sinks: Vec<audit::SinkSpec>,
+
+    #[cfg(feature = "wasm")]
+    #[command(flatten)]
+    wasm_plugin: wasm_plugin::WasmPluginOption,
+
+    #[cfg(feature = "pdf")]
+    #[command(flatten)]
+    pdf: pdf::PdfOption,
+}
+
+impl AppConfig {
+    fn thumbnail_encode_quality(&self) -> encode::EncodeQuality {
+        encode::EncodeQuality {
+            webp: self.thumbnail_quality,
+            webp_lossless: false,
+            webp_options: self.webp,
+            avif: self.avif_thumbnail_quality,
+            avif_speed: self.avif_speed,
+            jpeg: self.jpeg_thumbnail_quality,
+            jpeg_options: self.jpeg,
+            #[cfg(feature = "jxl")]
+            jxl_distance: self.jxl_distance,
+        }
+    }
+
+    /// `--lossless-max-colors` heuristic for switching WebP output to lossless.
+    fn is_lossless_candidate(&self, img: &image::DynamicImage) -> bool {
+        self.lossless_max_colors
+            .is_some_and(|max_colors| encode::is_low_color(img, max_colors))
+    }
+
+    fn media_encode_quality(&self) -> encode::EncodeQuality {
+        encode::EncodeQuality {
+            webp: self.media_quality,
+            webp_lossless: false,
+            webp_options: self.webp,
+            avif: self.avif_media_quality,
+            avif_speed: self.avif_speed,
+            jpeg: self.jpeg_media_quality,
+            jpeg_options: self.jpeg,
+            #[cfg(feature = "jxl")]
+            jxl_distance: self.jxl_distance,
+        }
+    }
+}
+
+#[derive(Parser)]
+struct LoadImageOption {
+    #[command(flatten)]
+    keyframe: movie_keyframe::KeyframeOption,
+
+    /// Extensions decoded with ffmpeg and thumbnailed from a keyframe. `ts` is left out by
+    /// default because TypeScript sources share it
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "mp4,webm,mov,mkv,avi,m4v,flv"
+    )]
+    movie_extensions: Vec<String>,
+
+    #[arg(
+        long,
+        default_value = "/usr/share/fonts/truetype/dejavu/DejaVuSansMono.ttf"
+    )]
+    text_preview_font: PathBuf,
+
+    #[command(flatten)]
+    waveform: audio::WaveformOption,
+
+    #[command(flatten)]
+    model: model::ModelOption,
+
+    /// Map an extension or MIME type to a loader: `KEY=LOADER[:PRIORITY]` (e.g. `mkv=movie`)
+    #[arg(long = "loader", value_name = "KEY=LOADER[:PRIORITY]")]
+    loaders: Vec<loader::LoaderMapping>,
+}
+
+impl LoadImageOption {
+    fn is_movie_ext(&self, ext: &str) -> bool {
+        self.movie_extensions
+            .iter()
+            .any(|movie| movie.eq_ignore_ascii_case(ext))
+    }
+}
+
+struct AppData {
+    /// 元ファイルのディレクトリ。リモートの置き場所ではそのコピーとサイドカーを置く
+    base_path: PathBuf,
+    store: Box<dyn media_store::MediaStore>,
+    config: AppConfig,
+    loaders: loader::LoaderRegistry,
+    audit: Option<audit::AuditLog>,
+    jobs: jobs::Jobs,
+    queue: Option<queue::Queue>,
+    cache: cache::LayeredCache,
+    coalescer: coalesce::Coalescer<(Bytes, OutputFormat)>,
+    failures: failure_cache::FailureCache,
+}
+
+fn build_loaders(config: &AppConfig) -> loader::LoaderRegistry {
+    let mut loaders = loader::LoaderRegistry::with_builtin();
+    loaders.register_movie_extensions(&config.load_image_option.movie_extensions);
+    #[cfg(feature = "wasm")]
+    wasm_plugin::register_plugins(&mut loaders, &config.wasm_plugin).expect("Invalid wasm plugin");
+    #[cfg(feature = "pdf")]
+    pdf::register_loader(&mut loaders, &config.pdf).expect("Failed to load pdfium");
+    for mapping in &config.load_image_option.loaders {
+        loaders.apply(mapping).expect("Invalid loader mapping");
+    }
+    loaders
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or("INFO"));
+
+    let args = Args::parse();
+    if let Some(Command::CacheGc(gc_args)) = &args.command {
+        return cache_gc::run(gc_args, &args.config.cache);
+    }
+    let loaders = build_loaders(&args.config);
+
+    if let Some(Command::Bench(bench_args)) = &args.command {
+        return bench::run(bench_args, &args.config, &loaders);
+    }
+
+    // サブコマンドがあると clap は必須の引数を確かめないので、ここで同じエラーにする
+    let Some(base_path) = args.base_path else {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "the following required arguments were not provided:\n  --base-path <BASE_PATH>",
+            )
+            .exit();
+    };
+    let base_path = base_path.canonicalize().expect("Invalid base path");
+    assert!(
+        args.config
+            .ingest_steps
+            .iter()
+            .all(|step| *step == ingest::IngestStep::Thumbnails)
+            || args.config.sidecar.is_enabled(),
+        "--ingest-steps blurhash, phash and probe require --sidecar-mode"
+    );
+    assert!(
+        !args.config.offload.is_enabled() || args.config.cache.cache_dir().is_some(),
+        "--offload requires --cache-dir"
+    );
+    let s3 = s3::S3Store::new(&args.config.s3, base_path.clone()).expect("Invalid --s3-bucket");
+    let origin = http_origin::HttpOrigin::new(&args.config.origin, base_path.clone());
+    let store: Box<dyn media_store::MediaStore> = match (s3, origin) {
+        (Some(s3), _) => Box::new(s3),
+        (None, Some(origin)) => Box::new(origin),
+        (None, None) => Box::new(media_store::LocalStore::new(base_path.clone())),
+    };
+    assert!(
+        !args.config.watch || store.name() == "local",
+        "--watch requires the sources under --base-path"
+    );
+    let audit = audit::AuditLog::start(&args.config.audit_sinks).expect("Invalid audit log sink");
+    #[cfg(feature = "redis")]
+    let redis = redis_cache::RedisCache::new(&args.config.redis_cache)
+        .expect("Invalid --redis-url")
+        .map(|redis| Box::new(redis) as Box<dyn cache::ThumbnailCache>);
+    #[cfg(not(feature = "redis"))]
+    let redis = None;
+    let cache = cache::LayeredCache::new(&args.config.cache, redis);
+    // ジョブの結果はキャッシュから返すので、メモリに入らない大きな出力は作っても返せない
+    if args.config.jobs.async_video
+        && cache
+            .layers()
+            .all(|layer| matches!(layer.name(), "memory" | "noop"))
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "--async-video requires a disk or Redis cache layer (--cache-dir or --redis-url)",
+        ));
+    }
+    let failures = failure_cache::FailureCache::new(&args.config.failure_cache);
+    let queue = queue::Queue::open(&args.config.queue).expect("Failed to open --queue-db");
+    // 最初のリクエストを待たずに開き、失敗を起動時に知らせる
+    args.config.load_image_option.keyframe.index();
+    let app_data = web::Data::new(AppData {
+        base_path,
+        store,
+        config: args.config,
+        loaders,
+        audit,
+        jobs: jobs::Jobs::default(),
+        queue,
+        cache,
+        coalescer: coalesce::Coalescer::default(),
+        failures,
+    });
+
+    if let Some(Command::Warmup(warmup_args)) = &args.command {
+        return warmup::run(warmup_args, &app_data);
+    }
+    queue::start_workers(&app_data);
+    let _watcher = app_data
+        .config
+        .watch
+        .then(|| watch::start(app_data.clone()).expect("Failed to watch the base path"));
 
     log::info!("Starting HTTP server at http://{}:{}", args.bind, args.port);
 
     HttpServer::new(move || {
         App::new()
             .wrap(Logger::default())
+            .wrap(middleware::from_fn(audit::middleware))
+            .wrap(middleware::from_fn(head::middleware))
             .app_data(app_data.clone())
             .service(thumbnail)
             .service(media)
+            .service(placeholder)
+            .service(thumbhash)
+            .service(preview)
+            .service(storyboard_sprite)
+            .service(contact_sheet_image)
+            .service(info)
+            .service(hls_file)
+            .service(transcode_proxy)
+            .service(video_clip)
             .service(original)
+            .service(ingest_file)
+            .service(metrics)
+            .service(purge_cache_key)
+            .service(purge_cache)
+            .service(cache_stats)
+            .service(keyframe_scores)
+            .service(queue_status)
+            .service(retry_dead_task)
+            .service(discard_dead_task)
+            .service(job_status)
+            .service(job_events)
     })
     .bind((args.bind.as_str(), args.port))?
     .run()
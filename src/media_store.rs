@@ -0,0 +1,290 @@
+//! 元ファイルの置き場所。
+//!
+//! 既定では `--base-path` 以下の `{先頭 2 文字}/{key}.{ext}` を読む。オブジェクトストレージなどの
+//! リモートから読むときも同じ構成のキーで探し、デコーダはシークするので `--base-path` に同じ構成で
+//! コピーしてから変換する。キーは内容のハッシュなので、コピーは大きさと更新時刻が同じ間は使い回す。
+//! コピーを残さない設定では、一時ディレクトリに使うたびにコピーし、使い終わったら消す。
+use crate::FileKey;
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// リモートの大きさと更新時刻を覚えておく時間。キーは内容のハッシュなので、消されたときだけ古くなる
+const METADATA_TTL: Duration = Duration::from_secs(60);
+/// 覚えておくキーの数。超えたら一度忘れる
+const METADATA_CAPACITY: usize = 65536;
+/// 同じ元ファイルを同時にコピーしないためのロックの数
+const STAGING_LOCKS: usize = 64;
+
+#[derive(Clone, Copy)]
+pub struct SourceMetadata {
+    pub len: u64,
+    pub modified: SystemTime,
+}
+
+/// Where the sources are read from.
+pub trait MediaStore: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Size and last modification of the source of `key`. `NotFound` when there is none.
+    fn metadata(&self, key: &FileKey) -> io::Result<SourceMetadata>;
+
+    /// Reads the source from `start`, `length` bytes of it or to the end.
+    fn open(
+        &self,
+        key: &FileKey,
+        start: u64,
+        length: Option<u64>,
+    ) -> io::Result<Box<dyn Read + Send>>;
+
+    /// A local file with the contents of the source, for decoders that need to seek.
+    fn local_path(&self, key: &FileKey) -> io::Result<LocalSource>;
+
+    /// Every source whose key starts with `prefix`.
+    fn keys(&self, prefix: &str) -> io::Result<Vec<FileKey>>;
+}
+
+/// A local file with the contents of a source. Temporary copies are removed when it is dropped.
+pub struct LocalSource {
+    path: PathBuf,
+    temporary: bool,
+}
+
+impl Deref for LocalSource {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for LocalSource {
+    fn drop(&mut self) {
+        if self.temporary {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Sources on a local or mounted file system.
+pub struct LocalStore {
+    base_path: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(base_path: PathBuf) -> Self {
+        LocalStore { base_path }
+    }
+}
+
+impl MediaStore for LocalStore {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    fn metadata(&self, key: &FileKey) -> io::Result<SourceMetadata> {
+        let metadata = std::fs::metadata(key.build_path(&self.base_path))?;
+        Ok(SourceMetadata {
+            len: metadata.len(),
+            modified: metadata.modified().unwrap_or(SystemTime::now()),
+        })
+    }
+
+    fn open(
+        &self,
+        key: &FileKey,
+        start: u64,
+        length: Option<u64>,
+    ) -> io::Result<Box<dyn Read + Send>> {
+        use std::io::{Seek, SeekFrom};
+        let mut file = std::fs::File::open(key.build_path(&self.base_path))?;
+        file.seek(SeekFrom::Start(start))?;
+        Ok(match length {
+            Some(length) => Box::new(file.take(length)),
+            None => Box::new(file),
+        })
+    }
+
+    fn local_path(&self, key: &FileKey) -> io::Result<LocalSource> {
+        Ok(LocalSource {
+            path: key.build_path(&self.base_path),
+            temporary: false,
+        })
+    }
+
+    fn keys(&self, prefix: &str) -> io::Result<Vec<FileKey>> {
+        let mut keys = Vec::new();
+        for shard in std::fs::read_dir(&self.base_path)? {
+            let shard = shard?;
+            let name = shard.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            if !may_hold(name, prefix) || !shard.file_type()?.is_dir() {
+                continue;
+            }
+            for file in std::fs::read_dir(shard.path())? {
+                let file = file?;
+                // サイドカーは拡張子に `.` を含むので除かれる
+                let Some(key) = file
+                    .file_name()
+                    .to_str()
+                    .and_then(|name| FileKey::parse(name).ok())
+                    .filter(|key| key.hkey.starts_with(prefix))
+                else {
+                    continue;
+                };
+                if file.file_type()?.is_file() {
+                    keys.push(key);
+                }
+            }
+        }
+        Ok(keys)
+    }
+}
+
+/// Whether the shard directory `name` can hold keys starting with `prefix`.
+pub fn may_hold(name: &str, prefix: &str) -> bool {
+    // 元ファイルはキーの先頭 2 文字のディレクトリにあるので、どちらかがもう一方で始まればよい
+    name.starts_with(prefix) || prefix.starts_with(name)
+}
+
+/// Remembers the metadata of remote sources for a while, so that cached outputs are served
+/// without a round trip to the origin.
+#[derive(Default)]
+pub struct MetadataCache {
+    entries: Mutex<HashMap<String, (Instant, SourceMetadata)>>,
+}
+
+impl MetadataCache {
+    pub fn get_or_fetch(
+        &self,
+        key: &FileKey,
+        fetch: impl FnOnce() -> io::Result<SourceMetadata>,
+    ) -> io::Result<SourceMetadata> {
+        let name = key.build_filename().to_string_lossy().into_owned();
+        if let Some((fetched, metadata)) = self.entries.lock().unwrap().get(&name) {
+            if fetched.elapsed() < METADATA_TTL {
+                return Ok(*metadata);
+            }
+        }
+        let metadata = fetch()?;
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= METADATA_CAPACITY {
+            entries.clear();
+        }
+        entries.insert(name, (Instant::now(), metadata));
+        Ok(metadata)
+    }
+}
+
+/// Local copies of remote sources.
+pub struct Staging {
+    /// コピーを残すならベースパス、残さないなら一時ディレクトリ
+    dir: PathBuf,
+    keep: bool,
+    locks: Vec<Mutex<()>>,
+    /// 残さないコピーの名前を重ならないようにする
+    serial: AtomicU64,
+}
+
+impl Staging {
+    /// Copies kept under `base_path`, laid out like a local store.
+    pub fn new(base_path: PathBuf) -> Self {
+        Staging {
+            dir: base_path,
+            keep: true,
+            locks: (0..STAGING_LOCKS).map(|_| Mutex::new(())).collect(),
+            serial: AtomicU64::new(0),
+        }
+    }
+
+    /// Copies made in `dir` for every use and removed afterwards.
+    pub fn temporary(dir: PathBuf) -> Self {
+        Staging {
+            keep: false,
+            ..Staging::new(dir)
+        }
+    }
+
+    /// A copy of `key` fetched with `open`. A kept copy is reused while it has the size and
+    /// modification time of `metadata`.
+    pub fn fetch(
+        &self,
+        key: &FileKey,
+        metadata: SourceMetadata,
+        open: impl FnOnce() -> io::Result<Box<dyn Read + Send>>,
+    ) -> io::Result<LocalSource> {
+        if !self.keep {
+            // 拡張子で形式を見るデコーダがあるので残す
+            let path = self.dir.join(format!(
+                "{}-{}-{}.{}",
+                key.hkey,
+                std::process::id(),
+                self.serial.fetch_add(1, Ordering::Relaxed),
+                key.ext
+            ));
+            std::fs::create_dir_all(&self.dir)?;
+            let source = LocalSource {
+                path,
+                temporary: true,
+            };
+            copy(open, &source, metadata)?;
+            return Ok(source);
+        }
+        let path = key.build_path(&self.dir);
+        let is_current = |path: &Path| {
+            std::fs::metadata(path).is_ok_and(|local| {
+                local.len() == metadata.len && local.modified().ok() == Some(metadata.modified)
+            })
+        };
+        let source = LocalSource {
+            path,
+            temporary: false,
+        };
+        if is_current(&source) {
+            return Ok(source);
+        }
+        let lock = u8::from_str_radix(&key.hkey[..2], 16).map_or(0, usize::from) % self.locks.len();
+        let _guard = self.locks[lock].lock().unwrap();
+        // 待っている間に他のリクエストがコピーしたかもしれない
+        if is_current(&source) {
+            return Ok(source);
+        }
+        if let Some(parent) = source.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // 書きかけのファイルをデコーダに読ませないよう rename で置き換える
+        let mut tmp_path = source.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = scopeguard::guard(PathBuf::from(tmp_path), |path| {
+            let _ = std::fs::remove_file(path);
+        });
+        copy(open, &tmp_path, metadata)?;
+        std::fs::rename(&*tmp_path, &*source)?;
+        Ok(source)
+    }
+}
+
+/// Writes the source to `path` with its modification time.
+fn copy(
+    open: impl FnOnce() -> io::Result<Box<dyn Read + Send>>,
+    path: &Path,
+    metadata: SourceMetadata,
+) -> io::Result<()> {
+    let mut file = std::fs::File::create(path)?;
+    let copied = io::copy(&mut open()?, &mut file)?;
+    if copied != metadata.len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("Fetched {} of {} bytes", copied, metadata.len),
+        ));
+    }
+    file.set_modified(metadata.modified)?;
+    log::debug!("Staged {} bytes at {}", copied, path.display());
+    Ok(())
+}
@@ -0,0 +1,331 @@
+//! 3D モデル (glTF/GLB, OBJ, STL) をソフトウェアラスタライズで 1 枚の静止画にする。
+//!
+//! テクスチャやマテリアルは使わず、斜め上から見た形状をフラットシェーディングで描く。
+use crate::color::HexColor;
+use clap::Parser;
+use image::imageops::FilterType;
+use image::{DynamicImage, Rgba, RgbaImage};
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+pub const MODEL_EXTENSIONS: &[&str] = &["gltf", "glb", "obj", "stl"];
+
+/// ジャギー対策に縦横この倍率で描いてから縮小する
+const SUPERSAMPLE: u32 = 2;
+
+/// ターンテーブルの角度 (度)
+const YAW: f32 = -35.0;
+const PITCH: f32 = 25.0;
+
+type Vec3 = [f32; 3];
+type Triangle = [Vec3; 3];
+
+#[derive(Parser)]
+pub struct ModelOption {
+    /// Size of the square image 3D models are rendered into
+    #[arg(long, default_value_t = 1024)]
+    model_render_size: u32,
+
+    #[arg(long, default_value = "#b4b4b4")]
+    model_color: HexColor,
+}
+
+pub fn load_image_from_model(
+    path: &Path,
+    option: &ModelOption,
+) -> Result<DynamicImage, anyhow::Error> {
+    let ext = path
+        .extension()
+        .and_then(OsStr::to_str)
+        .unwrap_or("")
+        .to_lowercase();
+    let triangles = match ext.as_str() {
+        "gltf" | "glb" => load_gltf(path)?,
+        "obj" => load_obj(path)?,
+        "stl" => load_stl(path)?,
+        _ => anyhow::bail!("Unknown model format: {}", ext),
+    };
+    anyhow::ensure!(!triangles.is_empty(), "No triangles in model");
+    Ok(DynamicImage::ImageRgba8(render(&triangles, option)))
+}
+
+fn load_stl(path: &Path) -> Result<Vec<Triangle>, anyhow::Error> {
+    let mesh = stl_io::read_stl(&mut BufReader::new(File::open(path)?))?;
+    Ok(mesh
+        .faces
+        .iter()
+        .map(|face| face.vertices.map(|i| mesh.vertices[i].into()))
+        .collect())
+}
+
+fn load_obj(path: &Path) -> Result<Vec<Triangle>, anyhow::Error> {
+    let options = tobj::LoadOptions {
+        triangulate: true,
+        ..Default::default()
+    };
+    // マテリアルは使わないので .mtl は読まない
+    let (models, _) = tobj::load_obj_buf(&mut BufReader::new(File::open(path)?), &options, |_| {
+        Err(tobj::LoadError::OpenFileFailed)
+    })?;
+
+    let mut triangles = Vec::new();
+    for model in models {
+        let positions = &model.mesh.positions;
+        let vertex = |i: u32| -> Option<Vec3> {
+            let i = i as usize * 3;
+            Some([
+                *positions.get(i)?,
+                *positions.get(i + 1)?,
+                *positions.get(i + 2)?,
+            ])
+        };
+        for face in model.mesh.indices.chunks_exact(3) {
+            if let (Some(a), Some(b), Some(c)) = (vertex(face[0]), vertex(face[1]), vertex(face[2]))
+            {
+                triangles.push([a, b, c]);
+            }
+        }
+    }
+    Ok(triangles)
+}
+
+fn load_gltf(path: &Path) -> Result<Vec<Triangle>, anyhow::Error> {
+    let gltf::Gltf { document, blob } = gltf::Gltf::open(path)?;
+    // 外部バッファはモデルと同じディレクトリ以下のものだけ許可する
+    for buffer in document.buffers() {
+        if let gltf::buffer::Source::Uri(uri) = buffer.source() {
+            anyhow::ensure!(
+                uri.starts_with("data:")
+                    || !(uri.contains("..") || uri.starts_with('/') || uri.contains(':')),
+                "Refusing external buffer {}",
+                uri
+            );
+        }
+    }
+    let buffers = gltf::import_buffers(&document, path.parent(), blob)?;
+
+    let scene = document
+        .default_scene()
+        .or_else(|| document.scenes().next());
+    let mut triangles = Vec::new();
+    match scene {
+        Some(scene) => {
+            for node in scene.nodes() {
+                collect_node(&node, IDENTITY, &buffers, &mut triangles);
+            }
+        }
+        // シーンが無ければメッシュをそのまま並べる
+        None => {
+            for mesh in document.meshes() {
+                collect_mesh(&mesh, IDENTITY, &buffers, &mut triangles);
+            }
+        }
+    }
+    Ok(triangles)
+}
+
+type Mat4 = [[f32; 4]; 4];
+
+const IDENTITY: Mat4 = [
+    [1.0, 0.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0, 0.0],
+    [0.0, 0.0, 1.0, 0.0],
+    [0.0, 0.0, 0.0, 1.0],
+];
+
+/// glTF の行列は列優先
+fn mul_mat(a: &Mat4, b: &Mat4) -> Mat4 {
+    let mut out = [[0.0; 4]; 4];
+    for (col, out_col) in out.iter_mut().enumerate() {
+        for (row, value) in out_col.iter_mut().enumerate() {
+            *value = (0..4).map(|k| a[k][row] * b[col][k]).sum();
+        }
+    }
+    out
+}
+
+fn transform_point(m: &Mat4, p: Vec3) -> Vec3 {
+    let mut out = [0.0; 3];
+    for (row, value) in out.iter_mut().enumerate() {
+        *value = m[0][row] * p[0] + m[1][row] * p[1] + m[2][row] * p[2] + m[3][row];
+    }
+    out
+}
+
+fn collect_node(
+    node: &gltf::Node,
+    parent: Mat4,
+    buffers: &[gltf::buffer::Data],
+    triangles: &mut Vec<Triangle>,
+) {
+    let transform = mul_mat(&parent, &node.transform().matrix());
+    if let Some(mesh) = node.mesh() {
+        collect_mesh(&mesh, transform, buffers, triangles);
+    }
+    for child in node.children() {
+        collect_node(&child, transform, buffers, triangles);
+    }
+}
+
+fn collect_mesh(
+    mesh: &gltf::Mesh,
+    transform: Mat4,
+    buffers: &[gltf::buffer::Data],
+    triangles: &mut Vec<Triangle>,
+) {
+    for primitive in mesh.primitives() {
+        if primitive.mode() != gltf::mesh::Mode::Triangles {
+            continue;
+        }
+        let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(|data| &data.0[..]));
+        let Some(positions) = reader.read_positions() else {
+            continue;
+        };
+        let positions: Vec<Vec3> = positions.map(|p| transform_point(&transform, p)).collect();
+        let indices: Vec<u32> = match reader.read_indices() {
+            Some(indices) => indices.into_u32().collect(),
+            None => (0..positions.len() as u32).collect(),
+        };
+        for face in indices.chunks_exact(3) {
+            let vertex = |i: u32| positions.get(i as usize).copied();
+            if let (Some(a), Some(b), Some(c)) = (vertex(face[0]), vertex(face[1]), vertex(face[2]))
+            {
+                triangles.push([a, b, c]);
+            }
+        }
+    }
+}
+
+fn sub(a: Vec3, b: Vec3) -> Vec3 {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn cross(a: Vec3, b: Vec3) -> Vec3 {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+fn dot(a: Vec3, b: Vec3) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn normalize(v: Vec3) -> Vec3 {
+    let len = dot(v, v).sqrt();
+    if len > 0.0 {
+        [v[0] / len, v[1] / len, v[2] / len]
+    } else {
+        v
+    }
+}
+
+/// バウンディングボックスの中心を原点に、yaw → pitch の順に回したビュー座標に変換する
+fn to_view(triangles: &[Triangle]) -> Vec<Triangle> {
+    let mut min = [f32::INFINITY; 3];
+    let mut max = [f32::NEG_INFINITY; 3];
+    for p in triangles.iter().flatten() {
+        for i in 0..3 {
+            min[i] = min[i].min(p[i]);
+            max[i] = max[i].max(p[i]);
+        }
+    }
+    let center = [
+        (min[0] + max[0]) / 2.0,
+        (min[1] + max[1]) / 2.0,
+        (min[2] + max[2]) / 2.0,
+    ];
+    let (sin_yaw, cos_yaw) = YAW.to_radians().sin_cos();
+    let (sin_pitch, cos_pitch) = PITCH.to_radians().sin_cos();
+    triangles
+        .iter()
+        .map(|triangle| {
+            triangle.map(|p| {
+                let [x, y, z] = sub(p, center);
+                let (x, z) = (x * cos_yaw + z * sin_yaw, -x * sin_yaw + z * cos_yaw);
+                let (y, z) = (y * cos_pitch - z * sin_pitch, y * sin_pitch + z * cos_pitch);
+                [x, y, z]
+            })
+        })
+        .collect()
+}
+
+fn render(triangles: &[Triangle], option: &ModelOption) -> RgbaImage {
+    let size = option.model_render_size.max(1) * SUPERSAMPLE;
+    let view = to_view(triangles);
+
+    // 正射影で、どの向きから見ても収まるように外接球の半径で合わせる
+    let radius = view
+        .iter()
+        .flatten()
+        .map(|p| dot(*p, *p))
+        .fold(0.0f32, f32::max)
+        .sqrt()
+        .max(f32::EPSILON);
+    let scale = size as f32 / 2.0 * 0.95 / radius;
+    let half = size as f32 / 2.0;
+    let light = normalize([-0.4, 0.6, 0.7]);
+    let [r, g, b] = option.model_color.0 .0;
+
+    let mut image = RgbaImage::new(size, size);
+    let mut depth = vec![f32::NEG_INFINITY; (size * size) as usize];
+    for triangle in &view {
+        let normal = normalize(cross(
+            sub(triangle[1], triangle[0]),
+            sub(triangle[2], triangle[0]),
+        ));
+        // STL/OBJ は面の向きが当てにならないので両面ライティング
+        let shade = 0.3 + 0.7 * dot(normal, light).abs();
+        let color = Rgba([
+            (r as f32 * shade) as u8,
+            (g as f32 * shade) as u8,
+            (b as f32 * shade) as u8,
+            255,
+        ]);
+        let screen = triangle.map(|p| [half + p[0] * scale, half - p[1] * scale, p[2]]);
+        rasterize(&screen, color, &mut image, &mut depth);
+    }
+
+    image::imageops::resize(
+        &image,
+        option.model_render_size.max(1),
+        option.model_render_size.max(1),
+        FilterType::Triangle,
+    )
+}
+
+fn rasterize(screen: &Triangle, color: Rgba<u8>, image: &mut RgbaImage, depth: &mut [f32]) {
+    let [a, b, c] = *screen;
+    let edge =
+        |p: Vec3, q: Vec3, x: f32, y: f32| (q[0] - p[0]) * (y - p[1]) - (q[1] - p[1]) * (x - p[0]);
+    let area = edge(a, b, c[0], c[1]);
+    if area.abs() < f32::EPSILON {
+        return;
+    }
+
+    let (width, height) = image.dimensions();
+    let min_x = a[0].min(b[0]).min(c[0]).floor().max(0.0) as u32;
+    let min_y = a[1].min(b[1]).min(c[1]).floor().max(0.0) as u32;
+    let max_x = (a[0].max(b[0]).max(c[0]).ceil() as u32).min(width - 1);
+    let max_y = (a[1].max(b[1]).max(c[1]).ceil() as u32).min(height - 1);
+    for y in min_y..=max_y {
+        for x in min_x..=max_x {
+            let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+            let w0 = edge(b, c, px, py) / area;
+            let w1 = edge(c, a, px, py) / area;
+            let w2 = edge(a, b, px, py) / area;
+            if w0 < 0.0 || w1 < 0.0 || w2 < 0.0 {
+                continue;
+            }
+            let z = w0 * a[2] + w1 * b[2] + w2 * c[2];
+            let index = (y * width + x) as usize;
+            if z > depth[index] {
+                depth[index] = z;
+                image.put_pixel(x, y, color);
+            }
+        }
+    }
+}
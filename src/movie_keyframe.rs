@@ -1,33 +1,366 @@
-use crate::statistics;
+use crate::frame_scorer::FrameScoring;
+use crate::jobs::{self, Stage};
+use crate::keyframe_index::{self, KeyframeIndex, ScoredFrame};
+use crate::sidecar;
+use crate::tonemap::{self, ToneMapOperator};
 use anyhow::{Context, Result};
+use clap::Parser;
 use ffmpeg::codec;
 use ffmpeg::format::input;
+use ffmpeg::format::Pixel;
 use ffmpeg::software::scaling::{context::Context as ScalingContext, flag::Flags};
+use ffmpeg::util::color::{Range, TransferCharacteristic};
 use ffmpeg::util::frame::video::Video as FfmpegFrame;
 use ffmpeg_next as ffmpeg;
-use image::{DynamicImage, GrayImage, ImageBuffer, Rgb};
+use image::{DynamicImage, GrayImage, ImageBuffer, Rgb, Rgb32FImage, Rgba};
 use scopeguard::guard;
-use std::path::Path;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
 
+/// スコア計算用に縮小するフレームの長辺。4K をそのまま走査すると遅い
+const SCORING_MAX_SIZE: u32 = 480;
+
+/// 色ヒストグラムの 1 チャンネルあたりのビン数
+const HISTOGRAM_BINS: usize = 8;
+
+#[derive(Parser)]
+pub struct KeyframeOption {
+    #[arg(short, long, default_value_t = 10)]
+    movie_max_keyframes: i32,
+
+    /// How keyframes are scored
+    #[arg(long, value_enum, default_value_t = FrameScoring::Heuristic)]
+    movie_frame_scoring: FrameScoring,
+
+    /// Take the first keyframe scoring at least this. Defaults to a value suited to
+    /// `--movie-frame-scoring` (1.0 for `heuristic`)
+    #[arg(short, long)]
+    movie_frame_score_threshold: Option<f32>,
+
+    /// Add this many times the brightness entropy (0 to 8 bits) to the score, so that
+    /// detailed but colorless footage (documents, snow) is not passed over (0 disables)
+    #[arg(long, default_value_t = 0.0)]
+    movie_entropy_weight: f32,
+
+    #[arg(short, long)]
+    movie_frame_sharpness_threshold: Option<f32>,
+
+    /// How much a cut from the previous candidate raises its score (0 disables), so that the
+    /// poster is not one of several near-identical opening keyframes
+    #[arg(long, default_value_t = 0.5)]
+    movie_scene_change_weight: f32,
+
+    /// Skip keyframes where at least this fraction of pixels share one narrow brightness band
+    /// (black leader, fades, logos on black). They are only used when nothing else is found
+    #[arg(long, default_value_t = 0.9)]
+    movie_flat_frame_ratio: f32,
+
+    /// Also skip keyframes that look like title cards or credits (text on a plain background)
+    #[arg(long)]
+    movie_skip_text_frames: bool,
+
+    /// Percentage of the runtime at the start (intro bumpers) that keyframes are not taken from
+    #[arg(long, default_value_t = 0.0)]
+    movie_skip_head_percent: f64,
+
+    /// Percentage of the runtime at the end (credits) that keyframes are not taken from
+    #[arg(long, default_value_t = 0.0)]
+    movie_skip_tail_percent: f64,
+
+    /// Crop letterbox/pillarbox black bars from video frames before thumbnailing
+    #[arg(long)]
+    movie_crop_black_bars: bool,
+
+    /// SQLite database of the keyframe scores of each video. Videos are then scanned to the end
+    /// once, and changing the thresholds picks again from the stored scores without decoding
+    #[arg(long)]
+    movie_keyframe_db: Option<PathBuf>,
+
+    /// 開いた `--movie-keyframe-db`。接続はワーカーで共有する
+    #[arg(skip)]
+    keyframe_index: OnceLock<Option<Mutex<KeyframeIndex>>>,
+
+    #[cfg(feature = "face")]
+    #[command(flatten)]
+    face: crate::face::FaceOption,
+}
+
+impl KeyframeOption {
+    /// `crop_black_bars` when `--movie-crop-black-bars` is on.
+    pub fn crop(&self, image: DynamicImage) -> DynamicImage {
+        if self.movie_crop_black_bars {
+            crop_black_bars(image)
+        } else {
+            image
+        }
+    }
+
+    /// Range in seconds keyframes are taken from, `None` when the whole video is used.
+    fn window(&self, duration: f64) -> Option<(f64, f64)> {
+        let head = self.movie_skip_head_percent.clamp(0.0, 100.0);
+        let tail = self.movie_skip_tail_percent.clamp(0.0, 100.0);
+        // 長さが分からなければ全体を使う
+        (duration > 0.0 && (head > 0.0 || tail > 0.0))
+            .then(|| (duration * head / 100.0, duration * (1.0 - tail / 100.0)))
+    }
+
+    /// `--movie-frame-score-threshold` or the default of the scoring, and
+    /// `--movie-frame-sharpness-threshold`.
+    pub fn thresholds(&self) -> (f32, Option<f32>) {
+        let score = self.movie_frame_score_threshold.unwrap_or_else(|| {
+            self.movie_frame_scoring
+                .scorer(self.movie_entropy_weight)
+                .default_threshold()
+        });
+        (score, self.movie_frame_sharpness_threshold)
+    }
+
+    /// Digest of the options that decide the scores and which keyframes are scanned. The
+    /// thresholds are left out as the keyframe index picks again with new ones.
+    pub fn scoring_digest(&self) -> String {
+        let options = format!(
+            "{} {:?} {} {} {} {} {} {}",
+            self.movie_max_keyframes,
+            self.movie_frame_scoring,
+            self.movie_entropy_weight,
+            self.movie_scene_change_weight,
+            self.movie_flat_frame_ratio,
+            self.movie_skip_text_frames,
+            self.movie_skip_head_percent,
+            self.movie_skip_tail_percent,
+        );
+        #[cfg(feature = "face")]
+        let options = format!("{} {:?}", options, self.face);
+        let digest = Sha256::digest(options.as_bytes());
+        digest[..8].iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// `--movie-keyframe-db`, opened on the first call and shared afterwards. Videos are
+    /// scored without it when it cannot be opened.
+    pub fn index(&self) -> Option<&Mutex<KeyframeIndex>> {
+        self.keyframe_index
+            .get_or_init(|| {
+                let path = self.movie_keyframe_db.as_ref()?;
+                KeyframeIndex::open(path)
+                    .inspect_err(|err| log::warn!("Failed to open {}: {}", path.display(), err))
+                    .ok()
+                    .map(Mutex::new)
+            })
+            .as_ref()
+    }
+
+    /// Digest of the options that decide which keyframe wins. Cropping is applied afterwards
+    /// and is left out.
+    fn digest(&self) -> String {
+        let options = format!(
+            "{} {:?} {} {:?} {:?} {} {} {} {} {}",
+            self.movie_max_keyframes,
+            self.movie_frame_scoring,
+            self.movie_entropy_weight,
+            self.movie_frame_score_threshold,
+            self.movie_frame_sharpness_threshold,
+            self.movie_scene_change_weight,
+            self.movie_flat_frame_ratio,
+            self.movie_skip_text_frames,
+            self.movie_skip_head_percent,
+            self.movie_skip_tail_percent,
+        );
+        #[cfg(feature = "face")]
+        let options = format!("{} {:?}", options, self.face);
+        let digest = Sha256::digest(options.as_bytes());
+        digest[..8].iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+/// キーフレームの選択結果。次のリクエストではスコア計算をせずにこの位置へシークする
+#[derive(Serialize, Deserialize)]
+struct ChosenKeyframe {
+    /// presentation timestamp in the time base of the video stream
+    pts: i64,
+    /// `KeyframeOption::digest` at the time of the choice
+    options: String,
+}
+
+impl ChosenKeyframe {
+    /// Saved timestamp, unless the video or the options changed since it was written.
+    fn read(memo: &Path, source: &Path, option: &KeyframeOption) -> Option<i64> {
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        if modified(memo)? < modified(source)? {
+            return None;
+        }
+        let chosen: ChosenKeyframe = serde_json::from_slice(&std::fs::read(memo).ok()?).ok()?;
+        (chosen.options == option.digest()).then_some(chosen.pts)
+    }
+}
+
+/// `?stream=N` does not point at a video stream.
+#[derive(Debug, thiserror::Error)]
+#[error("stream {0} is not a video stream")]
+pub struct NotVideoStream(pub usize);
+
+/// Video stream to take frames from: `index` when given, otherwise the main track.
+///
+/// `streams().best()` goes by ffmpeg's own heuristics and sometimes picks the thumbnail track
+/// of an MP4 or the wrong angle. Embedded cover art is never chosen; among streams that run
+/// for most of the longest one, the largest resolution wins, then the default disposition.
+pub fn select_video_stream(
+    ictx: &ffmpeg::format::context::Input,
+    index: Option<usize>,
+) -> Result<ffmpeg::format::stream::Stream<'_>> {
+    use ffmpeg::format::stream::Disposition;
+
+    let is_video = |stream: &ffmpeg::format::stream::Stream| {
+        stream.parameters().medium() == ffmpeg::media::Type::Video
+            && !stream.disposition().contains(Disposition::ATTACHED_PIC)
+    };
+    if let Some(index) = index {
+        return ictx
+            .streams()
+            .find(|stream| stream.index() == index && is_video(stream))
+            .ok_or_else(|| NotVideoStream(index).into());
+    }
+
+    let candidates: Vec<_> = ictx
+        .streams()
+        .filter(is_video)
+        .map(|stream| {
+            let duration = stream.duration() as f64 * f64::from(stream.time_base());
+            let area = codec::Context::from_parameters(stream.parameters())
+                .ok()
+                .and_then(|context| context.decoder().video().ok())
+                .map_or(0, |decoder| {
+                    decoder.width() as u64 * decoder.height() as u64
+                });
+            let default = stream.disposition().contains(Disposition::DEFAULT);
+            (stream, duration.max(0.0), area, default)
+        })
+        .collect();
+    // サムネイル用のトラックは 1 フレームだけなど短い。長さが分からないストリームは除かない
+    let longest = candidates
+        .iter()
+        .map(|(_, duration, _, _)| *duration)
+        .fold(0.0, f64::max);
+    candidates
+        .into_iter()
+        .filter(|(_, duration, _, _)| *duration == 0.0 || *duration >= longest / 2.0)
+        .max_by_key(|(_, _, area, default)| (*area, *default))
+        .map(|(stream, _, _, _)| stream)
+        .context("No video stream found")
+}
+
+/// Picks the most representative keyframe. With `memo`, the timestamp of the winner is saved
+/// to that file and later calls seek straight to it while the video and the scoring options
+/// stay the same. With `--movie-keyframe-db` and `hkey`, the key of the source, the scores are
+/// kept under that key.
 pub fn load_image_from_movie_keyframe(
     path: &Path,
-    max_keyframes: i32,
-    threshold_score: f32,
-    threshold_sharpness: Option<f32>,
+    option: &KeyframeOption,
+    stream: Option<usize>,
+    memo: Option<&Path>,
+    hkey: Option<&str>,
 ) -> Result<DynamicImage, anyhow::Error> {
+    let frame_at = |pts| {
+        VideoSource::open(path, None, stream)
+            .and_then(|mut source| source.frame_at_pts(pts))
+            .inspect_err(|err| log::debug!("{}: ignoring saved keyframe: {}", path.display(), err))
+            .ok()
+    };
+    if let Some(pts) = memo.and_then(|memo| ChosenKeyframe::read(memo, path, option)) {
+        if let Some(image) = frame_at(pts) {
+            return Ok(image);
+        }
+    }
+
+    let version = option.scoring_digest();
+    // 走査中はロックを持たない
+    let (image, pts) = match option.index().zip(hkey) {
+        Some((index, hkey)) => {
+            let stored = index
+                .lock()
+                .unwrap()
+                .frames(hkey, stream, &version)
+                .inspect_err(|err| log::warn!("Failed to read keyframe scores: {}", err))
+                .ok()
+                .flatten();
+            let (threshold_score, threshold_sharpness) = option.thresholds();
+            let reselected = stored
+                .and_then(|frames| {
+                    keyframe_index::select(&frames, threshold_score, threshold_sharpness)
+                })
+                .and_then(|pts| Some((frame_at(pts)?, pts)));
+            match reselected {
+                Some((image, pts)) => {
+                    let selected =
+                        index
+                            .lock()
+                            .unwrap()
+                            .set_selected(hkey, stream, &version, Some(pts));
+                    if let Err(err) = selected {
+                        log::warn!("Failed to save keyframe scores: {}", err);
+                    }
+                    (image, Some(pts))
+                }
+                None => {
+                    let (image, pts, frames) = score_keyframes(path, option, stream, true)?;
+                    let stored = index
+                        .lock()
+                        .unwrap()
+                        .store(hkey, stream, &version, &frames, pts);
+                    if let Err(err) = stored {
+                        log::warn!("Failed to save keyframe scores: {}", err);
+                    }
+                    (image, pts)
+                }
+            }
+        }
+        None => {
+            let (image, pts, _) = score_keyframes(path, option, stream, false)?;
+            (image, pts)
+        }
+    };
+    if let (Some(memo), Some(pts)) = (memo, pts) {
+        let chosen = ChosenKeyframe {
+            pts,
+            options: option.digest(),
+        };
+        if let Err(err) = sidecar::write(memo, &serde_json::to_vec(&chosen)?) {
+            log::warn!("Failed to save {}: {}", memo.display(), err);
+        }
+    }
+    Ok(image)
+}
+
+/// Runs the keyframe scoring and returns the winner with its timestamp. With `record`, every
+/// keyframe up to `--movie-max-keyframes` is scored and returned, even after one passes the
+/// thresholds.
+fn score_keyframes(
+    path: &Path,
+    option: &KeyframeOption,
+    stream: Option<usize>,
+    record: bool,
+) -> Result<(DynamicImage, Option<i64>, Vec<ScoredFrame>), anyhow::Error> {
+    let max_keyframes = option.movie_max_keyframes;
+    let scorer = option
+        .movie_frame_scoring
+        .scorer(option.movie_entropy_weight);
+    let (threshold_score, threshold_sharpness) = option.thresholds();
+    #[cfg(feature = "face")]
+    let mut face_detector = option.face.detector();
+
     ffmpeg::init().ok(); // Ignore re-init
 
     let mut ictx = input(&path)?;
-    let input = ictx
-        .streams()
-        .best(ffmpeg::media::Type::Video)
-        .context("No video stream found")?;
+    let input = select_video_stream(&ictx, stream)?;
     let video_stream_index = input.index();
+    let time_base = f64::from(input.time_base());
+    let rotation = display_rotation(&input);
+    let duration = (ictx.duration() as f64 / AV_TIME_BASE).max(0.0);
+    let window = option.window(duration);
 
-    let codec_params = input.parameters();
-    let context_decoder = codec::Context::from_parameters(codec_params)?;
-
-    let decoder_bare = context_decoder.decoder().video()?;
+    let (decoder_bare, format) = open_decoder(&input)?;
+    let hdr = HdrTransfer::detect(&decoder_bare);
     let mut decoder = guard(decoder_bare, |mut decoder| {
         log::debug!("{}: flush remaining packets", path.display());
         decoder.send_eof().unwrap_or_else(|err| {
@@ -35,20 +368,40 @@ pub fn load_image_from_movie_keyframe(
         })
     });
 
+    // スコアは縮小したフレームで計算し、選ばれたフレームだけ元の解像度で変換する
+    let (score_width, score_height) =
+        fit_within(decoder.width(), decoder.height(), SCORING_MAX_SIZE);
     let mut scaler = ScalingContext::get(
-        decoder.format(),
-        decoder.width(),
-        decoder.height(),
-        ffmpeg::format::Pixel::RGB24,
+        format,
         decoder.width(),
         decoder.height(),
+        Pixel::RGB24,
+        score_width,
+        score_height,
         Flags::BILINEAR,
     )?;
 
-    let mut best_frame: Option<DynamicImage> = None;
+    let mut best_frame: Option<FfmpegFrame> = None;
     let mut best_score = -1.0_f32;
+    let mut previous_histogram: Option<Vec<f32>> = None;
+    // しきい値を超えた最初のフレーム。`record` でなければ見つかったところで返す
+    let mut chosen: Option<FfmpegFrame> = None;
+    let mut frames = Vec::new();
+    let skipped = |decoded: &FfmpegFrame, seconds: f64, reason: &str| ScoredFrame {
+        pts: decoded.timestamp(),
+        seconds,
+        score: None,
+        sharpness: None,
+        skipped: Some(reason.to_string()),
+    };
+
+    if let Some((start, _)) = window {
+        let position = (start * AV_TIME_BASE) as i64;
+        ictx.seek(position, ..=position)?;
+    }
 
     let mut frame_index = 0;
+    let mut past_window = false;
 
     for (stream, packet) in ictx.packets() {
         if stream.index() != video_stream_index {
@@ -60,38 +413,111 @@ pub fn load_image_from_movie_keyframe(
         let mut decoded = FfmpegFrame::empty();
         while decoder.receive_frame(&mut decoded).is_ok() {
             if decoded.is_key() {
+                let seconds = decoded.timestamp().unwrap_or(0) as f64 * time_base;
+                jobs::report(
+                    Stage::Scanning,
+                    Some(frame_index as u64),
+                    Some((seconds, duration)),
+                );
+                if let Some((start, end)) = window {
+                    // 範囲外のキーフレームは他に候補がなかったときだけ使う
+                    if record && (seconds < start || seconds > end) {
+                        frames.push(skipped(&decoded, seconds, "outside"));
+                    }
+                    if (seconds < start || seconds > end) && best_frame.is_none() {
+                        best_frame = Some(std::mem::replace(&mut decoded, FfmpegFrame::empty()));
+                    }
+                    if seconds > end {
+                        past_window = true;
+                        break;
+                    }
+                    if seconds < start {
+                        continue;
+                    }
+                }
                 let mut rgb_frame = FfmpegFrame::empty();
                 scaler.run(&decoded, &mut rgb_frame)?;
 
                 let image = frame_to_dynamic_image(&rgb_frame)?;
-                let score = compute_frame_score(&image);
+                let rejected = if is_flat_frame(&image, option.movie_flat_frame_ratio) {
+                    Some("flat")
+                } else if option.movie_skip_text_frames && is_text_frame(&image) {
+                    Some("text")
+                } else {
+                    None
+                };
+                if let Some(reason) = rejected {
+                    log::debug!("{}[{}]: Skip {} frame", path.display(), frame_index, reason);
+                    if record {
+                        frames.push(skipped(&decoded, seconds, reason));
+                    }
+                    // 他に候補がなかったときのために、どのスコアよりも低い扱いで残す
+                    if best_frame.is_none() {
+                        best_frame = Some(std::mem::replace(&mut decoded, FfmpegFrame::empty()));
+                    }
+                    frame_index += 1;
+                    if frame_index >= max_keyframes {
+                        break;
+                    }
+                    continue;
+                }
+                // 直前の候補から場面が変わっていれば加点する。最初の候補は比べる相手がない
+                let histogram = color_histogram(&image);
+                let scene_change = previous_histogram
+                    .as_ref()
+                    .map_or(0.0, |previous| histogram_distance(previous, &histogram));
+                previous_histogram = Some(histogram);
+                let score =
+                    scorer.score(&image) * (1.0 + option.movie_scene_change_weight * scene_change);
+                // 顔が写っていれば加点する
+                #[cfg(feature = "face")]
+                let score = match face_detector.as_mut() {
+                    Some(detector) => score * detector.boost(&image),
+                    None => score,
+                };
                 log::debug!(
-                    "{}[{}]: Frame score: {}",
+                    "{}[{}]: Frame score: {} (scene change: {})",
                     path.display(),
                     frame_index,
-                    score
+                    score,
+                    scene_change
                 );
 
-                if score >= threshold_score {
-                    if let Some(threshold) = threshold_sharpness {
-                        let sharpness = compute_frame_sharpness(&image) as f32;
-                        log::debug!(
-                            "{}[{}]: Frame sharpness: {}",
-                            path.display(),
-                            frame_index,
-                            sharpness
-                        );
-                        if sharpness >= threshold {
-                            return Ok(image);
-                        }
-                    } else {
-                        return Ok(image);
-                    }
+                // 索引に残す場合は、後でしきい値を変えても選び直せるよう全部のフレームで測る
+                let sharpness = (record
+                    || (threshold_sharpness.is_some() && score >= threshold_score))
+                    .then(|| scorer.sharpness(&image) as f32);
+                if let Some(sharpness) = sharpness {
+                    log::debug!(
+                        "{}[{}]: Frame sharpness: {}",
+                        path.display(),
+                        frame_index,
+                        sharpness
+                    );
+                }
+                if record {
+                    frames.push(ScoredFrame {
+                        pts: decoded.timestamp(),
+                        seconds,
+                        score: Some(score),
+                        sharpness,
+                        skipped: None,
+                    });
                 }
+                let passes = score >= threshold_score
+                    && threshold_sharpness
+                        .is_none_or(|threshold| sharpness.is_some_and(|s| s >= threshold));
 
-                if score > best_score {
+                if passes && chosen.is_none() {
+                    if !record {
+                        let image = full_size_image(&decoded, rotation, hdr)?;
+                        return Ok((image, decoded.timestamp(), frames));
+                    }
+                    chosen = Some(std::mem::replace(&mut decoded, FfmpegFrame::empty()));
+                } else if score > best_score {
                     best_score = score;
-                    best_frame = Some(image);
+                    // 次の receive_frame で上書きされないよう取り出しておく
+                    best_frame = Some(std::mem::replace(&mut decoded, FfmpegFrame::empty()));
                 }
 
                 frame_index += 1;
@@ -101,70 +527,546 @@ pub fn load_image_from_movie_keyframe(
             }
         }
 
-        if frame_index >= max_keyframes {
+        if frame_index >= max_keyframes || past_window {
             break;
         }
     }
 
-    best_frame.ok_or_else(|| anyhow::anyhow!("No suitable frame found"))
+    let best_frame = chosen
+        .or(best_frame)
+        .ok_or_else(|| anyhow::anyhow!("No suitable frame found"))?;
+    let image = full_size_image(&best_frame, rotation, hdr)?;
+    Ok((image, best_frame.timestamp(), frames))
+}
+
+/// デコードしたフレームを元の解像度のまま RGB にして、表示する向きに回す
+fn full_size_image(
+    frame: &FfmpegFrame,
+    rotation: Option<i32>,
+    hdr: Option<HdrTransfer>,
+) -> Result<DynamicImage, anyhow::Error> {
+    let size = (frame.width(), frame.height());
+    let mut scaler = rgb_scaler(frame.format(), size, size, frame.color_range(), hdr)?;
+    let mut rgb_frame = FfmpegFrame::empty();
+    scaler.run(frame, &mut rgb_frame)?;
+    Ok(rotate(rgb_frame_to_image(&rgb_frame, hdr)?, rotation))
 }
 
+/// SDR の基準白 (BT.2408)。HDR のリニアな値はこれを 1.0 とする
+const SDR_WHITE_NITS: f32 = 203.0;
+/// HLG の OOTF で想定するディスプレイのピーク輝度と system gamma
+const HLG_PEAK_NITS: f32 = 1000.0;
+const HLG_SYSTEM_GAMMA: f32 = 1.2;
+
+/// BT.2020 → BT.709 の原色変換 (リニアな値に掛ける)
+const BT2020_TO_BT709: [[f32; 3]; 3] = [
+    [1.6605, -0.5876, -0.0728],
+    [-0.1246, 1.1329, -0.0083],
+    [-0.0182, -0.1006, 1.1187],
+];
+
+/// HDR10 (PQ) と HLG。どちらも BT.2020 の原色で符号化されている
+#[derive(Clone, Copy, Debug)]
+enum HdrTransfer {
+    Pq,
+    Hlg,
+}
+
+impl HdrTransfer {
+    fn detect(decoder: &ffmpeg::decoder::Video) -> Option<Self> {
+        match decoder.color_transfer_characteristic() {
+            TransferCharacteristic::SMPTE2084 => Some(HdrTransfer::Pq),
+            TransferCharacteristic::ARIB_STD_B67 => Some(HdrTransfer::Hlg),
+            _ => None,
+        }
+    }
+
+    /// Non-linear signal (0-1) to display light where 1.0 is SDR reference white.
+    fn to_linear(self, rgb: [f32; 3]) -> [f32; 3] {
+        match self {
+            HdrTransfer::Pq => rgb.map(|e| pq_eotf(e) / SDR_WHITE_NITS),
+            HdrTransfer::Hlg => {
+                let scene = rgb.map(hlg_inverse_oetf);
+                let luminance = 0.2627 * scene[0] + 0.6780 * scene[1] + 0.0593 * scene[2];
+                let gain = HLG_PEAK_NITS * luminance.max(0.0).powf(HLG_SYSTEM_GAMMA - 1.0)
+                    / SDR_WHITE_NITS;
+                scene.map(|e| e * gain)
+            }
+        }
+    }
+}
+
+/// SMPTE ST 2084 の EOTF。戻り値は nit
+fn pq_eotf(e: f32) -> f32 {
+    const M1: f32 = 2610.0 / 16384.0;
+    const M2: f32 = 2523.0 / 4096.0 * 128.0;
+    const C1: f32 = 3424.0 / 4096.0;
+    const C2: f32 = 2413.0 / 4096.0 * 32.0;
+    const C3: f32 = 2392.0 / 4096.0 * 32.0;
+    let p = e.clamp(0.0, 1.0).powf(1.0 / M2);
+    ((p - C1).max(0.0) / (C2 - C3 * p)).powf(1.0 / M1) * 10000.0
+}
+
+/// ARIB STD-B67 の OETF の逆。シーンのリニアな光 (0-1) を返す
+fn hlg_inverse_oetf(e: f32) -> f32 {
+    const A: f32 = 0.178_832_77;
+    const B: f32 = 0.284_668_92;
+    const C: f32 = 0.559_910_7;
+    let e = e.clamp(0.0, 1.0);
+    if e <= 0.5 {
+        e * e / 3.0
+    } else {
+        (((e - C) / A).exp() + B) / 12.0
+    }
+}
+
+/// Opens the decoder of a video stream and returns it with the pixel format of its frames.
+pub fn open_decoder(
+    stream: &ffmpeg::format::stream::Stream,
+) -> Result<(ffmpeg::decoder::Video, Pixel), anyhow::Error> {
+    let context = codec::Context::from_parameters(stream.parameters())?;
+    // VP9 の透過は Matroska の BlockAdditional に別に入っていて、内蔵デコーダは読まない。
+    // libvpx でデコードすると YUVA420P になる
+    let has_vp9_alpha = stream.parameters().id() == codec::Id::VP9
+        && stream.metadata().get("alpha_mode") == Some("1");
+    if let Some(libvpx) = has_vp9_alpha
+        .then(|| ffmpeg::decoder::find_by_name("libvpx-vp9"))
+        .flatten()
+    {
+        let decoder = context.decoder().open_as(libvpx)?.video()?;
+        return Ok((decoder, Pixel::YUVA420P));
+    }
+    let decoder = context.decoder().video()?;
+    let format = decoder.format();
+    Ok((decoder, format))
+}
+
+/// YUVA, RGBA, ProRes 4444 (YUVA444P10) など。成分数 2 はグレーと透過
+fn has_alpha(format: Pixel) -> bool {
+    format
+        .descriptor()
+        .is_some_and(|descriptor| matches!(descriptor.nb_components(), 2 | 4))
+}
+
+/// Scaler from decoded frames to RGB, or RGBA when `format` has alpha. HDR goes to 16-bit RGB
+/// with the BT.2020 matrix, which swscale does not pick by itself.
+fn rgb_scaler(
+    format: Pixel,
+    (src_width, src_height): (u32, u32),
+    (width, height): (u32, u32),
+    range: Range,
+    hdr: Option<HdrTransfer>,
+) -> Result<ScalingContext, anyhow::Error> {
+    let output = if hdr.is_some() {
+        Pixel::RGB48LE
+    } else if has_alpha(format) {
+        Pixel::RGBA
+    } else {
+        Pixel::RGB24
+    };
+    let mut scaler = ScalingContext::get(
+        format,
+        src_width,
+        src_height,
+        output,
+        width,
+        height,
+        Flags::BILINEAR,
+    )?;
+    if hdr.is_some() {
+        let full_range = i32::from(range == Range::JPEG);
+        // ffmpeg-next に色空間を指定する API が無い
+        unsafe {
+            let coefficients = ffmpeg::ffi::sws_getCoefficients(ffmpeg::ffi::SWS_CS_BT2020 as i32);
+            ffmpeg::ffi::sws_setColorspaceDetails(
+                scaler.as_mut_ptr(),
+                coefficients,
+                full_range,
+                coefficients,
+                1,
+                0,
+                1 << 16,
+                1 << 16,
+            );
+        }
+    }
+    Ok(scaler)
+}
+
+/// `rgb_scaler` の出力を画像にする。HDR は SDR の白を 1.0 とするリニアな BT.709 の浮動小数点
+/// 画像にして、トーンマッピングは `tonemap` に任せる
+fn rgb_frame_to_image(
+    frame: &FfmpegFrame,
+    hdr: Option<HdrTransfer>,
+) -> Result<DynamicImage, anyhow::Error> {
+    let Some(transfer) = hdr else {
+        return frame_to_dynamic_image(frame);
+    };
+    let (width, height) = (frame.width(), frame.height());
+    let data = frame.data(0);
+    let stride = frame.stride(0);
+    let mut buf = Vec::with_capacity((width * height * 3) as usize);
+    for y in 0..height as usize {
+        let row = &data[y * stride..y * stride + width as usize * 6];
+        for pixel in row.chunks_exact(6) {
+            let rgb = [0, 2, 4]
+                .map(|i| f32::from(u16::from_le_bytes([pixel[i], pixel[i + 1]])) / 65535.0);
+            let linear = transfer.to_linear(rgb);
+            buf.extend(
+                BT2020_TO_BT709
+                    .map(|m| (m[0] * linear[0] + m[1] * linear[1] + m[2] * linear[2]).max(0.0)),
+            );
+        }
+    }
+    let image = Rgb32FImage::from_raw(width, height, buf)
+        .ok_or_else(|| anyhow::anyhow!("Failed to build ImageBuffer"))?;
+    Ok(DynamicImage::ImageRgb32F(image))
+}
+
+/// `Input::duration` とシークの位置の単位
+pub const AV_TIME_BASE: f64 = 1_000_000.0;
+
+/// Returns the first frame shown at or after `seconds`, or the last frame when the video is
+/// shorter than that.
+pub fn load_image_at_timestamp(
+    path: &Path,
+    seconds: f64,
+    stream: Option<usize>,
+) -> Result<DynamicImage, anyhow::Error> {
+    VideoSource::open(path, None, stream)?.frame_at(seconds)
+}
+
+/// `count` frames evenly spaced over the runtime with their timestamps, the longer side
+/// scaled down to `max_size`. HDR frames are tone mapped with `tone_map`.
+pub fn sample_frames(
+    path: &Path,
+    count: usize,
+    max_size: u32,
+    tone_map: ToneMapOperator,
+) -> Result<Vec<(f64, DynamicImage)>, anyhow::Error> {
+    let mut source = VideoSource::open(path, Some(max_size), None)?;
+    let duration = source.duration();
+    anyhow::ensure!(duration > 0.0, "Unknown duration");
+    (0..count)
+        .map(|i| {
+            // 各区間の中央を取って先頭の黒味と末尾を避ける
+            let seconds = duration * (i as f64 + 0.5) / count as f64;
+            let frame = source.frame_at(seconds)?;
+            Ok((seconds, tonemap::tone_map(frame, tone_map)))
+        })
+        .collect()
+}
+
+/// Clockwise degrees (0, 90, 180 or 270) the frames must be rotated for display, from the
+/// display matrix side data or the legacy `rotate` tag.
+pub fn display_rotation(stream: &ffmpeg::format::stream::Stream) -> Option<i32> {
+    let from_matrix = stream
+        .side_data()
+        .find(|side_data| side_data.kind() == ffmpeg::codec::packet::side_data::Type::DisplayMatrix)
+        .and_then(|side_data| {
+            // 3x3 の行列。先頭 2x2 が 16.16 固定小数点の回転成分。av_display_rotation_get は
+            // 反時計回りの角度を返すので、符号を反転せずに時計回りの角度（`rotate` タグと同じ向き）にする
+            let m: Vec<f64> = side_data
+                .data()
+                .chunks_exact(4)
+                .take(5)
+                .map(|b| f64::from(i32::from_ne_bytes([b[0], b[1], b[2], b[3]])) / 65536.0)
+                .collect();
+            let [m0, m1, _, m3, m4] = m[..] else {
+                return None;
+            };
+            let (scale0, scale1) = (m0.hypot(m3), m1.hypot(m4));
+            (scale0 > 0.0 && scale1 > 0.0).then(|| (m1 / scale1).atan2(m0 / scale0).to_degrees())
+        });
+    let degrees = from_matrix.or_else(|| stream.metadata().get("rotate")?.parse().ok())?;
+    // 90 度単位に丸める
+    let rotation = ((degrees / 90.0).round() as i32 * 90).rem_euclid(360);
+    (rotation != 0).then_some(rotation)
+}
+
+/// Rotates a decoded frame clockwise by a `display_rotation` angle.
+pub fn rotate(image: DynamicImage, rotation: Option<i32>) -> DynamicImage {
+    match rotation {
+        Some(90) => image.rotate90(),
+        Some(180) => image.rotate180(),
+        Some(270) => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// アスペクト比を保って長辺を `max_size` 以下にする。YUV 4:2:0 に収まるよう偶数に揃える
+pub fn fit_within(width: u32, height: u32, max_size: u32) -> (u32, u32) {
+    let scale = (f64::from(max_size) / f64::from(width.max(height))).min(1.0);
+    let even = |v: u32| ((f64::from(v) * scale) as u32 & !1).max(2);
+    (even(width), even(height))
+}
+
+/// 動画ストリームを開いたまま任意の時刻のフレームを取り出す
+pub struct VideoSource {
+    ictx: ffmpeg::format::context::Input,
+    stream_index: usize,
+    /// seconds per stream timestamp unit
+    time_base: f64,
+    decoder: ffmpeg::decoder::Video,
+    scaler: ScalingContext,
+    /// size of the decoded frames before rotation
+    size: (u32, u32),
+    rotation: Option<i32>,
+    hdr: Option<HdrTransfer>,
+}
+
+impl VideoSource {
+    /// `max_size` bounds the longer side of the returned frames. `stream` overrides the
+    /// automatic choice of the video stream.
+    pub fn open(
+        path: &Path,
+        max_size: Option<u32>,
+        stream: Option<usize>,
+    ) -> Result<Self, anyhow::Error> {
+        ffmpeg::init().ok(); // Ignore re-init
+
+        let ictx = input(&path)?;
+        let stream = select_video_stream(&ictx, stream)?;
+        let stream_index = stream.index();
+        let time_base = f64::from(stream.time_base());
+        let rotation = display_rotation(&stream);
+        let (decoder, format) = open_decoder(&stream)?;
+
+        let (width, height) = match max_size {
+            Some(max_size) => fit_within(decoder.width(), decoder.height(), max_size),
+            None => (decoder.width(), decoder.height()),
+        };
+        let hdr = HdrTransfer::detect(&decoder);
+        let scaler = rgb_scaler(
+            format,
+            (decoder.width(), decoder.height()),
+            (width, height),
+            decoder.color_range(),
+            hdr,
+        )?;
+        Ok(VideoSource {
+            ictx,
+            stream_index,
+            time_base,
+            decoder,
+            scaler,
+            size: (width, height),
+            rotation,
+            hdr,
+        })
+    }
+
+    /// Size of the frames returned by `frame_at`, after rotation.
+    pub fn size(&self) -> (u32, u32) {
+        match self.rotation {
+            Some(90 | 270) => (self.size.1, self.size.0),
+            _ => self.size,
+        }
+    }
+
+    /// Seconds, or 0 when the container does not know.
+    pub fn duration(&self) -> f64 {
+        (self.ictx.duration() as f64 / AV_TIME_BASE).max(0.0)
+    }
+
+    /// Returns the first frame shown at or after `seconds`, or the last frame when the video
+    /// is shorter than that. HDR frames are linear `Rgb32F` to be tone mapped by the caller.
+    pub fn frame_at(&mut self, seconds: f64) -> Result<DynamicImage, anyhow::Error> {
+        // 直前のキーフレームに戻ってから目的の時刻までデコードする
+        let position = (seconds * AV_TIME_BASE) as i64;
+        self.ictx.seek(position, ..=position)?;
+        self.decoder.flush();
+
+        let mut last = None;
+        let mut found = false;
+        for (stream, packet) in self.ictx.packets() {
+            if stream.index() != self.stream_index {
+                continue;
+            }
+            self.decoder.send_packet(&packet)?;
+            found = receive_until(
+                &mut self.decoder,
+                &mut self.scaler,
+                self.time_base,
+                seconds,
+                &mut last,
+            )?;
+            if found {
+                break;
+            }
+        }
+        if !found {
+            // 末尾より後を指定された場合はデコーダに残ったフレームの最後を使う
+            self.decoder.send_eof()?;
+            receive_until(
+                &mut self.decoder,
+                &mut self.scaler,
+                self.time_base,
+                seconds,
+                &mut last,
+            )?;
+        }
+        let frame = last.ok_or_else(|| anyhow::anyhow!("No frame at {}s", seconds))?;
+        Ok(rotate(rgb_frame_to_image(&frame, self.hdr)?, self.rotation))
+    }
+
+    /// `frame_at` with a timestamp in the time base of the stream, as saved for a chosen keyframe.
+    fn frame_at_pts(&mut self, pts: i64) -> Result<DynamicImage, anyhow::Error> {
+        self.frame_at(pts as f64 * self.time_base)
+    }
+}
+
+/// `seconds` 以降のフレームが出たら `true`。変換したフレームは `last` に残す
+fn receive_until(
+    decoder: &mut ffmpeg::decoder::Video,
+    scaler: &mut ScalingContext,
+    time_base: f64,
+    seconds: f64,
+    last: &mut Option<FfmpegFrame>,
+) -> Result<bool, anyhow::Error> {
+    let mut decoded = FfmpegFrame::empty();
+    while decoder.receive_frame(&mut decoded).is_ok() {
+        let mut rgb_frame = FfmpegFrame::empty();
+        scaler.run(&decoded, &mut rgb_frame)?;
+        *last = Some(rgb_frame);
+        if decoded.timestamp().unwrap_or(0) as f64 * time_base >= seconds {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// RGB24 か RGBA のフレームを画像にする
 fn frame_to_dynamic_image(frame: &FfmpegFrame) -> Result<DynamicImage, anyhow::Error> {
     let width = frame.width();
     let height = frame.height();
     let data = frame.data(0);
     let stride = frame.stride(0);
+    let channels = if frame.format() == Pixel::RGBA { 4 } else { 3 };
 
-    let mut buf = Vec::with_capacity((width * height * 3) as usize);
+    let mut buf = Vec::with_capacity((width * height) as usize * channels);
     for y in 0..height {
         let offset = (y as usize) * stride;
-        buf.extend_from_slice(&data[offset..offset + (width as usize * 3)]);
+        buf.extend_from_slice(&data[offset..offset + (width as usize * channels)]);
     }
 
+    if channels == 4 {
+        let image = ImageBuffer::<Rgba<u8>, _>::from_raw(width, height, buf)
+            .ok_or_else(|| anyhow::anyhow!("Failed to build ImageBuffer"))?;
+        return Ok(DynamicImage::ImageRgba8(image));
+    }
     let image = ImageBuffer::<Rgb<u8>, _>::from_raw(width, height, buf)
         .ok_or_else(|| anyhow::anyhow!("Failed to build ImageBuffer"))?;
 
     Ok(DynamicImage::ImageRgb8(image))
 }
 
-fn compute_frame_score(image: &DynamicImage) -> f32 {
-    let rgb = image.to_rgb8();
-    let mut brightness_stats = statistics::OnlineStats::new();
-    let mut saturation_stats = statistics::OnlineStats::new();
-
-    for pixel in rgb.pixels() {
-        let [r, g, b] = pixel.0;
+/// 文字カードとみなす背景の割合と、文字の輪郭 (隣の画素との急な明るさの差) の密度
+const TEXT_BACKGROUND_RATIO: f32 = 0.6;
+const TEXT_EDGE_DENSITY: f32 = 0.02;
+const TEXT_EDGE_CONTRAST: u8 = 96;
 
-        // 明度 (Luma: Y)
-        // TODO: HSV の V で良い説
-        let luma = 0.299 * r as f64 + 0.587 * g as f64 + 0.114 * b as f64;
-        brightness_stats.update(luma);
+fn is_flat_frame(image: &DynamicImage, ratio: f32) -> bool {
+    dominant_band_ratio(&image.to_luma8()) >= ratio
+}
 
-        // 彩度 (HSV の S)
-        let rf = r as f64 / 255.0;
-        let gf = g as f64 / 255.0;
-        let bf = b as f64 / 255.0;
-        let max = rf.max(gf).max(bf);
-        let min = rf.min(gf).min(bf);
-        let saturation = if max == 0.0 { 0.0 } else { (max - min) / max };
-        saturation_stats.update(saturation);
+/// 字幕カードやエンドロール: 背景がほぼ一色で、その上に細かく高コントラストな輪郭が多い
+fn is_text_frame(image: &DynamicImage) -> bool {
+    let gray = image.to_luma8();
+    if dominant_band_ratio(&gray) < TEXT_BACKGROUND_RATIO {
+        return false;
     }
+    let edges = gray
+        .as_raw()
+        .chunks_exact(gray.width().max(1) as usize)
+        .flat_map(|row| row.windows(2))
+        .filter(|pair| pair[0].abs_diff(pair[1]) >= TEXT_EDGE_CONTRAST)
+        .count();
+    let total = (gray.width() * gray.height()).max(1) as f32;
+    edges as f32 / total >= TEXT_EDGE_DENSITY
+}
 
-    let brightness_penalty = 1.0 - ((brightness_stats.mean() - 128.0).abs() / 128.0);
-
-    (brightness_stats.stddev() * saturation_stats.mean() * brightness_penalty) as f32
+/// 明度を 8 段階ずつに分け、隣り合う 2 区間 (幅 16) に収まる画素の割合の最大値
+fn dominant_band_ratio(gray: &GrayImage) -> f32 {
+    let mut histogram = [0_u32; 32];
+    for pixel in gray.pixels() {
+        histogram[usize::from(pixel[0] >> 3)] += 1;
+    }
+    let total = (gray.width() * gray.height()).max(1) as f32;
+    let dominant = histogram
+        .windows(2)
+        .map(|pair| pair[0] + pair[1])
+        .max()
+        .unwrap_or(0);
+    dominant as f32 / total
 }
 
-fn compute_frame_sharpness(image: &DynamicImage) -> f64 {
-    let gray: GrayImage = image.to_luma8();
+/// 黒帯とみなす行・列の平均輝度の上限
+const BAR_MAX_LUMA: f64 = 24.0;
+/// 黒帯を除いて残す最小の割合。暗い場面を丸ごと黒帯と誤認して切り取らないようにする
+const BAR_MIN_REMAINING: f64 = 0.5;
 
-    let lap = imageproc::filter::laplacian_filter(&gray);
+/// Removes black bars around the picture (2.39:1 films in 16:9, 4:3 in 16:9). Returns the
+/// image unchanged when there are none or they would take more than half of a side.
+pub fn crop_black_bars(image: DynamicImage) -> DynamicImage {
+    let gray = image.to_luma8();
+    let (width, height) = gray.dimensions();
+    if width == 0 || height == 0 {
+        return image;
+    }
+    let row_mean = |y: u32| {
+        (0..width)
+            .map(|x| u64::from(gray.get_pixel(x, y)[0]))
+            .sum::<u64>() as f64
+            / f64::from(width)
+    };
+    let column_mean = |x: u32| {
+        (0..height)
+            .map(|y| u64::from(gray.get_pixel(x, y)[0]))
+            .sum::<u64>() as f64
+            / f64::from(height)
+    };
+    let top = (0..height)
+        .take_while(|&y| row_mean(y) < BAR_MAX_LUMA)
+        .count() as u32;
+    let bottom = (top..height)
+        .rev()
+        .take_while(|&y| row_mean(y) < BAR_MAX_LUMA)
+        .count() as u32;
+    let left = (0..width)
+        .take_while(|&x| column_mean(x) < BAR_MAX_LUMA)
+        .count() as u32;
+    let right = (left..width)
+        .rev()
+        .take_while(|&x| column_mean(x) < BAR_MAX_LUMA)
+        .count() as u32;
 
-    let mut stats = statistics::OnlineStats::new();
-    for pixel in lap.pixels() {
-        let v = pixel[0] as f64;
-        stats.update(v);
+    let cropped_width = width - left - right;
+    let cropped_height = height - top - bottom;
+    if (cropped_width, cropped_height) == (width, height)
+        || f64::from(cropped_width) < f64::from(width) * BAR_MIN_REMAINING
+        || f64::from(cropped_height) < f64::from(height) * BAR_MIN_REMAINING
+    {
+        return image;
     }
+    image.crop_imm(left, top, cropped_width, cropped_height)
+}
+
+/// Normalized joint RGB histogram with `HISTOGRAM_BINS` bins per channel.
+fn color_histogram(image: &DynamicImage) -> Vec<f32> {
+    let rgb = image.to_rgb8();
+    let mut histogram = vec![0.0_f32; HISTOGRAM_BINS.pow(3)];
+    let shift = 8 - HISTOGRAM_BINS.trailing_zeros();
+    for pixel in rgb.pixels() {
+        let [r, g, b] = pixel.0.map(|v| usize::from(v >> shift));
+        histogram[(r * HISTOGRAM_BINS + g) * HISTOGRAM_BINS + b] += 1.0;
+    }
+    let total = (rgb.width() * rgb.height()).max(1) as f32;
+    histogram.iter_mut().for_each(|count| *count /= total);
+    histogram
+}
 
-    stats.variance()
+/// 0 (同じ分布) から 1 (重なりなし) の距離
+fn histogram_distance(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| (x - y).abs()).sum::<f32>() / 2.0
 }
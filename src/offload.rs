@@ -0,0 +1,53 @@
+//! `--offload`: ディスクキャッシュにある出力を、前段の nginx などに送らせる。
+//!
+//! 本文を返す代わりに `X-Accel-Redirect` か `X-Sendfile` でキャッシュファイルを指し、
+//! 大きな `/media` の送信で actix のワーカーを塞がないようにする。
+use clap::{Parser, ValueEnum};
+use std::path::Path;
+
+#[derive(Parser)]
+pub struct OffloadOption {
+    /// Let the front proxy send outputs found in `--cache-dir` instead of streaming them
+    #[arg(long, value_enum)]
+    offload: Option<OffloadMode>,
+
+    /// URI prefix of the nginx `internal` location aliased to `--cache-dir`
+    #[arg(long, default_value = "/_cache/")]
+    offload_prefix: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OffloadMode {
+    /// `X-Accel-Redirect: {offload-prefix}{path in the cache dir}`, for nginx
+    XAccelRedirect,
+    /// `X-Sendfile: {absolute path}`, for Apache mod_xsendfile and lighttpd
+    XSendfile,
+}
+
+impl OffloadOption {
+    pub fn is_enabled(&self) -> bool {
+        self.offload.is_some()
+    }
+
+    /// The header that points the front proxy at `path` in `cache_dir`.
+    pub fn header(&self, cache_dir: &Path, path: &Path) -> Option<(&'static str, String)> {
+        match self.offload? {
+            OffloadMode::XAccelRedirect => {
+                // キャッシュのパスは 16 進数だけなのでエスケープしなくてよい
+                let relative = path.strip_prefix(cache_dir).ok()?;
+                let segments: Vec<_> = relative
+                    .components()
+                    .map(|c| c.as_os_str().to_str())
+                    .collect::<Option<_>>()?;
+                Some((
+                    "X-Accel-Redirect",
+                    format!("{}{}", self.offload_prefix, segments.join("/")),
+                ))
+            }
+            OffloadMode::XSendfile => Some((
+                "X-Sendfile",
+                std::path::absolute(path).ok()?.to_str()?.to_string(),
+            )),
+        }
+    }
+}
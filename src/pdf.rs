@@ -0,0 +1,77 @@
+use crate::loader::{LoadRequest, LoaderKey, LoaderRegistry, MediaLoader, BUILTIN_PRIORITY};
+use crate::{ApiError, LoadImageOption};
+use clap::Parser;
+use image::DynamicImage;
+use pdfium_render::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// 要求サイズが無い場合 (/media) のレンダリング幅
+const DEFAULT_RENDER_WIDTH: i32 = 2048;
+
+#[derive(Parser)]
+pub struct PdfOption {
+    /// Path to libpdfium; searched in the system library path if omitted
+    #[arg(long)]
+    pdfium_library: Option<PathBuf>,
+}
+
+pub fn register_loader(
+    registry: &mut LoaderRegistry,
+    option: &PdfOption,
+) -> Result<(), PdfiumError> {
+    let bindings = match &option.pdfium_library {
+        Some(path) => Pdfium::bind_to_library(path)?,
+        None => Pdfium::bind_to_system_library()?,
+    };
+    registry.add_loader(
+        "pdf",
+        Arc::new(PdfLoader {
+            pdfium: Pdfium::new(bindings),
+        }),
+    );
+    registry
+        .register(
+            LoaderKey::Extension("pdf".to_string()),
+            BUILTIN_PRIORITY,
+            "pdf",
+        )
+        .expect("builtin loader");
+    Ok(())
+}
+
+struct PdfLoader {
+    pdfium: Pdfium,
+}
+
+impl MediaLoader for PdfLoader {
+    fn load(
+        &self,
+        path: &Path,
+        _option: &LoadImageOption,
+        request: &LoadRequest,
+    ) -> Result<DynamicImage, ApiError> {
+        let to_api_error = |err: PdfiumError| ApiError::FailedToDecodeFormat("pdf", err.into());
+
+        let document = self
+            .pdfium
+            .load_pdf_from_file(path, None)
+            .map_err(to_api_error)?;
+        let pages = document.pages();
+        // ?page=N は 1 始まり
+        let index = request.page.unwrap_or(1) - 1;
+        if index >= u32::from(pages.len()) {
+            return Err(ApiError::NotFound());
+        }
+        let page = pages.get(index as PdfPageIndex).map_err(to_api_error)?;
+
+        let config = match request.target {
+            Some((w, h)) => PdfRenderConfig::new()
+                .set_target_width(w as i32)
+                .set_maximum_height(h as i32),
+            None => PdfRenderConfig::new().set_target_width(DEFAULT_RENDER_WIDTH),
+        };
+        let bitmap = page.render_with_config(&config).map_err(to_api_error)?;
+        Ok(bitmap.as_image())
+    }
+}
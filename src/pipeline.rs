@@ -0,0 +1,433 @@
+//! `?ops=resize:300x300,crop:1:1,grayscale,blur:3` 形式の操作列。
+//!
+//! 各操作は `name:arg:arg` で、`,` 区切りで左から順に適用する。
+use crate::color::HexColor;
+use crate::fit::{self, Fit, Gravity, ResizeFilter};
+use crate::tonemap::{self, ToneMapOperator};
+use image::{DynamicImage, Rgba};
+
+/// URL で指定できる操作数の上限
+const MAX_OPS: usize = 16;
+
+/// 縮小後の画像にかけるので大きなシグマは意味が無く、重いだけ
+pub const MAX_BLUR_SIGMA: f32 = 50.0;
+pub const MAX_BRIGHTNESS: i32 = 255;
+pub const MAX_CONTRAST: f32 = 100.0;
+pub const MAX_BORDER: u32 = 64;
+const MAX_SHARPEN_AMOUNT: f32 = 5.0;
+const DEFAULT_SHARPEN_RADIUS: f32 = 0.5;
+pub const DEFAULT_BORDER_COLOR: HexColor = HexColor(image::Rgb([0, 0, 0]));
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    Resize {
+        width: u32,
+        height: u32,
+        fit: Fit,
+        gravity: Gravity,
+        filter: Option<ResizeFilter>,
+    },
+    /// Crop to the `width`:`height` aspect ratio without scaling
+    Crop {
+        width: u32,
+        height: u32,
+        gravity: Gravity,
+    },
+    /// Letterbox to exactly `width`x`height` with transparent padding
+    Pad {
+        width: u32,
+        height: u32,
+    },
+    /// Frame drawn inside the edges so the size does not change
+    Border {
+        width: u32,
+        color: HexColor,
+    },
+    /// Unsharp mask: `amount` times the difference from a Gaussian blur of `radius`, skipped
+    /// where the difference is below `threshold`
+    Sharpen {
+        amount: f32,
+        radius: f32,
+        threshold: u8,
+    },
+    Grayscale,
+    Blur(f32),
+    Brightness(i32),
+    Contrast(f32),
+}
+
+impl Op {
+    fn is_geometry(&self) -> bool {
+        matches!(self, Op::Resize { .. } | Op::Crop { .. })
+    }
+
+    /// `parse` で読み戻せる正規形
+    fn to_canonical(self) -> String {
+        match self {
+            Op::Resize {
+                width,
+                height,
+                fit,
+                gravity,
+                filter,
+            } => {
+                let mut s = format!("resize:{}x{}:{}", width, height, fit.as_str());
+                if fit == Fit::Cover {
+                    s = format!("{}:{}", s, gravity.as_str());
+                }
+                if let Some(filter) = filter {
+                    s = format!("{}:{}", s, filter.as_str());
+                }
+                s
+            }
+            Op::Crop {
+                width,
+                height,
+                gravity,
+            } => format!("crop:{}:{}:{}", width, height, gravity.as_str()),
+            Op::Pad { width, height } => format!("pad:{}x{}", width, height),
+            Op::Border { width, color } => format!("border:{}:{}", width, color.to_hex()),
+            Op::Sharpen {
+                amount,
+                radius,
+                threshold,
+            } => format!("sharpen:{}:{}:{}", amount, radius, threshold),
+            Op::Grayscale => "grayscale".to_string(),
+            Op::Blur(sigma) => format!("blur:{}", sigma),
+            Op::Brightness(value) => format!("brightness:{}", value),
+            Op::Contrast(value) => format!("contrast:{}", value),
+        }
+    }
+
+    fn apply(&self, img: DynamicImage) -> DynamicImage {
+        match *self {
+            Op::Resize {
+                width,
+                height,
+                fit,
+                gravity,
+                filter,
+            } => fit::resize(&img, width, height, fit, gravity, filter),
+            Op::Crop {
+                width,
+                height,
+                gravity,
+            } => fit::crop(&img, width, height, gravity),
+            Op::Pad { width, height } => fit::pad(&img, width, height),
+            Op::Border { width, color } => border(img, width, color),
+            Op::Sharpen {
+                amount,
+                radius,
+                threshold,
+            } => unsharp_mask(img, amount, radius, threshold),
+            Op::Grayscale => {
+                // エンコーダーが RGB(A) 前提なので、アルファを残したまま RGB に戻す
+                let gray = img.grayscale();
+                if img.color().has_alpha() {
+                    DynamicImage::ImageRgba8(gray.to_rgba8())
+                } else {
+                    DynamicImage::ImageRgb8(gray.to_rgb8())
+                }
+            }
+            Op::Blur(sigma) => img.blur(sigma),
+            Op::Brightness(value) => img.brighten(value),
+            Op::Contrast(value) => img.adjust_contrast(value),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Pipeline {
+    ops: Vec<Op>,
+}
+
+impl Pipeline {
+    pub fn new(ops: Vec<Op>) -> Self {
+        Pipeline { ops }
+    }
+
+    /// Parses and validates `?ops=`. `max` bounds the size of every resize step.
+    pub fn parse(s: &str, max: (u32, u32)) -> Result<Self, String> {
+        let ops = s
+            .split(',')
+            .filter(|op| !op.is_empty())
+            .map(|op| parse_op(op, max))
+            .collect::<Result<Vec<_>, _>>()?;
+        if ops.len() > MAX_OPS {
+            return Err(format!("too many operations: {}", ops.len()));
+        }
+        Ok(Pipeline { ops })
+    }
+
+    pub fn has_resize(&self) -> bool {
+        self.ops.iter().any(|op| matches!(op, Op::Resize { .. }))
+    }
+
+    /// `op` を先頭に追加する
+    pub fn prepend(&mut self, op: Op) {
+        self.ops.insert(0, op);
+    }
+
+    /// 最後の縮小の直後に `op` を挟む。縮小が無ければ何もしない。
+    pub fn insert_after_resize(&mut self, op: Op) {
+        if let Some(i) = self
+            .ops
+            .iter()
+            .rposition(|op| matches!(op, Op::Resize { .. }))
+        {
+            self.ops.insert(i + 1, op);
+        }
+    }
+
+    /// Multiplies every size in the pipeline by the device pixel ratio, keeping within `max`.
+    pub fn scale(&mut self, factor: f32, max: (u32, u32)) {
+        let scale = |v: u32, limit: u32| ((v as f32 * factor).round() as u32).clamp(1, limit);
+        for op in &mut self.ops {
+            match op {
+                Op::Resize { width, height, .. } | Op::Pad { width, height } => {
+                    *width = scale(*width, max.0);
+                    *height = scale(*height, max.1);
+                }
+                Op::Border { width, .. } => *width = scale(*width, MAX_BORDER),
+                _ => {}
+            }
+        }
+    }
+
+    /// The box of the first resize, used as the decode target.
+    pub fn target(&self) -> Option<(u32, u32)> {
+        self.ops.iter().find_map(|op| match op {
+            Op::Resize { width, height, .. } => Some((*width, *height)),
+            _ => None,
+        })
+    }
+
+    /// サイドカー名に入れる識別子。`:` と `,` はファイル名で扱いにくいので置き換える。
+    pub fn variant(&self) -> String {
+        self.ops
+            .iter()
+            .map(|op| op.to_canonical().replace(':', "-"))
+            .collect::<Vec<_>>()
+            .join("_")
+    }
+
+    /// HDR のトーンマッピングは、色を触る最初の操作の前 (無ければ最後) に行う。
+    /// 縮小は高ビット深度のまま済ませる。
+    pub fn run(&self, img: DynamicImage, tone_map: ToneMapOperator) -> DynamicImage {
+        let mut img = img;
+        let mut tone_mapped = false;
+        for op in &self.ops {
+            if !tone_mapped && !op.is_geometry() {
+                img = tonemap::tone_map(img, tone_map);
+                tone_mapped = true;
+            }
+            img = op.apply(img);
+        }
+        if tone_mapped {
+            img
+        } else {
+            tonemap::tone_map(img, tone_map)
+        }
+    }
+}
+
+fn border(img: DynamicImage, width: u32, color: HexColor) -> DynamicImage {
+    let [r, g, b] = color.0 .0;
+    let color = Rgba([r, g, b, 255]);
+    let mut rgba = img.to_rgba8();
+    let (w, h) = rgba.dimensions();
+    for (x, y, px) in rgba.enumerate_pixels_mut() {
+        if x < width || y < width || x + width >= w || y + width >= h {
+            *px = color;
+        }
+    }
+    DynamicImage::ImageRgba8(rgba)
+}
+
+fn unsharp_mask(img: DynamicImage, amount: f32, radius: f32, threshold: u8) -> DynamicImage {
+    let has_alpha = img.color().has_alpha();
+    let mut rgba = img.to_rgba8();
+    let blurred = image::imageops::blur(&rgba, radius);
+    for (px, blurred) in rgba.pixels_mut().zip(blurred.pixels()) {
+        for c in 0..3 {
+            let diff = f32::from(px[c]) - f32::from(blurred[c]);
+            if diff.abs() >= f32::from(threshold) {
+                px[c] = (f32::from(px[c]) + amount * diff).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+    if has_alpha {
+        DynamicImage::ImageRgba8(rgba)
+    } else {
+        DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(rgba).to_rgb8())
+    }
+}
+
+fn parse_op(s: &str, max: (u32, u32)) -> Result<Op, String> {
+    let mut args = s.split(':');
+    let name = args.next().unwrap_or("");
+    let args: Vec<&str> = args.collect();
+    let invalid = || format!("invalid operation: {}", s);
+    let number = |i: usize| -> Result<f32, String> {
+        args.get(i)
+            .and_then(|v| v.parse::<f32>().ok())
+            .filter(|v| v.is_finite())
+            .ok_or_else(invalid)
+    };
+    let arity = |n: usize| {
+        if args.len() == n {
+            Ok(())
+        } else {
+            Err(invalid())
+        }
+    };
+
+    match name {
+        "resize" => {
+            let (w, h) = args
+                .first()
+                .and_then(|dims| dims.split_once('x'))
+                .ok_or_else(invalid)?;
+            // 片方を省略した場合は上限だけで制限する
+            let side = |v: &str, limit: u32| -> Result<u32, String> {
+                if v.is_empty() {
+                    return Ok(limit);
+                }
+                match v.parse::<u32>() {
+                    Ok(v) if v > 0 && v <= limit => Ok(v),
+                    _ => Err(invalid()),
+                }
+            };
+            if w.is_empty() && h.is_empty() {
+                return Err(invalid());
+            }
+            let (width, height) = (side(w, max.0)?, side(h, max.1)?);
+            let mut fit = Fit::Contain;
+            let mut gravity = Gravity::Center;
+            let mut filter = None;
+            // 名前が重ならないので順不同で受け付ける
+            for arg in &args[1..] {
+                if let Some(f) = Fit::parse(arg) {
+                    fit = f;
+                } else if let Some(g) = Gravity::parse(arg) {
+                    gravity = g;
+                } else if let Some(f) = ResizeFilter::parse(arg) {
+                    filter = Some(f);
+                } else {
+                    return Err(invalid());
+                }
+            }
+            // 片方の辺は元画像の縦横比から決まるので、`contain` と同じになる
+            if w.is_empty() || h.is_empty() {
+                fit = Fit::Contain;
+            }
+            Ok(Op::Resize {
+                width,
+                height,
+                fit,
+                gravity,
+                filter,
+            })
+        }
+        "pad" => {
+            arity(1)?;
+            let (w, h) = args[0].split_once('x').ok_or_else(invalid)?;
+            let side = |v: &str, limit: u32| match v.parse::<u32>() {
+                Ok(v) if v > 0 && v <= limit => Ok(v),
+                _ => Err(invalid()),
+            };
+            Ok(Op::Pad {
+                width: side(w, max.0)?,
+                height: side(h, max.1)?,
+            })
+        }
+        "border" => {
+            if !(1..=2).contains(&args.len()) {
+                return Err(invalid());
+            }
+            let width = match args[0].parse::<u32>() {
+                Ok(v) if v > 0 && v <= MAX_BORDER => v,
+                _ => return Err(invalid()),
+            };
+            let color = match args.get(1) {
+                Some(color) => color.parse().map_err(|_| invalid())?,
+                None => DEFAULT_BORDER_COLOR,
+            };
+            Ok(Op::Border { width, color })
+        }
+        "crop" => {
+            if !(2..=3).contains(&args.len()) {
+                return Err(invalid());
+            }
+            let ratio = |i: usize| match args[i].parse::<u32>() {
+                Ok(v) if v > 0 => Ok(v),
+                _ => Err(invalid()),
+            };
+            let gravity = match args.get(2) {
+                Some(g) => Gravity::parse(g).ok_or_else(invalid)?,
+                None => Gravity::Center,
+            };
+            Ok(Op::Crop {
+                width: ratio(0)?,
+                height: ratio(1)?,
+                gravity,
+            })
+        }
+        "sharpen" => {
+            if !(1..=3).contains(&args.len()) {
+                return Err(invalid());
+            }
+            let amount = number(0)?;
+            let radius = if args.len() > 1 {
+                number(1)?
+            } else {
+                DEFAULT_SHARPEN_RADIUS
+            };
+            let threshold = match args.get(2) {
+                Some(v) => v.parse::<u8>().map_err(|_| invalid())?,
+                None => 0,
+            };
+            if amount <= 0.0
+                || amount > MAX_SHARPEN_AMOUNT
+                || radius <= 0.0
+                || radius > MAX_BLUR_SIGMA
+            {
+                return Err(invalid());
+            }
+            Ok(Op::Sharpen {
+                amount,
+                radius,
+                threshold,
+            })
+        }
+        "grayscale" => {
+            arity(0)?;
+            Ok(Op::Grayscale)
+        }
+        "blur" => {
+            arity(1)?;
+            let sigma = number(0)?;
+            if sigma <= 0.0 || sigma > MAX_BLUR_SIGMA {
+                return Err(invalid());
+            }
+            Ok(Op::Blur(sigma))
+        }
+        "brightness" => {
+            arity(1)?;
+            let value = number(0)?.round() as i32;
+            if value.abs() > MAX_BRIGHTNESS {
+                return Err(invalid());
+            }
+            Ok(Op::Brightness(value))
+        }
+        "contrast" => {
+            arity(1)?;
+            let value = number(0)?;
+            if value.abs() > MAX_CONTRAST {
+                return Err(invalid());
+            }
+            Ok(Op::Contrast(value))
+        }
+        _ => Err(format!("unknown operation: {}", name)),
+    }
+}
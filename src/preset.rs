@@ -0,0 +1,83 @@
+//! `?preset=NAME` で展開する、名前付きのサムネイル設定。
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// プリセットに書ける `/thumbnail` のパラメータ
+const KEYS: &[&str] = &[
+    "size",
+    "w",
+    "h",
+    "fit",
+    "gravity",
+    "filter",
+    "grayscale",
+    "blur",
+    "brightness",
+    "contrast",
+    "ops",
+    "format",
+    "quality",
+    "bg",
+    "pad",
+    "border",
+    "border_color",
+    "dpr",
+    "max_bytes",
+    "lossless",
+    "webp_method",
+    "webp_alpha_quality",
+    "webp_psnr",
+    "sharp_yuv",
+];
+
+/// `NAME:QUERY` given on the command line, e.g. `grid_tile:w=256&h=256&fit=cover&quality=80`.
+#[derive(Clone, Debug)]
+pub struct Preset {
+    name: String,
+    params: Vec<(String, String)>,
+}
+
+impl FromStr for Preset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, query) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected NAME:QUERY: {}", s))?;
+        if name.is_empty() {
+            return Err(format!("empty preset name: {}", s));
+        }
+        let mut params = Vec::new();
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected KEY=VALUE in preset {}: {}", name, pair))?;
+            if !KEYS.contains(&key) {
+                return Err(format!("unknown parameter in preset {}: {}", name, key));
+            }
+            params.push((key.to_string(), value.to_string()));
+        }
+        Ok(Preset {
+            name: name.to_string(),
+            params,
+        })
+    }
+}
+
+/// Expands `?preset=` into `query`. Values from the preset win over the request so that
+/// rendering can be changed centrally. Returns `Err` with the name of an unknown preset.
+pub fn expand(presets: &[Preset], query: &mut HashMap<String, String>) -> Result<(), String> {
+    let Some(name) = query.remove("preset") else {
+        return Ok(());
+    };
+    // 後から指定したものを優先する
+    let preset = presets
+        .iter()
+        .rev()
+        .find(|preset| preset.name == name)
+        .ok_or(name)?;
+    for (key, value) in &preset.params {
+        query.insert(key.clone(), value.clone());
+    }
+    Ok(())
+}
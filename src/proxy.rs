@@ -0,0 +1,109 @@
+//! `/transcode` で返す、どのクライアントでも再生できる H.264/AAC の MP4 プロキシ。
+//!
+//! 4K の ProRes などは変換に時間がかかるので、リクエストではバックグラウンドで変換を始めて
+//! 202 を返し、できあがったファイルを以降のリクエストで返す。
+use crate::transcode::{self, Container, TranscodeOption};
+use crate::FileKey;
+use clap::Parser;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// 縦の解像度と bps の範囲。キャッシュが際限なく増えないよう丸める
+const HEIGHT_RANGE: (u32, u32) = (144, 2160);
+const BIT_RATE_RANGE: (usize, usize) = (100_000, 50_000_000);
+
+#[derive(Parser, Clone, Debug)]
+pub struct ProxyOption {
+    /// Where `/transcode` proxies are cached. Defaults to a directory under the system temp
+    /// dir
+    #[arg(long)]
+    proxy_cache_dir: Option<PathBuf>,
+}
+
+impl ProxyOption {
+    fn cache_dir(&self) -> PathBuf {
+        self.proxy_cache_dir
+            .clone()
+            .unwrap_or_else(|| std::env::temp_dir().join("media_converter-proxy"))
+    }
+
+    pub fn build_path(&self, key: &FileKey, params: &ProxyParams) -> PathBuf {
+        let mut name = key.hkey.clone();
+        if let Some(height) = params.height {
+            name.push_str(&format!(".h{}", height));
+        }
+        if let Some(bit_rate) = params.bit_rate {
+            name.push_str(&format!(".b{}", bit_rate));
+        }
+        name.push_str(".mp4");
+        self.cache_dir().join(name)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProxyParams {
+    pub height: Option<u32>,
+    pub bit_rate: Option<usize>,
+}
+
+impl ProxyParams {
+    /// `?height=720&bitrate=2M`. Invalid values are ignored.
+    pub fn from_query(query: &HashMap<String, String>) -> Self {
+        let height = query
+            .get("height")
+            .and_then(|s| s.parse::<u32>().ok())
+            .map(|height| height.clamp(HEIGHT_RANGE.0, HEIGHT_RANGE.1) & !1);
+        let bit_rate = query
+            .get("bitrate")
+            .and_then(|s| parse_bit_rate(s))
+            .map(|bit_rate| bit_rate.clamp(BIT_RATE_RANGE.0, BIT_RATE_RANGE.1));
+        ProxyParams { height, bit_rate }
+    }
+}
+
+/// `2M`, `800k` or plain bits per second.
+fn parse_bit_rate(s: &str) -> Option<usize> {
+    let (number, unit) = match s.char_indices().last()? {
+        (i, 'k' | 'K') => (&s[..i], 1_000.0),
+        (i, 'm' | 'M') => (&s[..i], 1_000_000.0),
+        _ => (s, 1.0),
+    };
+    let value = number.parse::<f64>().ok()?;
+    (value.is_finite() && value > 0.0).then_some((value * unit) as usize)
+}
+
+pub fn is_fresh(path: &Path, source_modified: SystemTime) -> bool {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .is_ok_and(|modified| modified >= source_modified)
+}
+
+pub fn generate(
+    source: &Path,
+    output: &Path,
+    params: &ProxyParams,
+    option: &TranscodeOption,
+) -> Result<(), anyhow::Error> {
+    if let Some(parent) = output.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let job = transcode::Job {
+        container: Container::Mp4,
+        max_height: params.height,
+        video_bit_rate: params.bit_rate,
+        range: None,
+        allow_encode: true,
+        // moov を先頭に置いてダウンロード完了前に再生を始められるようにする
+        muxer_options: vec![("movflags".to_string(), "+faststart".to_string())],
+    };
+    // 書きかけのファイルを返さないよう rename で置き換える
+    let mut tmp_path = output.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = scopeguard::guard(PathBuf::from(tmp_path), |path| {
+        let _ = std::fs::remove_file(path);
+    });
+    transcode::run(source, &tmp_path, &job, option)?;
+    std::fs::rename(&*tmp_path, output)?;
+    Ok(())
+}
@@ -0,0 +1,266 @@
+//! Streaming reader for the merged composite of PSD and PSB files.
+//!
+//! Layer data is skipped with seeks so that only the composite image section is read, and
+//! documents larger than `MAX_DIMENSION` are decimated row by row while decoding.
+use anyhow::Context;
+use image::{DynamicImage, GrayAlphaImage, GrayImage, RgbImage, RgbaImage};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+/// これを超える長辺は間引いてデコードする (30000px 級の PSB でもメモリに載るように)
+const MAX_DIMENSION: u32 = 8192;
+
+const COLOR_MODE_GRAYSCALE: u16 = 1;
+const COLOR_MODE_RGB: u16 = 3;
+const COLOR_MODE_CMYK: u16 = 4;
+
+/// Photoshop 5 以降のサムネイル (JFIF)
+const RESOURCE_THUMBNAIL: u16 = 1036;
+/// format, width, height, widthbytes, total size, compressed size, bpp, planes
+const THUMBNAIL_HEADER_LEN: usize = 28;
+
+const COMPRESSION_RAW: u16 = 0;
+const COMPRESSION_RLE: u16 = 1;
+
+struct Header {
+    is_psb: bool,
+    channels: u16,
+    height: u32,
+    width: u32,
+    depth: u16,
+    color_mode: u16,
+}
+
+fn read_u16(reader: &mut impl Read) -> std::io::Result<u16> {
+    let mut buf = [0; 2];
+    reader.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32(reader: &mut impl Read) -> std::io::Result<u32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64(reader: &mut impl Read) -> std::io::Result<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn read_header(reader: &mut impl Read) -> Result<Header, anyhow::Error> {
+    let mut signature = [0; 4];
+    reader.read_exact(&mut signature)?;
+    anyhow::ensure!(&signature == b"8BPS", "Not a PSD file");
+    let version = read_u16(reader)?;
+    anyhow::ensure!(version == 1 || version == 2, "Unknown version {}", version);
+    let mut reserved = [0; 6];
+    reader.read_exact(&mut reserved)?;
+
+    Ok(Header {
+        is_psb: version == 2,
+        channels: read_u16(reader)?,
+        height: read_u32(reader)?,
+        width: read_u32(reader)?,
+        depth: read_u16(reader)?,
+        color_mode: read_u16(reader)?,
+    })
+}
+
+/// Photoshop の PackBits
+fn unpack_bits(src: &[u8], dst: &mut Vec<u8>) {
+    dst.clear();
+    let mut i = 0;
+    while i < src.len() {
+        let n = src[i] as i8;
+        i += 1;
+        if n >= 0 {
+            let end = (i + n as usize + 1).min(src.len());
+            dst.extend_from_slice(&src[i..end]);
+            i = end;
+        } else if n != -128 {
+            if let Some(&value) = src.get(i) {
+                dst.extend(std::iter::repeat_n(value, (1 - n as isize) as usize));
+            }
+            i += 1;
+        }
+    }
+}
+
+/// image resources に保存されたサムネイル (長辺 160px 程度) を返す。無ければ `Ok(None)`。
+pub fn load_thumbnail_resource(path: &Path) -> Result<Option<DynamicImage>, anyhow::Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    read_header(&mut reader)?;
+    let color_mode_len = read_u32(&mut reader)?;
+    reader.seek_relative(i64::from(color_mode_len))?;
+
+    let mut remaining = u64::from(read_u32(&mut reader)?);
+    while remaining > 0 {
+        let mut signature = [0; 4];
+        reader.read_exact(&mut signature)?;
+        anyhow::ensure!(&signature == b"8BIM", "Broken image resource");
+        let id = read_u16(&mut reader)?;
+        // Pascal 文字列の名前は長さバイトを含めて偶数に詰められる
+        let mut name_len = [0; 1];
+        reader.read_exact(&mut name_len)?;
+        let name_len = (u64::from(name_len[0]) + 1).next_multiple_of(2);
+        reader.seek_relative(name_len as i64 - 1)?;
+        let len = u64::from(read_u32(&mut reader)?);
+        let padded_len = len.next_multiple_of(2);
+        // 壊れたファイルの長さをそのまま信じて巨大な確保をしないよう、セクションに収まるか確かめる
+        anyhow::ensure!(
+            4 + 2 + name_len + 4 + len <= remaining,
+            "Image resource exceeds its section"
+        );
+
+        if id == RESOURCE_THUMBNAIL {
+            anyhow::ensure!(len < 16 * 1024 * 1024, "Thumbnail resource too large");
+            let mut data = vec![0; len as usize];
+            reader.read_exact(&mut data)?;
+            let jpeg = data
+                .get(THUMBNAIL_HEADER_LEN..)
+                .context("Truncated thumbnail resource")?;
+            let img = image::load_from_memory_with_format(jpeg, image::ImageFormat::Jpeg)?;
+            return Ok(Some(img));
+        }
+        reader.seek_relative(padded_len as i64)?;
+        remaining = remaining.saturating_sub(4 + 2 + name_len + 4 + padded_len);
+    }
+    Ok(None)
+}
+
+pub fn load_image_from_psd_stream(path: &Path) -> Result<DynamicImage, anyhow::Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let header = read_header(&mut reader)?;
+    anyhow::ensure!(
+        header.depth == 8 || header.depth == 16,
+        "Unsupported depth {}",
+        header.depth
+    );
+    let color_channels = match header.color_mode {
+        COLOR_MODE_GRAYSCALE => 1,
+        COLOR_MODE_RGB => 3,
+        COLOR_MODE_CMYK => 4,
+        mode => anyhow::bail!("Unsupported color mode {}", mode),
+    };
+    anyhow::ensure!(
+        header.channels >= color_channels,
+        "Too few channels: {}",
+        header.channels
+    );
+    let has_alpha = header.channels > color_channels;
+    let decoded_channels = (color_channels + u16::from(has_alpha)) as usize;
+
+    // color mode data, image resources, layer and mask information は読み飛ばす
+    let color_mode_len = read_u32(&mut reader)?;
+    reader.seek_relative(i64::from(color_mode_len))?;
+    let resources_len = read_u32(&mut reader)?;
+    reader.seek_relative(i64::from(resources_len))?;
+    let layers_len = if header.is_psb {
+        read_u64(&mut reader)?
+    } else {
+        u64::from(read_u32(&mut reader)?)
+    };
+    reader.seek_relative(i64::try_from(layers_len)?)?;
+
+    let compression = read_u16(&mut reader)?;
+    let (width, height) = (header.width as usize, header.height as usize);
+    let step = header
+        .width
+        .max(header.height)
+        .div_ceil(MAX_DIMENSION)
+        .max(1) as usize;
+    let out_width = width.div_ceil(step);
+    let out_height = height.div_ceil(step);
+    let bytes_per_sample = header.depth as usize / 8;
+    let row_bytes = width * bytes_per_sample;
+
+    let row_lengths: Option<Vec<u64>> = match compression {
+        COMPRESSION_RAW => None,
+        COMPRESSION_RLE => {
+            let rows = header.channels as usize * height;
+            let mut lengths = Vec::with_capacity(rows);
+            for _ in 0..rows {
+                lengths.push(if header.is_psb {
+                    u64::from(read_u32(&mut reader)?)
+                } else {
+                    u64::from(read_u16(&mut reader)?)
+                });
+            }
+            Some(lengths)
+        }
+        other => anyhow::bail!("Unsupported compression {}", other),
+    };
+
+    let mut planes = vec![Vec::with_capacity(out_width * out_height); decoded_channels];
+    let mut compressed = Vec::new();
+    let mut row = Vec::with_capacity(row_bytes);
+    for (channel, plane) in planes.iter_mut().enumerate() {
+        for y in 0..height {
+            let keep = y % step == 0;
+            match &row_lengths {
+                None if keep => {
+                    row.resize(row_bytes, 0);
+                    reader.read_exact(&mut row)?;
+                }
+                None => reader.seek_relative(row_bytes as i64)?,
+                Some(lengths) => {
+                    let len = lengths[channel * height + y];
+                    if keep {
+                        compressed.resize(len as usize, 0);
+                        reader.read_exact(&mut compressed)?;
+                        unpack_bits(&compressed, &mut row);
+                        row.resize(row_bytes, 0);
+                    } else {
+                        reader.seek_relative(len as i64)?;
+                    }
+                }
+            }
+            if keep {
+                // 16bit は上位バイトだけ使う
+                plane.extend((0..out_width).map(|x| row[x * step * bytes_per_sample]));
+            }
+        }
+    }
+
+    let (w, h) = (out_width as u32, out_height as u32);
+    let img = match (header.color_mode, has_alpha) {
+        (COLOR_MODE_GRAYSCALE, false) => {
+            GrayImage::from_raw(w, h, planes.swap_remove(0)).map(DynamicImage::ImageLuma8)
+        }
+        (COLOR_MODE_GRAYSCALE, true) => {
+            GrayAlphaImage::from_raw(w, h, interleave(&planes)).map(DynamicImage::ImageLumaA8)
+        }
+        (COLOR_MODE_RGB, false) => {
+            RgbImage::from_raw(w, h, interleave(&planes)).map(DynamicImage::ImageRgb8)
+        }
+        (COLOR_MODE_RGB, true) => {
+            RgbaImage::from_raw(w, h, interleave(&planes)).map(DynamicImage::ImageRgba8)
+        }
+        _ => RgbImage::from_raw(w, h, cmyk_to_rgb(&planes)).map(DynamicImage::ImageRgb8),
+    };
+    img.context("Failed to build ImageBuffer")
+}
+
+fn interleave(planes: &[Vec<u8>]) -> Vec<u8> {
+    let len = planes[0].len();
+    let mut pixels = Vec::with_capacity(len * planes.len());
+    for i in 0..len {
+        pixels.extend(planes.iter().map(|plane| plane[i]));
+    }
+    pixels
+}
+
+/// PSD の CMYK は反転して格納されている (255 がインク無し)
+fn cmyk_to_rgb(planes: &[Vec<u8>]) -> Vec<u8> {
+    let scale = |v: u8, k: u8| (u16::from(v) * u16::from(k) / 255) as u8;
+    planes[0]
+        .iter()
+        .zip(&planes[1])
+        .zip(&planes[2])
+        .zip(&planes[3])
+        .flat_map(|(((&c, &m), &y), &k)| [scale(c, k), scale(m, k), scale(y, k)])
+        .collect()
+}
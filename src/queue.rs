@@ -0,0 +1,484 @@
+//! `--queue-db`: バックグラウンドの変換のタスクキュー。
+//!
+//! `/transcode` と `--async-video` の変換を、actix のブロッキングプールではなく決まった数の
+//! ワーカースレッドで順に処理する。タスクは SQLite に書いておくので、サーバーを再起動しても
+//! 残りから続ける。失敗したタスクは間隔を倍にしながら再試行し、`--queue-max-attempts` 回失敗したら
+//! dead letter として残して管理 API から確認・再投入できるようにする。
+//!
+//! タスクには変換の手順ではなくリクエストの内容を書き、実行するときにハンドラと同じ関数で組み立て直す。
+//! `warmup --enqueue` は別のプロセスから同じデータベースにタスクを積む。複数のサーバーで
+//! 1 つのデータベースを共有することは考えていない。
+use crate::encode::OutputFormat;
+use crate::jobs::Jobs;
+use crate::{
+    cache, convert_once, prepare_media, prepare_storyboard, prepare_thumbnail, proxy, AppData,
+    Convert, FileKey, Size,
+};
+use actix_web::web;
+use clap::Parser;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// 他のプロセスが積んだタスクや、再試行の時刻が来たタスクを見に行く間隔
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// 再試行の間隔の上限
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS tasks (
+    id TEXT PRIMARY KEY,
+    url TEXT NOT NULL,
+    task TEXT NOT NULL,
+    state TEXT NOT NULL DEFAULT 'queued',
+    background INTEGER NOT NULL DEFAULT 0,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    run_at INTEGER NOT NULL,
+    error TEXT,
+    created INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS tasks_due ON tasks (state, background, run_at);
+";
+
+#[derive(Parser)]
+pub struct QueueOption {
+    /// SQLite database of the task queue. Background conversions then run on
+    /// `--queue-workers` threads and survive restarts
+    #[arg(long)]
+    queue_db: Option<PathBuf>,
+
+    /// Number of threads running queued tasks
+    #[arg(long, default_value_t = 2)]
+    queue_workers: usize,
+
+    /// Attempts before a failing task is moved to the dead letters
+    #[arg(long, default_value_t = 5)]
+    queue_max_attempts: u32,
+
+    /// Delay before retrying a failed task, doubled on each further failure, e.g. `30s`
+    #[arg(long, value_parser = cache::parse_duration, default_value = "30s")]
+    queue_retry_delay: Duration,
+}
+
+/// What a queued task generates. The conversion is built again when it runs, from the same
+/// parameters as the request that queued it.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Task {
+    /// `/thumbnail` with the query after presets are expanded
+    Thumbnail {
+        key: String,
+        query: HashMap<String, String>,
+        format: String,
+    },
+    Media {
+        key: String,
+        query: HashMap<String, String>,
+        format: String,
+        webp_fallback: bool,
+    },
+    /// The sprite of `/storyboard`
+    Storyboard { key: String, format: String },
+    /// The MP4 proxy of `/transcode`
+    Transcode {
+        key: String,
+        height: Option<u32>,
+        bit_rate: Option<usize>,
+    },
+}
+
+impl Task {
+    fn key(&self) -> &str {
+        match self {
+            Task::Thumbnail { key, .. }
+            | Task::Media { key, .. }
+            | Task::Storyboard { key, .. }
+            | Task::Transcode { key, .. } => key,
+        }
+    }
+}
+
+/// A task taken by a worker.
+struct Claimed {
+    id: String,
+    url: String,
+    task: String,
+    attempts: u32,
+}
+
+#[derive(Default, Serialize)]
+pub struct QueueCounts {
+    queued: u64,
+    running: u64,
+    dead: u64,
+}
+
+#[derive(Serialize)]
+pub struct DeadLetter {
+    id: String,
+    url: String,
+    task: serde_json::Value,
+    attempts: u32,
+    error: Option<String>,
+    /// UNIX time of the last attempt
+    failed_at: i64,
+}
+
+pub struct Queue {
+    conn: Mutex<Connection>,
+    /// タスクが積まれたら待っているワーカーを起こす
+    queued: Condvar,
+    workers: usize,
+    max_attempts: u32,
+    retry_delay: Duration,
+}
+
+fn now() -> i64 {
+    crate::secs(SystemTime::now()) as i64
+}
+
+impl Queue {
+    /// Opens the queue of `--queue-db`, or `None` when it is not configured.
+    pub fn open(option: &QueueOption) -> rusqlite::Result<Option<Queue>> {
+        let Some(path) = &option.queue_db else {
+            return Ok(None);
+        };
+        let conn = Connection::open(path)?;
+        // `warmup --enqueue` が書き込んでいる間も読めるようにする
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Some(Queue {
+            conn: Mutex::new(conn),
+            queued: Condvar::new(),
+            workers: option.queue_workers.max(1),
+            max_attempts: option.queue_max_attempts.max(1),
+            retry_delay: option.queue_retry_delay,
+        }))
+    }
+
+    /// Queues `task` as the job `id` requested by `url`. A task already waiting keeps its place
+    /// but is no longer `background` once a request waits for it, and a dead one is tried again
+    /// from the start. `background` tasks run only when no
+    /// request is waiting for another.
+    pub fn push(&self, id: &str, url: &str, task: &Task, background: bool) -> rusqlite::Result<()> {
+        let task = serde_json::to_string(task)
+            .map_err(|err| rusqlite::Error::ToSqlConversionFailure(Box::new(err)))?;
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO tasks (id, url, task, background, run_at, created)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?5)
+             ON CONFLICT (id) DO UPDATE SET
+                 url = excluded.url, task = excluded.task, state = 'queued',
+                 background = excluded.background, attempts = 0, run_at = excluded.run_at,
+                 error = NULL
+             WHERE state = 'dead'",
+            params![id, url, task, background, now()],
+        )?;
+        // ウォームアップが積んだタスクをリクエストが待つなら、ウォームアップの列の後ろに置かない
+        conn.execute(
+            "UPDATE tasks SET background = MIN(background, ?2) WHERE id = ?1 AND state = 'queued'",
+            params![id, background],
+        )?;
+        self.queued.notify_one();
+        Ok(())
+    }
+
+    /// Queues `task` for the job `id` a request has just started. The job fails right away when
+    /// the queue cannot be written.
+    pub fn submit(&self, jobs: &Jobs, id: &str, url: &str, task: &Task) {
+        if let Err(err) = self.push(id, url, task, false) {
+            log::warn!("Failed to queue {}: {}", url, err);
+            jobs.finish(id, Err(err.to_string()));
+        }
+    }
+
+    /// Takes the next due task, waiting until there is one.
+    fn next(&self) -> rusqlite::Result<Claimed> {
+        let mut conn = self.conn.lock().unwrap();
+        loop {
+            let claimed = conn
+                .query_row(
+                    "UPDATE tasks SET state = 'running', attempts = attempts + 1
+                     WHERE id = (
+                         SELECT id FROM tasks WHERE state = 'queued' AND run_at <= ?1
+                         ORDER BY background, run_at LIMIT 1
+                     )
+                     RETURNING id, url, task, attempts",
+                    params![now()],
+                    |row| {
+                        Ok(Claimed {
+                            id: row.get(0)?,
+                            url: row.get(1)?,
+                            task: row.get(2)?,
+                            attempts: row.get(3)?,
+                        })
+                    },
+                )
+                .optional()?;
+            if let Some(claimed) = claimed {
+                return Ok(claimed);
+            }
+            // 他のプロセスが積んだタスクと再試行の時刻は通知されないので、時々見に行く
+            conn = self.queued.wait_timeout(conn, POLL_INTERVAL).unwrap().0;
+        }
+    }
+
+    fn complete(&self, claimed: &Claimed) -> rusqlite::Result<()> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM tasks WHERE id = ?1", params![claimed.id])?;
+        Ok(())
+    }
+
+    /// Records a failed attempt and schedules the retry. Returns whether the task was moved to
+    /// the dead letters instead.
+    fn fail(&self, claimed: &Claimed, error: &str) -> rusqlite::Result<bool> {
+        let dead = claimed.attempts >= self.max_attempts;
+        let (state, run_at) = if dead {
+            ("dead", now())
+        } else {
+            let delay = self
+                .retry_delay
+                .saturating_mul(1 << (claimed.attempts - 1).min(16))
+                .min(MAX_RETRY_DELAY);
+            ("queued", now() + delay.as_secs() as i64)
+        };
+        self.conn.lock().unwrap().execute(
+            "UPDATE tasks SET state = ?2, run_at = ?3, error = ?4 WHERE id = ?1",
+            params![claimed.id, state, run_at, error],
+        )?;
+        Ok(dead)
+    }
+
+    /// Puts back the tasks that were running when the server stopped.
+    fn recover(&self) -> rusqlite::Result<usize> {
+        self.conn.lock().unwrap().execute(
+            "UPDATE tasks SET state = 'queued' WHERE state = 'running'",
+            [],
+        )
+    }
+
+    pub fn counts(&self) -> rusqlite::Result<QueueCounts> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare("SELECT state, COUNT(*) FROM tasks GROUP BY state")?;
+        let mut rows = statement.query([])?;
+        let mut counts = QueueCounts::default();
+        while let Some(row) = rows.next()? {
+            let count = row.get(1)?;
+            match row.get_ref(0)?.as_str()? {
+                "queued" => counts.queued = count,
+                "running" => counts.running = count,
+                "dead" => counts.dead = count,
+                _ => {}
+            }
+        }
+        Ok(counts)
+    }
+
+    /// The tasks that gave up, most recent first.
+    pub fn dead_letters(&self) -> rusqlite::Result<Vec<DeadLetter>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(
+            "SELECT id, url, task, attempts, error, run_at FROM tasks
+             WHERE state = 'dead' ORDER BY run_at DESC",
+        )?;
+        statement
+            .query_map([], |row| {
+                let task: String = row.get(2)?;
+                Ok(DeadLetter {
+                    id: row.get(0)?,
+                    url: row.get(1)?,
+                    task: serde_json::from_str(&task).unwrap_or(serde_json::Value::String(task)),
+                    attempts: row.get(3)?,
+                    error: row.get(4)?,
+                    failed_at: row.get(5)?,
+                })
+            })?
+            .collect()
+    }
+
+    /// Queues the dead task `id` again. Returns the URL that queued it, or `None` when there is
+    /// no such dead task.
+    pub fn retry(&self, id: &str) -> rusqlite::Result<Option<String>> {
+        let url = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "UPDATE tasks SET state = 'queued', attempts = 0, run_at = ?2, error = NULL
+                 WHERE id = ?1 AND state = 'dead'
+                 RETURNING url",
+                params![id, now()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        self.queued.notify_one();
+        Ok(url)
+    }
+
+    /// Forgets the dead task `id`. Returns false when there is no such dead task.
+    pub fn discard(&self, id: &str) -> rusqlite::Result<bool> {
+        let deleted = self.conn.lock().unwrap().execute(
+            "DELETE FROM tasks WHERE id = ?1 AND state = 'dead'",
+            params![id],
+        )?;
+        Ok(deleted > 0)
+    }
+}
+
+/// Starts the workers of `--queue-db`, if any.
+pub fn start_workers(app_data: &web::Data<AppData>) {
+    let Some(queue) = &app_data.queue else {
+        return;
+    };
+    match queue.recover() {
+        Ok(0) => {}
+        Ok(recovered) => log::info!("Resuming {} interrupted tasks", recovered),
+        Err(err) => log::warn!("Failed to resume interrupted tasks: {}", err),
+    }
+    for i in 0..queue.workers {
+        let app_data = app_data.clone();
+        std::thread::Builder::new()
+            .name(format!("queue-{}", i))
+            .spawn(move || work(&app_data))
+            .expect("Failed to start a queue worker");
+    }
+}
+
+fn work(app_data: &web::Data<AppData>) {
+    let queue = app_data.queue.as_ref().unwrap();
+    loop {
+        let claimed = match queue.next() {
+            Ok(claimed) => claimed,
+            Err(err) => {
+                log::warn!("Failed to read the task queue: {}", err);
+                std::thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+        };
+        let tracker = app_data.jobs.resume(&claimed.id, &claimed.url);
+        let result = {
+            let _tracking = tracker.enter();
+            serde_json::from_str(&claimed.task)
+                .map_err(|err| err.to_string())
+                .and_then(|task| run(app_data, &task))
+        };
+        // 再試行するあいだジョブは実行中のままにしておく
+        let finished = match result {
+            Ok(()) => queue.complete(&claimed).map(|()| Some(Ok(()))),
+            Err(error) => {
+                log::warn!(
+                    "{} failed (attempt {}): {}",
+                    claimed.url,
+                    claimed.attempts,
+                    error
+                );
+                queue
+                    .fail(&claimed, &error)
+                    .map(|dead| dead.then_some(Err(error)))
+            }
+        };
+        match finished {
+            Ok(Some(result)) => app_data.jobs.finish(&claimed.id, result),
+            Ok(None) => {}
+            Err(err) => log::warn!("Failed to update the task queue: {}", err),
+        }
+    }
+}
+
+/// Runs `task`, skipping outputs that are already there.
+fn run(app_data: &web::Data<AppData>, task: &Task) -> Result<(), String> {
+    let key = FileKey::parse(task.key()).map_err(|err| err.to_string())?;
+    let modified_time = app_data
+        .store
+        .metadata(&key)
+        .map_err(|err| err.to_string())?
+        .modified;
+    match task {
+        Task::Thumbnail { query, format, .. } => {
+            let size = query
+                .get("size")
+                .map(|s| Size::from_str(s))
+                .unwrap_or(Size::Medium);
+            let (output_name, convert) = prepare_thumbnail(
+                app_data,
+                &key,
+                query,
+                size,
+                OutputFormat::from_str(format),
+                modified_time,
+            )
+            .map_err(|err| err.to_string())?;
+            generate(app_data, &key, modified_time, &output_name, convert)
+        }
+        Task::Media {
+            query,
+            format,
+            webp_fallback,
+            ..
+        } => {
+            let format = OutputFormat::from_str(format);
+            let (output_name, convert) =
+                prepare_media(app_data, &key, query, format, *webp_fallback, modified_time);
+            generate(app_data, &key, modified_time, &output_name(format), convert)
+        }
+        Task::Storyboard { format, .. } => {
+            let (output_name, convert) = prepare_storyboard(
+                app_data,
+                &key,
+                OutputFormat::from_str(format),
+                modified_time,
+            );
+            generate(app_data, &key, modified_time, &output_name, convert)
+        }
+        Task::Transcode {
+            height, bit_rate, ..
+        } => {
+            let params = proxy::ProxyParams {
+                height: *height,
+                bit_rate: *bit_rate,
+            };
+            let proxy_path = app_data.config.proxy.build_path(&key, &params);
+            if proxy::is_fresh(&proxy_path, modified_time) {
+                return Ok(());
+            }
+            let canonical_path = app_data
+                .store
+                .local_path(&key)
+                .map_err(|err| err.to_string())?;
+            proxy::generate(
+                &canonical_path,
+                &proxy_path,
+                &params,
+                &app_data.config.transcode,
+            )
+            .map_err(|err| err.to_string())
+        }
+    }
+}
+
+/// Runs `convert` unless its output `name` is cached already, e.g. by a request that did not
+/// wait for the queue.
+fn generate(
+    app_data: &AppData,
+    key: &FileKey,
+    modified_time: SystemTime,
+    name: &str,
+    convert: impl Convert,
+) -> Result<(), String> {
+    if app_data
+        .cache
+        .get(&cache::CacheKey::new(key, modified_time, name))
+        .is_some()
+    {
+        return Ok(());
+    }
+    convert_once(app_data, key, modified_time, name, convert)
+        .map(|_| ())
+        .map_err(|err| err.to_string())
+}
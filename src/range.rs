@@ -0,0 +1,236 @@
+//! `Range` と `If-Range`。
+//!
+//! actix-files の `NamedFile` は `If-Range` を見ずに常に部分を返すので、元ファイルが更新された後に
+//! 途中から再開したダウンロードが新旧の混ざったファイルになる。ここで判定してから返す。
+//! 複数の範囲を指定された場合は `NamedFile` と同じく最初の範囲だけを返す。
+use actix_files::HttpRange;
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::http::{header, StatusCode};
+use actix_web::rt::task::JoinHandle;
+use actix_web::web::Bytes;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder};
+use std::fs::File;
+use std::future::Future;
+use std::io::{Read, Seek, SeekFrom};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::SystemTime;
+
+/// 一度にブロッキングプールで読む大きさ
+const CHUNK_SIZE: u64 = 256 * 1024;
+
+enum Selection {
+    Full,
+    Partial(HttpRange),
+    Unsatisfiable,
+}
+
+/// `If-Range` が無いか、今の版を指しているか。日付は `Last-Modified` と秒単位で一致したときだけ
+fn if_range_matches(
+    req: &HttpRequest,
+    etag: &header::EntityTag,
+    modified_time: SystemTime,
+) -> bool {
+    match req.get_header::<header::IfRange>() {
+        None => true,
+        Some(header::IfRange::EntityTag(tag)) => tag.strong_eq(etag),
+        Some(header::IfRange::Date(date)) => {
+            crate::secs(SystemTime::from(date)) == crate::secs(modified_time)
+        }
+    }
+}
+
+fn select(
+    req: &HttpRequest,
+    len: u64,
+    etag: &header::EntityTag,
+    modified_time: SystemTime,
+) -> Selection {
+    // bytes 以外の単位や、版が変わった後の再開は全体を返す
+    let Some(range) = req
+        .headers()
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| value.starts_with("bytes="))
+    else {
+        return Selection::Full;
+    };
+    if !if_range_matches(req, etag, modified_time) {
+        return Selection::Full;
+    }
+    match HttpRange::parse(range, len) {
+        Ok(ranges) => match ranges.first() {
+            Some(range) if range.start == 0 && range.length == len => Selection::Full,
+            Some(range) => Selection::Partial(*range),
+            None => Selection::Full,
+        },
+        Err(_) => Selection::Unsatisfiable,
+    }
+}
+
+fn builder(
+    status: StatusCode,
+    content_type: &str,
+    etag: &header::EntityTag,
+    modified_time: SystemTime,
+) -> HttpResponseBuilder {
+    let mut builder = HttpResponse::build(status);
+    builder
+        .content_type(content_type)
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+        .insert_header(header::LastModified(modified_time.into()))
+        .insert_header(header::ETag(etag.clone()));
+    builder
+}
+
+/// Responds with `file` of `len` bytes, or the part of it the request asks for.
+pub fn file_response(
+    req: &HttpRequest,
+    mut file: File,
+    len: u64,
+    content_type: &str,
+    etag: &header::EntityTag,
+    modified_time: SystemTime,
+) -> std::io::Result<HttpResponse> {
+    reader_response(req, len, content_type, etag, modified_time, |start, _| {
+        file.seek(SeekFrom::Start(start))?;
+        Ok(Box::new(file))
+    })
+}
+
+/// Responds with a source of `len` bytes, or the part of it the request asks for. `open` is
+/// given the start and length of the part and returns a reader of it.
+pub fn reader_response(
+    req: &HttpRequest,
+    len: u64,
+    content_type: &str,
+    etag: &header::EntityTag,
+    modified_time: SystemTime,
+    open: impl FnOnce(u64, u64) -> std::io::Result<Box<dyn Read + Send>>,
+) -> std::io::Result<HttpResponse> {
+    let (status, start, length) = match select(req, len, etag, modified_time) {
+        Selection::Full => (StatusCode::OK, 0, len),
+        Selection::Partial(range) => (StatusCode::PARTIAL_CONTENT, range.start, range.length),
+        Selection::Unsatisfiable => {
+            return Ok(unsatisfiable(len, content_type, etag, modified_time))
+        }
+    };
+    let reader = open(start, length)?;
+    let mut response = builder(status, content_type, etag, modified_time);
+    if status == StatusCode::PARTIAL_CONTENT {
+        response.insert_header(content_range(start, length, len));
+    }
+    Ok(response.body(ReaderBody {
+        remaining: length,
+        state: ReadState::Idle(reader),
+    }))
+}
+
+/// Responds with `data`, or the part of it the request asks for.
+pub fn bytes_response(
+    req: &HttpRequest,
+    data: Bytes,
+    content_type: &str,
+    etag: &header::EntityTag,
+    modified_time: SystemTime,
+) -> HttpResponse {
+    let len = data.len() as u64;
+    match select(req, len, etag, modified_time) {
+        Selection::Full => builder(StatusCode::OK, content_type, etag, modified_time).body(data),
+        Selection::Partial(range) => {
+            let start = range.start as usize;
+            builder(
+                StatusCode::PARTIAL_CONTENT,
+                content_type,
+                etag,
+                modified_time,
+            )
+            .insert_header(content_range(range.start, range.length, len))
+            .body(data.slice(start..start + range.length as usize))
+        }
+        Selection::Unsatisfiable => unsatisfiable(len, content_type, etag, modified_time),
+    }
+}
+
+fn content_range(start: u64, length: u64, len: u64) -> (header::HeaderName, String) {
+    (
+        header::CONTENT_RANGE,
+        format!("bytes {}-{}/{}", start, start + length - 1, len),
+    )
+}
+
+fn unsatisfiable(
+    len: u64,
+    content_type: &str,
+    etag: &header::EntityTag,
+    modified_time: SystemTime,
+) -> HttpResponse {
+    builder(
+        StatusCode::RANGE_NOT_SATISFIABLE,
+        content_type,
+        etag,
+        modified_time,
+    )
+    .insert_header((header::CONTENT_RANGE, format!("bytes */{}", len)))
+    .finish()
+}
+
+/// Streams `remaining` bytes from a reader, reading each chunk on the blocking pool so that
+/// slow disks and object storage do not hold up the worker.
+struct ReaderBody {
+    remaining: u64,
+    state: ReadState,
+}
+
+enum ReadState {
+    Idle(Box<dyn Read + Send>),
+    Reading(JoinHandle<std::io::Result<(Box<dyn Read + Send>, Bytes)>>),
+    Done,
+}
+
+impl MessageBody for ReaderBody {
+    type Error = std::io::Error;
+
+    fn size(&self) -> BodySize {
+        BodySize::Sized(self.remaining)
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.get_mut();
+        loop {
+            match &mut this.state {
+                ReadState::Idle(_) if this.remaining == 0 => return Poll::Ready(None),
+                ReadState::Idle(_) => {
+                    let ReadState::Idle(mut reader) =
+                        std::mem::replace(&mut this.state, ReadState::Done)
+                    else {
+                        unreachable!();
+                    };
+                    let len = this.remaining.min(CHUNK_SIZE) as usize;
+                    this.state =
+                        ReadState::Reading(actix_web::rt::task::spawn_blocking(move || {
+                            let mut buf = vec![0; len];
+                            reader.read_exact(&mut buf)?;
+                            Ok((reader, Bytes::from(buf)))
+                        }));
+                }
+                ReadState::Reading(handle) => {
+                    let result = ready!(Pin::new(handle).poll(cx));
+                    this.state = ReadState::Done;
+                    let (reader, chunk) = match result {
+                        Ok(Ok(read)) => read,
+                        Ok(Err(err)) => return Poll::Ready(Some(Err(err))),
+                        Err(err) => return Poll::Ready(Some(Err(std::io::Error::other(err)))),
+                    };
+                    this.remaining -= chunk.len() as u64;
+                    this.state = ReadState::Idle(reader);
+                    return Poll::Ready(Some(Ok(chunk)));
+                }
+                ReadState::Done => return Poll::Ready(None),
+            }
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use image::{DynamicImage, ImageFormat};
+use std::path::Path;
+
+pub const RAW_EXTENSIONS: &[&str] = &["cr2", "nef", "arw", "dng"];
+
+const TAG_COMPRESSION: u16 = 0x0103;
+const TAG_PHOTOMETRIC: u16 = 0x0106;
+const TAG_STRIP_OFFSETS: u16 = 0x0111;
+const TAG_STRIP_BYTE_COUNTS: u16 = 0x0117;
+const TAG_SUB_IFDS: u16 = 0x014a;
+const TAG_JPEG_OFFSET: u16 = 0x0201;
+const TAG_JPEG_LENGTH: u16 = 0x0202;
+
+const PHOTOMETRIC_CFA: u32 = 32803;
+const PHOTOMETRIC_LINEAR_RAW: u32 = 34892;
+
+const MAX_IFDS: usize = 32;
+
+/// CR2/NEF/ARW/DNG はいずれも TIFF ベースで、カメラが生成したプレビュー JPEG を埋め込んでいる。
+/// まずそれを使い、見つからなければ (`raw` feature 有効時のみ) RAW データを現像する。
+pub fn load_image_from_raw(path: &Path) -> Result<DynamicImage, anyhow::Error> {
+    let bytes = std::fs::read(path)?;
+    for (offset, length) in find_previews(&bytes) {
+        let Some(jpeg) = bytes.get(offset..offset + length) else {
+            continue;
+        };
+        if !jpeg.starts_with(&[0xff, 0xd8]) {
+            continue;
+        }
+        // CR2 の RAW 本体は lossless JPEG なので、デコードに失敗したら次の候補へ
+        match image::load_from_memory_with_format(jpeg, ImageFormat::Jpeg) {
+            Ok(img) => return Ok(img),
+            Err(err) => log::debug!("Skipping embedded JPEG at {}: {}", offset, err),
+        }
+    }
+
+    develop(path)
+}
+
+#[cfg(feature = "raw")]
+fn develop(path: &Path) -> Result<DynamicImage, anyhow::Error> {
+    use anyhow::Context;
+    use rawloader::RawImageData;
+
+    let raw = rawloader::decode_file(path).map_err(|err| anyhow::anyhow!("{}", err))?;
+    anyhow::ensure!(raw.cpp == 1, "Unsupported RAW layout: cpp={}", raw.cpp);
+    let data: Vec<f32> = match raw.data {
+        RawImageData::Integer(data) => data.into_iter().map(f32::from).collect(),
+        RawImageData::Float(data) => data,
+    };
+
+    // サムネイル用途なので 2x2 のベイヤーブロックを 1 画素にまとめる (half-size demosaic)
+    let [top, right, bottom, left] = raw.crops;
+    let width = (raw.width - left - right) / 2;
+    let height = (raw.height - top - bottom) / 2;
+    let wb = if raw.wb_coeffs[1].is_finite() && raw.wb_coeffs[1] > 0.0 {
+        raw.wb_coeffs.map(|c| {
+            if c.is_finite() {
+                c / raw.wb_coeffs[1]
+            } else {
+                1.0
+            }
+        })
+    } else {
+        [1.0; 4]
+    };
+
+    let mut pixels = Vec::with_capacity(width * height * 3);
+    for y in 0..height {
+        for x in 0..width {
+            let mut sum = [0.0f32; 3];
+            let mut count = [0u32; 3];
+            for (dy, dx) in [(0, 0), (0, 1), (1, 0), (1, 1)] {
+                let row = top + y * 2 + dy;
+                let col = left + x * 2 + dx;
+                let color = raw.cfa.color_at(row, col);
+                let black = f32::from(raw.blacklevels[color]);
+                let white = f32::from(raw.whitelevels[color]);
+                let value = (data[row * raw.width + col] - black) / (white - black).max(1.0);
+                // 4 色目 (E) は G として扱う
+                let channel = color.min(2);
+                sum[channel] += value * wb[color];
+                count[channel] += 1;
+            }
+            for (s, n) in sum.iter().zip(count) {
+                let linear = if n > 0 { s / n as f32 } else { 0.0 };
+                pixels.push((linear.clamp(0.0, 1.0).powf(1.0 / 2.2) * 255.0).round() as u8);
+            }
+        }
+    }
+
+    let img = image::RgbImage::from_raw(width as u32, height as u32, pixels)
+        .context("Failed to build ImageBuffer")?;
+    Ok(DynamicImage::ImageRgb8(img))
+}
+
+#[cfg(not(feature = "raw"))]
+fn develop(_path: &Path) -> Result<DynamicImage, anyhow::Error> {
+    anyhow::bail!("No embedded preview found (build with the raw feature to develop RAW data)")
+}
+
+struct TiffReader<'a> {
+    bytes: &'a [u8],
+    little_endian: bool,
+}
+
+impl TiffReader<'_> {
+    fn u16(&self, offset: usize) -> Option<u16> {
+        let b: [u8; 2] = self.bytes.get(offset..offset + 2)?.try_into().ok()?;
+        Some(if self.little_endian {
+            u16::from_le_bytes(b)
+        } else {
+            u16::from_be_bytes(b)
+        })
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        let b: [u8; 4] = self.bytes.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if self.little_endian {
+            u32::from_le_bytes(b)
+        } else {
+            u32::from_be_bytes(b)
+        })
+    }
+
+    /// SHORT/LONG の値を読む。4 バイトに収まらない場合は値の位置をオフセットとして辿る。
+    fn values(&self, entry: usize) -> Vec<u32> {
+        let (Some(type_), Some(count)) = (self.u16(entry + 2), self.u32(entry + 4)) else {
+            return Vec::new();
+        };
+        let size = match type_ {
+            3 => 2,
+            4 | 13 => 4,
+            _ => return Vec::new(),
+        };
+        let count = count as usize;
+        let base = if size * count <= 4 {
+            entry + 8
+        } else {
+            match self.u32(entry + 8) {
+                Some(offset) => offset as usize,
+                None => return Vec::new(),
+            }
+        };
+        (0..count.min(1024))
+            .map_while(|i| match size {
+                2 => self.u16(base + i * 2).map(u32::from),
+                _ => self.u32(base + i * 4),
+            })
+            .collect()
+    }
+}
+
+/// 埋め込み JPEG の (offset, length) を大きい順に返す。
+fn find_previews(bytes: &[u8]) -> Vec<(usize, usize)> {
+    let little_endian = match bytes.get(0..2) {
+        Some(b"II") => true,
+        Some(b"MM") => false,
+        _ => return Vec::new(),
+    };
+    let reader = TiffReader {
+        bytes,
+        little_endian,
+    };
+    if reader.u16(2) != Some(42) {
+        return Vec::new();
+    }
+
+    let mut previews = Vec::new();
+    let mut queue: Vec<usize> = reader.u32(4).map(|o| o as usize).into_iter().collect();
+    let mut visited = Vec::new();
+    while let Some(ifd) = queue.pop() {
+        if ifd == 0 || visited.contains(&ifd) || visited.len() >= MAX_IFDS {
+            continue;
+        }
+        visited.push(ifd);
+        let Some(entries) = reader.u16(ifd) else {
+            continue;
+        };
+
+        let mut jpeg_offset = None;
+        let mut jpeg_length = None;
+        let mut compression = None;
+        let mut photometric = None;
+        let mut strip_offsets = Vec::new();
+        let mut strip_byte_counts = Vec::new();
+        for i in 0..entries as usize {
+            let entry = ifd + 2 + i * 12;
+            let Some(tag) = reader.u16(entry) else {
+                break;
+            };
+            let values = reader.values(entry);
+            match tag {
+                TAG_JPEG_OFFSET => jpeg_offset = values.first().copied(),
+                TAG_JPEG_LENGTH => jpeg_length = values.first().copied(),
+                TAG_COMPRESSION => compression = values.first().copied(),
+                TAG_PHOTOMETRIC => photometric = values.first().copied(),
+                TAG_STRIP_OFFSETS => strip_offsets = values,
+                TAG_STRIP_BYTE_COUNTS => strip_byte_counts = values,
+                TAG_SUB_IFDS => queue.extend(values.into_iter().map(|o| o as usize)),
+                _ => {}
+            }
+        }
+
+        if let (Some(offset), Some(length)) = (jpeg_offset, jpeg_length) {
+            previews.push((offset as usize, length as usize));
+        }
+        let is_raw_data = matches!(
+            photometric,
+            Some(PHOTOMETRIC_CFA) | Some(PHOTOMETRIC_LINEAR_RAW)
+        );
+        if matches!(compression, Some(6) | Some(7))
+            && !is_raw_data
+            && strip_offsets.len() == 1
+            && strip_byte_counts.len() == 1
+        {
+            previews.push((strip_offsets[0] as usize, strip_byte_counts[0] as usize));
+        }
+
+        if let Some(next) = reader.u32(ifd + 2 + entries as usize * 12) {
+            queue.push(next as usize);
+        }
+    }
+
+    previews.sort_by_key(|&(_, length)| std::cmp::Reverse(length));
+    previews.dedup();
+    previews
+}
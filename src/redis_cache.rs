@@ -0,0 +1,195 @@
+//! 複数インスタンスで生成結果を共有する Redis キャッシュ (`redis` feature)。
+//!
+//! ロードバランサの後ろの別インスタンスが作ったサムネイルを使い回す。大きい出力ほど
+//! メモリを食うので、サイズ区分ごとに TTL を決め、どの区分にも入らないものは置かない。
+use crate::cache::{self, CacheKey, CacheStats, Cached, Counters, ThumbnailCache};
+use actix_web::web::Bytes;
+use clap::Parser;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// 使い終わった接続を取っておく数
+const MAX_IDLE_CONNECTIONS: usize = 8;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+/// `clear` で 1 回の SCAN が返すキーの目安
+const SCAN_COUNT: usize = 1000;
+
+#[derive(Parser)]
+pub struct RedisCacheOption {
+    /// Redis URL of the shared cache, e.g. `redis://cache.local:6379/0`. Disabled when omitted
+    #[arg(long)]
+    redis_url: Option<String>,
+
+    /// Prefix of the keys written to Redis
+    #[arg(long, default_value = "media_converter:")]
+    redis_key_prefix: String,
+
+    /// `MAX_SIZE:TTL`, e.g. `64K:30d`. Outputs are stored with the TTL of the smallest class
+    /// they fit in; larger ones are not stored
+    #[arg(long = "redis-size-class", default_values = ["64K:30d", "1M:1d"])]
+    redis_size_classes: Vec<SizeClass>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SizeClass {
+    max_bytes: u64,
+    ttl: Duration,
+}
+
+impl FromStr for SizeClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (size, ttl) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected MAX_SIZE:TTL, got {}", s))?;
+        Ok(SizeClass {
+            max_bytes: cache::parse_size(size)?,
+            ttl: cache::parse_duration(ttl)?,
+        })
+    }
+}
+
+pub struct RedisCache {
+    client: redis::Client,
+    idle: Mutex<Vec<redis::Connection>>,
+    prefix: String,
+    size_classes: Vec<SizeClass>,
+    counters: Counters,
+}
+
+impl RedisCache {
+    pub fn new(option: &RedisCacheOption) -> Result<Option<Self>, redis::RedisError> {
+        let Some(url) = &option.redis_url else {
+            return Ok(None);
+        };
+        let mut size_classes = option.redis_size_classes.clone();
+        size_classes.sort_by_key(|class| class.max_bytes);
+        Ok(Some(RedisCache {
+            client: redis::Client::open(url.as_str())?,
+            idle: Mutex::new(Vec::new()),
+            prefix: option.redis_key_prefix.clone(),
+            size_classes,
+            counters: Counters::default(),
+        }))
+    }
+
+    /// Runs `command` on a pooled connection. Failures are logged and treated as a miss so
+    /// that a Redis outage only costs the conversion.
+    fn with_connection<T>(
+        &self,
+        command: impl FnOnce(&mut redis::Connection) -> redis::RedisResult<T>,
+    ) -> Option<T> {
+        let idle = self.idle.lock().unwrap().pop();
+        let mut connection = match idle {
+            Some(connection) => connection,
+            None => self
+                .client
+                .get_connection_with_timeout(CONNECT_TIMEOUT)
+                .inspect_err(|err| log::warn!("Failed to connect to Redis: {}", err))
+                .ok()?,
+        };
+        let result = command(&mut connection)
+            .inspect_err(|err| log::warn!("Redis command failed: {}", err))
+            .ok()?;
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < MAX_IDLE_CONNECTIONS {
+            idle.push(connection);
+        }
+        Some(result)
+    }
+
+    fn redis_key(&self, key: &CacheKey) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    fn size_class(&self, len: u64) -> Option<&SizeClass> {
+        self.size_classes
+            .iter()
+            .find(|class| len <= class.max_bytes)
+    }
+}
+
+impl ThumbnailCache for RedisCache {
+    fn name(&self) -> &'static str {
+        "redis"
+    }
+
+    fn accepts(&self, len: u64) -> bool {
+        self.size_class(len).is_some()
+    }
+
+    fn get(&self, key: &CacheKey) -> Option<Cached> {
+        let redis_key = self.redis_key(key);
+        let data = self
+            .with_connection(|connection| {
+                redis::cmd("GET")
+                    .arg(&redis_key)
+                    .query::<Option<Vec<u8>>>(connection)
+            })
+            .flatten();
+        self.counters
+            .record(data.map(|data| Cached::Bytes(Bytes::from(data))))
+    }
+
+    fn put(&self, key: &CacheKey, data: &[u8]) {
+        let Some(class) = self.size_class(data.len() as u64) else {
+            return;
+        };
+        let redis_key = self.redis_key(key);
+        self.with_connection(|connection| {
+            redis::cmd("SET")
+                .arg(&redis_key)
+                .arg(data)
+                .arg("EX")
+                .arg(class.ttl.as_secs().max(1))
+                .query::<()>(connection)
+        });
+    }
+
+    fn evict(&self, key: &CacheKey) {
+        let redis_key = self.redis_key(key);
+        let removed = self.with_connection(|connection| {
+            redis::cmd("DEL").arg(&redis_key).query::<u64>(connection)
+        });
+        if removed.is_some_and(|count| count > 0) {
+            self.counters.evicted();
+        }
+    }
+
+    /// `--redis-key-prefix` の付いたキーを全部消す。他のインスタンスの分も消える
+    fn clear(&self) -> u64 {
+        let pattern = format!("{}*", self.prefix);
+        let mut cursor = 0_u64;
+        let mut removed = 0;
+        while let Some((next, keys)) = self.with_connection(|connection| {
+            redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(&pattern)
+                .arg("COUNT")
+                .arg(SCAN_COUNT)
+                .query::<(u64, Vec<String>)>(connection)
+        }) {
+            if !keys.is_empty() {
+                removed += self
+                    .with_connection(|connection| {
+                        redis::cmd("DEL").arg(&keys).query::<u64>(connection)
+                    })
+                    .unwrap_or(0);
+            }
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        self.counters.evicted_many(removed);
+        removed
+    }
+
+    /// 件数や容量は他のインスタンスの分も含むので数えない
+    fn stats(&self) -> CacheStats {
+        self.counters.stats(None, None)
+    }
+}
@@ -0,0 +1,356 @@
+//! `--s3-bucket`: S3 互換のオブジェクトストレージから元ファイルを読む。
+//!
+//! オブジェクトは `{--s3-prefix}{先頭 2 文字}/{key}.{ext}` と NAS と同じ構成で置く。MinIO などでも
+//! 動くようにパス形式の URL を使い、署名は SigV4 で `UNSIGNED-PAYLOAD` にする。認証情報は
+//! `AWS_ACCESS_KEY_ID` と `AWS_SECRET_ACCESS_KEY`（あれば `AWS_SESSION_TOKEN`）から読む。
+use crate::media_store::{LocalSource, MediaStore, MetadataCache, SourceMetadata, Staging};
+use crate::FileKey;
+use clap::Parser;
+use sha2::{Digest, Sha256};
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+#[derive(Parser)]
+pub struct S3Option {
+    /// Read the sources from this S3 bucket instead of the base path, which then holds local
+    /// copies of them
+    #[arg(long)]
+    s3_bucket: Option<String>,
+
+    /// Prefix of the source objects in the bucket (e.g. `originals/`)
+    #[arg(long, default_value = "")]
+    s3_prefix: String,
+
+    /// Endpoint of S3-compatible storage such as MinIO. Defaults to AWS for `--s3-region`
+    #[arg(long)]
+    s3_endpoint: Option<String>,
+
+    #[arg(long, default_value = "us-east-1")]
+    s3_region: String,
+}
+
+struct Credentials {
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+
+pub struct S3Store {
+    agent: ureq::Agent,
+    /// `https://host[:port]`
+    endpoint: String,
+    host: String,
+    bucket: String,
+    prefix: String,
+    region: String,
+    credentials: Credentials,
+    metadata: MetadataCache,
+    staging: Staging,
+}
+
+impl S3Store {
+    /// `None` unless `--s3-bucket` is given. Copies are kept under `staging_dir`.
+    pub fn new(option: &S3Option, staging_dir: PathBuf) -> io::Result<Option<Self>> {
+        let Some(bucket) = &option.s3_bucket else {
+            return Ok(None);
+        };
+        let env = |name: &str| {
+            std::env::var(name).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not set", name))
+            })
+        };
+        let credentials = Credentials {
+            access_key: env("AWS_ACCESS_KEY_ID")?,
+            secret_key: env("AWS_SECRET_ACCESS_KEY")?,
+            session_token: env("AWS_SESSION_TOKEN").ok(),
+        };
+        let endpoint = option
+            .s3_endpoint
+            .clone()
+            .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", option.s3_region));
+        let endpoint = endpoint.trim_end_matches('/').to_string();
+        let host = endpoint
+            .split_once("://")
+            .map(|(_, rest)| rest)
+            .filter(|host| !host.is_empty() && !host.contains('/'))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid --s3-endpoint {}", endpoint),
+                )
+            })?
+            .to_string();
+        Ok(Some(S3Store {
+            agent: ureq::AgentBuilder::new().build(),
+            endpoint,
+            host,
+            bucket: bucket.clone(),
+            prefix: option.s3_prefix.clone(),
+            region: option.s3_region.clone(),
+            credentials,
+            metadata: MetadataCache::default(),
+            staging: Staging::new(staging_dir),
+        }))
+    }
+
+    fn object_name(&self, key: &FileKey) -> String {
+        format!(
+            "{}{}/{}",
+            self.prefix,
+            &key.hkey[..2],
+            key.build_filename().to_string_lossy()
+        )
+    }
+
+    /// A request signed with SigV4. `object` is the object name, empty for the bucket.
+    fn request(&self, method: &str, object: &str, query: &[(&str, &str)]) -> ureq::Request {
+        let now = chrono::Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = &amz_date[..8];
+        let mut path = format!("/{}", uri_encode(&self.bucket, true));
+        if !object.is_empty() {
+            path = format!("{}/{}", path, uri_encode(object, false));
+        }
+        let mut query: Vec<_> = query
+            .iter()
+            .map(|(name, value)| (uri_encode(name, true), uri_encode(value, true)))
+            .collect();
+        query.sort();
+        let canonical_query = query
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("&");
+        // 名前の順に並べる
+        let mut headers = vec![
+            ("host", self.host.as_str()),
+            ("x-amz-content-sha256", UNSIGNED_PAYLOAD),
+            ("x-amz-date", amz_date.as_str()),
+        ];
+        if let Some(token) = &self.credentials.session_token {
+            headers.push(("x-amz-security-token", token.as_str()));
+        }
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method,
+            path,
+            canonical_query,
+            headers
+                .iter()
+                .map(|(name, value)| format!("{}:{}\n", name, value))
+                .collect::<String>(),
+            signed_headers,
+            UNSIGNED_PAYLOAD
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let mut signing_key = hmac_sha256(
+            format!("AWS4{}", self.credentials.secret_key).as_bytes(),
+            date.as_bytes(),
+        );
+        for part in [self.region.as_str(), "s3", "aws4_request"] {
+            signing_key = hmac_sha256(&signing_key, part.as_bytes());
+        }
+        let signature = hex(&hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+
+        let url = if canonical_query.is_empty() {
+            format!("{}{}", self.endpoint, path)
+        } else {
+            format!("{}{}?{}", self.endpoint, path, canonical_query)
+        };
+        let mut request = self.agent.request(method, &url).set(
+            "Authorization",
+            &format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.credentials.access_key, scope, signed_headers, signature
+            ),
+        );
+        // Host は ureq が付ける
+        for (name, value) in &headers[1..] {
+            request = request.set(name, value);
+        }
+        request
+    }
+
+    /// Object names under `prefix`, following continuation tokens.
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", prefix)];
+            if let Some(token) = &token {
+                query.push(("continuation-token", token));
+            }
+            let body = self
+                .request("GET", "", &query)
+                .call()
+                .map_err(|err| to_io_error(err, &self.bucket))?
+                .into_string()?;
+            let document = roxmltree::Document::parse(&body).map_err(io::Error::other)?;
+            let child = |name: &str| {
+                document
+                    .root_element()
+                    .children()
+                    .find(|node| node.tag_name().name() == name)
+                    .and_then(|node| node.text())
+                    .map(str::to_string)
+            };
+            for contents in document
+                .root_element()
+                .children()
+                .filter(|node| node.tag_name().name() == "Contents")
+            {
+                if let Some(name) = contents
+                    .children()
+                    .find(|node| node.tag_name().name() == "Key")
+                    .and_then(|node| node.text())
+                {
+                    names.push(name.to_string());
+                }
+            }
+            token = child("NextContinuationToken");
+            if child("IsTruncated").as_deref() != Some("true") || token.is_none() {
+                return Ok(names);
+            }
+        }
+    }
+}
+
+impl MediaStore for S3Store {
+    fn name(&self) -> &'static str {
+        "s3"
+    }
+
+    fn metadata(&self, key: &FileKey) -> io::Result<SourceMetadata> {
+        self.metadata.get_or_fetch(key, || {
+            let object = self.object_name(key);
+            let response = self
+                .request("HEAD", &object, &[])
+                .call()
+                .map_err(|err| to_io_error(err, &object))?;
+            let len = response
+                .header("Content-Length")
+                .and_then(|len| len.parse().ok())
+                .ok_or_else(|| io::Error::other(format!("No Content-Length for {}", object)))?;
+            let modified = response
+                .header("Last-Modified")
+                .and_then(|date| httpdate::parse_http_date(date).ok())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            Ok(SourceMetadata { len, modified })
+        })
+    }
+
+    fn open(
+        &self,
+        key: &FileKey,
+        start: u64,
+        length: Option<u64>,
+    ) -> io::Result<Box<dyn Read + Send>> {
+        let object = self.object_name(key);
+        let mut request = self.request("GET", &object, &[]);
+        match length {
+            Some(0) => return Ok(Box::new(io::empty())),
+            Some(length) => {
+                request = request.set("Range", &format!("bytes={}-{}", start, start + length - 1));
+            }
+            None if start > 0 => request = request.set("Range", &format!("bytes={}-", start)),
+            None => {}
+        }
+        let response = request.call().map_err(|err| to_io_error(err, &object))?;
+        // 範囲を無視して全体を返された
+        if start > 0 && response.status() != 206 {
+            return Err(io::Error::other(format!(
+                "No partial content for {}",
+                object
+            )));
+        }
+        Ok(Box::new(response.into_reader()))
+    }
+
+    fn local_path(&self, key: &FileKey) -> io::Result<LocalSource> {
+        let metadata = self.metadata(key)?;
+        self.staging
+            .fetch(key, metadata, || self.open(key, 0, None))
+    }
+
+    fn keys(&self, prefix: &str) -> io::Result<Vec<FileKey>> {
+        // 先頭 2 文字が決まればそのディレクトリだけを列挙する
+        let list_prefix = match prefix.get(..2) {
+            Some(shard) => format!("{}{}/{}", self.prefix, shard, prefix),
+            None => format!("{}{}", self.prefix, prefix),
+        };
+        Ok(self
+            .list(&list_prefix)?
+            .iter()
+            .filter_map(|name| {
+                let (shard, name) = name.strip_prefix(&self.prefix)?.split_once('/')?;
+                let key = FileKey::parse(name).ok()?;
+                (key.hkey.starts_with(prefix) && key.hkey.starts_with(shard)).then_some(key)
+            })
+            .collect())
+    }
+}
+
+fn to_io_error(err: ureq::Error, object: &str) -> io::Error {
+    match err {
+        ureq::Error::Status(404, _) => {
+            io::Error::new(io::ErrorKind::NotFound, format!("{} not found", object))
+        }
+        ureq::Error::Status(status, _) => {
+            io::Error::other(format!("S3 returned {} for {}", status, object))
+        }
+        ureq::Error::Transport(transport) => io::Error::other(transport),
+    }
+}
+
+/// RFC 3986 の予約されていない文字以外をエンコードする。オブジェクト名の `/` は残す
+fn uri_encode(s: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    const BLOCK_SIZE: usize = 64;
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| block.map(|b| b ^ byte);
+    let inner = Sha256::new()
+        .chain_update(pad(0x36))
+        .chain_update(data)
+        .finalize();
+    Sha256::new()
+        .chain_update(pad(0x5c))
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
@@ -0,0 +1,60 @@
+use crate::FileKey;
+use clap::{Parser, ValueEnum};
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SidecarMode {
+    /// Do not persist generated derivatives
+    Off,
+    /// Write `{key}.{name}` next to the original
+    Adjacent,
+    /// Write into `--sidecar-dir` using the same `{prefix}/{key}` layout as the base path
+    Mirror,
+}
+
+#[derive(Parser)]
+pub struct SidecarOption {
+    #[arg(long, value_enum, default_value_t = SidecarMode::Off)]
+    sidecar_mode: SidecarMode,
+
+    #[arg(long, required_if_eq("sidecar_mode", "mirror"))]
+    sidecar_dir: Option<PathBuf>,
+}
+
+impl SidecarOption {
+    pub fn is_enabled(&self) -> bool {
+        self.sidecar_mode != SidecarMode::Off
+    }
+
+    pub fn build_path(&self, key: &FileKey, base_path: &Path, name: &str) -> Option<PathBuf> {
+        let root = match self.sidecar_mode {
+            SidecarMode::Off => return None,
+            SidecarMode::Adjacent => base_path,
+            SidecarMode::Mirror => self.sidecar_dir.as_deref()?,
+        };
+        // FileKey::parse は拡張子中の '.' を拒否するので、サイドカーが API から引かれることはない
+        let mut path = key.build_path(root).into_os_string();
+        path.push(format!(".{}", name));
+        Some(PathBuf::from(path))
+    }
+}
+
+pub fn write(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    // 他のコンシューマが書きかけのファイルを読まないよう rename で置き換える
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    std::fs::write(&tmp_path, data)?;
+    std::fs::rename(&tmp_path, path)
+}
+
+/// Inserts `variant` before the extension: `thumb.medium.webp` → `thumb.medium.cover.webp`.
+pub fn with_variant(name: &str, variant: &str) -> String {
+    match name.rsplit_once('.') {
+        Some((stem, ext)) => format!("{}.{}.{}", stem, variant, ext),
+        None => format!("{}.{}", name, variant),
+    }
+}
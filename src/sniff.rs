@@ -0,0 +1,92 @@
+//! 先頭のマジックバイトから中身の形式を推測する。
+//!
+//! 拡張子の無いキーや拡張子が実際の中身と食い違うファイルのために、ローダーの選択に使う
+//! 代表的な拡張子を返す。
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+const SNIFF_BYTES: u64 = 512;
+
+/// 判別できなければ `None`
+pub fn sniff_extension(path: &Path) -> Option<&'static str> {
+    let mut head = Vec::new();
+    File::open(path)
+        .ok()?
+        .take(SNIFF_BYTES)
+        .read_to_end(&mut head)
+        .ok()?;
+    sniff(&head)
+}
+
+fn sniff(head: &[u8]) -> Option<&'static str> {
+    let at = |offset: usize, magic: &[u8]| head.get(offset..offset + magic.len()) == Some(magic);
+
+    let ext = match head {
+        [0xff, 0xd8, 0xff, ..] => "jpg",
+        [0x89, b'P', b'N', b'G', ..] => "png",
+        [b'G', b'I', b'F', b'8', ..] => "gif",
+        [b'B', b'M', ..] => "bmp",
+        [b'I', b'I', 0x2a, 0x00, ..] | [b'M', b'M', 0x00, 0x2a, ..] => "tif",
+        [0xff, 0x0a, ..] => "jxl",
+        [0xff, 0x4f, 0xff, 0x51, ..] => "j2k",
+        [0x76, 0x2f, 0x31, 0x01, ..] => "exr",
+        [0x1a, 0x45, 0xdf, 0xa3, ..] => "mkv",
+        [b'I', b'D', b'3', ..] | [0xff, 0xfb | 0xf3 | 0xf2, ..] => "mp3",
+        _ if at(0, b"RIFF") && at(8, b"WEBP") => "webp",
+        _ if at(0, b"RIFF") && at(8, b"AVI ") => "avi",
+        _ if at(4, b"ftyp") => return sniff_ftyp(head),
+        _ if at(4, b"jP  ") => "jp2",
+        _ if at(4, b"JXL ") => "jxl",
+        _ if at(0, b"#?RADIANCE") || at(0, b"#?RGBE") => "hdr",
+        _ if at(0, b"8BPS") => "psd",
+        _ if at(0, b"gimp xcf ") => "xcf",
+        _ if at(0, b"CSFCHUNK") => "clip",
+        _ if at(0, b"%PDF-") => "pdf",
+        _ if at(0, b"fLaC") => "flac",
+        _ if at(0, b"OggS") => "ogg",
+        _ if at(0, b"FLV") => "flv",
+        _ if at(0, b"glTF") => "glb",
+        _ if at(0, b"PK\x03\x04") => return sniff_zip(head),
+        // MPEG-TS は 188 バイトごとに同期バイトが来る
+        _ if at(0, &[0x47]) && at(188, &[0x47]) => "ts",
+        _ if head.windows(4).any(|w| w == b"<svg") => "svg",
+        _ => return None,
+    };
+    Some(ext)
+}
+
+/// ISO BMFF は major brand で分ける
+fn sniff_ftyp(head: &[u8]) -> Option<&'static str> {
+    let ext = match head.get(8..12)? {
+        b"avif" | b"avis" => "avif",
+        b"heic" | b"heix" | b"mif1" | b"msf1" | b"hevc" => "heic",
+        b"qt  " => "mov",
+        b"M4A " => "m4a",
+        _ => "mp4",
+    };
+    Some(ext)
+}
+
+/// EPUB, ODF, KRA は最初のエントリが無圧縮の `mimetype`
+fn sniff_zip(head: &[u8]) -> Option<&'static str> {
+    if head.get(30..38)? != b"mimetype" {
+        return None;
+    }
+    let mimetype = &head[38..];
+    let starts_with = |prefix: &[u8]| mimetype.starts_with(prefix);
+    let ext = if starts_with(b"application/epub+zip") {
+        "epub"
+    } else if starts_with(b"application/x-krita") {
+        "kra"
+    } else if starts_with(b"application/vnd.oasis.opendocument.text") {
+        "odt"
+    } else if starts_with(b"application/vnd.oasis.opendocument.spreadsheet") {
+        "ods"
+    } else if starts_with(b"application/vnd.oasis.opendocument.presentation") {
+        "odp"
+    } else {
+        return None;
+    };
+    Some(ext)
+}
@@ -0,0 +1,51 @@
+//! エンコード結果の画質評価に使う SSIM。
+//!
+//! 輝度のみを 8x8 の重ならないブロックで計算して平均する簡易版。
+use image::GrayImage;
+
+const BLOCK: u32 = 8;
+const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+/// Mean SSIM of two images of the same size, 1.0 for identical images.
+pub fn ssim(a: &GrayImage, b: &GrayImage) -> f64 {
+    debug_assert_eq!(a.dimensions(), b.dimensions());
+    let (width, height) = a.dimensions();
+    let mut total = 0.0;
+    let mut blocks = 0;
+    for by in (0..height).step_by(BLOCK as usize) {
+        for bx in (0..width).step_by(BLOCK as usize) {
+            let bw = BLOCK.min(width - bx);
+            let bh = BLOCK.min(height - by);
+            total += block_ssim(a, b, bx, by, bw, bh);
+            blocks += 1;
+        }
+    }
+    if blocks == 0 {
+        1.0
+    } else {
+        total / blocks as f64
+    }
+}
+
+fn block_ssim(a: &GrayImage, b: &GrayImage, x0: u32, y0: u32, w: u32, h: u32) -> f64 {
+    let n = f64::from(w * h);
+    let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+    for y in y0..y0 + h {
+        for x in x0..x0 + w {
+            let va = f64::from(a.get_pixel(x, y)[0]);
+            let vb = f64::from(b.get_pixel(x, y)[0]);
+            sum_a += va;
+            sum_b += vb;
+            sum_aa += va * va;
+            sum_bb += vb * vb;
+            sum_ab += va * vb;
+        }
+    }
+    let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+    let var_a = sum_aa / n - mean_a * mean_a;
+    let var_b = sum_bb / n - mean_b * mean_b;
+    let cov = sum_ab / n - mean_a * mean_b;
+    ((2.0 * mean_a * mean_b + C1) * (2.0 * cov + C2))
+        / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2))
+}
@@ -35,3 +35,89 @@ impl OnlineStats {
         self.variance().sqrt()
     }
 }
+
+// P² algorithm (Jain & Chlamtac): estimates a single quantile without storing samples
+pub struct P2Quantile {
+    p: f64,
+    count: usize,
+    heights: [f64; 5],
+    positions: [f64; 5],
+    desired: [f64; 5],
+    increments: [f64; 5],
+}
+
+impl P2Quantile {
+    pub fn new(p: f64) -> Self {
+        P2Quantile {
+            p,
+            count: 0,
+            heights: [0.0; 5],
+            positions: [1.0, 2.0, 3.0, 4.0, 5.0],
+            desired: [1.0, 1.0 + 2.0 * p, 1.0 + 4.0 * p, 3.0 + 2.0 * p, 5.0],
+            increments: [0.0, p / 2.0, p, (1.0 + p) / 2.0, 1.0],
+        }
+    }
+
+    pub fn update(&mut self, value: f64) {
+        if self.count < 5 {
+            self.heights[self.count] = value;
+            self.count += 1;
+            if self.count == 5 {
+                self.heights.sort_by(|a, b| a.total_cmp(b));
+            }
+            return;
+        }
+        self.count += 1;
+
+        let q = &mut self.heights;
+        let k = if value < q[0] {
+            q[0] = value;
+            0
+        } else if value >= q[4] {
+            q[4] = value;
+            3
+        } else {
+            (0..4).rfind(|&i| q[i] <= value).unwrap_or(0)
+        };
+
+        for i in (k + 1)..5 {
+            self.positions[i] += 1.0;
+        }
+        for i in 0..5 {
+            self.desired[i] += self.increments[i];
+        }
+
+        for i in 1..4 {
+            let n = &mut self.positions;
+            let d = self.desired[i] - n[i];
+            if (d >= 1.0 && n[i + 1] - n[i] > 1.0) || (d <= -1.0 && n[i - 1] - n[i] < -1.0) {
+                let d = d.signum();
+                let parabolic = q[i]
+                    + d / (n[i + 1] - n[i - 1])
+                        * ((n[i] - n[i - 1] + d) * (q[i + 1] - q[i]) / (n[i + 1] - n[i])
+                            + (n[i + 1] - n[i] - d) * (q[i] - q[i - 1]) / (n[i] - n[i - 1]));
+                q[i] = if q[i - 1] < parabolic && parabolic < q[i + 1] {
+                    parabolic
+                } else {
+                    let j = if d > 0.0 { i + 1 } else { i - 1 };
+                    q[i] + d * (q[j] - q[i]) / (n[j] - n[i])
+                };
+                n[i] += d;
+            }
+        }
+    }
+
+    pub fn quantile(&self) -> f64 {
+        if self.count >= 5 {
+            return self.heights[2];
+        }
+        if self.count == 0 {
+            return 0.0;
+        }
+
+        // サンプルが少ないうちは厳密に計算する
+        let mut samples = self.heights[..self.count].to_vec();
+        samples.sort_by(|a, b| a.total_cmp(b));
+        samples[((self.count - 1) as f64 * self.p).round() as usize]
+    }
+}
@@ -0,0 +1,126 @@
+//! 動画プレーヤーのシークバー用のスプライト画像と、時刻と座標を対応付ける WebVTT。
+use crate::movie_keyframe::VideoSource;
+use crate::tonemap::{self, ToneMapOperator};
+use clap::Parser;
+use image::{DynamicImage, RgbImage};
+use std::fmt::Write;
+use std::path::Path;
+
+#[derive(Parser, Clone, Copy, Debug)]
+pub struct StoryboardOption {
+    /// Seconds between `/storyboard` tiles; widened so long videos fit in the tile limit
+    #[arg(long, default_value_t = 10.0)]
+    storyboard_interval: f64,
+
+    /// Longer side of each `/storyboard` tile
+    #[arg(long, default_value_t = 160)]
+    storyboard_tile_size: u32,
+
+    #[arg(long, default_value_t = 10)]
+    storyboard_columns: u32,
+
+    #[arg(long, default_value_t = 100)]
+    storyboard_max_tiles: u32,
+}
+
+/// タイルの並びと各タイルが表す時間
+pub struct Layout {
+    interval: f64,
+    duration: f64,
+    count: u32,
+    columns: u32,
+    tile: (u32, u32),
+}
+
+impl Layout {
+    /// Sprite sheet size in pixels.
+    pub fn sprite_size(&self) -> (u32, u32) {
+        let rows = self.count.div_ceil(self.columns);
+        (
+            self.tile.0 * self.columns.min(self.count),
+            self.tile.1 * rows,
+        )
+    }
+
+    fn position(&self, index: u32) -> (u32, u32) {
+        (
+            index % self.columns * self.tile.0,
+            index / self.columns * self.tile.1,
+        )
+    }
+
+    /// One cue per tile pointing at `sprite_url#xywh=`.
+    pub fn webvtt(&self, sprite_url: &str) -> String {
+        let mut vtt = String::from("WEBVTT\n");
+        for index in 0..self.count {
+            let start = self.interval * f64::from(index);
+            let end = (start + self.interval).min(self.duration);
+            let (x, y) = self.position(index);
+            let _ = write!(
+                vtt,
+                "\n{} --> {}\n{}#xywh={},{},{},{}\n",
+                vtt_timestamp(start),
+                vtt_timestamp(end),
+                sprite_url,
+                x,
+                y,
+                self.tile.0,
+                self.tile.1
+            );
+        }
+        vtt
+    }
+}
+
+fn vtt_timestamp(seconds: f64) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+/// Opens the video and lays out the tiles without decoding any frame, so the WebVTT is cheap.
+pub fn open(
+    path: &Path,
+    option: &StoryboardOption,
+) -> Result<(VideoSource, Layout), anyhow::Error> {
+    let source = VideoSource::open(path, Some(option.storyboard_tile_size), None)?;
+    let duration = source.duration();
+    anyhow::ensure!(duration > 0.0, "Unknown duration");
+    let max_tiles = option.storyboard_max_tiles.max(1);
+    let interval = option
+        .storyboard_interval
+        .max(duration / f64::from(max_tiles));
+    let count = ((duration / interval).ceil() as u32).clamp(1, max_tiles);
+    let layout = Layout {
+        interval,
+        duration,
+        count,
+        columns: option.storyboard_columns.max(1),
+        tile: source.size(),
+    };
+    Ok((source, layout))
+}
+
+/// 各タイルは担当する区間の先頭のフレーム
+pub fn render(
+    source: &mut VideoSource,
+    layout: &Layout,
+    tone_map: ToneMapOperator,
+) -> Result<DynamicImage, anyhow::Error> {
+    let (width, height) = layout.sprite_size();
+    let mut sprite = RgbImage::new(width, height);
+    for index in 0..layout.count {
+        let frame = tonemap::tone_map(
+            source.frame_at(layout.interval * f64::from(index))?,
+            tone_map,
+        );
+        let (x, y) = layout.position(index);
+        image::imageops::replace(&mut sprite, &frame.to_rgb8(), i64::from(x), i64::from(y));
+    }
+    Ok(DynamicImage::ImageRgb8(sprite))
+}
@@ -0,0 +1,228 @@
+//! 元ファイルから EXIF/XMP などのメタデータを取り除く (再エンコードせずにコンテナだけ組み直す)。
+//!
+//! 対応は JPEG, PNG, WebP のみ。色の再現に必要な ICC プロファイルと Adobe マーカーは残す。
+//! 画素は回さないので、EXIF は Orientation だけを入れた最小のものに置き換える。
+use anyhow::Context;
+
+/// Returns the sanitized file, or `None` when the format is not supported.
+pub fn strip_metadata(data: &[u8]) -> Result<Option<Vec<u8>>, anyhow::Error> {
+    match data {
+        [0xff, 0xd8, 0xff, ..] => strip_jpeg(data).map(Some),
+        [0x89, b'P', b'N', b'G', ..] => strip_png(data).map(Some),
+        _ if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") => {
+            strip_webp(data).map(Some)
+        }
+        _ => Ok(None),
+    }
+}
+
+const JPEG_SOS: u8 = 0xda;
+const JPEG_EOI: u8 = 0xd9;
+const JPEG_APP0: u8 = 0xe0;
+const JPEG_APP1: u8 = 0xe1;
+const JPEG_APP2: u8 = 0xe2;
+const JPEG_APP14: u8 = 0xee;
+const JPEG_COM: u8 = 0xfe;
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+
+/// JFIF (APP0), ICC プロファイル (APP2) と Adobe (APP14) 以外の APPn とコメントを落とす
+fn strip_jpeg(data: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(&data[..2]);
+    let mut pos = 2;
+    let mut has_exif = false;
+    loop {
+        // マーカー前のフィルバイトは読み飛ばす
+        while data.get(pos) == Some(&0xff) && data.get(pos + 1) == Some(&0xff) {
+            pos += 1;
+        }
+        let marker = *data.get(pos + 1).context("Truncated JPEG")?;
+        anyhow::ensure!(data[pos] == 0xff, "Broken JPEG marker at {}", pos);
+        if marker == JPEG_EOI {
+            out.extend_from_slice(&[0xff, JPEG_EOI]);
+            // EOI 以降に付け足されたデータ (MPF の追加画像など) も捨てる
+            return Ok(out);
+        }
+        let len = usize::from(u16::from_be_bytes([
+            *data.get(pos + 2).context("Truncated JPEG")?,
+            *data.get(pos + 3).context("Truncated JPEG")?,
+        ]));
+        let end = pos + 2 + len;
+        let segment = data.get(pos..end).context("Truncated JPEG segment")?;
+        let keep = match marker {
+            JPEG_APP0 | JPEG_APP14 => true,
+            JPEG_APP2 => segment
+                .get(4..)
+                .is_some_and(|p| p.starts_with(b"ICC_PROFILE\0")),
+            0xe1..=0xef | JPEG_COM => false,
+            _ => true,
+        };
+        if keep {
+            out.extend_from_slice(segment);
+        } else if marker == JPEG_APP1 && !has_exif {
+            if let Some(tiff) = segment
+                .get(4..)
+                .and_then(|p| p.strip_prefix(EXIF_HEADER))
+                .and_then(orientation_exif)
+            {
+                has_exif = true;
+                let len = u16::try_from(2 + EXIF_HEADER.len() + tiff.len())?;
+                out.extend_from_slice(&[0xff, JPEG_APP1]);
+                out.extend_from_slice(&len.to_be_bytes());
+                out.extend_from_slice(EXIF_HEADER);
+                out.extend_from_slice(&tiff);
+            }
+        }
+        pos = end;
+
+        if marker == JPEG_SOS {
+            // エントロピー符号化データは次のマーカー (RSTn とスタッフィングを除く) まで
+            let start = pos;
+            while pos + 1 < data.len() {
+                if data[pos] == 0xff && !matches!(data[pos + 1], 0x00 | 0xd0..=0xd7 | 0xff) {
+                    break;
+                }
+                pos += 1;
+            }
+            anyhow::ensure!(pos + 1 < data.len(), "Truncated JPEG scan");
+            out.extend_from_slice(&data[start..pos]);
+        }
+    }
+}
+
+const PNG_SIGNATURE_LEN: usize = 8;
+const PNG_METADATA_CHUNKS: &[&[u8; 4]] = &[b"tEXt", b"zTXt", b"iTXt", b"eXIf", b"tIME"];
+
+fn strip_png(data: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(data.get(..PNG_SIGNATURE_LEN).context("Truncated PNG")?);
+    let mut pos = PNG_SIGNATURE_LEN;
+    while pos < data.len() {
+        let header = data.get(pos..pos + 8).context("Truncated PNG chunk")?;
+        let len = u32::from_be_bytes(header[..4].try_into()?) as usize;
+        let kind = &header[4..8];
+        // length, type, data, CRC
+        let end = pos + 12 + len;
+        let chunk = data.get(pos..end).context("Truncated PNG chunk")?;
+        if !PNG_METADATA_CHUNKS.iter().any(|k| k.as_slice() == kind) {
+            out.extend_from_slice(chunk);
+        } else if kind == b"eXIf" {
+            if let Some(tiff) = orientation_exif(&chunk[8..8 + len]) {
+                let mut crc = flate2::Crc::new();
+                crc.update(b"eXIf");
+                crc.update(&tiff);
+                out.extend_from_slice(&u32::try_from(tiff.len())?.to_be_bytes());
+                out.extend_from_slice(b"eXIf");
+                out.extend_from_slice(&tiff);
+                out.extend_from_slice(&crc.sum().to_be_bytes());
+            }
+        }
+        pos = end;
+        if kind == b"IEND" {
+            break;
+        }
+    }
+    Ok(out)
+}
+
+const VP8X_FLAG_EXIF: u8 = 0x08;
+const VP8X_FLAG_XMP: u8 = 0x04;
+
+fn strip_webp(data: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    let mut body = Vec::with_capacity(data.len());
+    body.extend_from_slice(b"WEBP");
+    let mut pos = 12;
+    // VP8X のフラグを後から直すための位置
+    let mut vp8x_flags = None;
+    let mut has_exif = false;
+    while pos < data.len() {
+        let header = data.get(pos..pos + 8).context("Truncated WebP chunk")?;
+        let kind: [u8; 4] = header[..4].try_into()?;
+        let len = u32::from_le_bytes(header[4..8].try_into()?) as usize;
+        // 奇数長のチャンクは 1 バイト詰められる
+        let end = (pos + 8 + len + len % 2).min(data.len());
+        let chunk = data.get(pos..end).context("Truncated WebP chunk")?;
+        match &kind {
+            b"EXIF" if !has_exif => {
+                // 仕様では TIFF から始まるが、`Exif\0\0` を付けて書くソフトもある
+                let exif = chunk.get(8..8 + len).context("Truncated WebP chunk")?;
+                if let Some(tiff) = orientation_exif(exif.strip_prefix(EXIF_HEADER).unwrap_or(exif))
+                {
+                    has_exif = true;
+                    body.extend_from_slice(b"EXIF");
+                    body.extend_from_slice(&u32::try_from(tiff.len())?.to_le_bytes());
+                    body.extend_from_slice(&tiff);
+                }
+            }
+            b"EXIF" | b"XMP " => {}
+            b"VP8X" => {
+                let mut chunk = chunk.to_vec();
+                let flags = chunk.get_mut(8).context("Truncated VP8X chunk")?;
+                *flags &= !(VP8X_FLAG_EXIF | VP8X_FLAG_XMP);
+                vp8x_flags = Some(body.len() + 8);
+                body.extend_from_slice(&chunk);
+            }
+            _ => body.extend_from_slice(chunk),
+        }
+        pos = end;
+    }
+    if let Some(flags) = vp8x_flags.filter(|_| has_exif) {
+        body[flags] |= VP8X_FLAG_EXIF;
+    }
+
+    let mut out = Vec::with_capacity(body.len() + 8);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&u32::try_from(body.len())?.to_le_bytes());
+    out.extend_from_slice(&body);
+    Ok(out)
+}
+
+const EXIF_TAG_ORIENTATION: u16 = 0x0112;
+const TIFF_TYPE_SHORT: u16 = 3;
+
+/// A TIFF holding nothing but the Orientation of `tiff`, or `None` when it is upright or
+/// unreadable.
+fn orientation_exif(tiff: &[u8]) -> Option<Vec<u8>> {
+    let big_endian = match tiff.get(..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let u16_at = |pos: usize| {
+        let bytes = [*tiff.get(pos)?, *tiff.get(pos + 1)?];
+        Some(if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    };
+    let u32_at = |pos: usize| {
+        let bytes = tiff.get(pos..pos + 4)?.try_into().ok()?;
+        Some(if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    };
+    let ifd = usize::try_from(u32_at(4)?).ok()?;
+    let orientation = (0..usize::from(u16_at(ifd)?))
+        .map(|i| ifd + 2 + i * 12)
+        .find(|&entry| u16_at(entry) == Some(EXIF_TAG_ORIENTATION))
+        .filter(|&entry| u16_at(entry + 2) == Some(TIFF_TYPE_SHORT))
+        .and_then(|entry| u16_at(entry + 8))
+        .filter(|orientation| (2..=8).contains(orientation))?;
+
+    // ヘッダ、エントリ 1 つの IFD0、次の IFD は無し
+    let mut out = Vec::with_capacity(26);
+    out.extend_from_slice(b"MM\0\x2a");
+    out.extend_from_slice(&8u32.to_be_bytes());
+    out.extend_from_slice(&1u16.to_be_bytes());
+    out.extend_from_slice(&EXIF_TAG_ORIENTATION.to_be_bytes());
+    out.extend_from_slice(&TIFF_TYPE_SHORT.to_be_bytes());
+    out.extend_from_slice(&1u32.to_be_bytes());
+    out.extend_from_slice(&orientation.to_be_bytes());
+    out.extend_from_slice(&[0, 0]);
+    out.extend_from_slice(&0u32.to_be_bytes());
+    Some(out)
+}
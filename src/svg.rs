@@ -0,0 +1,57 @@
+use anyhow::Context;
+use image::{DynamicImage, RgbaImage};
+use resvg::{tiny_skia, usvg};
+use std::path::Path;
+use std::sync::Arc;
+
+pub const SVG_EXTENSIONS: &[&str] = &["svg", "svgz"];
+
+/// 要求サイズが無い場合 (/media) の長辺の上限
+const MAX_DIMENSION: u32 = 4096;
+
+pub fn load_system_fonts() -> Arc<usvg::fontdb::Database> {
+    let mut fontdb = usvg::fontdb::Database::new();
+    fontdb.load_system_fonts();
+    Arc::new(fontdb)
+}
+
+/// `target` に収まるサイズでレンダリングする。指定が無ければ SVG 自身のサイズを使う。
+pub fn load_image_from_svg(
+    path: &Path,
+    fontdb: Arc<usvg::fontdb::Database>,
+    target: Option<(u32, u32)>,
+) -> Result<DynamicImage, anyhow::Error> {
+    let data = std::fs::read(path)?;
+    let options = usvg::Options {
+        fontdb,
+        // 外部ファイルの参照は許可しない (data URL のみ)
+        image_href_resolver: usvg::ImageHrefResolver {
+            resolve_string: Box::new(|_, _| None),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let tree = usvg::Tree::from_data(&data, &options)?;
+
+    let size = tree.size();
+    let (max_width, max_height) = target.unwrap_or((MAX_DIMENSION, MAX_DIMENSION));
+    let scale = match target {
+        Some(_) => (max_width as f32 / size.width()).min(max_height as f32 / size.height()),
+        None => (max_width as f32 / size.width())
+            .min(max_height as f32 / size.height())
+            .min(1.0),
+    };
+    let width = ((size.width() * scale).round() as u32).max(1);
+    let height = ((size.height() * scale).round() as u32).max(1);
+
+    let mut pixmap = tiny_skia::Pixmap::new(width, height).context("Invalid SVG size")?;
+    resvg::render(
+        &tree,
+        tiny_skia::Transform::from_scale(scale, scale),
+        &mut pixmap.as_mut(),
+    );
+
+    let img = RgbaImage::from_raw(width, height, pixmap.take_demultiplied())
+        .context("Failed to build ImageBuffer")?;
+    Ok(DynamicImage::ImageRgba8(img))
+}
@@ -0,0 +1,73 @@
+use ab_glyph::{Font, FontVec, PxScale, ScaleFont};
+use anyhow::{Context, Result};
+use image::{DynamicImage, Rgb, RgbImage};
+use std::io::Read;
+use std::path::Path;
+
+const MAX_LINES: usize = 40;
+const MAX_COLUMNS: usize = 100;
+const MAX_READ_BYTES: u64 = 64 * 1024;
+const TAB_WIDTH: usize = 4;
+
+const FONT_SIZE: f32 = 16.0;
+const LINE_HEIGHT: u32 = 20;
+const PADDING: u32 = 12;
+
+const BACKGROUND: Rgb<u8> = Rgb([250, 250, 250]);
+const FOREGROUND: Rgb<u8> = Rgb([40, 40, 40]);
+
+pub const TEXT_EXTENSIONS: &[&str] = &[
+    "txt", "md", "log", "csv", "json", "toml", "yaml", "yml", "xml", "html", "css", "rs", "py",
+    "js", "ts", "c", "h", "cpp", "hpp", "go", "java", "rb", "sh",
+];
+
+pub fn load_image_from_text(path: &Path, font_path: &Path) -> Result<DynamicImage, anyhow::Error> {
+    let font_bytes = std::fs::read(font_path)
+        .with_context(|| format!("Failed to read font {}", font_path.display()))?;
+    let font = FontVec::try_from_vec(font_bytes).context("Failed to parse font")?;
+
+    // 先頭だけ読めば十分
+    let mut bytes = Vec::new();
+    std::fs::File::open(path)?
+        .take(MAX_READ_BYTES)
+        .read_to_end(&mut bytes)?;
+    let text = String::from_utf8_lossy(&bytes);
+
+    let lines: Vec<String> = text
+        .lines()
+        .take(MAX_LINES)
+        .map(|line| {
+            line.replace('\t', &" ".repeat(TAB_WIDTH))
+                .chars()
+                .take(MAX_COLUMNS)
+                .collect()
+        })
+        .collect();
+
+    let scale = PxScale::from(FONT_SIZE);
+    let advance = font.as_scaled(scale).h_advance(font.glyph_id('M'));
+    let columns = lines
+        .iter()
+        .map(|line| line.chars().count())
+        .max()
+        .unwrap_or(0)
+        .max(1);
+
+    let width = PADDING * 2 + (advance * columns as f32).ceil() as u32;
+    let height = PADDING * 2 + LINE_HEIGHT * lines.len().max(1) as u32;
+
+    let mut canvas = RgbImage::from_pixel(width, height, BACKGROUND);
+    for (i, line) in lines.iter().enumerate() {
+        imageproc::drawing::draw_text_mut(
+            &mut canvas,
+            FOREGROUND,
+            PADDING as i32,
+            (PADDING + LINE_HEIGHT * i as u32) as i32,
+            scale,
+            &font,
+            line,
+        );
+    }
+
+    Ok(DynamicImage::ImageRgb8(canvas))
+}
@@ -0,0 +1,72 @@
+use anyhow::Context;
+use image::{
+    DynamicImage, GrayAlphaImage, GrayImage, ImageBuffer, Luma, Rgb, RgbImage, Rgba, RgbaImage,
+};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::ColorType;
+
+pub const TIFF_EXTENSIONS: &[&str] = &["tif", "tiff"];
+
+/// 1 始まりの `page` ページ目をデコードする。ページが存在しなければ `Ok(None)`。
+pub fn load_image_from_tiff_page(
+    path: &Path,
+    page: u32,
+) -> Result<Option<DynamicImage>, anyhow::Error> {
+    let mut decoder = Decoder::new(BufReader::new(File::open(path)?))?;
+    for _ in 1..page {
+        if !decoder.more_images() {
+            return Ok(None);
+        }
+        decoder.next_image()?;
+    }
+
+    let (width, height) = decoder.dimensions()?;
+    let color_type = decoder.colortype()?;
+    let img = match (color_type, decoder.read_image()?) {
+        (ColorType::Gray(1), DecodingResult::U8(data)) => {
+            GrayImage::from_raw(width, height, unpack_bilevel(&data, width, height))
+                .map(DynamicImage::ImageLuma8)
+        }
+        (ColorType::Gray(8), DecodingResult::U8(data)) => {
+            GrayImage::from_raw(width, height, data).map(DynamicImage::ImageLuma8)
+        }
+        (ColorType::GrayA(8), DecodingResult::U8(data)) => {
+            GrayAlphaImage::from_raw(width, height, data).map(DynamicImage::ImageLumaA8)
+        }
+        (ColorType::RGB(8), DecodingResult::U8(data)) => {
+            RgbImage::from_raw(width, height, data).map(DynamicImage::ImageRgb8)
+        }
+        (ColorType::RGBA(8), DecodingResult::U8(data)) => {
+            RgbaImage::from_raw(width, height, data).map(DynamicImage::ImageRgba8)
+        }
+        (ColorType::Gray(16), DecodingResult::U16(data)) => {
+            ImageBuffer::<Luma<u16>, _>::from_raw(width, height, data)
+                .map(DynamicImage::ImageLuma16)
+        }
+        (ColorType::RGB(16), DecodingResult::U16(data)) => {
+            ImageBuffer::<Rgb<u16>, _>::from_raw(width, height, data).map(DynamicImage::ImageRgb16)
+        }
+        (ColorType::RGBA(16), DecodingResult::U16(data)) => {
+            ImageBuffer::<Rgba<u16>, _>::from_raw(width, height, data)
+                .map(DynamicImage::ImageRgba16)
+        }
+        (color_type, _) => anyhow::bail!("Unsupported TIFF color type: {:?}", color_type),
+    };
+    img.context("Failed to build ImageBuffer").map(Some)
+}
+
+/// 1bit (スキャンした文書に多い) は行ごとにバイト境界でパディングされている
+fn unpack_bilevel(data: &[u8], width: u32, height: u32) -> Vec<u8> {
+    let row_bytes = (width as usize).div_ceil(8);
+    let mut pixels = Vec::with_capacity(width as usize * height as usize);
+    for row in data.chunks(row_bytes).take(height as usize) {
+        for x in 0..width as usize {
+            let bit = row.get(x / 8).map_or(0, |byte| (byte >> (7 - x % 8)) & 1);
+            pixels.push(if bit == 1 { 255 } else { 0 });
+        }
+    }
+    pixels
+}
@@ -0,0 +1,107 @@
+//! 浮動小数点 (Radiance HDR, OpenEXR, HDR 動画のフレームなど) のリニアな HDR 画像を
+//! 8bit sRGB に落とす。
+use clap::ValueEnum;
+use image::{DynamicImage, Rgba32FImage, RgbaImage};
+
+/// 自動露出で平均輝度をこの値 (middle grey) に合わせる
+const KEY_VALUE: f32 = 0.18;
+
+/// Hable のカーブで白になる入力値と、カーブに入れる前の露出補正
+const HABLE_WHITE: f32 = 11.2;
+const HABLE_EXPOSURE_BIAS: f32 = 2.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ToneMapOperator {
+    /// Clamp to [0, 1] without exposure adjustment
+    Clip,
+    /// Extended Reinhard on luminance, preserves hue
+    Reinhard,
+    /// ACES filmic curve (Narkowicz fit), more contrast
+    Aces,
+    /// Hable's filmic curve (Uncharted 2), soft highlight roll-off. Suits HDR10/HLG video
+    Hable,
+}
+
+/// 8bit/16bit の画像はそのまま返す
+pub fn tone_map(img: DynamicImage, operator: ToneMapOperator) -> DynamicImage {
+    let has_alpha = match &img {
+        DynamicImage::ImageRgb32F(_) => false,
+        DynamicImage::ImageRgba32F(_) => true,
+        _ => return img,
+    };
+    let hdr = img.into_rgba32f();
+    let exposure = match operator {
+        ToneMapOperator::Clip => 1.0,
+        _ => KEY_VALUE / log_average_luminance(&hdr),
+    };
+    let white = max_luminance(&hdr) * exposure;
+
+    let mut out = RgbaImage::new(hdr.width(), hdr.height());
+    for (src, dst) in hdr.pixels().zip(out.pixels_mut()) {
+        let rgb = [src[0], src[1], src[2]].map(|c| c.max(0.0) * exposure);
+        let mapped = match operator {
+            ToneMapOperator::Clip => rgb,
+            ToneMapOperator::Reinhard => {
+                let l = luminance(rgb);
+                if l <= 0.0 {
+                    [0.0; 3]
+                } else {
+                    let mapped_l = l * (1.0 + l / (white * white)) / (1.0 + l);
+                    rgb.map(|c| c * mapped_l / l)
+                }
+            }
+            ToneMapOperator::Aces => rgb.map(aces),
+            ToneMapOperator::Hable => {
+                rgb.map(|c| hable(c * HABLE_EXPOSURE_BIAS) / hable(HABLE_WHITE))
+            }
+        };
+        let [r, g, b] = mapped.map(encode_srgb);
+        dst.0 = [r, g, b, (src[3].clamp(0.0, 1.0) * 255.0).round() as u8];
+    }
+
+    let rgba = DynamicImage::ImageRgba8(out);
+    if has_alpha {
+        rgba
+    } else {
+        DynamicImage::ImageRgb8(rgba.into_rgb8())
+    }
+}
+
+fn luminance([r, g, b]: [f32; 3]) -> f32 {
+    0.2126 * r + 0.7152 * g + 0.0722 * b
+}
+
+fn log_average_luminance(img: &Rgba32FImage) -> f32 {
+    let count = (img.width() as f64 * img.height() as f64).max(1.0);
+    let sum: f64 = img
+        .pixels()
+        .map(|p| f64::from(luminance([p[0], p[1], p[2]]).max(0.0) + 1e-4).ln())
+        .sum();
+    ((sum / count).exp() as f32).max(1e-4)
+}
+
+fn max_luminance(img: &Rgba32FImage) -> f32 {
+    img.pixels()
+        .map(|p| luminance([p[0], p[1], p[2]]))
+        .fold(1e-4, f32::max)
+}
+
+fn aces(x: f32) -> f32 {
+    let (a, b, c, d, e) = (2.51, 0.03, 2.43, 0.59, 0.14);
+    (x * (a * x + b)) / (x * (c * x + d) + e)
+}
+
+fn hable(x: f32) -> f32 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    (x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f) - e / f
+}
+
+fn encode_srgb(linear: f32) -> u8 {
+    let linear = linear.clamp(0.0, 1.0);
+    let encoded = if linear <= 0.003_130_8 {
+        linear * 12.92
+    } else {
+        1.055 * linear.powf(1.0 / 2.4) - 0.055
+    };
+    (encoded * 255.0).round() as u8
+}
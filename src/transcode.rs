@@ -0,0 +1,802 @@
+//! ffmpeg による動画の再多重化とトランスコード。
+//!
+//! ブラウザで再生できるコーデックのストリームはそのままコピーし、それ以外 (と縮小やビットレート
+//! 指定がある場合) だけデコードしてエンコードし直す。映像と音声はそれぞれ最良の 1 本だけを使う。
+use crate::jobs::{self, Stage};
+use anyhow::Context;
+use clap::Parser;
+use ffmpeg::codec;
+use ffmpeg::format::{Pixel, Sample};
+use ffmpeg::software::scaling::{context::Context as ScalingContext, flag::Flags};
+use ffmpeg::util::frame::audio::Audio as AudioFrame;
+use ffmpeg::util::frame::video::Video as VideoFrame;
+use ffmpeg::{ChannelLayout, Rational};
+use ffmpeg_next as ffmpeg;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Container {
+    Mp4,
+    /// MPEG-TS segments with a VOD playlist
+    Hls,
+    WebM,
+}
+
+impl Container {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Container::Mp4 => "video/mp4",
+            Container::Hls => "application/vnd.apple.mpegurl",
+            Container::WebM => "video/webm",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4",
+            Container::Hls => "m3u8",
+            Container::WebM => "webm",
+        }
+    }
+
+    fn muxer(self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4",
+            Container::Hls => "hls",
+            Container::WebM => "webm",
+        }
+    }
+
+    fn can_copy_video(self, id: codec::Id) -> bool {
+        match self {
+            // MP4 にコピーした HEVC は hev1 になり Safari で再生できない
+            Container::Mp4 => id == codec::Id::H264,
+            // MPEG-TS に入れた HEVC は Safari 以外で再生できない
+            Container::Hls => id == codec::Id::H264,
+            Container::WebM => matches!(id, codec::Id::VP8 | codec::Id::VP9 | codec::Id::AV1),
+        }
+    }
+
+    fn can_copy_audio(self, id: codec::Id) -> bool {
+        match self {
+            // MP4 に入れた MP3 は再生できないプレイヤーがある
+            Container::Mp4 => id == codec::Id::AAC,
+            Container::Hls => matches!(id, codec::Id::AAC | codec::Id::MP3),
+            Container::WebM => matches!(id, codec::Id::OPUS | codec::Id::VORBIS),
+        }
+    }
+
+    /// 優先順に試すエンコーダ名
+    fn video_encoders(self) -> &'static [&'static str] {
+        match self {
+            Container::Mp4 | Container::Hls => &["libx264", "h264"],
+            Container::WebM => &["libvpx-vp9", "libvpx"],
+        }
+    }
+
+    fn audio_encoders(self) -> &'static [&'static str] {
+        match self {
+            Container::Mp4 | Container::Hls => &["aac", "libfdk_aac"],
+            Container::WebM => &["libopus", "opus", "libvorbis"],
+        }
+    }
+}
+
+/// Encoder settings shared by the endpoints that transcode video.
+#[derive(Parser, Clone, Debug)]
+pub struct TranscodeOption {
+    /// libx264 preset used when video has to be re-encoded
+    #[arg(long, default_value = "veryfast")]
+    transcode_preset: String,
+
+    /// Constant rate factor used when no bit rate is requested
+    #[arg(long, default_value_t = 23)]
+    transcode_crf: u32,
+
+    /// Bits per second of re-encoded audio
+    #[arg(long, default_value_t = 128_000)]
+    transcode_audio_bit_rate: usize,
+}
+
+/// Returned (inside `anyhow::Error`) when a stream would need re-encoding but `allow_encode`
+/// is off.
+#[derive(Debug, thiserror::Error)]
+#[error("codec {0} cannot be copied into this container")]
+pub struct UnsupportedCodec(pub String);
+
+pub struct Job {
+    pub container: Container,
+    /// Downscale video taller than this
+    pub max_height: Option<u32>,
+    /// Bits per second of re-encoded video; CRF when `None`
+    pub video_bit_rate: Option<usize>,
+    /// `(start, end)` in seconds
+    pub range: Option<(f64, f64)>,
+    /// Re-encode streams that cannot be copied instead of failing with `UnsupportedCodec`
+    pub allow_encode: bool,
+    /// Private options of the muxer, e.g. `hls_time`
+    pub muxer_options: Vec<(String, String)>,
+}
+
+impl Job {
+    /// 縮小かビットレート指定があれば映像はコピーできない
+    fn must_encode_video(&self, height: u32) -> bool {
+        self.video_bit_rate.is_some() || self.max_height.is_some_and(|max| height > max)
+    }
+}
+
+pub fn run(
+    input: &Path,
+    output: &Path,
+    job: &Job,
+    option: &TranscodeOption,
+) -> Result<(), anyhow::Error> {
+    ffmpeg::init().ok(); // Ignore re-init
+
+    let mut ictx = ffmpeg::format::input(&input)?;
+    let mut octx = ffmpeg::format::output_as(&output, job.container.muxer())?;
+    let global_header = octx
+        .format()
+        .flags()
+        .contains(ffmpeg::format::Flags::GLOBAL_HEADER);
+
+    let mut streams: Vec<Option<StreamJob>> = (0..ictx.nb_streams()).map(|_| None).collect();
+    for kind in [ffmpeg::media::Type::Video, ffmpeg::media::Type::Audio] {
+        let Some(ist) = ictx.streams().best(kind) else {
+            continue;
+        };
+        let stream_job = StreamJob::new(&ist, &mut octx, job, option, global_header)?;
+        streams[ist.index()] = Some(stream_job);
+    }
+    anyhow::ensure!(
+        streams.iter().any(Option::is_some),
+        "No audio or video stream found"
+    );
+
+    let mut options = ffmpeg::Dictionary::new();
+    for (key, value) in &job.muxer_options {
+        options.set(key, value);
+    }
+    // 切り出し時にコピーしたキーフレームが開始より前になっても 0 始まりにする
+    options.set("avoid_negative_ts", "make_zero");
+    octx.write_header_with(options)?;
+    for stream_job in streams.iter_mut().flatten() {
+        let ost_time_base = octx
+            .stream(stream_job.ost_index())
+            .context("No output stream")?
+            .time_base();
+        stream_job.set_ost_time_base(ost_time_base);
+    }
+
+    if let Some((start, _)) = job.range {
+        let position = (start * crate::movie_keyframe::AV_TIME_BASE) as i64;
+        ictx.seek(position, ..=position)?;
+    }
+    let video_index = ictx
+        .streams()
+        .best(ffmpeg::media::Type::Video)
+        .map(|stream| stream.index());
+    let duration = (ictx.duration() as f64 / crate::movie_keyframe::AV_TIME_BASE).max(0.0);
+    let mut video_packets = 0;
+    for (stream, packet) in ictx.packets() {
+        let Some(stream_job) = streams.get_mut(stream.index()).and_then(Option::as_mut) else {
+            continue;
+        };
+        let past_end = stream_job.push(&packet, &mut octx)?;
+        if Some(stream.index()) == video_index {
+            video_packets += 1;
+            let seconds = packet
+                .pts()
+                .map(|pts| pts as f64 * f64::from(stream.time_base()));
+            jobs::report(
+                Stage::Transcoding,
+                Some(video_packets),
+                seconds.map(|seconds| (seconds, duration)),
+            );
+            // B フレームの並べ替えがあるので映像の dts が終わりを過ぎるまで読む
+            if past_end {
+                break;
+            }
+        }
+    }
+    for stream_job in streams.iter_mut().flatten() {
+        stream_job.finish(&mut octx)?;
+    }
+    octx.write_trailer()?;
+    Ok(())
+}
+
+/// Runs `job` into a temporary file and returns its contents, for outputs small enough to be
+/// answered in one response.
+pub fn run_to_vec(
+    input: &Path,
+    job: &Job,
+    option: &TranscodeOption,
+) -> Result<Vec<u8>, anyhow::Error> {
+    // MP4 の moov を書き戻すためにシークできる出力が要る
+    let temp_path = std::env::temp_dir().join(format!(
+        "media_converter-transcode-{}-{}.{}",
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed),
+        job.container.extension()
+    ));
+    let temp_path = scopeguard::guard(temp_path, |path| {
+        let _ = std::fs::remove_file(path);
+    });
+    run(input, &temp_path, job, option)?;
+    Ok(std::fs::read(&*temp_path)?)
+}
+
+fn find_encoder(names: &[&str]) -> Result<codec::Codec, anyhow::Error> {
+    names
+        .iter()
+        .find_map(|name| ffmpeg::encoder::find_by_name(name))
+        .with_context(|| format!("No encoder available: {}", names.join(", ")))
+}
+
+/// 入力の 1 ストリーム分の処理
+enum StreamJob {
+    Copy(CopyStream),
+    Video(Box<VideoTranscoder>),
+    Audio(Box<AudioTranscoder>),
+}
+
+/// 入力ストリームの時間範囲 (ストリームの time base 単位)
+#[derive(Clone, Copy)]
+struct Window {
+    time_base: Rational,
+    start: i64,
+    end: Option<i64>,
+}
+
+impl Window {
+    fn new(time_base: Rational, range: Option<(f64, f64)>) -> Self {
+        let to_ts = |seconds: f64| (seconds / f64::from(time_base)) as i64;
+        Window {
+            time_base,
+            start: range.map_or(0, |(start, _)| to_ts(start)),
+            end: range.map(|(_, end)| to_ts(end)),
+        }
+    }
+
+    fn is_before(&self, ts: i64) -> bool {
+        ts < self.start
+    }
+
+    fn is_after(&self, ts: i64) -> bool {
+        self.end.is_some_and(|end| ts > end)
+    }
+}
+
+impl StreamJob {
+    fn new(
+        ist: &ffmpeg::format::stream::Stream,
+        octx: &mut ffmpeg::format::context::Output,
+        job: &Job,
+        option: &TranscodeOption,
+        global_header: bool,
+    ) -> Result<Self, anyhow::Error> {
+        let parameters = ist.parameters();
+        let id = parameters.id();
+        let window = Window::new(ist.time_base(), job.range);
+        let context = codec::Context::from_parameters(parameters)?;
+        match ist.parameters().medium() {
+            ffmpeg::media::Type::Video => {
+                let decoder = context.decoder().video()?;
+                if job.container.can_copy_video(id) && !job.must_encode_video(decoder.height()) {
+                    return CopyStream::new(ist, octx, window, true).map(StreamJob::Copy);
+                }
+                if !job.allow_encode {
+                    return Err(UnsupportedCodec(id.name().to_string()).into());
+                }
+                let transcoder =
+                    VideoTranscoder::new(ist, decoder, octx, job, option, window, global_header)?;
+                Ok(StreamJob::Video(Box::new(transcoder)))
+            }
+            _ => {
+                if job.container.can_copy_audio(id) {
+                    return CopyStream::new(ist, octx, window, false).map(StreamJob::Copy);
+                }
+                if !job.allow_encode {
+                    return Err(UnsupportedCodec(id.name().to_string()).into());
+                }
+                let decoder = context.decoder().audio()?;
+                let transcoder =
+                    AudioTranscoder::new(decoder, octx, job, option, window, global_header)?;
+                Ok(StreamJob::Audio(Box::new(transcoder)))
+            }
+        }
+    }
+
+    fn ost_index(&self) -> usize {
+        match self {
+            StreamJob::Copy(copy) => copy.ost_index,
+            StreamJob::Video(video) => video.ost_index,
+            StreamJob::Audio(audio) => audio.ost_index,
+        }
+    }
+
+    fn set_ost_time_base(&mut self, time_base: Rational) {
+        match self {
+            StreamJob::Copy(copy) => copy.ost_time_base = time_base,
+            StreamJob::Video(video) => video.ost_time_base = time_base,
+            StreamJob::Audio(audio) => audio.ost_time_base = time_base,
+        }
+    }
+
+    /// Returns `true` once the packet is past the end of the range.
+    fn push(
+        &mut self,
+        packet: &ffmpeg::Packet,
+        octx: &mut ffmpeg::format::context::Output,
+    ) -> Result<bool, anyhow::Error> {
+        match self {
+            StreamJob::Copy(copy) => copy.push(packet, octx),
+            StreamJob::Video(video) => video.push(packet, octx),
+            StreamJob::Audio(audio) => audio.push(packet, octx),
+        }
+    }
+
+    fn finish(&mut self, octx: &mut ffmpeg::format::context::Output) -> Result<(), anyhow::Error> {
+        match self {
+            StreamJob::Copy(_) => Ok(()),
+            StreamJob::Video(video) => video.finish(octx),
+            StreamJob::Audio(audio) => audio.finish(octx),
+        }
+    }
+}
+
+struct CopyStream {
+    ost_index: usize,
+    ost_time_base: Rational,
+    window: Window,
+    /// 映像はデコードに必要なので開始前のキーフレームからコピーする
+    keep_preroll: bool,
+}
+
+impl CopyStream {
+    fn new(
+        ist: &ffmpeg::format::stream::Stream,
+        octx: &mut ffmpeg::format::context::Output,
+        window: Window,
+        keep_preroll: bool,
+    ) -> Result<Self, anyhow::Error> {
+        let mut ost = octx.add_stream(ffmpeg::encoder::find(codec::Id::None))?;
+        ost.set_parameters(ist.parameters());
+        // 別のコンテナに入れるときはコーデックタグを付け直させる。高レベルの API が無い
+        unsafe {
+            (*ost.parameters().as_mut_ptr()).codec_tag = 0;
+        }
+        Ok(CopyStream {
+            ost_index: ost.index(),
+            ost_time_base: window.time_base,
+            window,
+            keep_preroll,
+        })
+    }
+
+    fn push(
+        &mut self,
+        packet: &ffmpeg::Packet,
+        octx: &mut ffmpeg::format::context::Output,
+    ) -> Result<bool, anyhow::Error> {
+        let ts = packet.dts().or(packet.pts()).unwrap_or(0);
+        if self.window.is_after(ts) {
+            return Ok(true);
+        }
+        if !self.keep_preroll && self.window.is_before(packet.pts().unwrap_or(ts)) {
+            return Ok(false);
+        }
+        let mut packet = packet.clone();
+        packet.set_pts(packet.pts().map(|pts| pts - self.window.start));
+        packet.set_dts(packet.dts().map(|dts| dts - self.window.start));
+        packet.rescale_ts(self.window.time_base, self.ost_time_base);
+        packet.set_position(-1);
+        packet.set_stream(self.ost_index);
+        packet.write_interleaved(octx)?;
+        Ok(false)
+    }
+}
+
+/// エンコーダから出たパケットを書き出す
+fn write_encoded(
+    encoder: &mut ffmpeg::encoder::Encoder,
+    octx: &mut ffmpeg::format::context::Output,
+    ost_index: usize,
+    encoder_time_base: Rational,
+    ost_time_base: Rational,
+) -> Result<(), anyhow::Error> {
+    let mut packet = ffmpeg::Packet::empty();
+    while encoder.receive_packet(&mut packet).is_ok() {
+        packet.set_stream(ost_index);
+        packet.rescale_ts(encoder_time_base, ost_time_base);
+        packet.write_interleaved(octx)?;
+    }
+    Ok(())
+}
+
+/// デコードしたフレームは回転されないので、縦向きの動画が横倒しにならないよう表示行列を引き継ぐ。
+/// コピーするストリームはパラメータごと引き継がれる
+fn copy_display_matrix(
+    ist: &ffmpeg::format::stream::Stream,
+    ost: &mut ffmpeg::format::stream::StreamMut,
+) {
+    let Some(side_data) = ist
+        .side_data()
+        .find(|side_data| side_data.kind() == codec::packet::side_data::Type::DisplayMatrix)
+    else {
+        return;
+    };
+    let data = side_data.data();
+    // 高レベルの API が無い
+    unsafe {
+        let parameters = ost.parameters().as_mut_ptr();
+        let added = ffmpeg::ffi::av_packet_side_data_new(
+            &mut (*parameters).coded_side_data,
+            &mut (*parameters).nb_coded_side_data,
+            ffmpeg::ffi::AVPacketSideDataType::AV_PKT_DATA_DISPLAYMATRIX,
+            data.len(),
+            0,
+        );
+        if !added.is_null() {
+            std::ptr::copy_nonoverlapping(data.as_ptr(), (*added).data, data.len());
+        }
+    }
+}
+
+struct VideoTranscoder {
+    ost_index: usize,
+    ost_time_base: Rational,
+    window: Window,
+    decoder: ffmpeg::decoder::Video,
+    encoder: ffmpeg::encoder::video::Encoder,
+    scaler: ScalingContext,
+}
+
+impl VideoTranscoder {
+    fn new(
+        ist: &ffmpeg::format::stream::Stream,
+        decoder: ffmpeg::decoder::Video,
+        octx: &mut ffmpeg::format::context::Output,
+        job: &Job,
+        option: &TranscodeOption,
+        window: Window,
+        global_header: bool,
+    ) -> Result<Self, anyhow::Error> {
+        let (width, height) = match job.max_height {
+            Some(max) if decoder.height() > max => {
+                let width =
+                    u64::from(decoder.width()) * u64::from(max) / u64::from(decoder.height());
+                (width as u32 & !1, max & !1)
+            }
+            // 4:2:0 に落とすので奇数の辺は 1px 削る
+            _ => (decoder.width() & !1, decoder.height() & !1),
+        };
+        let scaler = ScalingContext::get(
+            decoder.format(),
+            decoder.width(),
+            decoder.height(),
+            Pixel::YUV420P,
+            width,
+            height,
+            Flags::BICUBIC,
+        )?;
+
+        let codec = find_encoder(job.container.video_encoders())?;
+        let mut encoder = codec::Context::new_with_codec(codec).encoder().video()?;
+        encoder.set_width(width);
+        encoder.set_height(height);
+        encoder.set_format(Pixel::YUV420P);
+        encoder.set_time_base(window.time_base);
+        let frame_rate = decoder.frame_rate().or_else(|| {
+            let rate = ist.avg_frame_rate();
+            (rate.numerator() > 0 && rate.denominator() > 0).then_some(rate)
+        });
+        encoder.set_frame_rate(frame_rate);
+        // HLS のセグメントはキーフレームで切るので 2 秒ごとに入れる
+        encoder.set_gop(frame_rate.map_or(60, |rate| (f64::from(rate) * 2.0).round() as u32));
+        if global_header {
+            encoder.set_flags(codec::Flags::GLOBAL_HEADER);
+        }
+        let mut options = ffmpeg::Dictionary::new();
+        match job.video_bit_rate {
+            Some(bit_rate) => {
+                encoder.set_bit_rate(bit_rate);
+                encoder.set_max_bit_rate(bit_rate * 3 / 2);
+            }
+            None => options.set("crf", &option.transcode_crf.to_string()),
+        }
+        match job.container {
+            Container::Mp4 | Container::Hls => options.set("preset", &option.transcode_preset),
+            Container::WebM => {
+                options.set("deadline", "realtime");
+                options.set("row-mt", "1");
+            }
+        }
+        let encoder = encoder.open_with(options)?;
+
+        let mut ost = octx.add_stream(codec)?;
+        ost.set_parameters(&encoder);
+        copy_display_matrix(ist, &mut ost);
+        Ok(VideoTranscoder {
+            ost_index: ost.index(),
+            ost_time_base: window.time_base,
+            window,
+            decoder,
+            encoder,
+            scaler,
+        })
+    }
+
+    fn push(
+        &mut self,
+        packet: &ffmpeg::Packet,
+        octx: &mut ffmpeg::format::context::Output,
+    ) -> Result<bool, anyhow::Error> {
+        self.decoder.send_packet(packet)?;
+        self.receive_frames(octx)?;
+        Ok(self
+            .window
+            .is_after(packet.dts().or(packet.pts()).unwrap_or(0)))
+    }
+
+    fn receive_frames(
+        &mut self,
+        octx: &mut ffmpeg::format::context::Output,
+    ) -> Result<(), anyhow::Error> {
+        let mut decoded = VideoFrame::empty();
+        while self.decoder.receive_frame(&mut decoded).is_ok() {
+            let ts = decoded.timestamp().unwrap_or(0);
+            if self.window.is_before(ts) || self.window.is_after(ts) {
+                continue;
+            }
+            let mut scaled = VideoFrame::empty();
+            self.scaler.run(&decoded, &mut scaled)?;
+            scaled.set_pts(Some(ts - self.window.start));
+            self.encoder.send_frame(&scaled)?;
+            write_encoded(
+                &mut self.encoder,
+                octx,
+                self.ost_index,
+                self.window.time_base,
+                self.ost_time_base,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, octx: &mut ffmpeg::format::context::Output) -> Result<(), anyhow::Error> {
+        self.decoder.send_eof()?;
+        self.receive_frames(octx)?;
+        self.encoder.send_eof()?;
+        write_encoded(
+            &mut self.encoder,
+            octx,
+            self.ost_index,
+            self.window.time_base,
+            self.ost_time_base,
+        )
+    }
+}
+
+/// 可変フレームサイズを受け付けないエンコーダ向けの既定値
+const DEFAULT_AUDIO_FRAME_SIZE: usize = 1024;
+/// Opus は 48kHz しか受け付けない
+const OPUS_SAMPLE_RATE: u32 = 48_000;
+
+struct AudioTranscoder {
+    ost_index: usize,
+    ost_time_base: Rational,
+    window: Window,
+    decoder: ffmpeg::decoder::Audio,
+    encoder: ffmpeg::encoder::audio::Encoder,
+    resampler: ffmpeg::software::resampling::Context,
+    format: Sample,
+    layout: ChannelLayout,
+    rate: u32,
+    frame_size: usize,
+    /// エンコーダの 1 フレーム分に満たないサンプルを溜めておく (プレーンごと)
+    fifo: Vec<Vec<u8>>,
+    /// bytes per sample in each plane
+    sample_bytes: usize,
+    /// pts of the next encoded frame in samples
+    next_pts: i64,
+}
+
+impl AudioTranscoder {
+    fn new(
+        decoder: ffmpeg::decoder::Audio,
+        octx: &mut ffmpeg::format::context::Output,
+        job: &Job,
+        option: &TranscodeOption,
+        window: Window,
+        global_header: bool,
+    ) -> Result<Self, anyhow::Error> {
+        let codec = find_encoder(job.container.audio_encoders())?;
+        let format = codec
+            .audio()?
+            .formats()
+            .and_then(|mut formats| formats.next())
+            .unwrap_or(Sample::F32(ffmpeg::format::sample::Type::Planar));
+        // 多チャンネルはステレオにまとめる
+        let layout = match decoder.channels() {
+            1 => ChannelLayout::MONO,
+            _ => ChannelLayout::STEREO,
+        };
+        let rate = match job.container {
+            Container::WebM => OPUS_SAMPLE_RATE,
+            _ => decoder.rate(),
+        };
+
+        let mut encoder = codec::Context::new_with_codec(codec).encoder().audio()?;
+        encoder.set_rate(rate as i32);
+        encoder.set_channel_layout(layout);
+        encoder.set_format(format);
+        encoder.set_bit_rate(option.transcode_audio_bit_rate);
+        encoder.set_time_base((1, rate as i32));
+        if global_header {
+            encoder.set_flags(codec::Flags::GLOBAL_HEADER);
+        }
+        let encoder = encoder.open_with(ffmpeg::Dictionary::new())?;
+        let frame_size = match encoder.frame_size() {
+            0 => DEFAULT_AUDIO_FRAME_SIZE,
+            size => size as usize,
+        };
+        let resampler = decoder.resampler(format, layout, rate)?;
+
+        let mut ost = octx.add_stream(codec)?;
+        ost.set_parameters(&encoder);
+        let planes = if format.is_planar() {
+            usize::from(layout_channels(layout))
+        } else {
+            1
+        };
+        let sample_bytes = if format.is_planar() {
+            format.bytes()
+        } else {
+            format.bytes() * usize::from(layout_channels(layout))
+        };
+        Ok(AudioTranscoder {
+            ost_index: ost.index(),
+            ost_time_base: window.time_base,
+            window,
+            decoder,
+            encoder,
+            resampler,
+            format,
+            layout,
+            rate,
+            frame_size,
+            fifo: vec![Vec::new(); planes],
+            sample_bytes,
+            next_pts: 0,
+        })
+    }
+
+    fn push(
+        &mut self,
+        packet: &ffmpeg::Packet,
+        octx: &mut ffmpeg::format::context::Output,
+    ) -> Result<bool, anyhow::Error> {
+        self.decoder.send_packet(packet)?;
+        self.receive_frames(octx)?;
+        Ok(self.window.is_after(packet.pts().unwrap_or(0)))
+    }
+
+    fn receive_frames(
+        &mut self,
+        octx: &mut ffmpeg::format::context::Output,
+    ) -> Result<(), anyhow::Error> {
+        let mut decoded = AudioFrame::empty();
+        while self.decoder.receive_frame(&mut decoded).is_ok() {
+            let ts = decoded.timestamp().unwrap_or(0);
+            if self.window.is_before(ts) || self.window.is_after(ts) {
+                continue;
+            }
+            // レート変換で増える分も入るように余裕を持たせる
+            let capacity = decoded.samples() * self.rate as usize / decoded.rate().max(1) as usize
+                + DEFAULT_AUDIO_FRAME_SIZE;
+            let mut resampled = AudioFrame::new(self.format, capacity, self.layout);
+            self.resampler.run(&decoded, &mut resampled)?;
+            self.enqueue(&resampled);
+            self.encode_ready(octx, false)?;
+        }
+        Ok(())
+    }
+
+    fn enqueue(&mut self, frame: &AudioFrame) {
+        let len = frame.samples() * self.sample_bytes;
+        for (plane, buffer) in self.fifo.iter_mut().enumerate() {
+            buffer.extend_from_slice(plane_bytes(frame, plane, len));
+        }
+    }
+
+    /// `flush` では最後の端数も 1 フレームにする
+    fn encode_ready(
+        &mut self,
+        octx: &mut ffmpeg::format::context::Output,
+        flush: bool,
+    ) -> Result<(), anyhow::Error> {
+        loop {
+            let available = self.fifo[0].len() / self.sample_bytes;
+            let samples = if available >= self.frame_size {
+                self.frame_size
+            } else if flush && available > 0 {
+                available
+            } else {
+                return Ok(());
+            };
+            let len = samples * self.sample_bytes;
+            let mut frame = AudioFrame::new(self.format, samples, self.layout);
+            frame.set_rate(self.rate);
+            for (plane, buffer) in self.fifo.iter_mut().enumerate() {
+                plane_bytes_mut(&mut frame, plane, len).copy_from_slice(&buffer[..len]);
+                buffer.drain(..len);
+            }
+            frame.set_pts(Some(self.next_pts));
+            self.next_pts += samples as i64;
+            self.encoder.send_frame(&frame)?;
+            write_encoded(
+                &mut self.encoder,
+                octx,
+                self.ost_index,
+                Rational::new(1, self.rate as i32),
+                self.ost_time_base,
+            )?;
+        }
+    }
+
+    fn finish(&mut self, octx: &mut ffmpeg::format::context::Output) -> Result<(), anyhow::Error> {
+        self.decoder.send_eof()?;
+        self.receive_frames(octx)?;
+        // リサンプラーの遅延分を吐き出す
+        loop {
+            let mut resampled = AudioFrame::new(self.format, DEFAULT_AUDIO_FRAME_SIZE, self.layout);
+            self.resampler.flush(&mut resampled)?;
+            if resampled.samples() == 0 {
+                break;
+            }
+            self.enqueue(&resampled);
+        }
+        self.encode_ready(octx, true)?;
+        self.encoder.send_eof()?;
+        write_encoded(
+            &mut self.encoder,
+            octx,
+            self.ost_index,
+            Rational::new(1, self.rate as i32),
+            self.ost_time_base,
+        )
+    }
+}
+
+/// `frame::Audio::data` は長さに `linesize[index]` を使うが、音声はプレーン 0 にしか
+/// linesize が入らないので、ポインタから直接切り出す。`len` は 1 プレーン分のバイト数以下
+fn plane_bytes(frame: &AudioFrame, plane: usize, len: usize) -> &[u8] {
+    assert!(plane < frame.planes());
+    unsafe {
+        let frame = frame.as_ptr();
+        assert!(len <= (*frame).linesize[0] as usize);
+        std::slice::from_raw_parts((*frame).data[plane], len)
+    }
+}
+
+fn plane_bytes_mut(frame: &mut AudioFrame, plane: usize, len: usize) -> &mut [u8] {
+    assert!(plane < frame.planes());
+    unsafe {
+        let frame = frame.as_mut_ptr();
+        assert!(len <= (*frame).linesize[0] as usize);
+        std::slice::from_raw_parts_mut((*frame).data[plane], len)
+    }
+}
+
+fn layout_channels(layout: ChannelLayout) -> u16 {
+    if layout == ChannelLayout::MONO {
+        1
+    } else {
+        2
+    }
+}
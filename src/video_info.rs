@@ -0,0 +1,138 @@
+//! `/info` で返す動画のメタデータ。ffprobe を別に呼ばなくて済むように、デコードに使うのと同じ
+//! ffmpeg のコンテキストから集める。
+use crate::movie_keyframe::{self, AV_TIME_BASE};
+use ffmpeg::format::stream::Stream;
+use ffmpeg_next as ffmpeg;
+use serde::Serialize;
+use std::path::Path;
+
+#[derive(Serialize)]
+pub struct VideoInfo {
+    /// Container format, e.g. `mov,mp4,m4a,3gp,3g2,mj2`
+    format: String,
+    /// Seconds
+    duration: Option<f64>,
+    /// Bits per second of the whole container
+    bit_rate: Option<i64>,
+    // 以下は主となる映像・音声ストリームのもの
+    #[serde(skip_serializing_if = "Option::is_none")]
+    width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    video_codec: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    audio_codec: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frame_rate: Option<f64>,
+    /// Clockwise degrees the video is rotated for display
+    rotation: i32,
+    streams: Vec<StreamInfo>,
+}
+
+#[derive(Serialize)]
+pub struct StreamInfo {
+    index: usize,
+    /// `video`, `audio`, `subtitle`, `data`, `attachment` or `unknown`
+    #[serde(rename = "type")]
+    kind: &'static str,
+    codec: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frame_rate: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rotation: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sample_rate: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    channels: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    language: Option<String>,
+    /// The stream players pick by default
+    default: bool,
+}
+
+pub fn probe(path: &Path) -> Result<VideoInfo, anyhow::Error> {
+    ffmpeg::init().ok(); // Ignore re-init
+
+    let ictx = ffmpeg::format::input(&path)?;
+    let streams: Vec<StreamInfo> = ictx.streams().map(|stream| stream_info(&stream)).collect();
+    let best = |kind| {
+        ictx.streams()
+            .best(kind)
+            .and_then(|stream| streams.iter().find(|s| s.index == stream.index()))
+    };
+    let video = best(ffmpeg::media::Type::Video);
+    let audio = best(ffmpeg::media::Type::Audio);
+
+    Ok(VideoInfo {
+        format: ictx.format().name().to_string(),
+        duration: (ictx.duration() > 0).then(|| ictx.duration() as f64 / AV_TIME_BASE),
+        bit_rate: (ictx.bit_rate() > 0).then(|| ictx.bit_rate()),
+        width: video.and_then(|s| s.width),
+        height: video.and_then(|s| s.height),
+        video_codec: video.map(|s| s.codec.clone()),
+        audio_codec: audio.map(|s| s.codec.clone()),
+        frame_rate: video.and_then(|s| s.frame_rate),
+        rotation: video.and_then(|s| s.rotation).unwrap_or(0),
+        streams,
+    })
+}
+
+fn stream_info(stream: &Stream) -> StreamInfo {
+    let parameters = stream.parameters();
+    let mut info = StreamInfo {
+        index: stream.index(),
+        kind: match parameters.medium() {
+            ffmpeg::media::Type::Video => "video",
+            ffmpeg::media::Type::Audio => "audio",
+            ffmpeg::media::Type::Subtitle => "subtitle",
+            ffmpeg::media::Type::Data => "data",
+            ffmpeg::media::Type::Attachment => "attachment",
+            _ => "unknown",
+        },
+        codec: parameters.id().name().to_string(),
+        width: None,
+        height: None,
+        frame_rate: None,
+        rotation: None,
+        sample_rate: None,
+        channels: None,
+        language: stream
+            .metadata()
+            .get("language")
+            .filter(|lang| *lang != "und")
+            .map(str::to_string),
+        default: stream
+            .disposition()
+            .contains(ffmpeg::format::stream::Disposition::DEFAULT),
+    };
+
+    // 寸法やサンプルレートはデコーダを開かないと取れない。デコーダが無い形式は省く
+    let Ok(context) = ffmpeg::codec::Context::from_parameters(parameters) else {
+        return info;
+    };
+    match info.kind {
+        "video" => {
+            if let Ok(decoder) = context.decoder().video() {
+                info.width = Some(decoder.width());
+                info.height = Some(decoder.height());
+            }
+            let rate = stream.avg_frame_rate();
+            info.frame_rate =
+                (rate.numerator() > 0 && rate.denominator() > 0).then(|| f64::from(rate));
+            info.rotation = movie_keyframe::display_rotation(stream);
+        }
+        "audio" => {
+            if let Ok(decoder) = context.decoder().audio() {
+                info.sample_rate = Some(decoder.rate());
+                info.channels = Some(decoder.channels());
+            }
+        }
+        _ => {}
+    }
+    info
+}
@@ -0,0 +1,176 @@
+//! `warmup` サブコマンド: ベースパス（`--s3-bucket` ではバケット）以下の全ファイルのサムネイルを事前に作ってキャッシュに入れる。
+//!
+//! デプロイ直後の最初のギャラリー表示で変換が集中しないようにする。`/thumbnail` と同じ
+//! 名前・同じ手順で作るので、メモリ以外のキャッシュ層があればそのまま使われる。
+//!
+//! `--enqueue` を付けると自分では変換せず、`--queue-db` のキューにタスクを積んで終了する。
+//! 変換は動いているサーバーのワーカーが、リクエストの処理とは別に進める。
+use crate::encode::OutputFormat;
+use crate::{cache, convert_once, jobs, prepare_thumbnail, queue, AppData, FileKey, Size};
+use actix_web::web;
+use clap::Parser;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+/// この件数ごとに進捗をログに出す
+const PROGRESS_INTERVAL: usize = 1000;
+
+#[derive(Parser)]
+pub struct WarmupArgs {
+    /// Thumbnail sizes to generate
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "small,medium,large",
+        value_parser = ["small", "medium", "large"]
+    )]
+    sizes: Vec<String>,
+
+    /// Output formats to generate. Browsers get AVIF or WebP depending on `Accept`
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "avif,webp",
+        value_parser = ["webp", "avif", "jpeg", "png"]
+    )]
+    formats: Vec<String>,
+
+    /// Number of files converted in parallel (defaults to the number of CPUs)
+    #[arg(long)]
+    concurrency: Option<usize>,
+
+    /// Queue the thumbnails in `--queue-db` for the server's workers instead of generating them
+    #[arg(long)]
+    enqueue: bool,
+}
+
+#[derive(Default)]
+struct Counts {
+    generated: AtomicUsize,
+    cached: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+fn warm(
+    app_data: &web::Data<AppData>,
+    key: &FileKey,
+    size: &str,
+    format: OutputFormat,
+    enqueue: bool,
+    counts: &Counts,
+) -> Result<(), actix_web::Error> {
+    let modified_time = app_data.store.metadata(key)?.modified;
+    let query = HashMap::new();
+    let (output_name, convert) = prepare_thumbnail(
+        app_data,
+        key,
+        &query,
+        Size::from_str(size),
+        format,
+        modified_time,
+    )?;
+    let cache_key = cache::CacheKey::new(key, modified_time, &output_name);
+    if app_data.cache.get(&cache_key).is_some() {
+        counts.cached.fetch_add(1, Ordering::Relaxed);
+        return Ok(());
+    }
+    match &app_data.queue {
+        Some(queue) if enqueue => {
+            let filename = key.build_filename().to_string_lossy().into_owned();
+            let task = queue::Task::Thumbnail {
+                key: filename.clone(),
+                query: HashMap::from([("size".to_string(), size.to_string())]),
+                format: format.extension().to_string(),
+            };
+            // リクエストから始まるジョブと同じ ID にして、同じ出力を二重に積まないようにする
+            let id = jobs::job_id(&cache_key.to_string());
+            let url = format!(
+                "/thumbnail/{}?size={}&format={}",
+                filename,
+                size,
+                format.extension()
+            );
+            queue
+                .push(&id, &url, &task, true)
+                .map_err(std::io::Error::other)?;
+        }
+        _ => {
+            convert_once(app_data, key, modified_time, &output_name, convert)?;
+        }
+    }
+    counts.generated.fetch_add(1, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn run(args: &WarmupArgs, app_data: &web::Data<AppData>) -> std::io::Result<()> {
+    if args.enqueue && app_data.queue.is_none() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "--enqueue requires --queue-db",
+        ));
+    }
+    if app_data
+        .cache
+        .layers()
+        .all(|layer| matches!(layer.name(), "memory" | "noop"))
+        && !app_data.config.sidecar.is_enabled()
+    {
+        log::warn!("Nothing generated by warmup outlives it without --cache-dir, --redis-url or --sidecar-mode");
+    }
+
+    let keys = app_data.store.keys("")?;
+    let formats: Vec<_> = args
+        .formats
+        .iter()
+        .map(|format| OutputFormat::from_str(format))
+        .collect();
+    let concurrency = args
+        .concurrency
+        .or_else(|| std::thread::available_parallelism().ok().map(|n| n.get()))
+        .unwrap_or(1);
+    log::info!(
+        "Warming up {} files with concurrency {}",
+        keys.len(),
+        concurrency
+    );
+
+    let next = AtomicUsize::new(0);
+    let counts = Counts::default();
+    let started = Instant::now();
+    std::thread::scope(|scope| {
+        for _ in 0..concurrency {
+            scope.spawn(|| {
+                while let Some((i, key)) = {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    keys.get(i).map(|key| (i, key))
+                } {
+                    for size in &args.sizes {
+                        for &format in &formats {
+                            if let Err(err) =
+                                warm(app_data, key, size, format, args.enqueue, &counts)
+                            {
+                                log::debug!("{}.{}: {}", key.hkey, key.ext, err);
+                                counts.failed.fetch_add(1, Ordering::Relaxed);
+                            }
+                        }
+                    }
+                    if (i + 1) % PROGRESS_INTERVAL == 0 {
+                        log::info!("Warmed up {}/{} files", i + 1, keys.len());
+                    }
+                }
+            });
+        }
+    });
+
+    println!(
+        "warmed up {} files in {:.2}s: {} {}, {} already cached, {} failed",
+        keys.len(),
+        started.elapsed().as_secs_f64(),
+        if args.enqueue { "queued" } else { "generated" },
+        counts.generated.into_inner(),
+        counts.cached.into_inner(),
+        counts.failed.into_inner(),
+    );
+    Ok(())
+}
@@ -0,0 +1,147 @@
+//! Decoders provided as sandboxed WebAssembly modules.
+//!
+//! A plugin is a core wasm module with no imports that exports:
+//!
+//! - `memory`
+//! - `alloc(len: i32) -> i32`: reserves `len` bytes for the source file
+//! - `decode(ptr: i32, len: i32) -> i64`: returns `(out_ptr << 32) | out_len`, or `0` on failure.
+//!   The output is `width: u32 LE`, `height: u32 LE` followed by RGBA8 pixels.
+//!
+//! Every decode runs in a fresh instance bounded by fuel and memory limits.
+use crate::loader::{LoadRequest, LoaderRegistry, MediaLoader};
+use crate::{ApiError, LoadImageOption};
+use anyhow::{Context, Result};
+use clap::Parser;
+use image::{DynamicImage, ImageBuffer, Rgba};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use wasmtime::{Config, Engine, Instance, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+/// `NAME=PATH` given on the command line.
+#[derive(Clone, Debug)]
+pub struct PluginSpec {
+    name: String,
+    path: PathBuf,
+}
+
+impl FromStr for PluginSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, path) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected NAME=PATH: {}", s))?;
+        Ok(PluginSpec {
+            name: name.to_string(),
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+#[derive(Parser)]
+pub struct WasmPluginOption {
+    /// Register a wasm decoder as loader `NAME`; map extensions to it with `--loader`
+    #[arg(long = "wasm-plugin", value_name = "NAME=PATH")]
+    plugins: Vec<PluginSpec>,
+
+    #[arg(long, default_value_t = 5_000_000_000)]
+    wasm_plugin_fuel: u64,
+
+    #[arg(long, default_value_t = 512 * 1024 * 1024)]
+    wasm_plugin_max_memory: usize,
+}
+
+pub fn register_plugins(registry: &mut LoaderRegistry, option: &WasmPluginOption) -> Result<()> {
+    if option.plugins.is_empty() {
+        return Ok(());
+    }
+
+    let mut config = Config::new();
+    config.consume_fuel(true);
+    let engine = Engine::new(&config)?;
+
+    for spec in &option.plugins {
+        let module = Module::from_file(&engine, &spec.path)
+            .with_context(|| format!("Failed to compile plugin {}", spec.path.display()))?;
+        log::info!("Loaded wasm plugin {}: {}", spec.name, spec.path.display());
+        registry.add_loader(
+            spec.name.clone(),
+            Arc::new(WasmLoader {
+                engine: engine.clone(),
+                module,
+                fuel: option.wasm_plugin_fuel,
+                max_memory: option.wasm_plugin_max_memory,
+            }),
+        );
+    }
+    Ok(())
+}
+
+struct WasmLoader {
+    engine: Engine,
+    module: Module,
+    fuel: u64,
+    max_memory: usize,
+}
+
+struct PluginState {
+    limits: StoreLimits,
+}
+
+impl WasmLoader {
+    fn decode(&self, bytes: &[u8]) -> Result<DynamicImage> {
+        let mut store = Store::new(
+            &self.engine,
+            PluginState {
+                limits: StoreLimitsBuilder::new()
+                    .memory_size(self.max_memory)
+                    .build(),
+            },
+        );
+        store.limiter(|state| &mut state.limits);
+        store.set_fuel(self.fuel)?;
+
+        // import は何も渡さない (ファイルシステムやネットワークに触れさせない)
+        let instance = Instance::new(&mut store, &self.module, &[])?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .context("plugin does not export memory")?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+        let decode = instance.get_typed_func::<(i32, i32), i64>(&mut store, "decode")?;
+
+        let len = i32::try_from(bytes.len()).context("source too large for wasm32")?;
+        let ptr = alloc.call(&mut store, len)?;
+        memory.write(&mut store, ptr as u32 as usize, bytes)?;
+
+        let packed = decode.call(&mut store, (ptr, len))? as u64;
+        anyhow::ensure!(packed != 0, "plugin failed to decode");
+        let out_ptr = (packed >> 32) as usize;
+        let out_len = (packed & 0xffff_ffff) as usize;
+
+        let output = memory
+            .data(&store)
+            .get(out_ptr..out_ptr + out_len)
+            .context("plugin output out of bounds")?;
+        anyhow::ensure!(output.len() >= 8, "plugin output too short");
+        let width = u32::from_le_bytes(output[0..4].try_into()?);
+        let height = u32::from_le_bytes(output[4..8].try_into()?);
+
+        let img_buf = ImageBuffer::<Rgba<u8>, _>::from_raw(width, height, output[8..].to_vec())
+            .context("plugin output size mismatch")?;
+        Ok(DynamicImage::ImageRgba8(img_buf))
+    }
+}
+
+impl MediaLoader for WasmLoader {
+    fn load(
+        &self,
+        path: &Path,
+        _option: &LoadImageOption,
+        _request: &LoadRequest,
+    ) -> Result<DynamicImage, ApiError> {
+        let bytes =
+            std::fs::read(path).map_err(|err| ApiError::FailedToDecodePlugin(err.into()))?;
+        self.decode(&bytes).map_err(ApiError::FailedToDecodePlugin)
+    }
+}
@@ -0,0 +1,81 @@
+//! `--watch`: ベースパスを監視し、元ファイルが置き換えられたり消されたりしたらその出力をキャッシュから消す。
+//!
+//! キャッシュのキーには更新時刻が入っているので、普通の更新なら古い出力は引かれない。
+//! `rsync -t` などで更新時刻を保ったまま中身を置き換えた場合に、古い出力を返し続けないようにする。
+//! inotify はネットワーク越しの変更を拾えないので、NAS 上で動かすときだけ使える。
+use crate::{AppData, FileKey};
+use actix_web::web;
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime};
+
+/// 書き込み中のイベントをまとめるため、最後のイベントからこれだけ待ってから消す
+const DEBOUNCE: Duration = Duration::from_secs(1);
+
+/// Starts watching the base path. Watching stops when the returned watcher is dropped.
+pub fn start(app_data: web::Data<AppData>) -> notify::Result<notify::RecommendedWatcher> {
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    watcher.watch(&app_data.base_path, RecursiveMode::Recursive)?;
+    std::thread::Builder::new()
+        .name("fs-watcher".to_string())
+        .spawn(move || run(&app_data, receiver))?;
+    Ok(watcher)
+}
+
+fn run(app_data: &AppData, events: mpsc::Receiver<notify::Result<notify::Event>>) {
+    let mut pending: HashMap<String, FileKey> = HashMap::new();
+    loop {
+        let event = if pending.is_empty() {
+            events
+                .recv()
+                .map_err(|_| mpsc::RecvTimeoutError::Disconnected)
+        } else {
+            events.recv_timeout(DEBOUNCE)
+        };
+        match event {
+            Ok(Ok(event)) => {
+                if matches!(
+                    event.kind,
+                    EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+                ) {
+                    // サイドカーは拡張子に `.` を含むので FileKey にならない
+                    for key in event
+                        .paths
+                        .iter()
+                        .filter_map(|path| path.file_name()?.to_str())
+                        .filter_map(|name| FileKey::parse(name).ok())
+                    {
+                        pending.insert(key.hkey.clone(), key);
+                    }
+                }
+            }
+            Ok(Err(err)) => log::warn!("Failed to watch {}: {}", app_data.base_path.display(), err),
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                for (_, key) in pending.drain() {
+                    invalidate(app_data, &key);
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+    }
+}
+
+/// 残っていれば今の版の出力だけを消し、作り直させる。前の版の出力は
+/// `--stale-while-revalidate` で使うので残す。消えていれば全部消す
+fn invalidate(app_data: &AppData, key: &FileKey) {
+    let version = std::fs::metadata(key.build_path(&app_data.base_path))
+        .ok()
+        .map(|metadata| metadata.modified().unwrap_or(SystemTime::now()));
+    app_data.failures.forget(key);
+    let purged = app_data.cache.purge(key, version);
+    if purged > 0 {
+        log::info!(
+            "Purged {} cached outputs of {}.{}",
+            purged,
+            key.hkey,
+            key.ext
+        );
+    }
+}
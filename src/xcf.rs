@@ -0,0 +1,359 @@
+//! Minimal GIMP XCF reader.
+//!
+//! XCF has no merged image, so visible layers are composited here with the normal blend mode.
+//! Only 8-bit RGB, grayscale and indexed images are supported; blend modes, masks and
+//! channels are ignored.
+use flate2::read::ZlibDecoder;
+use image::{DynamicImage, Rgba, RgbaImage};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+const TILE_SIZE: u32 = 64;
+
+const PROP_END: u32 = 0;
+const PROP_COLORMAP: u32 = 1;
+const PROP_OPACITY: u32 = 6;
+const PROP_VISIBLE: u32 = 8;
+const PROP_OFFSETS: u32 = 15;
+const PROP_COMPRESSION: u32 = 17;
+const PROP_GROUP_ITEM: u32 = 29;
+const PROP_FLOAT_OPACITY: u32 = 33;
+
+const COMPRESSION_NONE: u8 = 0;
+const COMPRESSION_RLE: u8 = 1;
+const COMPRESSION_ZLIB: u8 = 2;
+
+/// GIMP 2.10 以降の 8bit gamma (v4 以降のみ precision フィールドがある)
+const PRECISION_U8_GAMMA: u32 = 150;
+
+struct XcfReader<R> {
+    reader: R,
+    /// v11 以降はポインタが 64bit
+    wide_pointers: bool,
+}
+
+impl<R: Read + Seek> XcfReader<R> {
+    fn u32(&mut self) -> std::io::Result<u32> {
+        let mut buf = [0; 4];
+        self.reader.read_exact(&mut buf)?;
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    fn pointer(&mut self) -> std::io::Result<u64> {
+        if self.wide_pointers {
+            let mut buf = [0; 8];
+            self.reader.read_exact(&mut buf)?;
+            Ok(u64::from_be_bytes(buf))
+        } else {
+            self.u32().map(u64::from)
+        }
+    }
+
+    fn pointers(&mut self) -> std::io::Result<Vec<u64>> {
+        let mut pointers = Vec::new();
+        loop {
+            match self.pointer()? {
+                0 => return Ok(pointers),
+                p => pointers.push(p),
+            }
+        }
+    }
+
+    fn seek(&mut self, offset: u64) -> std::io::Result<()> {
+        self.reader.seek(SeekFrom::Start(offset)).map(|_| ())
+    }
+
+    fn skip_string(&mut self) -> std::io::Result<()> {
+        let len = self.u32()?;
+        self.reader
+            .seek(SeekFrom::Current(i64::from(len)))
+            .map(|_| ())
+    }
+
+    /// プロパティを (type, payload) の列として読む
+    fn properties(&mut self) -> Result<Vec<(u32, Vec<u8>)>, anyhow::Error> {
+        let mut properties = Vec::new();
+        loop {
+            let prop_type = self.u32()?;
+            let len = self.u32()?;
+            if prop_type == PROP_END {
+                return Ok(properties);
+            }
+            anyhow::ensure!(len < 16 * 1024 * 1024, "Property too large");
+            let mut payload = vec![0; len as usize];
+            self.reader.read_exact(&mut payload)?;
+            properties.push((prop_type, payload));
+        }
+    }
+}
+
+fn be_u32(payload: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        payload.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+struct Layer {
+    kind: u32,
+    visible: bool,
+    opacity: f32,
+    offset: (i32, i32),
+    is_group: bool,
+    hierarchy: u64,
+}
+
+pub fn load_image_from_xcf(path: &Path) -> Result<DynamicImage, anyhow::Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut magic = [0; 14];
+    reader.read_exact(&mut magic)?;
+    anyhow::ensure!(&magic[..9] == b"gimp xcf ", "Not an XCF file");
+    let version = match &magic[9..13] {
+        b"file" => 0,
+        v if v[0] == b'v' => std::str::from_utf8(&v[1..])?.parse::<u32>()?,
+        _ => anyhow::bail!("Unknown XCF version"),
+    };
+
+    let mut xcf = XcfReader {
+        reader,
+        wide_pointers: version >= 11,
+    };
+    let width = xcf.u32()?;
+    let height = xcf.u32()?;
+    let _base_type = xcf.u32()?;
+    if version >= 4 {
+        let precision = xcf.u32()?;
+        anyhow::ensure!(
+            precision == PRECISION_U8_GAMMA,
+            "Unsupported precision {}",
+            precision
+        );
+    }
+
+    let mut compression = COMPRESSION_NONE;
+    let mut colormap = Vec::new();
+    for (prop_type, payload) in xcf.properties()? {
+        match prop_type {
+            PROP_COMPRESSION => compression = payload.first().copied().unwrap_or(0),
+            PROP_COLORMAP => colormap = payload.get(4..).unwrap_or_default().to_vec(),
+            _ => {}
+        }
+    }
+
+    let layer_pointers = xcf.pointers()?;
+    let mut canvas = RgbaImage::new(width, height);
+    // 先頭が最前面なので下のレイヤーから合成する
+    for &pointer in layer_pointers.iter().rev() {
+        xcf.seek(pointer)?;
+        let layer = read_layer(&mut xcf)?;
+        if !layer.visible || layer.is_group || layer.opacity <= 0.0 {
+            continue;
+        }
+        let pixels = read_hierarchy(&mut xcf, &layer, compression, &colormap)?;
+        blend(&mut canvas, &pixels, &layer);
+    }
+
+    Ok(DynamicImage::ImageRgba8(canvas))
+}
+
+fn read_layer<R: Read + Seek>(xcf: &mut XcfReader<R>) -> Result<Layer, anyhow::Error> {
+    let _width = xcf.u32()?;
+    let _height = xcf.u32()?;
+    let kind = xcf.u32()?;
+    xcf.skip_string()?;
+
+    let mut layer = Layer {
+        kind,
+        visible: true,
+        opacity: 1.0,
+        offset: (0, 0),
+        is_group: false,
+        hierarchy: 0,
+    };
+    for (prop_type, payload) in xcf.properties()? {
+        match prop_type {
+            PROP_VISIBLE => layer.visible = be_u32(&payload, 0).unwrap_or(1) != 0,
+            PROP_OPACITY => {
+                layer.opacity = be_u32(&payload, 0).unwrap_or(255) as f32 / 255.0;
+            }
+            PROP_FLOAT_OPACITY => {
+                if let Some(bits) = be_u32(&payload, 0) {
+                    layer.opacity = f32::from_bits(bits);
+                }
+            }
+            PROP_OFFSETS => {
+                layer.offset = (
+                    be_u32(&payload, 0).unwrap_or(0) as i32,
+                    be_u32(&payload, 4).unwrap_or(0) as i32,
+                );
+            }
+            PROP_GROUP_ITEM => layer.is_group = true,
+            _ => {}
+        }
+    }
+    layer.hierarchy = xcf.pointer()?;
+    Ok(layer)
+}
+
+/// レイヤーを RGBA に展開する
+fn read_hierarchy<R: Read + Seek>(
+    xcf: &mut XcfReader<R>,
+    layer: &Layer,
+    compression: u8,
+    colormap: &[u8],
+) -> Result<RgbaImage, anyhow::Error> {
+    xcf.seek(layer.hierarchy)?;
+    let _width = xcf.u32()?;
+    let _height = xcf.u32()?;
+    let bpp = xcf.u32()? as usize;
+    let expected_bpp = match layer.kind {
+        0 => 3,
+        1 => 4,
+        2 | 4 => 1,
+        3 | 5 => 2,
+        kind => anyhow::bail!("Unsupported layer type {}", kind),
+    };
+    anyhow::ensure!(bpp == expected_bpp, "Unsupported bytes per pixel {}", bpp);
+
+    // 最初のレベルが原寸
+    let level = xcf.pointer()?;
+    xcf.seek(level)?;
+    let width = xcf.u32()?;
+    let height = xcf.u32()?;
+    let tiles = xcf.pointers()?;
+
+    let tiles_x = width.div_ceil(TILE_SIZE);
+    let mut pixels = RgbaImage::new(width, height);
+    let mut tile = Vec::new();
+    for (index, &pointer) in tiles.iter().enumerate() {
+        let tx = index as u32 % tiles_x * TILE_SIZE;
+        let ty = index as u32 / tiles_x * TILE_SIZE;
+        if ty >= height {
+            break;
+        }
+        let tw = TILE_SIZE.min(width - tx);
+        let th = TILE_SIZE.min(height - ty);
+        let len = (tw * th) as usize * bpp;
+
+        xcf.seek(pointer)?;
+        read_tile(&mut xcf.reader, compression, bpp, len, &mut tile)?;
+
+        for y in 0..th {
+            for x in 0..tw {
+                let offset = (y * tw + x) as usize * bpp;
+                let px = to_rgba(layer.kind, &tile[offset..offset + bpp], colormap);
+                pixels.put_pixel(tx + x, ty + y, px);
+            }
+        }
+    }
+    Ok(pixels)
+}
+
+/// インターリーブされた `len` バイトのタイルを `tile` に読み込む
+fn read_tile(
+    reader: &mut impl Read,
+    compression: u8,
+    bpp: usize,
+    len: usize,
+    tile: &mut Vec<u8>,
+) -> Result<(), anyhow::Error> {
+    tile.resize(len, 0);
+    match compression {
+        COMPRESSION_NONE => reader.read_exact(tile)?,
+        COMPRESSION_ZLIB => ZlibDecoder::new(reader).read_exact(tile)?,
+        COMPRESSION_RLE => {
+            // RLE はチャンネルごとに分かれている
+            let pixels = len / bpp;
+            for channel in 0..bpp {
+                let plane = read_rle(reader, pixels)?;
+                for (i, value) in plane.into_iter().enumerate() {
+                    tile[i * bpp + channel] = value;
+                }
+            }
+        }
+        other => anyhow::bail!("Unsupported compression {}", other),
+    }
+    Ok(())
+}
+
+fn read_rle(reader: &mut impl Read, len: usize) -> Result<Vec<u8>, anyhow::Error> {
+    let mut byte = || -> std::io::Result<u8> {
+        let mut buf = [0; 1];
+        reader.read_exact(&mut buf)?;
+        Ok(buf[0])
+    };
+    let mut out = Vec::with_capacity(len);
+    while out.len() < len {
+        let n = byte()?;
+        match n {
+            0..=126 => {
+                let value = byte()?;
+                out.extend(std::iter::repeat_n(value, n as usize + 1));
+            }
+            127 => {
+                let count = usize::from(byte()?) << 8 | usize::from(byte()?);
+                let value = byte()?;
+                out.extend(std::iter::repeat_n(value, count));
+            }
+            128 => {
+                let count = usize::from(byte()?) << 8 | usize::from(byte()?);
+                for _ in 0..count {
+                    out.push(byte()?);
+                }
+            }
+            _ => {
+                for _ in 0..(256 - n as usize) {
+                    out.push(byte()?);
+                }
+            }
+        }
+    }
+    out.truncate(len);
+    Ok(out)
+}
+
+fn to_rgba(kind: u32, px: &[u8], colormap: &[u8]) -> Rgba<u8> {
+    let indexed = |i: u8| {
+        let i = i as usize * 3;
+        colormap
+            .get(i..i + 3)
+            .map_or([0, 0, 0], |c| [c[0], c[1], c[2]])
+    };
+    match kind {
+        0 => Rgba([px[0], px[1], px[2], 255]),
+        1 => Rgba([px[0], px[1], px[2], px[3]]),
+        2 => Rgba([px[0], px[0], px[0], 255]),
+        3 => Rgba([px[0], px[0], px[0], px[1]]),
+        4 => {
+            let [r, g, b] = indexed(px[0]);
+            Rgba([r, g, b, 255])
+        }
+        _ => {
+            let [r, g, b] = indexed(px[0]);
+            Rgba([r, g, b, px[1]])
+        }
+    }
+}
+
+/// Normal モードでの source-over
+fn blend(canvas: &mut RgbaImage, layer_pixels: &RgbaImage, layer: &Layer) {
+    let (ox, oy) = layer.offset;
+    for (x, y, src) in layer_pixels.enumerate_pixels() {
+        let (cx, cy) = (ox + x as i32, oy + y as i32);
+        if cx < 0 || cy < 0 || cx as u32 >= canvas.width() || cy as u32 >= canvas.height() {
+            continue;
+        }
+        let dst = canvas.get_pixel_mut(cx as u32, cy as u32);
+        let src_a = src[3] as f32 / 255.0 * layer.opacity;
+        let dst_a = dst[3] as f32 / 255.0;
+        let out_a = src_a + dst_a * (1.0 - src_a);
+        if out_a <= 0.0 {
+            continue;
+        }
+        for c in 0..3 {
+            let value = (src[c] as f32 * src_a + dst[c] as f32 * dst_a * (1.0 - src_a)) / out_a;
+            dst[c] = value.round() as u8;
+        }
+        dst[3] = (out_a * 255.0).round() as u8;
+    }
+}